@@ -0,0 +1,32 @@
+/*
+ * C interface to music-turtles, built as the cdylib of the crate (libmusic_turtles).
+ * Requests and results are JSON strings, and every result is either the answer or
+ * {"error": "..."}. Strings handed out have to be given back to vibelive_free.
+ * Names in the grammars are kept until the process exits, so only pass grammars from
+ * sources you trust. See src/capi.rs for what the requests look like.
+ */
+#ifndef VIBELIVE_H
+#define VIBELIVE_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Parse a grammar. Takes {"grammar": "<grammar text>"} and gives back the grammar as JSON. */
+char *vibelive_parse(const char *request);
+
+/*
+ * Rewrite a grammar from its start symbol and compose it. Takes
+ * {"grammar": "<grammar text>", "iterations": 8, "bpm": 120, "seed": 1}, where bpm and
+ * seed can be left out, and gives back the notes as a JSON array.
+ */
+char *vibelive_compose(const char *request);
+
+/* Free a string returned by vibelive_parse or vibelive_compose. NULL is ignored. */
+void vibelive_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
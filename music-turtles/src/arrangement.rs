@@ -0,0 +1,179 @@
+// Song forms built out of separately composed sections, with the jumps of written music
+// (D.C., D.S., To Coda, Fine) instead of one production that spells the whole song out.
+
+use std::fmt::Display;
+use crate::composition::{Composition, Marker};
+use crate::time::MusicTime;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub composition: Composition,
+}
+
+/// One step of the form, read from left to right like a score.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormElement {
+    Play { section: String, times: usize },
+    /// Where D.S. goes back to.
+    Segno,
+    /// Where To Coda jumps to.
+    Coda,
+    /// Jump to the coda, but only once the form has gone back with D.C. or D.S.
+    ToCoda,
+    /// Stop here, but only once the form has gone back with D.C. or D.S.
+    Fine,
+    /// Go back to the start, the first time this is reached.
+    DaCapo,
+    /// Go back to the segno, the first time this is reached.
+    DalSegno,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Arrangement {
+    pub sections: Vec<Section>,
+    pub form: Vec<FormElement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArrangementError {
+    UnknownSection(String),
+    MissingJumpTarget(String),
+    NoSections(String),
+}
+
+impl Display for ArrangementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArrangementError::UnknownSection(s) => write!(f, "Unknown section: {s}"),
+            ArrangementError::MissingJumpTarget(s) => write!(f, "Nowhere to jump to: {s}"),
+            ArrangementError::NoSections(s) => write!(f, "Nothing to play: {s}"),
+        }
+    }
+}
+
+impl Arrangement {
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// The sections in the order they are played, after following the jumps.
+    /// Like in a score, every jump back is only taken once.
+    pub fn play_order(&self) -> Result<Vec<&Section>, ArrangementError> {
+        let find = |element: FormElement, name: &str| self.form.iter()
+            .position(|e| *e == element)
+            .ok_or_else(|| ArrangementError::MissingJumpTarget(format!("{name} without a matching sign")));
+        let mut order = vec![];
+        let mut jumped = false;
+        let mut i = 0;
+        while let Some(element) = self.form.get(i) {
+            match element {
+                FormElement::Play { section, times } => {
+                    let section = self.section(section)
+                        .ok_or_else(|| ArrangementError::UnknownSection(section.clone()))?;
+                    order.extend(std::iter::repeat_n(section, *times));
+                }
+                FormElement::Segno | FormElement::Coda => {}
+                FormElement::ToCoda if jumped => {
+                    i = find(FormElement::Coda, "To Coda")?;
+                    continue;
+                }
+                FormElement::Fine if jumped => break,
+                FormElement::ToCoda | FormElement::Fine => {}
+                FormElement::DaCapo if !jumped => {
+                    jumped = true;
+                    i = 0;
+                    continue;
+                }
+                FormElement::DalSegno if !jumped => {
+                    jumped = true;
+                    i = find(FormElement::Segno, "D.S.")?;
+                    continue;
+                }
+                FormElement::DaCapo | FormElement::DalSegno => {}
+            }
+            i += 1;
+        }
+        Ok(order)
+    }
+
+    /// All sections in play order, back to back, in the time signature of the first one played.
+    /// Each one starts with a marker named after it, so playback can jump between them.
+    pub fn compose(&self) -> Result<Composition, ArrangementError> {
+        let order = self.play_order()?;
+        let Some(first) = order.first() else {
+            return Err(ArrangementError::NoSections("the form doesn't play any sections".to_string()));
+        };
+        let time_signature = first.composition.time_signature;
+        let mut composition = Composition { tracks: vec![], time_signature, markers: vec![] };
+        let mut offset = MusicTime::zero();
+        for section in order {
+            composition.markers.push(Marker { name: section.name.clone(), time: offset });
+            composition.overlay(&section.composition, offset);
+            // everything before ends by `offset`, so the end is now the end of this section
+            offset = composition.get_end().unwrap_or(offset).max(offset);
+        }
+        Ok(composition)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::arrangement::{Arrangement, ArrangementError, FormElement, Section};
+    use crate::builder::CompositionBuilder;
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::time::{Beat, MusicTime};
+
+    fn section(name: &str, note: u8) -> Section {
+        Section {
+            name: name.to_string(),
+            composition: CompositionBuilder::track(Instrument::Piano)
+                .note(Pitch(4, note), MusicTime::zero(), Beat::whole(4), Volume(50))
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn play(section: &str, times: usize) -> FormElement {
+        FormElement::Play { section: section.to_string(), times }
+    }
+
+    #[test]
+    fn test_dal_segno_al_coda() {
+        let arrangement = Arrangement {
+            sections: vec![section("intro", 0), section("verse", 2), section("chorus", 4), section("outro", 5)],
+            form: vec![
+                play("intro", 1),
+                FormElement::Segno,
+                play("verse", 2),
+                FormElement::ToCoda,
+                play("chorus", 1),
+                FormElement::DalSegno,
+                FormElement::Coda,
+                play("outro", 1),
+            ],
+        };
+        let order = arrangement.play_order().unwrap().iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, vec!["intro", "verse", "verse", "chorus", "verse", "verse", "outro"]);
+
+        let composition = arrangement.compose().unwrap();
+        assert_eq!(composition.get_end(), Some(MusicTime::measures(7)));
+        assert_eq!(composition.marker("outro"), Some(MusicTime::measures(6)));
+        assert_eq!(composition.markers.len(), 7);
+    }
+
+    #[test]
+    fn test_da_capo_al_fine() {
+        let arrangement = Arrangement {
+            sections: vec![section("a", 0), section("b", 2)],
+            form: vec![play("a", 1), FormElement::Fine, play("b", 1), FormElement::DaCapo],
+        };
+        let order = arrangement.play_order().unwrap().iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(order, vec!["a", "b", "a"]);
+
+        let broken = Arrangement { form: vec![play("c", 1)], ..arrangement.clone() };
+        assert_eq!(broken.play_order(), Err(ArrangementError::UnknownSection("c".to_string())));
+        let broken = Arrangement { form: vec![play("a", 1), FormElement::DalSegno], ..arrangement };
+        assert!(matches!(broken.play_order(), Err(ArrangementError::MissingJumpTarget(_))));
+    }
+}
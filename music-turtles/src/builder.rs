@@ -0,0 +1,182 @@
+// Builds compositions straight from Rust, for when writing a grammar would be a detour.
+
+use std::fmt::Display;
+use crate::composition::{Composition, Event, Instrument, Modulation, Pitch, Track, TrackId, Volume, MAX_VOLUME};
+use crate::time::{Beat, MusicTime, TimeSignature};
+
+/// Adds notes one track at a time, checking them all when the composition is built:
+///
+/// `CompositionBuilder::track(Instrument::Piano).note(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(50)).build()`
+#[derive(Debug, Clone)]
+pub struct CompositionBuilder {
+    time_signature: TimeSignature,
+    tracks: Vec<Track>,
+    /// Index into `tracks` of the track notes are added to.
+    current: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    ZeroDuration(String),
+    VolumeTooHigh(String),
+    InvalidPitch(String),
+    /// The beats of a start time don't fit in a measure of the time signature.
+    InvalidStart(String),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::ZeroDuration(s) => write!(f, "Note has no duration: {s}"),
+            BuildError::VolumeTooHigh(s) => write!(f, "Volume over {MAX_VOLUME}: {s}"),
+            BuildError::InvalidPitch(s) => write!(f, "Pitch out of range: {s}"),
+            BuildError::InvalidStart(s) => write!(f, "Start time not within a measure: {s}"),
+        }
+    }
+}
+
+impl CompositionBuilder {
+    /// Starts a composition in common time, adding notes to a track for `instrument`.
+    pub fn track(instrument: Instrument) -> Self {
+        CompositionBuilder {
+            time_signature: TimeSignature::common(),
+            tracks: vec![new_track(instrument)],
+            current: 0,
+        }
+    }
+
+    pub fn time_signature(mut self, time_signature: TimeSignature) -> Self {
+        self.time_signature = time_signature;
+        self
+    }
+
+    /// Adds the following notes to the track of `instrument`, which is created if there isn't one yet.
+    pub fn then_track(mut self, instrument: Instrument) -> Self {
+        self.current = match self.tracks.iter().position(|t| t.instrument == instrument) {
+            Some(i) => i,
+            None => {
+                self.tracks.push(new_track(instrument));
+                self.tracks.len() - 1
+            }
+        };
+        self
+    }
+
+    pub fn note(self, pitch: Pitch, start: MusicTime, duration: Beat, volume: Volume) -> Self {
+        self.event(Event {
+            start,
+            duration,
+            volume,
+            pitch,
+            modulation: Modulation::NONE,
+            spelling: None,
+            channel: None,
+            tag: None,
+            lyric: None,
+        })
+    }
+
+    /// Adds an event as is, for notes that need modulation or a channel.
+    pub fn event(mut self, event: Event) -> Self {
+        self.tracks[self.current].events_mut().push(event);
+        self
+    }
+
+    pub fn rest(mut self, start: MusicTime, duration: Beat) -> Self {
+        self.tracks[self.current].rests.push(Event {
+            start,
+            duration,
+            volume: Volume(0),
+            pitch: Pitch(0, 0),
+            modulation: Modulation::NONE,
+            spelling: None,
+            channel: None,
+            tag: None,
+            lyric: None,
+        });
+        self
+    }
+
+    /// The composition with its tracks sorted, or the first note that is not valid.
+    pub fn build(mut self) -> Result<Composition, BuildError> {
+        for track in &mut self.tracks {
+            for event in track.events() {
+                check_event(event, &track.instrument, self.time_signature)?;
+            }
+            for rest in &track.rests {
+                check_start(rest, &track.instrument, self.time_signature)?;
+            }
+            track.sort();
+        }
+        Ok(Composition { tracks: self.tracks, time_signature: self.time_signature, markers: vec![] })
+    }
+}
+
+fn new_track(instrument: Instrument) -> Track {
+    Track::new(TrackId::Instrument(instrument), instrument, vec![], vec![])
+}
+
+fn check_start(event: &Event, instrument: &Instrument, time_signature: TimeSignature) -> Result<(), BuildError> {
+    if event.start.1 >= Beat::whole(time_signature.0) {
+        return Err(BuildError::InvalidStart(describe(event, instrument)));
+    }
+    if event.duration == Beat::zero() {
+        return Err(BuildError::ZeroDuration(describe(event, instrument)));
+    }
+    Ok(())
+}
+
+fn check_event(event: &Event, instrument: &Instrument, time_signature: TimeSignature) -> Result<(), BuildError> {
+    check_start(event, instrument, time_signature)?;
+    if event.volume.0 > MAX_VOLUME {
+        return Err(BuildError::VolumeTooHigh(describe(event, instrument)));
+    }
+    let midi = event.pitch.0 as i32 * 12 + event.pitch.1 as i32 + 9;
+    if event.pitch.1 > 11 || !(0..=127).contains(&midi) {
+        return Err(BuildError::InvalidPitch(describe(event, instrument)));
+    }
+    Ok(())
+}
+
+fn describe(event: &Event, instrument: &Instrument) -> String {
+    format!("{:?} at {:?} on {:?}", event.pitch, event.start, instrument)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builder::{BuildError, CompositionBuilder};
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_builder() {
+        let composition = CompositionBuilder::track(Instrument::Piano)
+            .time_signature(TimeSignature(3, 4))
+            .note(Pitch(4, 2), MusicTime::beats(1), Beat::whole(1), Volume(60))
+            .note(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(60))
+            .then_track(Instrument::Bass)
+            .note(Pitch(2, 0), MusicTime::zero(), Beat::whole(3), Volume(80))
+            .then_track(Instrument::Piano)
+            .rest(MusicTime::beats(2), Beat::whole(1))
+            .build()
+            .unwrap();
+        assert_eq!(composition.time_signature, TimeSignature(3, 4));
+        assert_eq!(composition.tracks.len(), 2);
+        let piano = &composition.tracks[0];
+        assert_eq!(piano.events().iter().map(|e| e.pitch).collect::<Vec<_>>(), vec![Pitch(4, 0), Pitch(4, 2)]);
+        assert_eq!(piano.rests.len(), 1);
+        assert_eq!(composition.get_end(), Some(MusicTime::measures(1)));
+    }
+
+    #[test]
+    fn test_builder_validation() {
+        let build = |pitch, start, duration, volume| CompositionBuilder::track(Instrument::SineWave)
+            .note(pitch, start, duration, volume)
+            .build();
+        assert!(build(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(50)).is_ok());
+        assert!(matches!(build(Pitch(4, 0), MusicTime::zero(), Beat::zero(), Volume(50)), Err(BuildError::ZeroDuration(_))));
+        assert!(matches!(build(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(101)), Err(BuildError::VolumeTooHigh(_))));
+        assert!(matches!(build(Pitch(4, 12), MusicTime::zero(), Beat::whole(1), Volume(50)), Err(BuildError::InvalidPitch(_))));
+        assert!(matches!(build(Pitch(4, 0), MusicTime::beats(4), Beat::whole(1), Volume(50)), Err(BuildError::InvalidStart(_))));
+    }
+}
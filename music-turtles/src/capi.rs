@@ -0,0 +1,154 @@
+// A C ABI for hosts that can't link Rust, like a Unity visualizer or Python through ctypes.
+// Requests and results are JSON strings, and every result is either the answer or
+// `{"error": "..."}`. Strings handed out have to be given back to `vibelive_free`.
+// Names in the grammars are interned for the life of the process, so hosts shouldn't pass on
+// grammars from sources they don't trust.
+// include/vibelive.h declares these for C.
+
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::cfg::{ComposeCache, Grammar, MusicPrimitive, MusicString, Symbol};
+use crate::time::{TimeSignature, BPM};
+use crate::web::web_audio_events;
+
+#[derive(Deserialize)]
+struct ParseRequest {
+    grammar: String,
+}
+
+#[derive(Deserialize)]
+struct ComposeRequest {
+    grammar: String,
+    /// How many times the start symbol is rewritten.
+    iterations: usize,
+    #[serde(default = "default_bpm")]
+    bpm: BPM,
+    /// Seeds the choices of productions, weighted splits and `?` transforms, so the same seed
+    /// always gives the same music.
+    seed: Option<u64>,
+}
+
+fn default_bpm() -> BPM {
+    120.
+}
+
+/// Parse a grammar. Takes `{"grammar": "<grammar text>"}` and gives back the grammar as JSON.
+///
+/// # Safety
+/// `request` has to be null or a valid NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vibelive_parse(request: *const c_char) -> *mut c_char {
+    // SAFETY: passed on from the caller
+    let result = unsafe { handle(request, |request: ParseRequest| {
+        let grammar = Grammar::from_str(&request.grammar).map_err(|e| format!("{e:?}"))?;
+        serde_json::to_value(grammar).map_err(|e| e.to_string())
+    }) };
+    into_c_string(result)
+}
+
+/// Rewrite a grammar from its start symbol and compose it. Takes
+/// `{"grammar": "<grammar text>", "iterations": 8, "bpm": 120, "seed": 1}`, where `bpm` and
+/// `seed` can be left out, and gives back the notes like `web_audio_events` does.
+///
+/// # Safety
+/// `request` has to be null or a valid NUL terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vibelive_compose(request: *const c_char) -> *mut c_char {
+    // SAFETY: passed on from the caller
+    let result = unsafe { handle(request, |request: ComposeRequest| {
+        let grammar = Grammar::from_str(&request.grammar).map_err(|e| format!("{e:?}"))?;
+        let start = MusicString(vec![MusicPrimitive::Simple(Symbol::NT(grammar.start().clone()))]);
+        let string = match request.seed {
+            Some(seed) => {
+                let mut rng = StdRng::seed_from_u64(seed);
+                start.parallel_rewrite_n_with(&grammar, true, false, request.iterations, TimeSignature::common(), &mut rng)
+            }
+            None => start.parallel_rewrite_n(&grammar, true, false, request.iterations),
+        };
+        let mut cache = request.seed.map(ComposeCache::seeded).unwrap_or_default();
+        let music = string.compose_cached(TimeSignature::common(), None, &mut cache).map_err(|e| format!("{e:?}"))?;
+        serde_json::to_value(web_audio_events(&music, request.bpm)).map_err(|e| e.to_string())
+    }) };
+    into_c_string(result)
+}
+
+/// Free a string returned by `vibelive_parse` or `vibelive_compose`. Null is ignored.
+///
+/// # Safety
+/// `string` has to be null or come from one of those, and not be freed already.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn vibelive_free(string: *mut c_char) {
+    if !string.is_null() {
+        // SAFETY: made by `CString::into_raw` in `into_c_string`
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Read the request, answer it, and make sure nothing panics across the C boundary.
+///
+/// # Safety
+/// `request` has to be null or a valid NUL terminated string.
+unsafe fn handle<R: DeserializeOwned>(request: *const c_char, answer: impl FnOnce(R) -> Result<Value, String>) -> Value {
+    if request.is_null() {
+        return json!({ "error": "request is null" });
+    }
+    // SAFETY: checked for null, the rest is up to the caller
+    let request = unsafe { CStr::from_ptr(request) };
+    let result = request.to_str()
+        .map_err(|e| e.to_string())
+        .and_then(|r| serde_json::from_str(r).map_err(|e| e.to_string()))
+        .and_then(|r| catch_unwind(AssertUnwindSafe(|| answer(r))).unwrap_or_else(|_| Err("panicked".to_string())));
+    result.unwrap_or_else(|e| json!({ "error": e }))
+}
+
+fn into_c_string(value: Value) -> *mut c_char {
+    // JSON escapes NUL inside strings, so there can't be one in the text
+    CString::new(value.to_string()).unwrap().into_raw()
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::{CStr, CString};
+    use serde_json::{json, Value};
+    use crate::capi::{vibelive_compose, vibelive_free, vibelive_parse};
+
+    fn call(f: unsafe extern "C" fn(*const std::ffi::c_char) -> *mut std::ffi::c_char, request: Value) -> Value {
+        let request = CString::new(request.to_string()).unwrap();
+        unsafe {
+            let result = f(request.as_ptr());
+            let value = serde_json::from_str(CStr::from_ptr(result).to_str().unwrap()).unwrap();
+            vibelive_free(result);
+            value
+        }
+    }
+
+    #[test]
+    fn test_capi() {
+        let grammar = "start S\nS = A A\nA = :c :e";
+        let parsed = call(vibelive_parse, json!({ "grammar": grammar }));
+        assert!(parsed.get("productions").is_some(), "{parsed}");
+        assert!(call(vibelive_parse, json!({ "grammar": "start S\nS = :c <" })).get("error").is_some());
+        assert_eq!(call(vibelive_parse, json!({})).get("error").and_then(Value::as_str).map(|e| e.contains("grammar")), Some(true));
+
+        let composed = call(vibelive_compose, json!({ "grammar": grammar, "iterations": 2, "bpm": 60, "seed": 1 }));
+        let starts = composed.as_array().unwrap().iter().map(|e| e["start_s"].as_f64().unwrap()).collect::<Vec<_>>();
+        assert_eq!(starts, vec![0., 1., 2., 3.]);
+
+        // the same seed picks the same productions
+        let choices = json!({ "grammar": "start S\nS = A A A A A A A A\nA = :c\nA = :d\nA = :e", "iterations": 2, "seed": 9 });
+        let frequencies = |composed: Value| composed.as_array().unwrap().iter().map(|e| e["freq"].clone()).collect::<Vec<_>>();
+        let first = frequencies(call(vibelive_compose, choices.clone()));
+        assert_eq!(first, frequencies(call(vibelive_compose, choices)));
+        assert!(first.iter().any(|f| *f != first[0]), "{first:?}");
+        unsafe {
+            assert!(CStr::from_ptr(vibelive_parse(std::ptr::null())).to_str().unwrap().contains("null"));
+            vibelive_free(std::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// Recording a performer playing along, from the computer's audio input, so it can be saved
+// next to what was played. Everything recorded is placed on the playback clock to line it up.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rodio::cpal;
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{FromSample, Sample, SizedSample};
+use rodio::Source;
+use crate::clock::Clock;
+use crate::player::Player;
+use crate::time::Seconds;
+
+/// Audio recorded from one source, placed on the playback clock.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Take {
+    /// When on the playback clock the first sample was recorded.
+    pub start: Seconds,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Interleaved, like in a WAV file.
+    pub samples: Vec<f32>,
+}
+
+/// Records the default audio input until it is stopped.
+/// The stream stops when this is dropped, so it has to stay on the thread that started it.
+pub struct InputRecorder {
+    _stream: cpal::Stream,
+    take: Arc<Mutex<Take>>,
+    /// Input waiting to be played back by `Monitor`, if the input is being monitored.
+    monitored: Arc<Mutex<Option<VecDeque<f32>>>>,
+}
+
+/// Plays the input live, for hearing yourself over the music.
+pub struct Monitor {
+    queue: Arc<Mutex<Option<VecDeque<f32>>>>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureError {
+    NoInputDevice(String),
+    Stream(String),
+}
+
+impl Display for CaptureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureError::NoInputDevice(s) => write!(f, "No audio input: {s}"),
+            CaptureError::Stream(s) => write!(f, "Audio input failed: {s}"),
+        }
+    }
+}
+
+/// Most input the monitor holds on to. Anything older is dropped, so a monitor that fell behind
+/// catches up instead of lagging further and further.
+const MONITOR_BUFFER: Seconds = 0.1;
+
+impl InputRecorder {
+    /// Start recording the default input, from now on `clock`.
+    pub fn start(clock: &impl Clock) -> Result<Self, CaptureError> {
+        let device = cpal::default_host().default_input_device()
+            .ok_or_else(|| CaptureError::NoInputDevice("no default input device".to_string()))?;
+        let config = device.default_input_config()
+            .map_err(|e| CaptureError::NoInputDevice(e.to_string()))?;
+        let take = Arc::new(Mutex::new(Take {
+            start: clock.elapsed(),
+            sample_rate: config.sample_rate().0,
+            channels: config.channels(),
+            samples: vec![],
+        }));
+        let monitored = Arc::new(Mutex::new(None));
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), &take, &monitored),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), &take, &monitored),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), &take, &monitored),
+            format => Err(CaptureError::Stream(format!("unsupported sample format {format}"))),
+        }?;
+        stream.play().map_err(|e| CaptureError::Stream(e.to_string()))?;
+        Ok(InputRecorder { _stream: stream, take, monitored })
+    }
+
+    /// Play the input through `player` as it comes in.
+    pub fn monitor(&self, player: &Player) {
+        let take = self.take.lock().unwrap();
+        *self.monitored.lock().unwrap() = Some(VecDeque::new());
+        player.play(Monitor {
+            queue: self.monitored.clone(),
+            channels: take.channels,
+            sample_rate: take.sample_rate,
+        });
+    }
+
+    /// Stop recording and monitoring, and hand over what was recorded.
+    pub fn stop(self) -> Take {
+        *self.monitored.lock().unwrap() = None;
+        std::mem::take(&mut *self.take.lock().unwrap())
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    take: &Arc<Mutex<Take>>,
+    monitored: &Arc<Mutex<Option<VecDeque<f32>>>>,
+) -> Result<cpal::Stream, CaptureError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let (take, monitored) = (take.clone(), monitored.clone());
+    let max_monitored = (MONITOR_BUFFER * config.sample_rate.0 as Seconds) as usize * config.channels as usize;
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let samples = data.iter().map(|s| f32::from_sample(*s));
+            take.lock().unwrap().samples.extend(samples.clone());
+            if let Some(queue) = monitored.lock().unwrap().as_mut() {
+                queue.extend(samples);
+                let excess = queue.len().saturating_sub(max_monitored);
+                queue.drain(..excess);
+            }
+        },
+        |e| warn!("Audio input: {e}"),
+        None,
+    ).map_err(|e| CaptureError::Stream(e.to_string()))
+}
+
+impl Iterator for Monitor {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        // silence while waiting for more input, until the recorder stops
+        let mut queue = self.queue.lock().unwrap();
+        Some(queue.as_mut()?.pop_front().unwrap_or(0.))
+    }
+}
+
+impl Source for Monitor {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Take {
+    /// A recording made with `Player::start_recording`, which starts at zero on the playback clock.
+    pub fn from_wav(path: impl AsRef<Path>) -> Result<Self, hound::Error> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+            SampleFormat::Int => {
+                let full_scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / full_scale))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        Ok(Take { start: 0., sample_rate: spec.sample_rate, channels: spec.channels, samples })
+    }
+
+    /// The take mixed down to one channel and resampled to `sample_rate`.
+    fn mono_at(&self, sample_rate: u32) -> Vec<f32> {
+        let channels = self.channels.max(1) as usize;
+        let mono = self.samples.chunks(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect::<Vec<_>>();
+        if self.sample_rate == sample_rate || mono.is_empty() {
+            return mono;
+        }
+        let step = self.sample_rate as f64 / sample_rate as f64;
+        let length = (mono.len() as f64 / step) as usize;
+        (0..length).map(|i| {
+            let position = i as f64 * step;
+            let before = position as usize;
+            let after = (before + 1).min(mono.len() - 1);
+            let along = (position - before as f64) as f32;
+            mono[before] * (1. - along) + mono[after] * along
+        }).collect()
+    }
+}
+
+/// Write each take to its own channel of one WAV file, lined up by when they were recorded.
+/// The file starts with the take that started first.
+pub fn write_multitrack(path: impl AsRef<Path>, takes: &[Take], sample_rate: u32) -> Result<(), hound::Error> {
+    let first_start = takes.iter().map(|t| t.start).min_by(f32::total_cmp).unwrap_or(0.);
+    let tracks = takes.iter().map(|take| {
+        let delay = ((take.start - first_start) * sample_rate as Seconds).round() as usize;
+        let mut track = vec![0.; delay];
+        track.extend(take.mono_at(sample_rate));
+        track
+    }).collect::<Vec<_>>();
+    let spec = WavSpec {
+        channels: tracks.len().max(1) as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(path, spec)?;
+    let length = tracks.iter().map(Vec::len).max().unwrap_or(0);
+    for i in 0..length {
+        for track in &tracks {
+            writer.write_sample(track.get(i).copied().unwrap_or(0.))?;
+        }
+    }
+    writer.finalize()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::capture::{write_multitrack, Take};
+
+    #[test]
+    fn test_multitrack_lines_up_takes() {
+        let path = std::env::temp_dir().join("music-turtles-multitrack-test.wav");
+        // the played music from the start, in stereo
+        let music = Take { start: 0., sample_rate: 10, channels: 2, samples: vec![1., 0., 1., 0., 1., 0., 1., 0.] };
+        // a guitar coming in 0.2s later, recorded at twice the rate
+        let guitar = Take { start: 0.2, sample_rate: 20, channels: 1, samples: vec![0.5, 0.5, 0.25, 0.25] };
+        write_multitrack(&path, &[music, guitar], 10).unwrap();
+        let recorded = Take::from_wav(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded.channels, 2);
+        let (music, guitar): (Vec<f32>, Vec<f32>) = recorded.samples.chunks(2).map(|f| (f[0], f[1])).unzip();
+        assert_eq!(music, vec![0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(guitar, vec![0., 0., 0.5, 0.25]);
+    }
+}
@@ -0,0 +1,127 @@
+// Completion candidates for the word being typed in grammar text, for editors.
+// The text is usually half written, so it is read line by line instead of scanned as a grammar.
+
+use std::collections::BTreeSet;
+use crate::composition::Instrument;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CompletionKind {
+    NonTerminal,
+    Instrument,
+    MetaControl,
+    Transform,
+    /// A stack of transforms named with `def`.
+    Macro,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Completion {
+    /// Replaces the text from `Completions::start` up to the cursor.
+    pub text: String,
+    pub kind: CompletionKind,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Completions {
+    /// Byte offset where the word being completed starts.
+    pub start: usize,
+    pub candidates: Vec<Completion>,
+}
+
+const META_CONTROLS: [&str; 9] = ["::i=", "::v=", "::vib=", "::trem=", "::d=", "::ch=", "::tag=", "::mark=", "::accent="];
+const TRANSFORMS: [&str; 9] = ["x", "T", ">>", "<<", "v*", "M", "st", "?", "bar"];
+
+/// What could go where the word before byte offset `cursor` in `text` is.
+pub fn complete(text: &str, cursor: usize) -> Completions {
+    let before = &text[..cursor.min(text.len())];
+    let start = before.rfind(|c: char| c.is_whitespace() || "[]{}|".contains(c)).map_or(0, |i| i + 1);
+    let word = &before[start..];
+    let candidates = if let Some(instrument) = word.strip_prefix("::i=") {
+        Instrument::str_values()
+            .filter(|(_, name)| name.to_ascii_lowercase().starts_with(&instrument.to_ascii_lowercase()))
+            .map(|(_, name)| completion(format!("::i={name}"), CompletionKind::Instrument))
+            .collect()
+    } else if word.starts_with("::") {
+        META_CONTROLS.iter()
+            .filter(|m| m.starts_with(word))
+            .map(|m| completion(m.to_string(), CompletionKind::MetaControl))
+            .collect()
+    } else if word.starts_with(':') {
+        // notes are too many to list
+        vec![]
+    } else if in_transform_list(before, start) {
+        let macros = definitions(text, true).into_iter()
+            .filter(|name| name.starts_with(word))
+            .map(|name| completion(name, CompletionKind::Macro));
+        TRANSFORMS.iter()
+            .filter(|t| t.starts_with(word))
+            .map(|t| completion(t.to_string(), CompletionKind::Transform))
+            .chain(macros)
+            .collect()
+    } else {
+        definitions(text, false).into_iter()
+            .filter(|name| name.starts_with(word) && name != word)
+            .map(|name| completion(name, CompletionKind::NonTerminal))
+            .collect()
+    };
+    Completions { start, candidates }
+}
+
+fn completion(text: String, kind: CompletionKind) -> Completion {
+    Completion { text, kind }
+}
+
+/// Whether the word starting at `start` is in the first brackets of `[x2][...]`, where the
+/// transforms go, rather than in the brackets of the music they apply to.
+fn in_transform_list(before: &str, start: usize) -> bool {
+    let head = &before[..start];
+    match head.rfind(['[', ']']) {
+        Some(open) if head[open..].starts_with('[') => !head[..open].ends_with(']'),
+        _ => false,
+    }
+}
+
+/// Names given productions (and the start symbol), or with `macros` the names of `def`s.
+fn definitions(text: &str, macros: bool) -> BTreeSet<String> {
+    text.lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            if let Some(start) = line.strip_prefix("start ") {
+                return (!macros).then(|| start.trim().to_string());
+            }
+            let (left, _right) = line.split_once('=')?;
+            let (is_macro, name) = match left.strip_prefix("def ") {
+                Some(name) => (true, name),
+                None => (false, left),
+            };
+            // a guard can follow the name, e.g. `S (depth < 3) = ...`
+            let name = name.split_whitespace().next()?;
+            (is_macro == macros && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                .then(|| name.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::complete::{complete, CompletionKind};
+
+    #[test]
+    fn test_complete() {
+        let text = "start Song\ndef swing = >>2 T1\nSong = Verse Ve\nVerse (depth < 2) = ::i=pi :c [x2 sw][Chorus]\nChorus = ::t";
+        let texts = |cursor: usize| complete(text, cursor).candidates.into_iter().map(|c| (c.text, c.kind)).collect::<Vec<_>>();
+        let after = |s: &str| text.find(s).unwrap() + s.len();
+
+        assert_eq!(texts(after("Verse Ve")), vec![("Verse".to_string(), CompletionKind::NonTerminal)]);
+        assert_eq!(complete(text, after("Verse Ve")).start, after("Verse "));
+        assert_eq!(texts(after("::i=pi")), vec![("::i=Piano".to_string(), CompletionKind::Instrument)]);
+        assert_eq!(texts(after("::t")), vec![
+            ("::trem=".to_string(), CompletionKind::MetaControl),
+            ("::tag=".to_string(), CompletionKind::MetaControl),
+        ]);
+        assert_eq!(texts(after("x2 sw")), vec![("swing".to_string(), CompletionKind::Macro)]);
+        // inside the music of a transform it's a non-terminal again
+        assert_eq!(texts(after("[Ch")), vec![("Chorus".to_string(), CompletionKind::NonTerminal)]);
+        assert!(texts(after(":c")).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+// Grammars stored as TOML, for keeping them in config repositories. The JSON form of nested
+// primitives is miserable to edit by hand, so the music in here is written the way it is in a
+// grammar file. The field names are part of the file format and have to stay as they are.
+// There is no YAML form, so `.yaml` and `.yml` paths are refused instead of being taken for grammar files.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::cfg::Grammar;
+use crate::cfg::scan::ScanError;
+use crate::error::Error;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrammarDocument {
+    pub start: String,
+    /// Transforms by name, written as in a `def`, e.g. `"v*0.6 >>1"`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub macros: BTreeMap<String, String>,
+    #[serde(default)]
+    pub productions: Vec<ProductionDocument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProductionDocument {
+    pub name: String,
+    /// As written in front of the body in a grammar file, e.g. `"(depth < 3)"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guard: Option<String>,
+    pub body: String,
+}
+
+impl GrammarDocument {
+    pub fn from_grammar(grammar: &Grammar) -> Self {
+        let macros = grammar.macros.iter()
+            .map(|(name, transforms)| {
+                let transforms = transforms.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+                (name.clone(), transforms.join(" "))
+            })
+            .collect();
+        let productions = grammar.productions.iter()
+            .map(|production| ProductionDocument {
+                name: production.0.to_string(),
+                guard: production.2.map(|guard| guard.to_string()),
+                body: production.1.to_string().trim().to_string(),
+            })
+            .collect();
+        GrammarDocument { start: grammar.start.to_string(), macros, productions }
+    }
+
+    /// The grammar file this stands for.
+    pub fn to_source(&self) -> String {
+        let mut lines = vec![format!("start {}", self.start)];
+        lines.extend(self.macros.iter().map(|(name, transforms)| format!("def {name} = [{transforms}]")));
+        lines.extend(self.productions.iter().map(|p| match &p.guard {
+            Some(guard) => format!("{} = {guard} {}", p.name, p.body),
+            None => format!("{} = {}", p.name, p.body),
+        }));
+        lines.join("\n")
+    }
+}
+
+impl Grammar {
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(&GrammarDocument::from_grammar(self)).expect("grammar documents are plain tables and strings")
+    }
+
+    pub fn from_toml(text: &str) -> Result<Grammar, ScanError> {
+        let document: GrammarDocument = toml::from_str(text)
+            .map_err(|e| ScanError::Generic(format!("Invalid grammar TOML: {e}")))?;
+        document.to_source().parse()
+    }
+
+    /// Read the grammar at `path`, as TOML if it ends in `.toml` and as a grammar file otherwise.
+    /// YAML paths are an error.
+    pub fn load(path: impl AsRef<Path>) -> Result<Grammar, Error> {
+        let path = path.as_ref();
+        refuse_yaml(path)?;
+        let text = std::fs::read_to_string(path)?;
+        if is_toml(path) {
+            Ok(Grammar::from_toml(&text)?)
+        } else {
+            Ok(text.parse()?)
+        }
+    }
+
+    /// Write the grammar to `path`, as TOML if it ends in `.toml` and as a grammar file otherwise.
+    /// YAML paths are an error, and nothing is written.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        refuse_yaml(path)?;
+        let text = if is_toml(path) {
+            self.to_toml()
+        } else {
+            GrammarDocument::from_grammar(self).to_source()
+        };
+        Ok(std::fs::write(path, text)?)
+    }
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("toml"))
+}
+
+fn refuse_yaml(path: &Path) -> Result<(), Error> {
+    if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("yaml") || e.eq_ignore_ascii_case("yml")) {
+        let message = format!("{} is YAML, but grammars can only be stored as TOML or grammar files", path.display());
+        return Err(std::io::Error::new(ErrorKind::Unsupported, message).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use std::str::FromStr;
+    use crate::cfg::Grammar;
+    use crate::cfg::document::GrammarDocument;
+    use crate::error::Error;
+
+    #[test]
+    fn test_toml_round_trip() {
+        let text = "start S\ndef soft = [v*0.6 >>2]\nS =(depth<3) [soft][S S]\nS = {:c | 2 :d'}pad [x2 T5][:e<1/2> ::i=piano]";
+        let grammar = Grammar::from_str(text).unwrap();
+        let toml = grammar.to_toml();
+        let document: GrammarDocument = toml::from_str(&toml).unwrap();
+        assert_eq!(document.productions[0].guard.as_deref(), Some("(depth<3)"));
+        assert_eq!(document.macros["soft"], "v*0.6 >>2");
+        let back = Grammar::from_toml(&toml).unwrap();
+        assert_eq!(back.productions, grammar.productions);
+        assert_eq!(back.start, grammar.start);
+
+        for entry in std::fs::read_dir("../data").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "mtx") {
+                let grammar = Grammar::load(&path).unwrap();
+                assert_eq!(Grammar::from_toml(&grammar.to_toml()).unwrap().productions, grammar.productions, "{}", path.display());
+            }
+        }
+        assert!(Grammar::from_toml("start = \"S\"\nproduction = []").is_err());
+    }
+
+    #[test]
+    fn test_yaml_is_refused() {
+        let grammar = Grammar::from_str("start S\nS = :c :d").unwrap();
+        let path = std::env::temp_dir().join(format!("music-turtles-grammar-{}.yaml", std::process::id()));
+        assert!(matches!(grammar.save(&path), Err(Error::Io(e)) if e.kind() == ErrorKind::Unsupported));
+        // not written as a grammar file under a YAML name
+        assert!(!path.exists());
+        assert!(matches!(Grammar::load(path.with_extension("YML")), Err(Error::Io(e)) if e.kind() == ErrorKind::Unsupported));
+    }
+}
@@ -0,0 +1,280 @@
+// Works out how long each non-terminal can end up being before anything is rewritten,
+// so a split that can never line up is found without composing it.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use num::rational::Ratio;
+use crate::cfg::{Grammar, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Production, SplitMode, SplitPolicy, Symbol, Terminal};
+use crate::cfg::script::ScriptTarget;
+use crate::time::{Beat, BeatUnit, MusicTime, TimeSignature};
+
+/// Shortest and longest duration something can have, in beats.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DurationRange {
+    pub min: Beat,
+    /// `None` if it can get arbitrarily long, by recursing.
+    pub max: Option<Beat>,
+}
+
+/// A split that has to have branches of the same length, but whose branches can never be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnequalSplit {
+    pub non_terminal: NonTerminal,
+    /// Which of the productions of `non_terminal` the split is in, counted from 0.
+    pub production: usize,
+    pub branches: Vec<DurationRange>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Durations {
+    /// Non-terminals that can't finish expanding are left out.
+    pub non_terminals: HashMap<NonTerminal, DurationRange>,
+    pub unequal_splits: Vec<UnequalSplit>,
+}
+
+impl DurationRange {
+    pub fn exactly(beats: Beat) -> Self {
+        DurationRange { min: beats, max: Some(beats) }
+    }
+
+    fn then(self, other: DurationRange) -> Self {
+        DurationRange {
+            min: self.min + other.min,
+            max: self.max.zip(other.max).map(|(a, b)| a + b),
+        }
+    }
+
+    fn either(self, other: DurationRange) -> Self {
+        DurationRange {
+            min: self.min.min(other.min),
+            max: self.max.zip(other.max).map(|(a, b)| a.max(b)),
+        }
+    }
+
+    fn scale(self, factor: Ratio<BeatUnit>, time_signature: TimeSignature) -> Self {
+        let scale = |b: Beat| (b.as_music_time(time_signature).with(time_signature) * factor).total_beats();
+        DurationRange { min: scale(self.min), max: self.max.map(scale) }
+    }
+
+    /// Whether the ranges have no duration in common.
+    fn disjoint(ranges: &[DurationRange]) -> bool {
+        let highest_min = ranges.iter().map(|r| r.min).max();
+        let lowest_max = ranges.iter().filter_map(|r| r.max).min();
+        matches!((highest_min, lowest_max), (Some(min), Some(max)) if min > max)
+    }
+}
+
+impl Display for DurationRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let beats = |b: Beat| if b.denominator() == 1 {
+            b.numerator().to_string()
+        } else {
+            format!("{}/{}", b.numerator(), b.denominator())
+        };
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{} beats", beats(max)),
+            Some(max) => write!(f, "{} to {} beats", beats(self.min), beats(max)),
+            None => write!(f, "at least {} beats", beats(self.min)),
+        }
+    }
+}
+
+impl Display for UnequalSplit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let branches = self.branches.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", ");
+        write!(f, "{} (production {}): split branches can never be the same length ({branches})",
+               self.non_terminal.to_string(), self.production + 1)
+    }
+}
+
+impl Grammar {
+    /// How long each non-terminal can be once fully expanded, and the splits that will fail to compose.
+    /// Every production is assumed to start with the default duration of one beat.
+    pub fn durations(&self, time_signature: TimeSignature) -> Durations {
+        let ranges = self.non_terminal_ranges(time_signature);
+        let mut unequal_splits = vec![];
+        let mut index: HashMap<&NonTerminal, usize> = HashMap::new();
+        for Production(nt, ms, _guard) in &self.productions {
+            let production = index.entry(nt).or_default();
+            string_range(ms, &ranges, time_signature, MusicTime::beats(1), &mut |branches| {
+                unequal_splits.push(UnequalSplit { non_terminal: nt.clone(), production: *production, branches });
+            });
+            *production += 1;
+        }
+        Durations { non_terminals: ranges, unequal_splits }
+    }
+
+    /// How long each non-terminal that can finish expanding can be.
+    pub(crate) fn non_terminal_ranges(&self, time_signature: TimeSignature) -> HashMap<NonTerminal, DurationRange> {
+        let mut ranges: HashMap<NonTerminal, DurationRange> = HashMap::new();
+        // a shortest expansion never needs more rounds than there are non-terminals,
+        // so anything still growing after that is recursive and has no upper bound
+        let rounds = self.productions.len() + 2;
+        for round in 0..rounds * 2 {
+            let mut next: HashMap<NonTerminal, DurationRange> = HashMap::new();
+            for Production(nt, ms, _guard) in &self.productions {
+                if let Some(range) = string_range(ms, &ranges, time_signature, MusicTime::beats(1), &mut |_| {}) {
+                    next.entry(nt.clone())
+                        .and_modify(|r| *r = r.either(range))
+                        .or_insert(range);
+                }
+            }
+            if next == ranges {
+                break;
+            }
+            if round >= rounds {
+                for (nt, range) in next.iter_mut() {
+                    if ranges.get(nt).is_some_and(|old| old.max != range.max) {
+                        range.max = None;
+                    }
+                }
+            }
+            ranges = next;
+        }
+        ranges
+    }
+}
+
+/// Duration of `music_string` starting with `default_duration` for notes without their own,
+/// or `None` if it uses a non-terminal that has no range yet.
+/// `unequal` is called with the branches of every strict split that can't line up.
+fn string_range(
+    music_string: &MusicString,
+    ranges: &HashMap<NonTerminal, DurationRange>,
+    time_signature: TimeSignature,
+    mut default_duration: MusicTime,
+    unequal: &mut dyn FnMut(Vec<DurationRange>),
+) -> Option<DurationRange> {
+    let mut total = DurationRange::exactly(Beat::zero());
+    let mut known = true;
+    for mp in &music_string.0 {
+        match primitive_range(mp, ranges, time_signature, &mut default_duration, unequal) {
+            Some(range) => total = total.then(range),
+            None => known = false,
+        }
+    }
+    known.then_some(total)
+}
+
+/// Duration of `mp`, or `None` if it uses a non-terminal that has no range yet.
+/// `default_duration` is the one for notes without their own, and changed by `::d=`.
+pub(crate) fn primitive_range(
+    mp: &MusicPrimitive,
+    ranges: &HashMap<NonTerminal, DurationRange>,
+    time_signature: TimeSignature,
+    default_duration: &mut MusicTime,
+    unequal: &mut dyn FnMut(Vec<DurationRange>),
+) -> Option<DurationRange> {
+    let nested = |ms: &MusicString, unequal: &mut dyn FnMut(Vec<DurationRange>)| string_range(ms, ranges, time_signature, *default_duration, unequal);
+    let range = match mp {
+        MusicPrimitive::Simple(Symbol::T(Terminal::Music { duration, .. })) => {
+            DurationRange::exactly(duration.unwrap_or(*default_duration).with(time_signature).total_beats())
+        }
+        MusicPrimitive::Simple(Symbol::T(Terminal::Meta(control))) => {
+            if let MetaControl::DefaultDuration(d) = control {
+                *default_duration = *d;
+            }
+            DurationRange::exactly(Beat::zero())
+        }
+        MusicPrimitive::Simple(Symbol::NT(nt)) => *ranges.get(nt)?,
+        MusicPrimitive::Split { branches, policy, mode } => {
+            let branch_ranges = branches.iter()
+                .map(|b| nested(b, unequal))
+                .collect::<Option<Vec<_>>>()?;
+            if *mode == SplitMode::Parallel && *policy == SplitPolicy::Strict && DurationRange::disjoint(&branch_ranges) {
+                unequal(branch_ranges.clone());
+            }
+            let mut branch_ranges = branch_ranges.into_iter();
+            let Some(first) = branch_ranges.next() else {
+                return Some(DurationRange::exactly(Beat::zero()));
+            };
+            match (mode, policy) {
+                (SplitMode::Parallel, SplitPolicy::Truncate) => branch_ranges.fold(first, |a, b| DurationRange {
+                    min: a.min.min(b.min),
+                    max: match (a.max, b.max) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    },
+                }),
+                (SplitMode::Parallel, _) => branch_ranges.fold(first, |a, b| DurationRange {
+                    min: a.min.max(b.min),
+                    max: a.max.zip(b.max).map(|(a, b)| a.max(b)),
+                }),
+                (SplitMode::Choice { .. }, _) => branch_ranges.fold(first, DurationRange::either),
+            }
+        }
+        MusicPrimitive::Volta { endings } => match endings.first() {
+            Some(ending) => nested(ending, unequal)?,
+            None => DurationRange::exactly(Beat::zero()),
+        },
+        #[allow(deprecated)]
+        MusicPrimitive::Repeat { num, content } => {
+            nested(content, unequal)?.scale(Ratio::from_integer(*num as BeatUnit), time_signature)
+        }
+        MusicPrimitive::Transform { transform, content } => {
+            let range = nested(content, unequal)?;
+            match transform {
+                MusicTransform::Repeat { num } => range.scale(Ratio::from_integer(*num as BeatUnit), time_signature),
+                MusicTransform::Compression { factor } => {
+                    let factor = Ratio::new(factor.0.numer().unsigned_abs() as BeatUnit, factor.0.denom().unsigned_abs() as BeatUnit);
+                    range.scale(factor, time_signature)
+                }
+                MusicTransform::AlignToBar => {
+                    // rounded up to whole measures, and it may have to wait up to a measure to start
+                    let measure = Beat::whole(time_signature.0);
+                    let round_up = |b: Beat| {
+                        let MusicTime(measures, beats) = b.as_music_time(time_signature);
+                        let measures = if beats == Beat::zero() { measures } else { measures + 1 };
+                        Beat::whole(measures * time_signature.0)
+                    };
+                    DurationRange {
+                        min: round_up(range.min),
+                        max: range.max.map(|max| round_up(max) + measure),
+                    }
+                }
+                // scripts could come to anything, so they can leave it out or repeat it any number of times
+                MusicTransform::Script { target: ScriptTarget::Repeat, .. } => DurationRange { min: Beat::zero(), max: None },
+                MusicTransform::Script { target: ScriptTarget::When, .. } => DurationRange { min: Beat::zero(), ..range },
+                _ => range,
+            }
+        }
+    };
+    Some(range)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::durations::{DurationRange, UnequalSplit};
+    use crate::cfg::{Grammar, NonTerminal};
+    use crate::time::{Beat, TimeSignature};
+
+    #[test]
+    fn test_durations() {
+        let grammar = Grammar::from_str(
+            "start S\n\
+            S = {A | B} C\n\
+            A = :c<2> :d\n\
+            B = [x2][::d=1/2 :e :f]\n\
+            C = C :g\n\
+            C = :_<1/2>\n\
+            D = D\n\
+            E = ::d=1/2 [x2][:e :f]"
+        ).unwrap();
+        let durations = grammar.durations(TimeSignature::common());
+        let range = |nt: &str| durations.non_terminals.get(&NonTerminal::Custom(nt.into())).copied();
+        assert_eq!(range("A"), Some(DurationRange::exactly(Beat::whole(3))));
+        assert_eq!(range("B"), Some(DurationRange::exactly(Beat::whole(2))));
+        assert_eq!(range("C"), Some(DurationRange { min: Beat::new(1, 2), max: None }));
+        assert_eq!(range("S"), Some(DurationRange { min: Beat::new(7, 2), max: None }));
+        assert_eq!(range("D"), None);
+        assert_eq!(range("E"), Some(DurationRange::exactly(Beat::whole(2))));
+        assert_eq!(durations.unequal_splits, vec![UnequalSplit {
+            non_terminal: NonTerminal::Custom("S".into()),
+            production: 0,
+            branches: vec![DurationRange::exactly(Beat::whole(3)), DurationRange::exactly(Beat::whole(2))],
+        }]);
+        assert_eq!(durations.unequal_splits[0].to_string(),
+                   "S (production 1): split branches can never be the same length (3 beats, 2 beats)");
+    }
+}
@@ -0,0 +1,195 @@
+// Turning flat music back into grammar form, for starting a grammar from something composed,
+// recorded or imported. The music is cut into bars, bars played several times in a row become
+// `[xN]` transforms, and runs of bars that come back later become non-terminals, longest first.
+// Composing the grammar gives the music back, except that notes held over a barline stop there.
+
+use std::collections::HashMap;
+use crate::cfg::{Grammar, MusicPrimitive, MusicString, MusicTransform, NonTerminal, SplitMode, SplitPolicy, Symbol};
+use crate::composition::Composition;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Item {
+    /// One of the distinct bars, by index.
+    Bar(usize),
+    /// One of the motifs found so far, by index.
+    Motif(usize),
+    Repeat(usize, Vec<Item>),
+}
+
+/// Replace runs of the same items over and over by a repeat, the longest run first at every
+/// position and the shortest unit that makes it up.
+fn collapse_repeats(items: &[Item]) -> Vec<Item> {
+    let mut collapsed = vec![];
+    let mut i = 0;
+    while i < items.len() {
+        let mut best: Option<(usize, usize)> = None;
+        for unit in 1..=(items.len() - i) / 2 {
+            let copies = 1 + items[i + unit..].chunks_exact(unit)
+                .take_while(|chunk| *chunk == &items[i..i + unit])
+                .count();
+            if copies > 1 && best.is_none_or(|(u, c)| unit * copies > u * c) {
+                best = Some((unit, copies));
+            }
+        }
+        match best {
+            Some((unit, copies)) => {
+                collapsed.push(Item::Repeat(copies, collapse_repeats(&items[i..i + unit])));
+                i += unit * copies;
+            }
+            None => {
+                collapsed.push(items[i].clone());
+                i += 1;
+            }
+        }
+    }
+    collapsed
+}
+
+/// Where `pattern` is in `items`, left to right without overlapping.
+fn occurrences(items: &[Item], pattern: &[Item]) -> Vec<usize> {
+    let mut found = vec![];
+    let mut i = 0;
+    while i + pattern.len() <= items.len() {
+        if items[i..i + pattern.len()] == *pattern {
+            found.push(i);
+            i += pattern.len();
+        } else {
+            i += 1;
+        }
+    }
+    found
+}
+
+/// The longest run of items that comes up at least twice in `sequences` in all, first found
+/// first. A single motif on its own is already named, so it doesn't count.
+fn longest_repeated(sequences: &[Vec<Item>]) -> Option<Vec<Item>> {
+    let longest = sequences.iter().map(Vec::len).max().unwrap_or(0);
+    for len in (1..=longest / 2).rev().chain(if longest == 1 { Some(1) } else { None }) {
+        for sequence in sequences {
+            for pattern in sequence.windows(len) {
+                if let [Item::Motif(_)] = pattern {
+                    continue;
+                }
+                let count = sequences.iter().map(|s| occurrences(s, pattern).len()).sum::<usize>();
+                if count > 1 {
+                    return Some(pattern.to_vec());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn replace(items: &[Item], pattern: &[Item], with: &Item) -> Vec<Item> {
+    let mut replaced = vec![];
+    let mut last = 0;
+    for start in occurrences(items, pattern) {
+        replaced.extend_from_slice(&items[last..start]);
+        replaced.push(with.clone());
+        last = start + pattern.len();
+    }
+    replaced.extend_from_slice(&items[last..]);
+    replaced
+}
+
+fn motif_name(motif: usize) -> NonTerminal {
+    NonTerminal::Custom(format!("Motif-{}", motif + 1).into())
+}
+
+fn to_music(items: &[Item], bars: &[MusicString]) -> MusicString {
+    let mut string = vec![];
+    for item in items {
+        match item {
+            Item::Bar(bar) => string.extend(bars[*bar].0.iter().cloned()),
+            Item::Motif(motif) => string.push(MusicPrimitive::Simple(Symbol::NT(motif_name(*motif)))),
+            Item::Repeat(num, content) => string.push(MusicPrimitive::Transform {
+                transform: MusicTransform::Repeat { num: *num },
+                content: to_music(content, bars),
+            }),
+        }
+    }
+    MusicString(string)
+}
+
+impl Grammar {
+    /// A grammar that composes to `composition`, with what repeats in it written as repeats
+    /// and motifs. It starts from `S`, which plays every track at once, and the motifs are
+    /// `Motif-1` and so on, the longest first.
+    pub fn factor(composition: &Composition) -> Grammar {
+        let mut bar_ids: HashMap<String, usize> = HashMap::new();
+        let mut bars = vec![];
+        let mut tracks = vec![];
+        for track in &composition.tracks {
+            let items = MusicString::from_track_bars(track, composition.time_signature).into_iter()
+                .map(|bar| {
+                    let next_id = bars.len();
+                    let id = *bar_ids.entry(bar.to_string()).or_insert(next_id);
+                    if id == next_id {
+                        bars.push(bar);
+                    }
+                    Item::Bar(id)
+                })
+                .collect::<Vec<_>>();
+            tracks.push(collapse_repeats(&items));
+        }
+
+        // the tracks first, then the motifs
+        let mut sequences = tracks;
+        let track_count = sequences.len();
+        while let Some(pattern) = longest_repeated(&sequences) {
+            let motif = Item::Motif(sequences.len() - track_count);
+            sequences = sequences.iter()
+                .map(|s| collapse_repeats(&replace(s, &pattern, &motif)))
+                .collect();
+            sequences.push(pattern);
+        }
+        debug!(bars = bars.len(), motifs = sequences.len() - track_count, "factored composition");
+
+        let mut tracks = sequences.iter().take(track_count).map(|s| to_music(s, &bars)).collect::<Vec<_>>();
+        let start = NonTerminal::Custom("S".into());
+        let mut grammar = Grammar::new(start.clone(), vec![]);
+        let body = if tracks.len() == 1 {
+            tracks.remove(0)
+        } else {
+            MusicString(vec![MusicPrimitive::Split { branches: tracks, policy: SplitPolicy::Pad, mode: SplitMode::Parallel }])
+        };
+        grammar.add_production(start, body);
+        for (motif, items) in sequences.iter().skip(track_count).enumerate() {
+            grammar.add_production(motif_name(motif), to_music(items, &bars));
+        }
+        grammar
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::{Grammar, MusicString};
+    use crate::cfg::document::GrammarDocument;
+    use crate::testing::assert_same_music;
+    use crate::time::TimeSignature;
+
+    #[test]
+    fn test_factor_repeats() {
+        let (a, b, c, d) = (":c :d :e :f", ":g<2> :g<2>", ":e<4>", ":_<2> :c :c");
+        let flat = format!("{{{a} {b} {a} {c} {a} {b} {d} {d} {d} | ::i=bass :c<4> :c<4> :c<4> :c<4> :g<4> :c<4> :c<4>}}pad");
+        let music = MusicString::from_str(&flat).unwrap().compose(TimeSignature::common(), None).unwrap();
+        let grammar = Grammar::factor(&music);
+        let text = GrammarDocument::from_grammar(&grammar).to_source();
+        // the first two bars come back, and the first one on its own once more
+        assert!(text.contains("Motif-1 Motif-2 ::i=SineWave ::v=50 :4E<1m+0> Motif-1 [x3]["), "{text}");
+        assert!(text.contains("Motif-1 = Motif-2 ::i=SineWave ::v=50 :4G<2> :4G<2>\n"), "{text}");
+        assert!(text.contains("Motif-2 = ::i=SineWave ::v=50 :4C<1> :4D<1> :4E<1> :4F<1>"), "{text}");
+        // the last bar is played three times in a row and the bass four times
+        assert!(text.contains("[x3]["), "{text}");
+        assert!(text.contains("[x4][::i=Bass"), "{text}");
+        assert_eq!(text.matches("Motif-1").count(), 3, "{text}");
+
+        let factored = text.parse::<Grammar>().unwrap();
+        let start = MusicString::from_str("S").unwrap();
+        let composed = start.parallel_rewrite_n(&factored, false, true, 4)
+            .compose(TimeSignature::common(), None)
+            .unwrap();
+        assert_same_music(&composed, &music);
+    }
+}
@@ -3,15 +3,104 @@
 // so that they can be reversed. An interactive CFG can be rendered into a MusicString.
 
 use std::collections::HashMap;
-use rocket::yansi::Paint;
+use std::fmt::Display;
+use std::str::FromStr;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use crate::cfg::{Grammar, MusicPrimitive, MusicString, Production, Symbol};
+use crate::cfg::{ComposeError, Grammar, MusicPrimitive, MusicString, NonTerminal, Production, SplitMode, SplitPolicy, Symbol, Terminal, TerminalNote};
+use crate::cfg::scan::ScanError;
+use crate::composition::{Composition, Instrument};
+use crate::time::{Beat, BeatUnit, TimeSignature};
+#[cfg(feature = "native")]
+use crate::composition::{Modulation, Octave, Pitch, Volume, MAX_VOLUME};
+#[cfg(feature = "native")]
+use crate::player::{AtomicSound, AudioPlayer};
+#[cfg(feature = "native")]
+use crate::recorder::RecordedNote;
+#[cfg(feature = "native")]
+use crate::time::Seconds;
 
 pub struct InteractiveCFG {
     grammar: Grammar,
-    root: TracedString
+    root: TracedString,
+    /// Edits that can be undone, each with the grammar from before it, the latest last.
+    undo: Vec<(GrammarEdit, Grammar)>,
+    /// Edits that were undone, each with the grammar from after it, the latest undone last.
+    redo: Vec<(GrammarEdit, Grammar)>,
 }
 
+/// A change to the grammar made while playing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrammarEdit {
+    /// Add a production, after the ones already there.
+    Add(NonTerminal, MusicString),
+    /// Remove the production at this index, counting all productions in order.
+    Remove(usize),
+    /// Give the production at this index a new right-hand side.
+    Replace(usize, MusicString),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditError {
+    NoSuchProduction(String),
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::NoSuchProduction(s) => write!(f, "No such production: {s}"),
+        }
+    }
+}
+
+impl Display for GrammarEdit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrammarEdit::Add(nt, body) => write!(f, "add {} -> {}", nt.to_string(), body.to_string().trim()),
+            GrammarEdit::Remove(i) => write!(f, "remove production {i}"),
+            GrammarEdit::Replace(i, body) => write!(f, "replace production {i} with {}", body.to_string().trim()),
+        }
+    }
+}
+
+/// Typing in a pattern one step at a time on a fixed grid, which is a lot quicker than writing
+/// out drum patterns by hand. Each step is a rest (`.`), holds on the step before (`-`), or is
+/// one or more notes played together, written like in a grammar without the colon (`c+e+g`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepEntry {
+    time_signature: TimeSignature,
+    step: Beat,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Rest,
+    Hold,
+    Notes(Vec<TerminalNote>),
+}
+
+/// The computer keyboard played like a piano, for trying out notes without MIDI gear.
+/// The bottom letter row plays from C (`z s x d c v g b h n j m ,`), the top one from the C an
+/// octave up (`q 2 w 3 e r 5 t 6 y 7 u i`), with the black keys on the row above each.
+/// A terminal doesn't say when a key is let go, so every note is played for the same time.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone)]
+pub struct KeyboardInstrument {
+    pub instrument: Instrument,
+    pub volume: Volume,
+    pub note_length: Seconds,
+    /// Octave of the C the bottom row starts on.
+    octave: Octave,
+    /// Notes played since recording started, if recording.
+    take: Option<Vec<RecordedNote>>,
+}
+
+#[cfg(feature = "native")]
+const BOTTOM_ROW: &str = "zsxdcvgbhnjm,";
+#[cfg(feature = "native")]
+const TOP_ROW: &str = "q2w3er5t6y7ui";
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TracedString {
     original: MusicString,
@@ -22,9 +111,194 @@ impl InteractiveCFG {
     pub fn new(grammar: Grammar, music_string: MusicString) -> InteractiveCFG {
         InteractiveCFG {
             grammar,
-            root: TracedString::new(music_string)
+            root: TracedString::new(music_string),
+            undo: vec![],
+            redo: vec![],
         }
     }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Change the grammar, in a way that can be undone. Anything undone before can't be redone after.
+    pub fn edit(&mut self, edit: GrammarEdit) -> Result<(), EditError> {
+        let before = self.grammar.clone();
+        let productions = &mut self.grammar.productions;
+        match &edit {
+            GrammarEdit::Add(nt, body) => productions.push(Production(nt.clone(), body.clone(), None)),
+            GrammarEdit::Remove(i) | GrammarEdit::Replace(i, _) if *i >= productions.len() => {
+                return Err(EditError::NoSuchProduction(format!("{i}, there are {}", productions.len())));
+            }
+            GrammarEdit::Remove(i) => {
+                productions.remove(*i);
+            }
+            GrammarEdit::Replace(i, body) => productions[*i].1 = body.clone(),
+        }
+        self.undo.push((edit, before));
+        self.redo.clear();
+        Ok(())
+    }
+
+    /// Take back the last edit that hasn't been undone yet, returning it.
+    pub fn undo(&mut self) -> Option<&GrammarEdit> {
+        let (edit, before) = self.undo.pop()?;
+        let after = std::mem::replace(&mut self.grammar, before);
+        self.redo.push((edit, after));
+        self.redo.last().map(|(edit, _)| edit)
+    }
+
+    /// Make the last undone edit again, returning it.
+    pub fn redo(&mut self) -> Option<&GrammarEdit> {
+        let (edit, after) = self.redo.pop()?;
+        let before = std::mem::replace(&mut self.grammar, after);
+        self.undo.push((edit, before));
+        self.undo.last().map(|(edit, _)| edit)
+    }
+
+    /// The music the session started from, rewritten `n` times with the grammar as it is now,
+    /// so it follows every edit that is in effect and none that were undone.
+    pub fn derive(&self, n: usize, time_signature: TimeSignature, rng: &mut impl Rng) -> MusicString {
+        self.root.render().parallel_rewrite_n_with(&self.grammar, true, false, n, time_signature, rng)
+    }
+
+    /// The edits that are in effect, oldest first.
+    pub fn history(&self) -> impl Iterator<Item=&GrammarEdit> {
+        self.undo.iter().map(|(edit, _)| edit)
+    }
+}
+
+impl StepEntry {
+    /// Start an empty pattern where every step is `step` long.
+    pub fn new(time_signature: TimeSignature, step: Beat) -> Self {
+        StepEntry { time_signature, step, steps: vec![] }
+    }
+
+    /// Add the next step.
+    pub fn enter(&mut self, input: &str) -> Result<(), ScanError> {
+        let step = match input.trim() {
+            "." => Step::Rest,
+            "-" => Step::Hold,
+            notes => Step::Notes(notes.split('+').map(scan_note).collect::<Result<_, _>>()?),
+        };
+        self.steps.push(step);
+        Ok(())
+    }
+
+    /// Take back the last step, for typos.
+    pub fn undo(&mut self) {
+        self.steps.pop();
+    }
+
+    /// The last step on its own, to hear what was just entered.
+    pub fn preview(&self, instrument: Option<Instrument>) -> Result<Composition, ComposeError> {
+        let last = self.steps.iter().rposition(|s| !matches!(s, Step::Hold)).map(|i| &self.steps[i..]).unwrap_or(&[]);
+        let last = StepEntry { steps: last.to_vec(), ..self.clone() };
+        last.to_music_string().compose(self.time_signature, instrument)
+    }
+
+    /// The pattern so far. Holds lengthen the notes or rests before them, and notes entered
+    /// together are split into parallel branches.
+    pub fn to_music_string(&self) -> MusicString {
+        let mut string = vec![];
+        for (i, step) in self.steps.iter().enumerate() {
+            let held = self.steps[i + 1..].iter().take_while(|s| matches!(s, Step::Hold)).count();
+            let steps = held as BeatUnit + 1;
+            let duration = Beat::new(self.step.numerator() * steps, self.step.denominator()).as_music_time(self.time_signature);
+            let terminal = |note: &TerminalNote| MusicPrimitive::Simple(Symbol::T(Terminal::Music {
+                duration: Some(duration),
+                note: note.clone(),
+                tied: false,
+                lyric: None,
+            }));
+            match step {
+                // a hold at the very start has nothing to hold, so it rests
+                Step::Hold if i > 0 => {}
+                Step::Rest | Step::Hold => string.push(terminal(&TerminalNote::Rest)),
+                Step::Notes(notes) if notes.len() == 1 => string.push(terminal(&notes[0])),
+                Step::Notes(notes) => string.push(MusicPrimitive::Split {
+                    branches: notes.iter().map(|n| MusicString(vec![terminal(n)])).collect(),
+                    policy: SplitPolicy::Strict,
+                    mode: SplitMode::Parallel,
+                }),
+            }
+        }
+        MusicString(string)
+    }
+
+    /// Add the pattern to `grammar` as a production for `nt`.
+    pub fn finish(self, grammar: &mut Grammar, nt: NonTerminal) {
+        grammar.add_production(nt, self.to_music_string());
+    }
+}
+
+#[cfg(feature = "native")]
+impl KeyboardInstrument {
+    pub fn new(instrument: Instrument) -> Self {
+        KeyboardInstrument { instrument, volume: Volume(70), note_length: 0.5, octave: 4, take: None }
+    }
+
+    /// Move both rows up or down by `octaves`.
+    pub fn shift_octave(&mut self, octaves: Octave) {
+        self.octave = self.octave.saturating_add(octaves);
+    }
+
+    /// The pitch `key` plays, if it plays one.
+    pub fn pitch(&self, key: char) -> Option<Pitch> {
+        let key = key.to_ascii_lowercase();
+        let semitones = BOTTOM_ROW.find(key).or_else(|| TOP_ROW.find(key).map(|i| i + 12))?;
+        let c = Pitch(self.octave, 3).to_midi_note() as usize;
+        let key = u8::try_from(c + semitones).ok().filter(|k| *k < 128)?;
+        Some(Pitch::from_midi_note(key))
+    }
+
+    /// Play the note for `key` on `player`, now being `at` on the clock the player uses.
+    /// Returns false if `key` doesn't play a note.
+    pub fn press(&mut self, key: char, at: Seconds, player: &mut impl AudioPlayer) -> bool {
+        let Some(pitch) = self.pitch(key) else {
+            return false;
+        };
+        player.play(AtomicSound {
+            start: at,
+            duration: self.note_length,
+            volume: self.volume,
+            pitch,
+            instrument: self.instrument,
+            modulation: Modulation::NONE,
+            channel: None,
+            tag: None,
+            lyric: None,
+        });
+        if let Some(take) = &mut self.take {
+            take.push(RecordedNote {
+                channel: 0,
+                key: pitch.to_midi_note(),
+                velocity: (self.volume.0 * 127 / MAX_VOLUME) as u8,
+                start: at,
+                end: at + self.note_length,
+            });
+        }
+        true
+    }
+
+    /// Keep the notes played from now on, to quantize them into a track later.
+    pub fn start_recording(&mut self) {
+        self.take = Some(vec![]);
+    }
+
+    /// The notes played since recording started.
+    pub fn stop_recording(&mut self) -> Vec<RecordedNote> {
+        self.take.take().unwrap_or_default()
+    }
+}
+
+/// One note the way it is written in a grammar, without the colon in front.
+fn scan_note(input: &str) -> Result<TerminalNote, ScanError> {
+    let string = MusicString::from_str(&format!(":{}", input.trim()))?;
+    match string.0.as_slice() {
+        [MusicPrimitive::Simple(Symbol::T(Terminal::Music { note, duration: None, .. }))] => Ok(note.clone()),
+        _ => Err(ScanError::Generic(format!("Not a single note without a duration: {input}"))),
+    }
 }
 
 impl TracedString {
@@ -47,4 +321,96 @@ impl TracedString {
         }
         MusicString(v)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::cfg::interactive::{EditError, GrammarEdit, InteractiveCFG, StepEntry};
+    use crate::cfg::{Grammar, MusicString, NonTerminal};
+    use crate::composition::{Instrument, Pitch};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+    #[cfg(feature = "native")]
+    use crate::cfg::interactive::KeyboardInstrument;
+    #[cfg(feature = "native")]
+    use crate::player::{AtomicSound, AudioPlayer};
+    #[cfg(feature = "native")]
+    use crate::recorder::quantize;
+
+    #[cfg(feature = "native")]
+    impl AudioPlayer for Vec<AtomicSound> {
+        fn play(&mut self, event: AtomicSound) {
+            self.push(event);
+        }
+    }
+
+    #[test]
+    fn test_step_entry() {
+        let mut steps = StepEntry::new(TimeSignature::common(), Beat::new(1, 2));
+        for step in ["c", "-", ".", "e+g", "x", "a"] {
+            assert_eq!(steps.enter(step).is_ok(), step != "x");
+        }
+        assert!(steps.enter("c<1>").is_err());
+        steps.undo();
+        assert_eq!(steps.to_music_string().to_string(), ":4C<1> :_<1/2> {:4E<1/2>  | :4G<1/2> } ");
+        let preview = steps.preview(Some(Instrument::Piano)).unwrap();
+        let mut chord = preview.tracks.iter().flat_map(|t| t.events().iter().map(|e| (e.start, e.pitch))).collect::<Vec<_>>();
+        chord.sort();
+        assert_eq!(chord, vec![(MusicTime::zero(), Pitch(4, 7)), (MusicTime::zero(), Pitch(4, 10))]);
+
+        let mut grammar = Grammar::new(NonTerminal::Custom("S".into()), vec![]);
+        steps.finish(&mut grammar, NonTerminal::Custom("Beat".into()));
+        assert!(grammar.get_production(&NonTerminal::Custom("Beat".into()), Default::default()).is_some());
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn test_keyboard_instrument() {
+        let mut keyboard = KeyboardInstrument::new(Instrument::Piano);
+        let mut heard = vec![];
+        keyboard.start_recording();
+        assert!(keyboard.press('z', 0., &mut heard));
+        assert!(keyboard.press('E', 0.5, &mut heard));
+        assert!(!keyboard.press('p', 1., &mut heard));
+        keyboard.shift_octave(-1);
+        assert!(keyboard.press(',', 1., &mut heard));
+        assert_eq!(heard.iter().map(|s| s.pitch).collect::<Vec<_>>(), vec![Pitch(4, 3), Pitch(5, 7), Pitch(4, 3)]);
+
+        let take = keyboard.stop_recording();
+        let track = quantize(&take, TimeSignature::common(), 120., Beat::whole(1), Instrument::Piano);
+        let notes = track.events().iter().map(|e| (e.start, e.pitch)).collect::<Vec<_>>();
+        assert_eq!(notes, vec![(MusicTime::zero(), Pitch(4, 3)), (MusicTime::beats(1), Pitch(5, 7)), (MusicTime::beats(2), Pitch(4, 3))]);
+        assert!(keyboard.stop_recording().is_empty());
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let grammar = Grammar::from_str("start S\nS = :c :d").unwrap();
+        let mut cfg = InteractiveCFG::new(grammar, MusicString::from_str("S").unwrap());
+        let derived = |cfg: &InteractiveCFG| cfg.derive(1, TimeSignature::common(), &mut StdRng::seed_from_u64(0)).to_string();
+        let riff = NonTerminal::Custom("Riff".into());
+        cfg.edit(GrammarEdit::Add(riff.clone(), MusicString::from_str(":e").unwrap())).unwrap();
+        cfg.edit(GrammarEdit::Replace(1, MusicString::from_str(":f").unwrap())).unwrap();
+        assert_eq!(cfg.edit(GrammarEdit::Remove(2)), Err(EditError::NoSuchProduction("2, there are 2".to_string())));
+        cfg.edit(GrammarEdit::Remove(0)).unwrap();
+        assert_eq!(derived(&cfg), "");
+        let history = cfg.history().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(history, vec!["add Riff -> :4E", "replace production 1 with :4F", "remove production 0"]);
+
+        let body = |cfg: &InteractiveCFG| cfg.grammar().get_production(&riff, Default::default()).map(|p| p.1.to_string().trim().to_string());
+        assert_eq!(cfg.undo(), Some(&GrammarEdit::Remove(0)));
+        assert_eq!(cfg.undo().map(|e| e.to_string()), Some("replace production 1 with :4F".to_string()));
+        assert_eq!(body(&cfg), Some(":4E".to_string()));
+        assert_eq!(derived(&cfg), ":4C :4D ");
+        cfg.redo();
+        assert_eq!(body(&cfg), Some(":4F".to_string()));
+        // a new edit drops what could have been redone
+        cfg.edit(GrammarEdit::Remove(1)).unwrap();
+        assert_eq!(cfg.redo(), None);
+        assert_eq!(body(&cfg), None);
+        assert_eq!(cfg.history().count(), 3);
+        while cfg.undo().is_some() {}
+        assert_eq!(cfg.grammar().productions.len(), 1);
+    }
+}
@@ -0,0 +1,111 @@
+// Non-terminal names, kept once each for the whole program in the table tags and lyrics are
+// interned in. Grammars use the same few names over and over, and get cloned on every rewrite
+// and every live edit, so a name is a pointer into that table. Two names are equal when they
+// point at the same entry, without looking at the text.
+// Nothing is ever taken out of the table, so it must not be fed untrusted input: every new name
+// someone sends is memory the program doesn't get back until it exits. Grammars and music from
+// outside, like the ones given to the C API, should come from whoever runs the program.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The one `Name` for `name`, added to the table if it's new. Names are never taken out of it.
+pub fn intern(name: &str) -> Name {
+    Name(crate::composition::intern(name))
+}
+
+/// An interned name. Copying, comparing and hashing it never touch the text.
+#[derive(Copy, Clone)]
+pub struct Name(&'static str);
+
+impl Name {
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0.as_ptr() as usize).hash(state);
+    }
+}
+
+impl Deref for Name {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl AsRef<str> for Name {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl From<&str> for Name {
+    fn from(name: &str) -> Self {
+        intern(name)
+    }
+}
+
+impl From<String> for Name {
+    fn from(name: String) -> Self {
+        intern(&name)
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Debug for Name {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.0, f)
+    }
+}
+
+impl Serialize for Name {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Name::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::intern::{intern, Name};
+
+    #[test]
+    fn test_names_are_shared() {
+        let a = intern("Verse");
+        let b = Name::from("Verse".to_string());
+        assert!(std::ptr::eq(a.0, b.0));
+        assert_eq!(a, b);
+        assert_ne!(a, intern("Chorus"));
+        assert_eq!(a.as_str(), "Verse");
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert_eq!(json, "\"Verse\"");
+        let back: Name = serde_json::from_str(&json).unwrap();
+        assert!(std::ptr::eq(a.0, back.0));
+    }
+}
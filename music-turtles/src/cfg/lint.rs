@@ -0,0 +1,166 @@
+// Lints point out things in a grammar that are allowed but probably not what was meant.
+// Unlike scan errors they don't stop the grammar from being used.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use crate::cfg::{Grammar, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Production, SplitMode, SplitPolicy, Symbol, Terminal, TerminalNote};
+use crate::composition::{Pitch, Volume, MAX_VOLUME};
+use crate::time::{Beat, MusicTime, TimeSignature};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    /// None of the productions of this non-terminal can ever take up any time.
+    ZeroDuration(NonTerminal),
+    RepeatZero(NonTerminal),
+    VolumeTooHigh(NonTerminal, Volume),
+    NoteOutOfMidiRange(NonTerminal, Pitch),
+    /// A split that has to have branches of equal length, but whose lengths are one beat apart.
+    SplitOffByOneBeat(NonTerminal, Vec<MusicTime>),
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lint::ZeroDuration(nt) =>
+                write!(f, "{}: always produces music with no duration", nt.to_string()),
+            Lint::RepeatZero(nt) =>
+                write!(f, "{}: repeats something 0 times, so it is never played", nt.to_string()),
+            Lint::VolumeTooHigh(nt, volume) =>
+                write!(f, "{}: volume {} is over the maximum of {MAX_VOLUME}", nt.to_string(), volume.0),
+            Lint::NoteOutOfMidiRange(nt, pitch) =>
+                write!(f, "{}: note {}{} is outside the MIDI range", nt.to_string(), pitch.0, pitch.letter_name()),
+            Lint::SplitOffByOneBeat(nt, durations) => {
+                let durations = durations.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", ");
+                write!(f, "{}: split branches are one beat apart ({durations})", nt.to_string())
+            }
+        }
+    }
+}
+
+impl Grammar {
+    /// Warnings about this grammar, in the order of the productions they were found in.
+    pub fn lint(&self, time_signature: TimeSignature) -> Vec<Lint> {
+        let mut lints = vec![];
+        let timed = self.non_terminals_with_duration();
+        let mut reported = HashSet::new();
+        for Production(nt, _ms, _guard) in &self.productions {
+            if !timed.contains(nt) && reported.insert(nt) {
+                lints.push(Lint::ZeroDuration(nt.clone()));
+            }
+        }
+        for Production(nt, ms, _guard) in &self.productions {
+            lint_string(nt, ms, time_signature, &mut lints);
+        }
+        lints
+    }
+
+    /// Non-terminals with at least one production that can take up time.
+    fn non_terminals_with_duration(&self) -> HashSet<NonTerminal> {
+        let mut timed = HashSet::new();
+        // keep going until nothing changes, since non-terminals can refer to each other
+        loop {
+            let before = timed.len();
+            for Production(nt, ms, _guard) in &self.productions {
+                if !timed.contains(nt) && has_duration(ms, &timed) {
+                    timed.insert(nt.clone());
+                }
+            }
+            if timed.len() == before {
+                return timed;
+            }
+        }
+    }
+}
+
+fn has_duration(music_string: &MusicString, timed: &HashSet<NonTerminal>) -> bool {
+    music_string.0.iter().any(|mp| match mp {
+        MusicPrimitive::Simple(Symbol::T(Terminal::Music { .. })) => true,
+        MusicPrimitive::Simple(Symbol::T(Terminal::Meta(_))) => false,
+        MusicPrimitive::Simple(Symbol::NT(nt)) => timed.contains(nt),
+        MusicPrimitive::Split { branches, .. } => branches.iter().any(|b| has_duration(b, timed)),
+        MusicPrimitive::Volta { endings } => endings.iter().any(|e| has_duration(e, timed)),
+        #[allow(deprecated)]
+        MusicPrimitive::Repeat { num, content } => *num > 0 && has_duration(content, timed),
+        MusicPrimitive::Transform { transform: MusicTransform::Repeat { num: 0 }, .. } => false,
+        MusicPrimitive::Transform { content, .. } => has_duration(content, timed),
+    })
+}
+
+fn lint_string(nt: &NonTerminal, music_string: &MusicString, time_signature: TimeSignature, lints: &mut Vec<Lint>) {
+    for mp in &music_string.0 {
+        match mp {
+            MusicPrimitive::Simple(Symbol::T(Terminal::Meta(MetaControl::ChangeVolume(volume)))) if volume.0 > MAX_VOLUME => {
+                lints.push(Lint::VolumeTooHigh(nt.clone(), *volume));
+            }
+            MusicPrimitive::Simple(Symbol::T(Terminal::Music { note: TerminalNote::Note { pitch, relative: 0, .. }, .. })) => {
+                let midi = pitch.0 as i32 * 12 + pitch.1 as i32 + 9;
+                if !(0..=127).contains(&midi) {
+                    lints.push(Lint::NoteOutOfMidiRange(nt.clone(), *pitch));
+                }
+            }
+            MusicPrimitive::Simple(_) => {}
+            MusicPrimitive::Split { branches, policy, mode } => {
+                // only branches without non-terminals have a length that is known before rewriting
+                if *policy == SplitPolicy::Strict && *mode == SplitMode::Parallel && branches.iter().all(|b| b.non_terminals().is_empty()) {
+                    let durations = branches.iter()
+                        .filter_map(|b| b.compose(time_signature, None).ok())
+                        .map(|c| c.get_duration())
+                        .collect::<Vec<_>>();
+                    if let (Some(shortest), Some(longest)) = (durations.iter().min(), durations.iter().max())
+                        && (longest.with(time_signature) - *shortest).with(time_signature).total_beats() == Beat::whole(1) {
+                        lints.push(Lint::SplitOffByOneBeat(nt.clone(), durations));
+                    }
+                }
+                branches.iter().for_each(|b| lint_string(nt, b, time_signature, lints));
+            }
+            MusicPrimitive::Volta { endings } => {
+                endings.iter().for_each(|e| lint_string(nt, e, time_signature, lints));
+            }
+            #[allow(deprecated)]
+            MusicPrimitive::Repeat { num, content } => {
+                if *num == 0 {
+                    lints.push(Lint::RepeatZero(nt.clone()));
+                }
+                lint_string(nt, content, time_signature, lints);
+            }
+            MusicPrimitive::Transform { transform, content } => {
+                if *transform == (MusicTransform::Repeat { num: 0 }) {
+                    lints.push(Lint::RepeatZero(nt.clone()));
+                }
+                lint_string(nt, content, time_signature, lints);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::{Grammar, NonTerminal};
+    use crate::cfg::lint::Lint;
+    use crate::composition::{Pitch, Volume};
+    use crate::time::{MusicTime, TimeSignature};
+
+    #[test]
+    fn test_lint() {
+        let grammar = Grammar::from_str(
+            "start S\n\
+            S = A B C D E\n\
+            A = ::v=120 :c\n\
+            B = ::v=50 B\n\
+            C = [x0][:c] :9g#\n\
+            D = {:c :d | :e}\n\
+            E = {:c :d | :e}pad"
+        ).unwrap();
+        let nt = |s: &str| NonTerminal::Custom(s.into());
+        let lints = grammar.lint(TimeSignature::common());
+        assert_eq!(lints, vec![
+            Lint::ZeroDuration(nt("B")),
+            Lint::VolumeTooHigh(nt("A"), Volume(120)),
+            Lint::RepeatZero(nt("C")),
+            Lint::NoteOutOfMidiRange(nt("C"), Pitch(9, 11)),
+            Lint::SplitOffByOneBeat(nt("D"), vec![MusicTime::beats(2), MusicTime::beats(1)]),
+        ]);
+        assert_eq!(lints[0].to_string(), "B: always produces music with no duration");
+    }
+}
@@ -0,0 +1,242 @@
+// Patterns in the mini-notation of TidalCycles, for live coders who already think in it.
+// They are turned into a `MusicString`, so they compose like anything else.
+//
+// A pattern fills one cycle, and its steps share the cycle equally:
+//
+// - `bd sn hh sn`  a word is a note (`c`, `4e`, `f#'`) or a drum (`bd`, `sn`, `sd`, `hh`, `oh`,
+//                  `lt`, `ht`, `sh`), and `~` is a rest
+// - `[bd bd]`      steps inside brackets share the step the brackets take
+// - `[c e, g]`     patterns separated by commas play at the same time
+// - `bd*2`         the step is played twice as fast, so twice in its time
+// - `bd?`          the step is left out half the time
+// - `<c e g>`      one step per cycle, taking turns
+
+use crate::cfg::scan::{consume, Nesting, NoteScanner, ParseLimits, ScanError, Scanner};
+use crate::cfg::{MetaControl, MusicPrimitive, MusicString, MusicTransform, SplitMode, SplitPolicy, Symbol, Terminal, TerminalNote};
+use crate::composition::{Instrument, Pitch};
+use crate::time::{Beat, BeatUnit, MusicTime};
+
+/// The pitch drums are played at.
+const DRUM_PITCH: Pitch = Pitch(4, 0);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Note(TerminalNote),
+    Drum(Instrument),
+    Rest,
+    /// Patterns playing at the same time, each a sequence of steps.
+    Stack(Vec<Vec<Step>>),
+    Alternate(Vec<Step>),
+    Fast(Box<Step>, usize),
+    Maybe(Box<Step>),
+}
+
+impl MusicString {
+    /// `cycles` cycles of the mini-notation `pattern`, each `cycle` beats long.
+    pub fn from_mini(pattern: &str, cycle: Beat, cycles: usize) -> Result<MusicString, ScanError> {
+        let (steps, rest) = scan_stack(pattern)?;
+        if !rest.trim().is_empty() {
+            return Err(ScanError::Generic(format!("Unexpected '{}' in pattern", rest.trim())));
+        }
+        let mut string = vec![];
+        for i in 0..cycles {
+            lower(&steps, cycle, i, &mut string);
+        }
+        Ok(MusicString(string))
+    }
+}
+
+/// Sequences separated by commas, up to a `]` or the end.
+fn scan_stack(input: &str) -> Result<(Step, &str), ScanError> {
+    let _nesting = Nesting::enter(input)?;
+    let mut sequences = vec![];
+    let mut rest = input;
+    loop {
+        let (sequence, after) = scan_sequence(rest)?;
+        sequences.push(sequence);
+        match after.strip_prefix(',') {
+            Some(after) => rest = after,
+            None => return Ok((Step::Stack(sequences), after)),
+        }
+    }
+}
+
+/// Steps separated by whitespace, up to a `,`, `]`, `>` or the end.
+fn scan_sequence(input: &str) -> Result<(Vec<Step>, &str), ScanError> {
+    let mut steps = vec![];
+    let mut rest = input.trim_start();
+    while !rest.is_empty() && !rest.starts_with([',', ']', '>']) {
+        let (step, after) = scan_step(rest)?;
+        steps.push(step);
+        rest = after.trim_start();
+    }
+    Ok((steps, rest))
+}
+
+fn scan_step(input: &str) -> Result<(Step, &str), ScanError> {
+    let (mut step, mut rest) = if let Some(inner) = input.strip_prefix('[') {
+        let (stack, rest) = scan_stack(inner)?;
+        let rest = rest.strip_prefix(']').ok_or_else(|| ScanError::Generic("Expected ']' in pattern".to_string()))?;
+        (stack, rest)
+    } else if let Some(inner) = input.strip_prefix('<') {
+        let _nesting = Nesting::enter(inner)?;
+        let (steps, rest) = scan_sequence(inner)?;
+        let rest = rest.strip_prefix('>').ok_or_else(|| ScanError::Generic("Expected '>' in pattern".to_string()))?;
+        (Step::Alternate(steps), rest)
+    } else {
+        let end = input.find(|c: char| !(c.is_ascii_alphanumeric() || "#'+-~".contains(c))).unwrap_or(input.len());
+        (scan_word(&input[..end])?, &input[end..])
+    };
+    loop {
+        if let Some(after) = rest.strip_prefix('*') {
+            let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+            let times = after[..digits].parse().ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| ScanError::Generic("Expected positive integer after '*' in pattern".to_string()))?;
+            step = Step::Fast(Box::new(step), ParseLimits::check_repeat(times)?);
+            rest = &after[digits..];
+        } else if let Some(after) = rest.strip_prefix('?') {
+            step = Step::Maybe(Box::new(step));
+            rest = after;
+        } else {
+            return Ok((step, rest));
+        }
+    }
+}
+
+fn scan_word(word: &str) -> Result<Step, ScanError> {
+    let drum = match word {
+        "~" => return Ok(Step::Rest),
+        "" => return Err(ScanError::Generic("Expected a note, drum or '~' in pattern".to_string())),
+        "bd" => Instrument::BassDrum,
+        "sn" => Instrument::Snare,
+        "sd" => Instrument::Snare2,
+        "hh" => Instrument::HiHatClosed,
+        "oh" => Instrument::HiHatOpen,
+        "lt" => Instrument::BongoLow,
+        "ht" => Instrument::BongoHigh,
+        "sh" => Instrument::Shaker1,
+        _ => {
+            return consume(NoteScanner).scan(word)
+                .map(|(note, _empty)| Step::Note(note))
+                .map_err(|_| ScanError::Generic(format!("Expected a note or drum in pattern, found '{word}'")));
+        }
+    };
+    Ok(Step::Drum(drum))
+}
+
+/// Add `steps` sharing `span` beats in cycle number `cycle` to `string`.
+fn lower_sequence(steps: &[Step], span: Beat, cycle: usize, string: &mut Vec<MusicPrimitive>) {
+    if steps.is_empty() {
+        string.push(music(span, TerminalNote::Rest));
+        return;
+    }
+    let each = span / steps.len() as BeatUnit;
+    for step in steps {
+        lower(step, each, cycle, string);
+    }
+}
+
+fn lower(step: &Step, span: Beat, cycle: usize, string: &mut Vec<MusicPrimitive>) {
+    match step {
+        Step::Note(note) => string.push(music(span, note.clone())),
+        Step::Rest => string.push(music(span, TerminalNote::Rest)),
+        // in a split of its own, so the notes after it keep their instrument
+        Step::Drum(drum) => string.push(MusicPrimitive::Split {
+            branches: vec![MusicString(vec![
+                MusicPrimitive::Simple(Symbol::T(Terminal::Meta(MetaControl::ChangeInstrument(*drum)))),
+                music(span, TerminalNote::Note { pitch: DRUM_PITCH, spelling: None, relative: 0 }),
+            ])],
+            policy: SplitPolicy::Strict,
+            mode: SplitMode::Parallel,
+        }),
+        Step::Stack(sequences) if sequences.len() == 1 => lower_sequence(&sequences[0], span, cycle, string),
+        Step::Stack(sequences) => {
+            let branches = sequences.iter()
+                .map(|steps| {
+                    let mut branch = vec![];
+                    lower_sequence(steps, span, cycle, &mut branch);
+                    MusicString(branch)
+                })
+                .collect();
+            string.push(MusicPrimitive::Split { branches, policy: SplitPolicy::Pad, mode: SplitMode::Parallel });
+        }
+        Step::Alternate(steps) if steps.is_empty() => string.push(music(span, TerminalNote::Rest)),
+        Step::Alternate(steps) => lower(&steps[cycle % steps.len()], span, cycle / steps.len(), string),
+        Step::Fast(step, times) => {
+            // every time through counts as a cycle of its own for what is inside
+            let each = span / *times as BeatUnit;
+            for i in 0..*times {
+                lower(step, each, cycle * times + i, string);
+            }
+        }
+        Step::Maybe(step) => {
+            let mut content = vec![];
+            lower(step, span, cycle, &mut content);
+            string.push(MusicPrimitive::Transform {
+                transform: MusicTransform::Probability { percent: 50 },
+                content: MusicString(content),
+            });
+        }
+    }
+}
+
+fn music(span: Beat, note: TerminalNote) -> MusicPrimitive {
+    MusicPrimitive::Simple(Symbol::T(Terminal::Music { duration: Some(MusicTime(0, span)), note, tied: false, lyric: None }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::MusicString;
+    use crate::cfg::ComposeCache;
+    use crate::composition::{Composition, Instrument};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    fn hits(music: &Composition, instrument: Instrument) -> Vec<MusicTime> {
+        music.tracks.iter()
+            .filter(|t| t.instrument == instrument)
+            .flat_map(|t| t.events().iter().map(|e| e.start))
+            .collect()
+    }
+
+    #[test]
+    fn test_mini_notation() {
+        let time_signature = TimeSignature::common();
+        let at = |beats, of| Beat::new(beats, of).as_music_time(time_signature);
+        let music = MusicString::from_mini("bd [~ sn] hh*2 <bd sn>", Beat::whole(4), 2).unwrap()
+            .compose(time_signature, None)
+            .unwrap();
+        assert_eq!(hits(&music, Instrument::BassDrum), vec![at(0, 1), at(3, 1), at(4, 1)]);
+        assert_eq!(hits(&music, Instrument::Snare), vec![at(3, 2), at(11, 2), at(7, 1)]);
+        assert_eq!(hits(&music, Instrument::HiHatClosed), vec![at(2, 1), at(5, 2), at(6, 1), at(13, 2)]);
+        assert_eq!(music.get_duration(), MusicTime::measures(2));
+
+        // a chord over two notes, with the notes on the piano
+        let music = MusicString::from_mini("[c e, g]", Beat::whole(4), 1).unwrap()
+            .compose(time_signature, Some(Instrument::Piano))
+            .unwrap();
+        let notes = music.tracks.iter().flat_map(|t| t.events().iter().map(|e| (e.start, e.duration))).collect::<Vec<_>>();
+        assert_eq!(notes.len(), 3);
+        assert!(notes.contains(&(at(0, 1), Beat::whole(4))) && notes.contains(&(at(2, 1), Beat::whole(2))));
+
+        // left out about half the time, but always taking its step
+        let maybe = MusicString::from_mini("c? d", Beat::whole(2), 200).unwrap();
+        let music = maybe.compose_cached(time_signature, None, &mut ComposeCache::seeded(3)).unwrap();
+        let played = music.tracks.iter().map(|t| t.events().len()).sum::<usize>();
+        assert!((250..350).contains(&played), "{played}");
+
+        // nothing between the brackets or commas is a rest that still takes its step
+        for empty in ["", "[]", "bd [] sn", "bd ,", "[bd, ]"] {
+            let music = MusicString::from_mini(empty, Beat::whole(4), 1).unwrap()
+                .compose(time_signature, None)
+                .unwrap();
+            assert_eq!(music.get_duration(), MusicTime::measures(1), "{empty:?}");
+        }
+        let music = MusicString::from_mini("bd [] sn", Beat::whole(3), 1).unwrap().compose(time_signature, None).unwrap();
+        assert_eq!(hits(&music, Instrument::Snare), vec![at(2, 1)]);
+
+        assert!(MusicString::from_mini("[bd sn", Beat::whole(4), 1).is_err());
+        assert!(MusicString::from_mini("bd kazoo", Beat::whole(4), 1).is_err());
+        assert!(MusicString::from_mini(&"[".repeat(100), Beat::whole(4), 1).is_err());
+    }
+}
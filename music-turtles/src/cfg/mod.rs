@@ -1,35 +1,142 @@
 pub mod scan;
 pub mod interactive;
+pub mod lint;
+pub mod durations;
+pub mod validate;
+pub mod transcribe;
+pub mod complete;
+pub mod document;
+pub mod script;
+pub mod mini;
+pub mod factor;
+pub mod intern;
 
-use crate::cfg::scan::{consume, MusicStringScanner, ScanError};
-use crate::cfg::scan::{GrammarScanner, Scanner};
-use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume};
-use crate::time::{Beat, MusicTime, TimeCompression, TimeSignature};
+use crate::cfg::durations::{primitive_range, DurationRange};
+use crate::cfg::intern::Name;
+use crate::cfg::scan::{consume, strip_comments, MusicStringScanner, ScanError};
+use crate::cfg::scan::{GrammarScanner, ParseLimits, Scanner};
+use crate::cfg::script::{run_script, Expr, ScriptTarget};
+use crate::composition::{Composition, Event, Instrument, Lfo, Marker, MidiChannel, MAX_VOLUME, Modulation, NoteNum, Octave, Pitch, Scale, Spelling, Syllable, Tag, Track, TrackId, Volume};
+use crate::time::{Beat, BeatUnit, MusicTime, TimeCompression, TimeSignature};
+use num::rational::Ratio;
 use num::Zero;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::distributions::WeightedIndex;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::rc::Rc;
 use std::str::FromStr;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Grammar {
     start: NonTerminal,
     productions: Vec<Production>,
+    /// Transform stacks named with `def`, already substituted into the productions.
+    #[serde(default)]
+    macros: HashMap<String, Vec<MusicTransform>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Production(NonTerminal, MusicString);
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Production(NonTerminal, MusicString, #[serde(default)] Option<Guard>);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What guards can see about the rewrite a production is chosen in.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Derivation {
+    /// Number of rewrites done before this one, so the axiom is rewritten at depth 0.
+    pub depth: usize,
+    /// The measure the symbol being rewritten starts in, counting from 0.
+    pub bar: usize,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum GuardVariable {
+    Depth,
+    Bar,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Comparison {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+        }
+    }
+}
+
+/// Condition for choosing a production, written after the `=` like `(depth<3)` or `(bar%4==0)`.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Guard {
+    pub variable: GuardVariable,
+    /// Compare the variable modulo this instead of the variable itself.
+    pub modulus: Option<usize>,
+    pub comparison: Comparison,
+    pub value: usize,
+}
+
+impl Guard {
+    pub fn allows(&self, derivation: Derivation) -> bool {
+        let variable = match self.variable {
+            GuardVariable::Depth => derivation.depth,
+            GuardVariable::Bar => derivation.bar,
+        };
+        let variable = self.modulus.map_or(variable, |m| variable % m);
+        match self.comparison {
+            Comparison::Less => variable < self.value,
+            Comparison::LessOrEqual => variable <= self.value,
+            Comparison::Greater => variable > self.value,
+            Comparison::GreaterOrEqual => variable >= self.value,
+            Comparison::Equal => variable == self.value,
+            Comparison::NotEqual => variable != self.value,
+        }
+    }
+}
+
+impl Display for Guard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let variable = match self.variable {
+            GuardVariable::Depth => "depth",
+            GuardVariable::Bar => "bar",
+        };
+        let modulus = self.modulus.map(|m| format!("%{m}")).unwrap_or_default();
+        write!(f, "({variable}{modulus}{}{})", self.comparison.symbol(), self.value)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct MusicString(pub Vec<MusicPrimitive>);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum MusicPrimitive {
     Simple(Symbol),
     Split {
-        branches: Vec<MusicString>
+        branches: Vec<MusicString>,
+        #[serde(default)]
+        policy: SplitPolicy,
+        #[serde(default)]
+        mode: SplitMode,
+    },
+    /// First, second, ... endings, written `{end1 | end2}volta`.
+    /// Inside a repeat each pass plays the next ending (the last one once they run out);
+    /// anywhere else the first ending is played.
+    Volta {
+        endings: Vec<MusicString>,
     },
     #[deprecated]
     Repeat {
@@ -42,7 +149,45 @@ pub enum MusicPrimitive {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What to do when the branches of a split are not all the same length.
+/// Written as a suffix after the closing brace, e.g. `{:c | :d :e}pad`.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum SplitPolicy {
+    /// Refuse to compose.
+    #[default]
+    Strict,
+    /// Fill the shorter branches with rests up to the longest one.
+    Pad,
+    /// Cut every branch off at the end of the shortest one.
+    Truncate,
+    /// Loop the shorter branches until the longest one ends.
+    Longest,
+}
+
+/// Whether a split plays all of its branches at once or just one of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum SplitMode {
+    #[default]
+    Parallel,
+    /// Play one branch, picked at random every time it is composed with these relative weights,
+    /// one per branch. Written with a weight before any branch, e.g. `{A |2 B |1 C}`.
+    Choice {
+        weights: Vec<u32>,
+    },
+}
+
+impl SplitPolicy {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            SplitPolicy::Strict => "",
+            SplitPolicy::Pad => "pad",
+            SplitPolicy::Truncate => "truncate",
+            SplitPolicy::Longest => "longest",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum MusicTransform {
     Transpose {
@@ -53,62 +198,162 @@ pub enum MusicTransform {
     },
     Compression {
         factor: TimeCompression,
-    }
+    },
+    /// Multiply the volume of everything inside, in percent so the transform stays hashable.
+    ScaleVolume {
+        percent: u32,
+    },
+    /// Reflect pitches around a center note, optionally staying in the scale built on it.
+    Mirror {
+        center: Pitch,
+        scale: Option<Scale>,
+    },
+    /// Retrigger every note inside this many times within its own duration.
+    Stutter {
+        times: usize,
+    },
+    /// Start on the next barline (or right away on one) and fill up the last measure with rest,
+    /// so the content always takes a whole number of measures.
+    AlignToBar,
+    /// A stack of transforms named with `def`. Reading a grammar replaces it by the transforms
+    /// it stands for, so one left over can't be composed.
+    Named {
+        name: String,
+    },
+    /// Only include what is inside with this chance, rolled again every time it is composed.
+    /// Left out content still takes up its time.
+    Probability {
+        percent: u32,
+    },
+    /// Worked out by a script each time the production it is in is expanded.
+    Script {
+        target: ScriptTarget,
+        expr: Expr,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum Symbol {
     NT(NonTerminal),
     T(Terminal),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum NonTerminal {
-    Custom(String),
+    Custom(Name),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum Terminal {
     Music {
-        duration: MusicTime,
+        /// `None` when the note has no `<...>` suffix and takes the current default duration.
+        duration: Option<MusicTime>,
         note: TerminalNote,
+        /// Held on into the next note if that one has the same pitch.
+        #[serde(default)]
+        tied: bool,
+        /// Syllable of lyrics sung on the note.
+        #[serde(default)]
+        lyric: Option<String>,
     },
     Meta(MetaControl),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum TerminalNote {
     Note {
-        pitch: Pitch
+        pitch: Pitch,
+        /// How the note was written, kept for notation export.
+        #[serde(default)]
+        spelling: Option<Spelling>,
+        /// Octave marks: positive puts the note in the nearest octave above the previous note,
+        /// negative in the nearest one below, with one more octave for each mark after the first.
+        /// 0 means `pitch` already has its octave.
+        #[serde(default)]
+        relative: i8,
     },
     Rest,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum MetaControl {
     ChangeInstrument(Instrument),
     ChangeVolume(Volume),
+    Vibrato(Lfo),
+    Tremolo(Lfo),
+    /// Duration of the following notes that don't give their own.
+    DefaultDuration(MusicTime),
+    /// Send the following notes on this MIDI channel, counted from 0,
+    /// whatever channel their instrument is mapped to.
+    Channel(MidiChannel),
+    /// Tag the following notes, for finding them again after composing.
+    Tag(String),
+    /// Name this point in the music, so playback can jump to it.
+    Mark(String),
+    /// Make the following notes louder when they start on the accent's beats.
+    Accent(Accent),
+}
+
+/// Extra volume for notes that start on some of the beats of a bar, for a groove without writing
+/// `::v=` before every note. Beats are counted from the start of the piece, whatever brackets
+/// the notes are in, where the notes are heard after a `[>>n]` or `[stN]` has moved them. An
+/// accent set inside such brackets counts the beats from before they were moved.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Accent {
+    /// Added to the volume, which doesn't go over `MAX_VOLUME` for it. 0 turns accents off.
+    pub amount: u32,
+    /// Beats of the bar that are accented, counting the downbeat as 1.
+    pub beats: Vec<BeatUnit>,
+}
+
+impl Accent {
+    /// Accent only the first beat of every bar.
+    pub fn downbeats(amount: u32) -> Self {
+        Accent { amount, beats: vec![1] }
+    }
+
+    /// `volume` for a note starting at `start`.
+    pub fn volume_at(&self, volume: Volume, start: MusicTime) -> Volume {
+        let MusicTime(_, beat) = start;
+        if beat.denominator() == 1 && self.beats.contains(&(beat.numerator() + 1)) {
+            Volume((volume.0 + self.amount).min(volume.0.max(MAX_VOLUME)))
+        } else {
+            volume
+        }
+    }
 }
 
 impl Grammar {
     pub fn new(start: NonTerminal, productions: Vec<Production>) -> Self {
-        Grammar { start, productions }
+        Grammar { start, productions, macros: HashMap::new() }
+    }
+
+    pub fn start(&self) -> &NonTerminal {
+        &self.start
+    }
+
+    /// Add a production for `nt`, after the ones already there.
+    pub fn add_production(&mut self, nt: NonTerminal, body: MusicString) {
+        self.productions.push(Production(nt, body, None));
     }
 
-    pub fn get_production(&self, nt: &NonTerminal) -> Option<&Production> {
-        self.productions.iter().find(|p| &p.0 == nt)
+    /// The first production for `nt` whose guard, if any, allows `derivation`.
+    pub fn get_production(&self, nt: &NonTerminal, derivation: Derivation) -> Option<&Production> {
+        self.productions.iter().find(|p| p.applies(nt, derivation))
     }
 
+    /// Any of the productions for `nt` whose guards allow `derivation`, picked with `rng`.
     pub fn get_production_random(
         &self,
         nt: &NonTerminal,
+        derivation: Derivation,
+        rng: &mut impl Rng,
     ) -> Option<&Production> {
-        let mut rng = rand::thread_rng();
-        let productions: Vec<_> = self.productions.iter().filter(|p| &p.0 == nt).collect();
+        let productions: Vec<_> = self.productions.iter().filter(|p| p.applies(nt, derivation)).collect();
         if productions.is_empty() {
             None
         } else {
@@ -117,48 +362,461 @@ impl Grammar {
     }
 }
 
+impl Grammar {
+    /// Move every note in the grammar by `semitones`.
+    pub fn transpose(&mut self, semitones: i8) {
+        self.map_notes(|mut pitch| {
+            pitch.transpose(semitones);
+            pitch
+        });
+    }
+
+    /// Replace the pitch of every note in the grammar, and of the centers of mirrors, by `f` of it.
+    /// Notes written with relative octave marks keep their marks, so only the note name counts.
+    pub fn map_notes<F: FnMut(Pitch) -> Pitch>(&mut self, mut f: F) {
+        for Production(_nt, ms, _guard) in &mut self.productions {
+            ms.map_notes(&mut f);
+        }
+        for transforms in self.macros.values_mut() {
+            transforms.iter_mut().for_each(|t| t.map_notes(&mut f));
+        }
+    }
+}
+
+impl MusicTransform {
+    fn map_notes<F: FnMut(Pitch) -> Pitch>(&mut self, f: &mut F) {
+        if let MusicTransform::Mirror { center, .. } = self {
+            *center = f(*center);
+        }
+    }
+}
+
+impl Production {
+    fn applies(&self, nt: &NonTerminal, derivation: Derivation) -> bool {
+        &self.0 == nt && self.2.is_none_or(|guard| guard.allows(derivation))
+    }
+}
+
 impl FromStr for Grammar {
     type Err = ScanError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let _span = debug_span!("parse_grammar", bytes = s.len()).entered();
         let scanner = consume(GrammarScanner);
-        let (grammar, _s) = scanner.scan(s)?;
+        let (grammar, _s) = scanner.scan(s).inspect_err(|e| debug!(error = ?e, "grammar didn't parse"))?;
+        debug!(productions = grammar.productions.len(), "parsed grammar");
         Ok(grammar)
     }
 }
 
+impl Grammar {
+    /// Parse with other limits than the defaults, e.g. tighter ones for grammars from strangers.
+    pub fn parse_with_limits(s: &str, limits: ParseLimits) -> Result<Self, ScanError> {
+        limits.apply(|| s.parse())
+    }
+}
+
 #[derive(Debug)]
 pub enum ComposeError {
     MismatchedLengths(String),
+    UnknownTransform(String),
+    BadWeights(String),
+
+}
 
+impl Display for ComposeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ComposeError::MismatchedLengths(s) => write!(f, "Mismatched lengths: {s}"),
+            ComposeError::UnknownTransform(s) => write!(f, "Unknown transform: {s}"),
+            ComposeError::BadWeights(s) => write!(f, "Bad weights: {s}"),
+        }
+    }
 }
 
+impl std::error::Error for ComposeError {}
+
 impl Display for MusicTransform {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
             MusicTransform::Transpose { semitones } => format!("T{}", semitones),
             MusicTransform::Repeat { num } => format!("x{}", num),
-            MusicTransform::Compression { factor } => format!(">>{}", factor.to_string()),
+            // written the way it reads, which is the inverse of the factor for `>>`
+            MusicTransform::Compression { factor } if factor.0 > Ratio::new(1, 1) => format!("<<{}", factor.0),
+            MusicTransform::Compression { factor } => format!(">>{}", factor.0.recip()),
+            MusicTransform::ScaleVolume { percent } => format!("v*{}", *percent as f32 / 100.),
+            MusicTransform::Mirror { center, scale } => {
+                let scale = scale.map(|s| format!(" {}", s.name())).unwrap_or_default();
+                format!("M {}{}{scale}", center.0, center.letter_name())
+            }
+            MusicTransform::Stutter { times } => format!("st{}", times),
+            MusicTransform::Probability { percent } => format!("?{}", *percent as f32 / 100.),
+            MusicTransform::AlignToBar => "bar".to_string(),
+            MusicTransform::Named { name } => name.clone(),
+            MusicTransform::Script { target, expr } => format!("{}{{{expr}}}", target.prefix()),
         };
         write!(f, "{}", str)
     }
 }
 
+/// Compositions of `MusicString` subtrees that were already composed, relative to time zero.
+/// Grammars reuse the same material over and over, so after rewriting the same subtree
+/// shows up many times and only has to be composed once per context it starts in.
+/// It also carries the random number generator for `?` transforms, so that a seeded cache
+/// composes the same piece every time.
+pub struct ComposeCache {
+    compositions: HashMap<Context, HashMap<MusicString, Rc<Composition>>>,
+    rng: StdRng,
+    pad_tracks: bool,
+    parallel: bool,
+    accent: Option<Accent>,
+}
+
+impl Default for ComposeCache {
+    fn default() -> Self {
+        ComposeCache {
+            compositions: HashMap::new(),
+            rng: unseeded_rng(),
+            pad_tracks: false,
+            parallel: true,
+            accent: None,
+        }
+    }
+}
+
+/// A generator seeded by the system. Without the `native` feature there may be nothing to ask
+/// (wasm32-unknown-unknown has no source of randomness), so it starts from the same seed every
+/// time, and `ComposeCache::seeded` and `MusicString::parallel_rewrite_n_with` are how to get
+/// different pieces there.
+fn unseeded_rng() -> StdRng {
+    #[cfg(feature = "native")]
+    return StdRng::from_entropy();
+    #[cfg(not(feature = "native"))]
+    StdRng::seed_from_u64(0)
+}
+
+impl ComposeCache {
+    pub fn seeded(seed: u64) -> Self {
+        ComposeCache {
+            compositions: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+            pad_tracks: false,
+            parallel: true,
+            accent: None,
+        }
+    }
+
+    /// Pad every composed piece with `Composition::pad_to_common_end`, so tracks that stop early
+    /// don't drift apart from the others when it loops. Off by default.
+    pub fn set_pad_tracks(&mut self, pad_tracks: bool) {
+        self.pad_tracks = pad_tracks;
+    }
+
+    /// Compose the branches of big splits at the same time, with the `rayon` feature. On by default.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel;
+    }
+
+    /// The accent every piece of music starts with, until an `::accent=` changes it. Off by default.
+    pub fn set_accent(&mut self, accent: Option<Accent>) {
+        self.accent = accent;
+    }
+}
+
+/// What music in brackets takes over from the music around it, so the meta controls before the
+/// brackets still hold inside them. Changes made inside don't last past the closing bracket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Context {
+    instrument: Instrument,
+    /// For notes without a `<...>` duration.
+    duration: MusicTime,
+    /// Set by `::ch=`, over the one the instrument is mapped to.
+    channel: Option<MidiChannel>,
+    /// Vibrato and tremolo from `::vib=` and `::trem=`.
+    modulation: Modulation,
+    /// Set by `::tag=`.
+    tag: Option<Tag>,
+    /// What relative octaves are measured from.
+    previous_pitch: Option<Pitch>,
+    /// Set by `::accent=`, or the cache's to begin with.
+    accent: Option<Accent>,
+    /// How far into a bar the music starts, for finding the beats to accent. Left at zero when
+    /// nothing is accented, so the same music starting elsewhere in a bar is only composed once.
+    bar_offset: Beat,
+}
+
+impl Context {
+    /// Where music that isn't nested in anything starts.
+    fn new(instrument: Instrument) -> Self {
+        Context {
+            instrument,
+            duration: MusicTime::beats(1),
+            channel: None,
+            modulation: Modulation::NONE,
+            tag: None,
+            previous_pitch: None,
+            accent: None,
+            bar_offset: Beat::zero(),
+        }
+    }
+
+    /// The context for `primitive` composed at `start` in the music this is the context of.
+    fn nested(&self, primitive: &MusicPrimitive, start: MusicTime, time_signature: TimeSignature) -> Context {
+        let bar_offset = if self.accent.is_some() || primitive.sets_accent() {
+            (MusicTime(0, self.bar_offset).with(time_signature) + start).1
+        } else {
+            Beat::zero()
+        };
+        Context { bar_offset, ..self.clone() }
+    }
+}
+
+/// Splits with fewer primitives than this in all are composed one branch after another even
+/// with the `rayon` feature, since handing them to other threads takes longer than composing them.
+pub const PARALLEL_SPLIT_SIZE: usize = 512;
+
+/// The branches of a split, each composed in `context`. With the `rayon` feature, big
+/// splits that don't roll any dice are composed on all cores, each branch with a cache of its
+/// own, and the results go into `cache` afterwards.
+fn compose_branches(branches: &[MusicString], time_signature: TimeSignature, context: &Context, cache: &mut ComposeCache) -> Result<Vec<Rc<Composition>>, ComposeError> {
+    #[cfg(feature = "rayon")]
+    if cache.parallel
+        && branches.len() > 1
+        && branches.iter().map(MusicString::primitive_count).sum::<usize>() >= PARALLEL_SPLIT_SIZE
+        && branches.iter().all(MusicString::is_deterministic) {
+        use rayon::prelude::*;
+        let pad_tracks = cache.pad_tracks;
+        let composed = branches.par_iter()
+            .map(|branch| {
+                let mut cache = ComposeCache { pad_tracks, ..ComposeCache::seeded(0) };
+                branch.compose_with(time_signature, context, &mut cache)
+            })
+            .collect::<Vec<_>>();
+        return composed.into_iter().zip(branches)
+            .map(|(composed, branch)| {
+                let composed = Rc::new(composed?);
+                cache.compositions.entry(context.clone()).or_default().insert(branch.clone(), Rc::clone(&composed));
+                Ok(composed)
+            })
+            .collect();
+    }
+    branches.iter()
+        .map(|branch| branch.compose_in(time_signature, context, cache))
+        .collect()
+}
+
+/// Composed passes of repeated music, each with where it starts.
+type Passes = Vec<(Rc<Composition>, MusicTime)>;
+
+/// `content` `num` times over from `start`, each pass with where it starts, and how long they
+/// take together. A pass is only composed again when it starts somewhere else in a bar and
+/// accents fall elsewhere in it.
+fn compose_repeats(content: &MusicString, num: usize, start: MusicTime, nested: &impl Fn(MusicTime) -> Context, time_signature: TimeSignature, cache: &mut ComposeCache) -> Result<(Passes, MusicTime), ComposeError> {
+    let mut context = nested(start);
+    let mut composed = content.compose_in(time_signature, &context, cache)?;
+    let duration = composed.get_duration();
+    let mut passes = Vec::with_capacity(num);
+    let mut offset = start;
+    for _i in 0..num {
+        let pass = nested(offset);
+        if pass != context {
+            composed = content.compose_in(time_signature, &pass, cache)?;
+            context = pass;
+        }
+        passes.push((Rc::clone(&composed), offset));
+        offset = offset.with(time_signature) + duration;
+    }
+    let mut total_duration = MusicTime::zero();
+    for _i in 0..num {
+        total_duration = total_duration.with(time_signature) + duration;
+    }
+    Ok((passes, total_duration))
+}
+
+/// `content` composed in `context` and then moved about by `move_notes`, like in a `[>>n]`. The
+/// notes are accented by where they end up, unless the content sets an accent of its own, which
+/// counts the beats from before they were moved.
+fn compose_moved(content: &MusicString, context: &Context, time_signature: TimeSignature, cache: &mut ComposeCache, move_notes: impl FnOnce(&mut Composition)) -> Result<Composition, ComposeError> {
+    let accent = context.accent.as_ref().filter(|_| !content.0.iter().any(MusicPrimitive::sets_accent));
+    let Some(accent) = accent else {
+        let mut composed = Rc::unwrap_or_clone(content.compose_in(time_signature, context, cache)?);
+        move_notes(&mut composed);
+        return Ok(composed);
+    };
+    let unaccented = Context { accent: None, bar_offset: Beat::zero(), ..context.clone() };
+    let mut composed = Rc::unwrap_or_clone(content.compose_in(time_signature, &unaccented, cache)?);
+    move_notes(&mut composed);
+    let start = MusicTime(0, context.bar_offset);
+    for track in &mut composed.tracks {
+        for e in track.events_mut() {
+            e.volume = accent.volume_at(e.volume, start.with(time_signature) + e.start);
+        }
+    }
+    Ok(composed)
+}
+
+/// Place a note written with relative octave marks next to the note before it.
+/// Without a previous note, it is measured from the note in the default octave.
+fn resolve_relative(pitch: Pitch, relative: i8, previous: Option<Pitch>) -> Pitch {
+    if relative == 0 {
+        return pitch;
+    }
+    let linear = |p: Pitch| p.0 as i32 * 12 + p.1 as i32;
+    let reference = linear(previous.unwrap_or(pitch));
+    let class = pitch.1 as i32;
+    let nearest = if relative > 0 {
+        // smallest pitch of this class strictly above the reference
+        reference + 1 + (class - reference - 1).rem_euclid(12)
+    } else {
+        reference - 1 - (reference - 1 - class).rem_euclid(12)
+    };
+    let linear = nearest + 12 * (relative.signum() as i32) * (relative.abs() as i32 - 1);
+    Pitch(linear.div_euclid(12) as Octave, linear.rem_euclid(12) as NoteNum)
+}
+
+impl MusicPrimitive {
+    /// Whether there is an `::accent=` in it.
+    fn sets_accent(&self) -> bool {
+        let any = |strings: &[MusicString]| strings.iter().flat_map(|s| &s.0).any(MusicPrimitive::sets_accent);
+        match self {
+            MusicPrimitive::Simple(symbol) => matches!(symbol, Symbol::T(Terminal::Meta(MetaControl::Accent(_)))),
+            MusicPrimitive::Split { branches, .. } => any(branches),
+            MusicPrimitive::Volta { endings } => any(endings),
+            #[allow(deprecated)]
+            MusicPrimitive::Repeat { content, .. } => any(std::slice::from_ref(content)),
+            MusicPrimitive::Transform { content, .. } => any(std::slice::from_ref(content)),
+        }
+    }
+}
+
 impl MusicString {
+    /// Replace the pitch of every note by `f` of it. Spellings of notes that change are dropped.
+    pub fn map_notes<F: FnMut(Pitch) -> Pitch>(&mut self, f: &mut F) {
+        for mp in &mut self.0 {
+            match mp {
+                MusicPrimitive::Simple(Symbol::T(Terminal::Music { note: TerminalNote::Note { pitch, spelling, .. }, .. })) => {
+                    let mapped = f(*pitch);
+                    if mapped != *pitch {
+                        *pitch = mapped;
+                        *spelling = None;
+                    }
+                }
+                MusicPrimitive::Simple(_) => {}
+                MusicPrimitive::Split { branches, .. } => branches.iter_mut().for_each(|b| b.map_notes(f)),
+                MusicPrimitive::Volta { endings } => endings.iter_mut().for_each(|e| e.map_notes(f)),
+                #[allow(deprecated)]
+                MusicPrimitive::Repeat { content, .. } => content.map_notes(f),
+                MusicPrimitive::Transform { transform, content } => {
+                    transform.map_notes(f);
+                    content.map_notes(f);
+                }
+            }
+        }
+    }
+
+    /// Every non-terminal in this string, including the ones inside brackets, in order.
+    pub fn non_terminals(&self) -> Vec<&NonTerminal> {
+        let mut non_terminals = vec![];
+        for mp in &self.0 {
+            match mp {
+                MusicPrimitive::Simple(Symbol::NT(nt)) => non_terminals.push(nt),
+                MusicPrimitive::Simple(_) => {}
+                MusicPrimitive::Split { branches, .. } => non_terminals.extend(branches.iter().flat_map(MusicString::non_terminals)),
+                MusicPrimitive::Volta { endings } => non_terminals.extend(endings.iter().flat_map(MusicString::non_terminals)),
+                #[allow(deprecated)]
+                MusicPrimitive::Repeat { content, .. } => non_terminals.extend(content.non_terminals()),
+                MusicPrimitive::Transform { content, .. } => non_terminals.extend(content.non_terminals()),
+            }
+        }
+        non_terminals
+    }
+
+    /// Whether composing this string always gives the same result, i.e. nothing in it is left to chance.
+    pub fn is_deterministic(&self) -> bool {
+        self.0.iter().all(|mp| match mp {
+            MusicPrimitive::Simple(_) => true,
+            MusicPrimitive::Split { mode: SplitMode::Choice { .. }, .. } => false,
+            MusicPrimitive::Split { branches, .. } => branches.iter().all(MusicString::is_deterministic),
+            MusicPrimitive::Volta { endings } => endings.iter().all(MusicString::is_deterministic),
+            #[allow(deprecated)]
+            MusicPrimitive::Repeat { content, .. } => content.is_deterministic(),
+            MusicPrimitive::Transform { transform: MusicTransform::Probability { .. }, .. } => false,
+            MusicPrimitive::Transform { transform: MusicTransform::Script { expr, .. }, .. } if expr.is_random() => false,
+            MusicPrimitive::Transform { content, .. } => content.is_deterministic(),
+        })
+    }
+
+    pub fn has_volta(&self) -> bool {
+        self.0.iter().any(|mp| matches!(mp, MusicPrimitive::Volta { .. }))
+    }
+
+    /// This string with every volta replaced by the ending for `pass`, counted from 0.
+    pub fn with_ending(&self, pass: usize) -> MusicString {
+        let mut string = Vec::with_capacity(self.0.len());
+        for mp in &self.0 {
+            match mp {
+                MusicPrimitive::Volta { endings } => {
+                    if let Some(ending) = endings.get(pass).or(endings.last()) {
+                        string.extend(ending.0.iter().cloned());
+                    }
+                }
+                mp => string.push(mp.clone()),
+            }
+        }
+        MusicString(string)
+    }
+
+    /// How many primitives there are in the string, counting the ones nested inside others.
+    pub fn primitive_count(&self) -> usize {
+        self.0.iter().map(|mp| 1 + match mp {
+            MusicPrimitive::Simple(_) => 0,
+            MusicPrimitive::Split { branches, .. } => branches.iter().map(MusicString::primitive_count).sum(),
+            MusicPrimitive::Volta { endings } => endings.iter().map(MusicString::primitive_count).sum(),
+            #[allow(deprecated)]
+            MusicPrimitive::Repeat { content, .. } => content.primitive_count(),
+            MusicPrimitive::Transform { content, .. } => content.primitive_count(),
+        }).sum()
+    }
+
     pub fn compose(&self, time_signature: TimeSignature, starting_instrument: Option<Instrument>) -> Result<Composition, ComposeError> {
-        let mut tracks = HashMap::new();
+        let context = Context::new(starting_instrument.unwrap_or(Instrument::SineWave));
+        self.compose_with(time_signature, &context, &mut ComposeCache::default())
+    }
+
+    /// Compose, reusing an earlier result for an identical subtree if there is one.
+    /// The cache must only be shared between calls with the same time signature.
+    pub fn compose_cached(&self, time_signature: TimeSignature, starting_instrument: Option<Instrument>, cache: &mut ComposeCache) -> Result<Rc<Composition>, ComposeError> {
+        let context = Context {
+            accent: cache.accent.clone(),
+            ..Context::new(starting_instrument.unwrap_or(Instrument::SineWave))
+        };
+        self.compose_in(time_signature, &context, cache)
+    }
+
+    fn compose_in(&self, time_signature: TimeSignature, context: &Context, cache: &mut ComposeCache) -> Result<Rc<Composition>, ComposeError> {
+        if !self.is_deterministic() {
+            // every occurrence gets its own roll of the dice
+            return Ok(Rc::new(self.compose_with(time_signature, context, cache)?));
+        }
+        if let Some(composed) = cache.compositions.get(context).and_then(|c| c.get(self)) {
+            return Ok(Rc::clone(composed));
+        }
+        let composed = Rc::new(self.compose_with(time_signature, context, cache)?);
+        cache.compositions.entry(context.clone())
+            .or_default()
+            .insert(self.clone(), Rc::clone(&composed));
+        Ok(composed)
+    }
+
+    fn compose_with(&self, time_signature: TimeSignature, context: &Context, cache: &mut ComposeCache) -> Result<Composition, ComposeError> {
+        let mut tracks: HashMap<Instrument, Track> = HashMap::new();
         fn add_event(tracks: &mut HashMap<Instrument, Track>, e: Event, instrument: Instrument) {
             if let Some(mut track) = tracks.get_mut(&instrument) {
-                track.events.push(e);
+                track.events_mut().push(e);
             } else {
                 tracks.insert(
                     instrument,
-                    Track {
-                        identifier: TrackId::Instrument(instrument),
-                        instrument,
-                        events: vec![e],
-                        rests: vec![],
-                    },
+                    Track::new(TrackId::Instrument(instrument), instrument, vec![e], vec![]),
                 );
             }
         }
@@ -169,186 +827,387 @@ impl MusicString {
             } else {
                 tracks.insert(
                     instrument,
-                    Track {
-                        identifier: TrackId::Instrument(instrument),
-                        instrument,
-                        events: vec![],
-                        rests: vec![e],
-                    },
+                    Track::new(TrackId::Instrument(instrument), instrument, vec![], vec![e]),
                 );
             }
         }
-        fn add_track(tracks: &mut HashMap<Instrument, Track>, track: Track) {
-            if let Some(mtrack) = tracks.remove(&track.instrument) {
-                tracks.insert(mtrack.instrument, mtrack + track);
-            } else {
-                tracks.insert(track.instrument, track);
-            }
-        }
-        fn add_composition(tracks: &mut HashMap<Instrument, Track>, composition: Composition) {
-            for track in composition.tracks {
-                add_track(tracks, track);
+        /// Copy the events of `composition` into `tracks`, moved later by `offset`.
+        /// The composition itself is left alone, so repeated material is never deep-copied
+        /// just to be shifted. Tracks are sorted once at the end of `compose`.
+        fn add_composition_at(tracks: &mut HashMap<Instrument, Track>, markers: &mut Vec<Marker>, composition: &Composition, offset: MusicTime) {
+            let time_signature = composition.time_signature;
+            let shift = |e: &Event| Event {
+                start: e.start.with(time_signature) + offset,
+                ..*e
+            };
+            for track in &composition.tracks {
+                let target = tracks.entry(track.instrument).or_insert_with(||
+                    Track::new(TrackId::Instrument(track.instrument), track.instrument, vec![], vec![])
+                );
+                target.events_mut().extend(track.events().iter().map(shift));
+                target.rests.extend(track.rests.iter().map(shift));
             }
+            markers.extend(composition.markers.iter().map(|m| Marker {
+                name: m.name.clone(),
+                time: m.time.with(time_signature) + offset,
+            }));
         }
         let mut current_mt = MusicTime::zero();
-        let mut current_instrument = starting_instrument.unwrap_or(Instrument::SineWave);
+        let mut current = context.clone();
         let mut current_volume = Volume(50);
+        let mut markers = vec![];
+        // the note still waiting for the note it is tied to, as an index into its track
+        let mut tie: Option<(Instrument, usize)> = None;
         for mp in self.0.iter() {
+            if !matches!(mp, MusicPrimitive::Simple(_)) {
+                // ties only join notes written next to each other
+                tie = None;
+            }
+            // where music in `mp` starts out when it begins at some time in this music
+            let nested = |start: MusicTime| current.nested(mp, start, time_signature);
             let duration = match mp {
                 MusicPrimitive::Simple(sym) => match sym {
                     Symbol::NT(_) => MusicTime::zero(),
-                    Symbol::T(Terminal::Music { note, duration }) => match note {
-                        TerminalNote::Note { pitch } => {
-                            add_event(
-                                &mut tracks,
-                                Event {
-                                    start: current_mt,
-                                    duration: duration.with(time_signature).total_beats(),
-                                    volume: current_volume,
-                                    pitch: *pitch,
-                                },
-                                current_instrument,
-                            );
-                            *duration
-                        }
-                        TerminalNote::Rest => {
-                            add_rest_event(
-                                &mut tracks,
-                                Event {
-                                    start: current_mt,
-                                    duration: duration.with(time_signature).total_beats(),
-                                    volume: Volume(0),
-                                    pitch: Pitch(0, 0),
-                                },
-                                current_instrument,
-                            );
-                            *duration
+                    Symbol::T(Terminal::Music { note, duration, tied, lyric }) => {
+                        let duration = duration.unwrap_or(current.duration);
+                        let pending_tie = tie.take();
+                        match note {
+                            TerminalNote::Note { pitch, spelling, relative } => {
+                                let pitch = &resolve_relative(*pitch, *relative, current.previous_pitch);
+                                current.previous_pitch = Some(*pitch);
+                                let continued = pending_tie
+                                    .filter(|(instrument, _)| *instrument == current.instrument)
+                                    .and_then(|(instrument, i)| tracks.get_mut(&instrument).map(|t| &mut t.events_mut()[i]))
+                                    .filter(|e| e.pitch == *pitch);
+                                if let Some(e) = continued {
+                                    e.duration = e.duration + duration.with(time_signature).total_beats();
+                                    if *tied {
+                                        tie = pending_tie;
+                                    }
+                                } else {
+                                    add_event(
+                                        &mut tracks,
+                                        Event {
+                                            start: current_mt,
+                                            duration: duration.with(time_signature).total_beats(),
+                                            volume: current.accent.as_ref().map_or(current_volume, |a| {
+                                                a.volume_at(current_volume, MusicTime(0, current.bar_offset).with(time_signature) + current_mt)
+                                            }),
+                                            pitch: *pitch,
+                                            modulation: current.modulation,
+                                            spelling: *spelling,
+                                            channel: current.channel,
+                                            tag: current.tag,
+                                            lyric: lyric.as_deref().map(Syllable::new),
+                                        },
+                                        current.instrument,
+                                    );
+                                    if *tied {
+                                        tie = Some((current.instrument, tracks[&current.instrument].events().len() - 1));
+                                    }
+                                }
+                                duration
+                            }
+                            TerminalNote::Rest => {
+                                add_rest_event(
+                                    &mut tracks,
+                                    Event {
+                                        start: current_mt,
+                                        duration: duration.with(time_signature).total_beats(),
+                                        volume: Volume(0),
+                                        pitch: Pitch(0, 0),
+                                        modulation: Modulation::NONE,
+                                        spelling: None,
+                                        channel: None,
+                                        tag: None,
+                                        lyric: None,
+                                    },
+                                    current.instrument,
+                                );
+                                duration
+                            }
                         }
-                    },
+                    }
                     Symbol::T(Terminal::Meta(control)) => {
                         match control {
                             MetaControl::ChangeInstrument(i) => {
-                                current_instrument = *i;
+                                current.instrument = *i;
                             }
                             MetaControl::ChangeVolume(v) => {
                                 current_volume = *v;
                             }
+                            MetaControl::Vibrato(lfo) => {
+                                current.modulation.vibrato = *lfo;
+                            }
+                            MetaControl::Tremolo(lfo) => {
+                                current.modulation.tremolo = *lfo;
+                            }
+                            MetaControl::DefaultDuration(d) => {
+                                current.duration = *d;
+                            }
+                            MetaControl::Channel(channel) => {
+                                current.channel = Some(*channel);
+                            }
+                            MetaControl::Tag(tag) => {
+                                current.tag = Some(Tag::new(tag));
+                            }
+                            MetaControl::Mark(name) => {
+                                markers.push(Marker { name: name.clone(), time: current_mt });
+                            }
+                            MetaControl::Accent(accent) => {
+                                current.accent = Some(accent.clone());
+                            }
                         }
                         MusicTime::zero()
                     }
                 },
-                MusicPrimitive::Split { branches } => {
-                    let comps: Vec<_> = branches
+                MusicPrimitive::Split { branches, mode: SplitMode::Choice { weights }, .. } => {
+                    let weights = WeightedIndex::new(weights)
+                        .map_err(|e| ComposeError::BadWeights(format!("Can't choose a branch with weights {weights:?}: {e}")))?;
+                    let branch = &branches[cache.rng.sample(&weights)];
+                    let composed = branch.compose_in(time_signature, &nested(current_mt), cache)?;
+                    add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                    composed.get_duration()
+                }
+                MusicPrimitive::Split { branches, policy, .. } => {
+                    let comps: Vec<_> = compose_branches(branches, time_signature, &nested(current_mt), cache)?
                         .into_iter()
-                        .map(|ms| ms.compose(time_signature, Some(current_instrument)))
-                        .err_first()?
-                        .map(|mut c| {
-                            c.shift_by(current_mt);
-                            c
-                        })
                         .map(|c| (c.get_duration(), c))
                         .collect();
-                    let uniform_duration = match comps.first() {
-                        Some((duration, _c)) => {
-                            if comps.iter().all(|(d, _c)| d == duration) {
-                                Some(*duration)
-                            } else {
-                                None
+                    let shortest = comps.iter().map(|(d, _c)| *d).min();
+                    let longest = comps.iter().map(|(d, _c)| *d).max();
+                    match (policy, shortest, longest) {
+                        // there are none, so they all have the same length
+                        (_, None, _) | (_, _, None) => MusicTime::zero(),
+                        (_, Some(shortest), Some(longest)) if shortest == longest => {
+                            for (_d, comp) in comps {
+                                add_composition_at(&mut tracks, &mut markers, &comp, current_mt);
                             }
+                            longest
                         }
-                        // there are none, so yes they are
-                        None => Some(MusicTime::zero()),
-                    };
-                    if let Some(dur) = uniform_duration {
-                        for (_d, comp) in comps {
-                            add_composition(&mut tracks, comp);
+                        (SplitPolicy::Strict, _, _) => {
+                            return Err(ComposeError::MismatchedLengths(
+                                format!("Not all split tracks have the same duration: {:?}",
+                                        comps.iter().map(|(d, _c)| d).collect::<Vec<_>>()
+                                )));
+                        }
+                        (SplitPolicy::Pad, _, Some(longest)) => {
+                            for (d, comp) in comps {
+                                add_composition_at(&mut tracks, &mut markers, &comp, current_mt);
+                                if d < longest {
+                                    // the rest goes with whatever the branch ends on
+                                    let instrument = comp.tracks.iter()
+                                        .filter_map(|t| Some((t.get_end(time_signature)?, t.instrument)))
+                                        .max()
+                                        .map_or(current.instrument, |(_end, instrument)| instrument);
+                                    add_rest_event(
+                                        &mut tracks,
+                                        Event {
+                                            start: current_mt.with(time_signature) + d,
+                                            duration: (longest.with(time_signature) - d).with(time_signature).total_beats(),
+                                            volume: Volume(0),
+                                            pitch: Pitch(0, 0),
+                                            modulation: Modulation::NONE,
+                                            spelling: None,
+                                            channel: None,
+                                            tag: None,
+                                            lyric: None,
+                                        },
+                                        instrument,
+                                    );
+                                }
+                            }
+                            longest
+                        }
+                        (SplitPolicy::Truncate, Some(shortest), _) => {
+                            for (_d, comp) in comps {
+                                let mut comp = Rc::unwrap_or_clone(comp);
+                                comp.truncate(shortest);
+                                add_composition_at(&mut tracks, &mut markers, &comp, current_mt);
+                            }
+                            shortest
+                        }
+                        (SplitPolicy::Longest, _, Some(longest)) => {
+                            for (_d, comp) in comps {
+                                add_composition_at(&mut tracks, &mut markers, &comp.looped(longest), current_mt);
+                            }
+                            longest
                         }
-                        dur
-                    } else {
-                        return Err(ComposeError::MismatchedLengths(
-                            format!("Not all split tracks have the same duration: {:?}",
-                                    comps.iter().map(|(d, c)| d).collect::<Vec<_>>()
-                            )));
                     }
                 }
+                MusicPrimitive::Volta { .. } => {
+                    // not inside a repeat, so this is the first pass
+                    let ending = MusicString(vec![mp.clone()]).with_ending(0);
+                    let composed = ending.compose_in(time_signature, &nested(current_mt), cache)?;
+                    add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                    composed.get_duration()
+                }
                 MusicPrimitive::Repeat { content, num } => {
-                    let composed = content.compose(time_signature, Some(current_instrument))?;
-                    let duration = composed.get_duration();
-                    let mut offset = current_mt;
-                    for _i in 0..*num {
-                        let mut comp_i = composed.clone();
-                        comp_i.shift_by(offset);
-                        add_composition(&mut tracks, comp_i);
-                        offset = offset.with(time_signature) + duration;
+                    let (passes, total_duration) = compose_repeats(content, *num, current_mt, &nested, time_signature, cache)?;
+                    for (composed, offset) in &passes {
+                        add_composition_at(&mut tracks, &mut markers, composed, *offset);
                     }
-                    let mut total_duration = MusicTime::zero();
-                    for _i in 0..*num {
-                        total_duration = total_duration.with(time_signature) + duration;
-                    }
-                    // println!("total duration for {num} repeats is {total_duration:?}, or {:?} * {num}",
-                    //          composed.get_duration());
                     total_duration
                 },
                 MusicPrimitive::Transform { transform, content } => {
                     match transform {
                         MusicTransform::Transpose { semitones} => {
-                            let mut composed = content.compose(time_signature, Some(current_instrument))?;
+                            let mut composed = Rc::unwrap_or_clone(content.compose_in(time_signature, &nested(current_mt), cache)?);
                             composed.transpose(*semitones);
-                            composed.shift_by(current_mt);
                             let duration = composed.get_duration();
-                            add_composition(&mut tracks, composed);
+                            add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
                             duration
                         }
-                        MusicTransform::Repeat { num } => {
-                            let composed = content.compose(time_signature, Some(current_instrument))?;
-                            let duration = composed.get_duration();
+                        MusicTransform::Repeat { num } if content.has_volta() => {
+                            // every pass can be different, so compose them one by one
                             let mut offset = current_mt;
-                            for _i in 0..*num {
-                                let mut comp_i = composed.clone();
-                                comp_i.shift_by(offset);
-                                add_composition(&mut tracks, comp_i);
-                                offset = offset.with(time_signature) + duration;
-                            }
                             let mut total_duration = MusicTime::zero();
-                            for _i in 0..*num {
+                            for pass in 0..*num {
+                                let composed = content.with_ending(pass).compose_in(time_signature, &nested(offset), cache)?;
+                                let duration = composed.get_duration();
+                                add_composition_at(&mut tracks, &mut markers, &composed, offset);
+                                offset = offset.with(time_signature) + duration;
                                 total_duration = total_duration.with(time_signature) + duration;
                             }
-                            // println!("total duration for {num} repeats is {total_duration:?}, or {:?} * {num}",
-                            //          composed.get_duration());
+                            total_duration
+                        }
+                        MusicTransform::Repeat { num } => {
+                            let (passes, total_duration) = compose_repeats(content, *num, current_mt, &nested, time_signature, cache)?;
+                            for (composed, offset) in &passes {
+                                add_composition_at(&mut tracks, &mut markers, composed, *offset);
+                            }
                             total_duration
                         }
                         MusicTransform::Compression { factor } => {
-                            let mut composed = content.compose(time_signature, Some(current_instrument))?;
-                            composed.compress(*factor);
-                            composed.shift_by(current_mt);
+                            let composed = compose_moved(content, &nested(current_mt), time_signature, cache, |c| c.compress(*factor))?;
+                            let duration = composed.get_duration();
+                            add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                            duration
+                        }
+                        MusicTransform::ScaleVolume { percent } => {
+                            let mut composed = Rc::unwrap_or_clone(content.compose_in(time_signature, &nested(current_mt), cache)?);
+                            composed.scale_volume(*percent as f32 / 100.);
                             let duration = composed.get_duration();
-                            add_composition(&mut tracks, composed);
+                            add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
                             duration
                         }
+                        MusicTransform::Mirror { center, scale } => {
+                            let mut composed = Rc::unwrap_or_clone(content.compose_in(time_signature, &nested(current_mt), cache)?);
+                            composed.mirror(*center, *scale);
+                            let duration = composed.get_duration();
+                            add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                            duration
+                        }
+                        MusicTransform::Stutter { times } => {
+                            let composed = compose_moved(content, &nested(current_mt), time_signature, cache, |c| c.stutter(*times))?;
+                            let duration = composed.get_duration();
+                            add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                            duration
+                        }
+                        MusicTransform::AlignToBar => {
+                            let whole_measures = |t: MusicTime| if t.1 == Beat::zero() { t } else { MusicTime(t.0 + 1, Beat::zero()) };
+                            let start = whole_measures(current_mt);
+                            let composed = content.compose_in(time_signature, &nested(start), cache)?;
+                            let end = start.with(time_signature) + whole_measures(composed.get_duration());
+                            add_composition_at(&mut tracks, &mut markers, &composed, start);
+                            let content_end = start.with(time_signature) + composed.get_duration();
+                            for (from, to) in [(current_mt, start), (content_end, end)] {
+                                if from < to {
+                                    add_rest_event(
+                                        &mut tracks,
+                                        Event {
+                                            start: from,
+                                            duration: (to.with(time_signature) - from).with(time_signature).total_beats(),
+                                            volume: Volume(0),
+                                            pitch: Pitch(0, 0),
+                                            modulation: Modulation::NONE,
+                                            spelling: None,
+                                            channel: None,
+                                            tag: None,
+                                            lyric: None,
+                                        },
+                                        current.instrument,
+                                    );
+                                }
+                            }
+                            end.with(time_signature) - current_mt
+                        }
+                        MusicTransform::Named { name } => {
+                            return Err(ComposeError::UnknownTransform(format!("No transform named '{name}' was defined")));
+                        }
+                        MusicTransform::Probability { percent } => {
+                            let composed = content.compose_in(time_signature, &nested(current_mt), cache)?;
+                            if cache.rng.gen_bool((*percent).min(100) as f64 / 100.) {
+                                add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                            }
+                            composed.get_duration()
+                        }
+                        // one that no rewrite has expanded, e.g. in the axiom
+                        MusicTransform::Script { target, expr } => {
+                            match run_script(*target, expr, content.clone(), Derivation::default(), &mut cache.rng) {
+                                Some(primitive) => {
+                                    let composed = MusicString(vec![primitive]).compose_in(time_signature, &nested(current_mt), cache)?;
+                                    add_composition_at(&mut tracks, &mut markers, &composed, current_mt);
+                                    composed.get_duration()
+                                }
+                                None => MusicTime::zero(),
+                            }
+                        }
                     }
                 }
             };
             current_mt = current_mt.with(time_signature) + duration;
         }
-        Ok(Composition {
+        tracks.values_mut().for_each(Track::sort);
+        let mut composition = Composition {
             tracks: tracks.into_values().collect(),
             time_signature,
-        })
+            markers,
+        };
+        if cache.pad_tracks {
+            composition.pad_to_common_end();
+        }
+        Ok(composition)
     }
 
     /// Rewrites the music string according to the grammar, replacing non-terminals with their productions.
     /// If `random` is true, it will choose a random production for each non-terminal.
     /// If `panic_on_bad_production` is true, it will panic if a non-terminal has no production.
+    /// Bars are counted in common time.
     pub fn parallel_rewrite(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool) -> Self {
+        self.parallel_rewrite_at(grammar, random, panic_on_bad_production, Derivation::default(), TimeSignature::common(), &mut unseeded_rng())
+    }
+
+    /// Like `parallel_rewrite`, but only choosing productions whose guards allow `derivation`,
+    /// and picking random ones with `rng`. The string starts in `derivation.bar`, and each
+    /// symbol sees the bar it starts in, with the non-terminals before it counted as the
+    /// shortest music they can become.
+    pub fn parallel_rewrite_at(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, derivation: Derivation, time_signature: TimeSignature, rng: &mut impl Rng) -> Self {
+        let pass = RewritePass {
+            grammar,
+            random,
+            panic_on_bad_production,
+            time_signature,
+            ranges: grammar.non_terminal_ranges(time_signature),
+        };
+        self.rewrite_from(&pass, derivation, MusicTime::zero(), MusicTime::beats(1), rng)
+    }
+
+    /// Rewrite this string starting `start` after the beginning of `derivation.bar`.
+    fn rewrite_from(&self, pass: &RewritePass, derivation: Derivation, mut start: MusicTime, mut default_duration: MusicTime, rng: &mut impl Rng) -> Self {
+        let time_signature = pass.time_signature;
         let mut new_string = vec![];
         for (i, mp) in self.0.iter().enumerate() {
+            let at = Derivation { bar: derivation.bar + start.0 as usize, ..derivation };
+            let mut nested = |ms: &MusicString, start: MusicTime| ms.rewrite_from(pass, derivation, start, default_duration, rng);
             match mp {
                 MusicPrimitive::Simple(x) => match x {
                     Symbol::NT(nt) => {
-                        if let Some(Production(nt, ms)) = if random { grammar.get_production_random(nt) } else { grammar.get_production(nt) } {
-                            new_string.extend(ms.clone().0);
+                        let production = if pass.random { pass.grammar.get_production_random(nt, at, rng) } else { pass.grammar.get_production(nt, at) };
+                        if let Some(Production(_nt, ms, _guard)) = production {
+                            new_string.extend(ms.run_scripts(at, rng).0);
                         } else {
-                            if panic_on_bad_production {
+                            if pass.panic_on_bad_production {
                                 panic!("No production found for non-terminal {:?} at index {}", nt, i);
                             }
                         }
@@ -357,41 +1216,80 @@ impl MusicString {
                         new_string.push(MusicPrimitive::Simple(x.clone()));
                     }
                 }
-                MusicPrimitive::Split { branches } => {
+                MusicPrimitive::Split { branches, policy, mode } => {
                     let new_branches = branches
                         .iter()
-                        .map(|ms| ms.parallel_rewrite(grammar, random, panic_on_bad_production))
+                        .map(|ms| nested(ms, start))
+                        .collect::<Vec<_>>();
+                    new_string.push(MusicPrimitive::Split { branches: new_branches, policy: *policy, mode: mode.clone() });
+                }
+                MusicPrimitive::Volta { endings } => {
+                    let new_endings = endings
+                        .iter()
+                        .map(|ms| nested(ms, start))
                         .collect::<Vec<_>>();
-                    new_string.push(MusicPrimitive::Split { branches: new_branches });
+                    new_string.push(MusicPrimitive::Volta { endings: new_endings });
                 }
                 MusicPrimitive::Repeat { num, content } => {
-                    let new_content = content.parallel_rewrite(grammar, random, panic_on_bad_production);
+                    let new_content = nested(content, start);
                     new_string.push(MusicPrimitive::Repeat {
                         num: *num,
                         content: new_content,
                     });
                 }
                 MusicPrimitive::Transform { transform, content } => {
-                    let new_content = content.parallel_rewrite(grammar, random, panic_on_bad_production);
+                    let content_start = match transform {
+                        MusicTransform::AlignToBar if start.1 != Beat::zero() => MusicTime::measures(start.0 + 1),
+                        _ => start,
+                    };
+                    let new_content = nested(content, content_start);
                     new_string.push(MusicPrimitive::Transform {
                         transform: transform.clone(),
                         content: new_content,
                     });
                 }
             }
+            let length = primitive_range(mp, &pass.ranges, time_signature, &mut default_duration, &mut |_| {})
+                .map_or(Beat::zero(), |range| range.min);
+            start = start.with(time_signature) + length.as_music_time(time_signature);
         }
         MusicString(new_string)
     }
 
+    /// Rewrite `n` times, counting the depth up from 0 and bars in common time.
     pub fn parallel_rewrite_n(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, n: usize) -> Self {
+        self.parallel_rewrite_n_with(grammar, random, panic_on_bad_production, n, TimeSignature::common(), &mut unseeded_rng())
+    }
+
+    /// Like `parallel_rewrite_n`, counting bars in `time_signature` and picking random
+    /// productions with `rng`, so a seeded one rewrites the same way every time.
+    pub fn parallel_rewrite_n_with(&self, grammar: &Grammar, random: bool, panic_on_bad_production: bool, n: usize, time_signature: TimeSignature, rng: &mut impl Rng) -> Self {
+        let pass = RewritePass {
+            grammar,
+            random,
+            panic_on_bad_production,
+            time_signature,
+            ranges: grammar.non_terminal_ranges(time_signature),
+        };
         let mut new_string = self.clone();
-        for _i in 0..n {
-            new_string = new_string.parallel_rewrite(grammar, random, panic_on_bad_production);
+        for depth in 0..n {
+            let derivation = Derivation { depth, bar: 0 };
+            new_string = new_string.rewrite_from(&pass, derivation, MusicTime::zero(), MusicTime::beats(1), rng);
         }
         new_string
     }
 }
 
+/// What stays the same for every symbol rewritten in one pass.
+struct RewritePass<'a> {
+    grammar: &'a Grammar,
+    random: bool,
+    panic_on_bad_production: bool,
+    time_signature: TimeSignature,
+    /// How long each non-terminal can be, for finding the bar the symbols after it start in.
+    ranges: HashMap<NonTerminal, DurationRange>,
+}
+
 impl ToString for MusicString {
     fn to_string(&self) -> String {
         let mut s = String::new();
@@ -401,14 +1299,28 @@ impl ToString for MusicString {
                     let sym_to_string = sym.to_string();
                     s.push_str(&sym_to_string);
                 }
-                MusicPrimitive::Split { branches } => {
+                MusicPrimitive::Split { branches, policy, mode } => {
                     s.push_str("{");
                     let str = branches.into_iter()
-                        .map(|b| b.to_string())
+                        .enumerate()
+                        .map(|(i, b)| match mode {
+                            SplitMode::Choice { weights } if i > 0 || weights[0] != 1 => format!("{} {}", weights[i], b.to_string()),
+                            _ => b.to_string(),
+                        })
                         .reduce(|b1, b2| b1 + " | " + &b2)
                         .unwrap_or("".to_string());
                     s.push_str(&str);
                     s.push('}');
+                    s.push_str(policy.suffix());
+                }
+                MusicPrimitive::Volta { endings } => {
+                    s.push_str("{");
+                    let str = endings.iter()
+                        .map(|b| b.to_string())
+                        .reduce(|b1, b2| b1 + " | " + &b2)
+                        .unwrap_or("".to_string());
+                    s.push_str(&str);
+                    s.push_str("}volta");
                 }
                 MusicPrimitive::Repeat { num, content } => panic!("Repeat is deprecated, use Transform instead"),
                 MusicPrimitive::Transform { transform, content } => {
@@ -435,7 +1347,7 @@ impl ToString for Symbol {
 impl ToString for NonTerminal {
     fn to_string(&self) -> String {
         match self {
-            NonTerminal::Custom(s) => s.clone(),
+            NonTerminal::Custom(s) => s.to_string(),
         }
     }
 }
@@ -443,14 +1355,28 @@ impl ToString for NonTerminal {
 impl ToString for Terminal {
     fn to_string(&self) -> String {
         match self {
-            Terminal::Music { duration, note } => {
+            Terminal::Music { duration, note, tied, lyric } => {
+                let duration = match duration {
+                    Some(duration) => format!("<{}>", duration.to_string()),
+                    None => String::new(),
+                };
+                let duration = if *tied { format!("{duration}~") } else { duration };
+                let duration = match lyric {
+                    Some(lyric) => format!("{duration}\"{lyric}\""),
+                    None => duration,
+                };
                 match note {
-                    TerminalNote::Note { pitch } => {
-                        let letter = pitch.letter_name();
-                        format!(":{letter}<{}>", duration.to_string())
+                    TerminalNote::Note { pitch, spelling, relative } => {
+                        let letter = spelling.map(|s| s.to_string()).unwrap_or_else(|| pitch.letter_name());
+                        let letter = match relative {
+                            0 => format!("{}{letter}", pitch.0),
+                            r if *r > 0 => format!("{letter}{}", "'".repeat(*r as usize)),
+                            r => format!("{letter}{}", ",".repeat(r.unsigned_abs() as usize)),
+                        };
+                        format!(":{letter}{duration}")
                     }
                     TerminalNote::Rest => {
-                        format!(":_<{}>", duration.to_string())
+                        format!(":_{duration}")
                     }
                 }
             }
@@ -483,7 +1409,17 @@ impl ToString for MetaControl {
     fn to_string(&self) -> String {
         match self {
             MetaControl::ChangeInstrument(i) => format!("::i={:?}", i),
-            MetaControl::ChangeVolume(v) => format!("::v={:?}", v),
+            MetaControl::ChangeVolume(v) => format!("::v={}", v.0),
+            MetaControl::Vibrato(lfo) => format!("::vib={}/{}", lfo.rate(), lfo.depth()),
+            MetaControl::Tremolo(lfo) => format!("::trem={}/{}", lfo.rate(), lfo.depth()),
+            MetaControl::DefaultDuration(d) => format!("::d={}", d.to_string()),
+            MetaControl::Channel(channel) => format!("::ch={}", channel + 1),
+            MetaControl::Tag(tag) => format!("::tag={tag}"),
+            MetaControl::Mark(name) => format!("::mark={name}"),
+            MetaControl::Accent(Accent { amount, beats }) if *beats == [1] => format!("::accent={amount}"),
+            MetaControl::Accent(Accent { amount, beats }) => {
+                format!("::accent={amount}/{}", beats.iter().map(|b| b.to_string()).collect::<Vec<_>>().join("."))
+            }
         }
     }
 }
@@ -491,8 +1427,11 @@ impl FromStr for MusicString {
     type Err = ScanError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let _span = debug_span!("parse_music_string", bytes = s.len()).entered();
         let scanner = consume(MusicStringScanner);
-        scanner.scan(s).map(|(r, _s)| r)
+        scanner.scan(&strip_comments(s)?)
+            .map(|(r, _s)| r)
+            .inspect_err(|e| debug!(error = ?e, "music string didn't parse"))
     }
 }
 
@@ -519,5 +1458,460 @@ where
 
 #[cfg(test)]
 mod test {
-    
-}
\ No newline at end of file
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use std::rc::Rc;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::cfg::{Accent, ComposeCache, Derivation, Grammar, MusicPrimitive, MusicString, Symbol, Terminal, TerminalNote};
+    use crate::composition::{Composition, Instrument, Lfo, Pitch, Volume};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_compose_repeat_offsets() {
+        let string = MusicString::from_str("[x64][:c :d {:e | :g}]").unwrap();
+        let music = string.compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(music.tracks.len(), 1);
+        let events = music.tracks[0].events();
+        assert_eq!(events.len(), 64 * 4);
+        assert_eq!(music.get_duration(), MusicTime::measures(48));
+        let last = events.last().unwrap();
+        assert_eq!(last.start, MusicTime(47, Beat::whole(3)));
+        assert_eq!(last.pitch, Pitch(4, 10));
+    }
+
+    #[test]
+    fn test_compose_cached_reuses_subtrees() {
+        let ts = TimeSignature::common();
+        let phrase = MusicString::from_str(":c :d [T2][:e :f]").unwrap();
+        let mut cache = ComposeCache::default();
+        let first = phrase.compose_cached(ts, None, &mut cache).unwrap();
+        let second = phrase.compose_cached(ts, None, &mut cache).unwrap();
+        assert!(Rc::ptr_eq(&first, &second));
+        let other_instrument = phrase.compose_cached(ts, Some(Instrument::Piano), &mut cache).unwrap();
+        assert!(!Rc::ptr_eq(&first, &other_instrument));
+        assert_eq!(*first, phrase.compose(ts, None).unwrap());
+    }
+
+    #[test]
+    fn test_compose_vibrato_meta_control() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c ::vib=5/0.3 :d ::vib=0/0 :e").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let modulation = composition.tracks[0].events().iter()
+            .map(|e| e.modulation.vibrato)
+            .collect::<Vec<_>>();
+        assert_eq!(modulation, vec![Lfo::OFF, Lfo::new(5., 0.3), Lfo::OFF]);
+
+        let nested = MusicString::from_str("::vib=5/0.3 [x2][:c] {:d | ::trem=2/0.5 :e}").unwrap();
+        let composition = nested.compose(ts, None).unwrap();
+        let modulation = composition.tracks[0].events().iter()
+            .map(|e| (e.modulation.vibrato, e.modulation.tremolo))
+            .collect::<Vec<_>>();
+        let vibrato = Lfo::new(5., 0.3);
+        assert_eq!(modulation, vec![(vibrato, Lfo::OFF), (vibrato, Lfo::OFF), (vibrato, Lfo::OFF), (vibrato, Lfo::new(2., 0.5))]);
+    }
+
+    #[test]
+    fn test_compose_default_duration() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c ::d=1/4 :d :e<2> :f ::d=2 :g").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let events = composition.tracks[0].events();
+        let durations = events.iter().map(|e| e.duration).collect::<Vec<_>>();
+        assert_eq!(durations, vec![Beat::whole(1), Beat::new(1, 4), Beat::whole(2), Beat::new(1, 4), Beat::whole(2)]);
+        assert_eq!(events[4].start, MusicTime(0, Beat::new(7, 2)));
+
+        // still holds inside brackets, but not after a change made in them
+        let nested = MusicString::from_str("::d=1/2 [x2][:c :d] {:e | ::d=2 :g}truncate :a").unwrap();
+        let composition = nested.compose(ts, None).unwrap();
+        let durations = composition.tracks[0].events().iter().map(|e| e.duration).collect::<Vec<_>>();
+        assert_eq!(durations, vec![Beat::new(1, 2); 7]);
+    }
+
+    #[test]
+    fn test_compose_dotted_and_tied() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c<1.> :d<1/2> :e<2>~ :e<2>~ ::v=80 :e :f~ :g :a").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let events = composition.tracks[0].events();
+        let notes = events.iter().map(|e| (e.pitch, e.duration)).collect::<Vec<_>>();
+        assert_eq!(notes, vec![
+            (Pitch(4, 3), Beat::new(3, 2)),
+            (Pitch(4, 5), Beat::new(1, 2)),
+            (Pitch(4, 7), Beat::whole(5)),
+            (Pitch(4, 8), Beat::whole(1)),
+            (Pitch(4, 10), Beat::whole(1)),
+            (Pitch(4, 0), Beat::whole(1)),
+        ]);
+        assert_eq!(events[3].start, MusicTime(1, Beat::whole(3)));
+    }
+
+    #[test]
+    fn test_compose_relative_octaves() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":4c :g, :e' :c'' :c, :e+ :g-").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let pitches = composition.tracks[0].events().iter().map(|e| e.pitch).collect::<Vec<_>>();
+        assert_eq!(pitches, vec![
+            Pitch(4, 3),  // c
+            Pitch(3, 10), // nearest g below
+            Pitch(4, 7),  // nearest e above
+            Pitch(6, 3),  // an octave past the nearest c above
+            Pitch(5, 3),
+            Pitch(5, 7),
+            Pitch(4, 10),
+        ]);
+
+        // the first note in brackets goes on from the note before them
+        let nested = MusicString::from_str(":5c [x2][:d'] {:b, | :e'}").unwrap();
+        let pitches = nested.compose(ts, None).unwrap().tracks[0].events().iter().map(|e| e.pitch).collect::<Vec<_>>();
+        assert_eq!(pitches, vec![Pitch(5, 3), Pitch(5, 5), Pitch(5, 5), Pitch(5, 2), Pitch(5, 7)]);
+    }
+
+    #[test]
+    fn test_relative_octave_cannot_follow_octave_number() {
+        assert!(MusicString::from_str(":4c'").is_err());
+        let relative = MusicString::from_str(":c#'' :d,").unwrap();
+        let written = relative.0.iter()
+            .map(|p| match p {
+                MusicPrimitive::Simple(Symbol::T(t)) => t.to_string(),
+                _ => panic!("expected only notes"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(written, vec![":C#''", ":D,"]);
+    }
+
+    #[test]
+    fn test_compose_channel_meta_control() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c ::ch=10 :d :e").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let channels = composition.tracks[0].events().iter().map(|e| e.channel).collect::<Vec<_>>();
+        assert_eq!(channels, vec![None, Some(9), Some(9)]);
+        let nested = MusicString::from_str("::ch=3 [x2][:c] {:d | ::i=piano :e}").unwrap().compose(ts, None).unwrap();
+        assert!(nested.tracks.iter().flat_map(|t| t.events()).all(|e| e.channel == Some(2)));
+        assert!(MusicString::from_str("::ch=0 :c").is_err());
+        assert!(MusicString::from_str("::ch=17 :c").is_err());
+    }
+
+    #[test]
+    fn test_compose_tags_and_markers() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c ::tag=lead ::mark=verse :d [x2][::tag=hook :e ::mark=chorus :f] {:g | :a} :b").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let tags = composition.tracks[0].events().iter().map(|e| e.tag.map(|t| t.as_str())).collect::<Vec<_>>();
+        // the hook ends with the brackets it is in, and the lead goes on into the split
+        assert_eq!(tags, vec![None, Some("lead"), Some("hook"), Some("hook"), Some("hook"), Some("hook"), Some("lead"), Some("lead"), Some("lead")]);
+        let markers = composition.markers.iter().map(|m| (m.name.as_str(), m.time)).collect::<Vec<_>>();
+        assert_eq!(markers, vec![("verse", MusicTime::beats(1)), ("chorus", MusicTime::beats(3)), ("chorus", MusicTime(1, Beat::whole(1)))]);
+        assert_eq!(composition.marker("chorus"), Some(MusicTime::beats(3)));
+        assert!(MusicString::from_str("::mark= :c").is_err());
+    }
+
+    #[test]
+    fn test_compose_lyrics() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c\"twin\" :c<2>~\"kle\" :c :g").unwrap();
+        assert_eq!(music.to_string().trim(), ":4C\"twin\" :4C<2>~\"kle\" :4C :4G");
+        let composition = music.compose(ts, None).unwrap();
+        let lyrics = composition.tracks[0].events().iter().map(|e| e.lyric.map(|l| l.as_str())).collect::<Vec<_>>();
+        assert_eq!(lyrics, vec![Some("twin"), Some("kle"), None]);
+        assert!(MusicString::from_str(":c\"twin :d").is_err());
+    }
+
+    #[test]
+    fn test_compose_split_policies() {
+        let ts = TimeSignature::common();
+        let compose = |s: &str| MusicString::from_str(s).unwrap().compose(ts, None);
+        assert!(compose("{:c :d | :e<3>}").is_err());
+
+        let padded = compose("{:c :d | :e<3>}pad :f").unwrap();
+        assert_eq!(padded.get_duration(), MusicTime::measures(1));
+        let track = &padded.tracks[0];
+        assert_eq!(track.rests.len(), 1);
+        assert_eq!(track.rests[0].start, MusicTime::beats(2));
+
+        let truncated = compose("{:c :d | :e<3>}truncate").unwrap();
+        assert_eq!(truncated.get_duration(), MusicTime::beats(2));
+        let e = truncated.tracks[0].events().iter().find(|e| e.pitch == Pitch(4, 7)).unwrap();
+        assert_eq!(e.duration, Beat::whole(2));
+
+        let looped = compose("{:c :d | :e<3>}longest").unwrap();
+        assert_eq!(looped.get_duration(), MusicTime::beats(3));
+        let starts = looped.tracks[0].events().iter()
+            .filter(|e| e.pitch != Pitch(4, 7))
+            .map(|e| (e.start, e.pitch))
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![
+            (MusicTime::zero(), Pitch(4, 3)),
+            (MusicTime::beats(1), Pitch(4, 5)),
+            (MusicTime::beats(2), Pitch(4, 3)),
+        ]);
+    }
+
+    #[test]
+    fn test_compose_volta_endings() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str("[x3][:c {:d | :e<2> | :f}volta] {:g | :a}volta").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let notes = composition.tracks[0].events().iter().map(|e| (e.start, e.pitch)).collect::<Vec<_>>();
+        assert_eq!(notes, vec![
+            (MusicTime::zero(), Pitch(4, 3)),
+            (MusicTime::beats(1), Pitch(4, 5)),
+            (MusicTime::beats(2), Pitch(4, 3)),
+            (MusicTime::beats(3), Pitch(4, 7)),
+            (MusicTime::measures(1).with(ts) + MusicTime::beats(1), Pitch(4, 3)),
+            (MusicTime::measures(1).with(ts) + MusicTime::beats(2), Pitch(4, 8)),
+            (MusicTime::measures(1).with(ts) + MusicTime::beats(3), Pitch(4, 10)),
+        ]);
+        let written = music.to_string();
+        assert!(written.contains("{:4D  | :4E<2>  | :4F }volta"), "{written}");
+    }
+
+    #[test]
+    fn test_compose_volume_scaling() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str("::v=80 :c [v*0.5][:d ::v=60 :e] [v*2][:f]").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let volumes = composition.tracks[0].events().iter().map(|e| e.volume).collect::<Vec<_>>();
+        // the bracket starts over at the default volume of 50
+        assert_eq!(volumes, vec![Volume(80), Volume(25), Volume(30), Volume(100)]);
+    }
+
+    #[test]
+    fn test_compose_probability() {
+        let ts = TimeSignature::common();
+        let compose = |s: &str, seed: u64| {
+            let music = MusicString::from_str(s).unwrap();
+            Rc::unwrap_or_clone(music.compose_cached(ts, None, &mut ComposeCache::seeded(seed)).unwrap())
+        };
+        let never = compose("[?0][:c :d] :e", 0);
+        assert_eq!(never.tracks[0].events().len(), 1);
+        assert_eq!(never.tracks[0].events()[0].start, MusicTime::beats(2));
+        let always = compose("[?1][:c :d] :e", 0);
+        assert_eq!(always.tracks[0].events().len(), 3);
+
+        // the same seed makes the same choices, even for identical subtrees
+        let sparse = "[?0.5][:c] [?0.5][:c] [?0.5][:c] [?0.5][:c] [?0.5][:c] [?0.5][:c] [?0.5][:c] [?0.5][:c]";
+        let starts = |c: &Composition| c.tracks.iter()
+            .flat_map(|t| t.events().iter().map(|e| e.start))
+            .collect::<Vec<_>>();
+        assert_eq!(starts(&compose(sparse, 7)), starts(&compose(sparse, 7)));
+        let played = (0..20).map(|seed| starts(&compose(sparse, seed)).len()).collect::<Vec<_>>();
+        assert!(played.iter().any(|&n| n > 0 && n < 8), "{played:?}");
+    }
+
+    #[test]
+    fn test_compose_stutter() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str("[st4][:c<2>] :d").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let events = composition.tracks[0].events();
+        assert_eq!(events.len(), 5);
+        let starts = events.iter().map(|e| e.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![
+            MusicTime::zero(),
+            MusicTime(0, Beat::new(1, 2)),
+            MusicTime::beats(1),
+            MusicTime(0, Beat::new(3, 2)),
+            MusicTime::beats(2),
+        ]);
+        assert!(events[..4].iter().all(|e| e.duration == Beat::new(1, 2) && e.pitch == Pitch(4, 3)));
+        assert_eq!(music.to_string().trim(), "[st4][:4C<2> ] :4D");
+    }
+
+    #[test]
+    fn test_compose_mirror() {
+        let ts = TimeSignature::common();
+        let pitches = |s: &str| MusicString::from_str(s).unwrap()
+            .compose(ts, None).unwrap()
+            .tracks[0].events().iter().map(|e| e.pitch).collect::<Vec<_>>();
+        // chromatic: e f g mirrored around e is e d# c#
+        assert_eq!(pitches("[M 4e][:4e :4f :4g]"), vec![Pitch(4, 7), Pitch(4, 6), Pitch(4, 4)]);
+        // in c major, d and e mirror to b and a below c, and the c# is snapped down to c first
+        assert_eq!(pitches("[M 4c major][:4d :4e :4c#]"), vec![Pitch(4, 2), Pitch(4, 0), Pitch(4, 3)]);
+        // mirroring twice gives the original back
+        assert_eq!(pitches("[M 4e M 4e][:4e :4f :4g]"), vec![Pitch(4, 7), Pitch(4, 8), Pitch(4, 10)]);
+    }
+
+    #[test]
+    fn test_guarded_productions() {
+        let grammar = Grammar::from_str("start S\nS =(depth<3) S S\nS = :c\nB =(bar%4==0) :c\nB = :d").unwrap();
+        let subdivided = MusicString::from_str("S").unwrap().parallel_rewrite_n(&grammar, false, true, 4);
+        assert_eq!(subdivided, MusicString::from_str(":c :c :c :c :c :c :c :c").unwrap());
+        let downbeat = |bar| MusicString::from_str("B").unwrap()
+            .parallel_rewrite_at(&grammar, false, true, Derivation { depth: 0, bar }, TimeSignature::common(), &mut StdRng::seed_from_u64(0));
+        assert_eq!(downbeat(8), MusicString::from_str(":c").unwrap());
+        assert_eq!(downbeat(9), MusicString::from_str(":d").unwrap());
+
+        // each symbol sees the bar it starts in, with the ones before it as long as they are going to be
+        let bars = Grammar::from_str("start S\nS = B B {B | :e<4>} A B\nA = [x2][:g<2>] :a<4>\nB =(bar%2==0) :c<4>\nB = :d<4>").unwrap();
+        let rewritten = MusicString::from_str("S").unwrap().parallel_rewrite_n(&bars, false, true, 2);
+        assert_eq!(rewritten, MusicString::from_str(":c<4> :d<4> {:c<4> | :e<4>} [x2][:g<2>] :a<4> :d<4>").unwrap());
+    }
+
+    #[test]
+    fn test_random_rewrite() {
+        let grammar = Grammar::from_str("start S\nS = :c\nS = :d\nS = :e").unwrap();
+        let axiom = MusicString::from_str(&"S ".repeat(12)).unwrap();
+        let rewrite = |seed| axiom.parallel_rewrite_n_with(&grammar, true, true, 1, TimeSignature::common(), &mut StdRng::seed_from_u64(seed));
+        // every symbol gets a choice of its own, and the same seed makes the same ones
+        let choices = rewrite(5).0.into_iter().collect::<HashSet<_>>();
+        assert!(choices.len() > 1, "{choices:?}");
+        assert_eq!(rewrite(5), rewrite(5));
+        assert_ne!(axiom.parallel_rewrite_n(&grammar, true, true, 1).0.into_iter().collect::<HashSet<_>>().len(), 1);
+    }
+
+    #[test]
+    fn test_compose_weighted_split() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str("{:c :d |3 :e<4>}").unwrap();
+        let mut cache = ComposeCache::seeded(3);
+        let mut long = 0;
+        for _i in 0..40 {
+            let composed = music.compose_cached(ts, None, &mut cache).unwrap();
+            // only ever one branch
+            let events = composed.tracks[0].events();
+            match events.len() {
+                1 => {
+                    long += 1;
+                    assert_eq!(composed.get_duration(), MusicTime::measures(1));
+                }
+                _ => assert_eq!(events.iter().map(|e| e.pitch).collect::<Vec<_>>(), vec![Pitch(4, 3), Pitch(4, 5)]),
+            }
+        }
+        // picked 3 times as often, so nearly always more than the other one
+        assert!(long > 20 && long < 40, "{long}");
+    }
+
+    #[test]
+    fn test_compose_align_to_bar() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str(":c [bar][:d :e<2>] :f").unwrap();
+        let composition = music.compose(ts, None).unwrap();
+        let starts = composition.tracks[0].events().iter().map(|e| e.start).collect::<Vec<_>>();
+        // waits for the second measure, then takes the whole of it even though it only lasts 3 beats
+        assert_eq!(starts, vec![MusicTime::zero(), MusicTime::measures(1), MusicTime(1, Beat::whole(1)), MusicTime::measures(2)]);
+        assert_eq!(composition.get_duration(), MusicTime(2, Beat::whole(1)));
+        // already on a barline and a whole measure long, so nothing changes
+        let aligned = MusicString::from_str("[bar][:c<4>] :d").unwrap().compose(ts, None).unwrap();
+        assert_eq!(aligned.tracks[0].events()[1].start, MusicTime::measures(1));
+    }
+
+    #[test]
+    fn test_compose_padded_tracks() {
+        let ts = TimeSignature::common();
+        let music = MusicString::from_str("{:c<4> | ::i=piano :d}pad :e").unwrap();
+        let mut cache = ComposeCache::default();
+        cache.set_pad_tracks(true);
+        let composition = music.compose_cached(ts, None, &mut cache).unwrap();
+        let end = composition.get_end();
+        assert!(composition.tracks.iter().all(|t| t.get_end(ts) == end));
+        let unpadded = music.compose(ts, None).unwrap();
+        assert!(unpadded.tracks.iter().any(|t| t.get_end(ts) != unpadded.get_end()));
+    }
+
+    #[test]
+    fn test_compose_accents() {
+        let ts = TimeSignature(3, 4);
+        let volumes = |music: &str, cache: &mut ComposeCache| MusicString::from_str(music).unwrap()
+            .compose_cached(ts, None, cache).unwrap()
+            .tracks[0].events().iter().map(|e| e.volume.0).collect::<Vec<_>>();
+        let plain = &mut ComposeCache::default();
+        // only notes starting right on the first beat, and the volume stays under the maximum
+        assert_eq!(volumes(":c ::accent=20 :d :e :f<1/2> :g<3/2> :a ::v=90 :b", plain), vec![50, 50, 50, 70, 50, 50, 100]);
+        assert_eq!(volumes(":c ::accent=10/1.3 :d :e :f ::accent=0 :g :a :b", plain), vec![50, 50, 60, 60, 50, 50, 50]);
+
+        let accented = &mut ComposeCache::default();
+        accented.set_accent(Some(Accent::downbeats(15)));
+        assert_eq!(volumes(":c :d :e :f", accented), vec![65, 50, 50, 65]);
+        assert_eq!(volumes("{:c :d :e | :g<3>} :f", accented), vec![65, 65, 50, 50, 65]);
+
+        // beats inside brackets are counted from the start of the piece, not of the brackets
+        let accented_starts = |music: &str, cache: &mut ComposeCache| MusicString::from_str(music).unwrap()
+            .compose_cached(ts, None, cache).unwrap()
+            .tracks[0].events().iter().filter(|e| e.volume.0 > 50).map(|e| e.start).collect::<Vec<_>>();
+        assert_eq!(accented_starts(":c<2> {:d :e :f | :a<3>}", accented), vec![MusicTime::zero(), MusicTime::measures(1)]);
+        assert_eq!(accented_starts("[x2][:c :d]", accented), vec![MusicTime::zero(), MusicTime::measures(1)]);
+        assert_eq!(accented_starts(":c [x2][::accent=15 :d :e]", plain), vec![MusicTime::measures(1)]);
+    }
+
+    #[test]
+    fn test_accents_after_time_changes() {
+        let accented = &mut ComposeCache::default();
+        accented.set_accent(Some(Accent::downbeats(15)));
+        let volumes = |music: &str, cache: &mut ComposeCache| MusicString::from_str(music).unwrap()
+            .compose_cached(TimeSignature::common(), None, cache).unwrap()
+            .tracks[0].events().iter().map(|e| e.volume.0).collect::<Vec<_>>();
+        // beats are counted where the notes are heard, not where they were written
+        assert_eq!(volumes("[>>2][:c :d :e :f :g :a :b :c] :d", accented), vec![65, 50, 50, 50, 50, 50, 50, 50, 65]);
+        assert_eq!(volumes(":c :d :e [<<2][:f :g] :a", accented), vec![65, 50, 50, 50, 50, 50]);
+        assert_eq!(volumes(":c :d [st2][:e<4>]", accented), vec![65, 50, 50, 65]);
+    }
+
+    #[test]
+    fn test_transpose_grammar() {
+        let ts = TimeSignature::common();
+        let mut grammar = Grammar::from_str("start S\nS = :4c [M 4e][{:d | :e'}] A\nA = :_ :g#").unwrap();
+        let compose = |grammar: &Grammar| MusicString::from_str("S").unwrap()
+            .parallel_rewrite_n(grammar, false, true, 2)
+            .compose(ts, None).unwrap()
+            .tracks[0].events().iter().map(|e| e.pitch).collect::<Vec<_>>();
+        let original = compose(&grammar);
+        grammar.transpose(5);
+        let transposed = compose(&grammar);
+        let expected = original.into_iter().map(|mut p| {
+            p.transpose(5);
+            p
+        }).collect::<Vec<_>>();
+        assert_eq!(transposed, expected);
+        // notes that changed lose the spelling they were written with
+        let note = |grammar: &Grammar| match &grammar.productions[1].1.0[1] {
+            MusicPrimitive::Simple(Symbol::T(Terminal::Music { note: TerminalNote::Note { pitch, spelling, .. }, .. })) => (*pitch, *spelling),
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(note(&grammar), (Pitch(5, 4), None));
+
+        grammar.map_notes(|p| Pitch(p.0 - 1, p.1));
+        assert_eq!(note(&grammar), (Pitch(4, 4), None));
+    }
+
+    /// A split of `branches` voices, each running up and down the scale for `notes` notes.
+    fn big_split(branches: usize, notes: usize) -> MusicString {
+        let letters = ["c", "d", "e", "f", "g", "a", "b"];
+        let voices = (0..branches)
+            .map(|b| (0..notes).map(|n| format!(":{}<1/4>", letters[(b + n) % 7])).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        MusicString::from_str(&format!("{{{}}}", voices.join(" | "))).unwrap()
+    }
+
+    #[test]
+    fn test_parallel_split_composes_the_same() {
+        let music = big_split(4, 200);
+        let ts = TimeSignature::common();
+        let mut sequential = ComposeCache::default();
+        sequential.set_parallel(false);
+        let expected = music.compose_cached(ts, None, &mut sequential).unwrap();
+        let composed = music.compose_cached(ts, None, &mut ComposeCache::default()).unwrap();
+        assert_eq!(composed, expected);
+        assert_eq!(composed.tracks[0].events().len(), 800);
+    }
+
+    // timing check for composing big splits on all cores, run with
+    // `cargo test --release --features rayon -- --ignored --nocapture`
+    #[ignore]
+    #[test]
+    fn test_parallel_split_timing() {
+        let music = big_split(16, 1000);
+        let ts = TimeSignature::common();
+        for parallel in [false, true] {
+            let mut cache = ComposeCache::default();
+            cache.set_parallel(parallel);
+            let start = std::time::Instant::now();
+            let composed = music.compose_cached(ts, None, &mut cache).unwrap();
+            let events = composed.tracks.iter().map(|t| t.events().len()).sum::<usize>();
+            println!("{events} events in 16 branches, parallel {parallel}: {:?}", start.elapsed());
+        }
+    }
+}
@@ -1,22 +1,53 @@
 /*
 
-Informally, line comments starting with `//` are allowed.
+Informally, comments are allowed anywhere whitespace is: `//` runs to the end of the line,
+and `/* ... */` can span several lines.
 
-Grammar := `start ` NonTerminal `\n` Production*
+Grammar := `start ` NonTerminal `\n` (Definition | Production)*
 
-Production := NonTerminal `=` MusicString
+Definition := `def ` Name `=` `[` MusicTransform+ `]`
+    // the name can then be used in place of those transforms, e.g. `def soft = [v*0.6 >>1]`
+    // and `[soft][:c :d]`; it can also be used in later definitions
+
+A production may span several lines, either by ending each line but the last with `\`
+or by leaving a `[` or `{` open until a later line.
+
+Production := NonTerminal `=` Guard? MusicString
+
+Guard := `(` (`depth` | `bar`) (`%` Int)? (`<` | `<=` | `>` | `>=` | `==` | `!=`) Int `)`
+    // the production can only be chosen when this holds. `depth` counts the rewrites done
+    // before this one, `bar` is the measure the music is being derived for
 
 MusicString := MusicPrimitive*
 
 MusicPrimitive :=
   | Symbol
-  | `{` (MusicString `|`)* MusicString? `}`
-  | `[` MusicTransform `][` MusicString `]`
+  | `{` (MusicString `|`)* MusicString? `}` SplitPolicy?
+  | `{` (MusicString `|`)* MusicString? `}volta`   // alternate endings, one per pass of a repeat
+  | `[` MusicTransform+ `][` MusicString `]`   // several transforms apply left to right
+
+SplitPolicy := `pad` | `truncate` | `longest`   // without one, all branches must be the same length
+
+A split where any branch starts with a weight, like `{A |2 B |1 C}`, plays a single branch
+chosen at random by weight instead of all of them. Branches without a weight count as 1.
 
 MusicTransform :=
     | `x` usize
     | `T` Int
-    | `>>` Fraction
+    | `>>` Fraction   // play faster by this factor
+    | `<<` Fraction   // play slower by this factor
+    | `v*` Float      // scale the volume
+    | `M` Note Scale? // mirror pitches around the note, keeping them in the scale built on it
+    | `st` usize      // retrigger every note this many times
+    | `?` Float       // include the content with this probability, 0 to 1
+    | `bar`           // start on the next barline and pad to whole measures
+    | Name            // transforms given a name with `def`
+    | (`x` | `T` | `v%` | `if`) `{` Script `}`
+        // worked out again every time the production is expanded; `v%` is the volume in percent
+        // and `if` leaves the content out unless the script comes to something other than 0
+
+Script := whole number arithmetic (`+ - * / %`), comparisons, `&&`, `||`, `!` and parentheses
+    over numbers, `depth`, `bar` and `rand(n)`, which is from 0 to n-1, e.g. `x{bar%4+1}`
 
 Symbol :=
   | NonTerminal
@@ -25,21 +56,42 @@ Symbol :=
 NonTerminal := [-a-zA-Z1-9/#\?]
 
 Terminal :=
-  | Note (`<` Duration `>`)?
+  | Note (`<` Duration `>`)? `~`? (`"` Lyric `"`)?
+      // `~` ties the note to the next one if it has the same pitch,
+      // and the lyric is the syllable sung on it
   | `:` MetaControl
 
 Note :=
   | `_`
-  | Int?[a-gA-G](b|#)?
+  | Int?[a-gA-G](bb|b|n|#|##|x)?   // `x` is the same as `##`, `n` is an explicit natural
+  | [a-gA-G](bb|b|n|#|##|x)?(`'`+|`,`+)
+      // nearest octave above (`'` or `+`) or below (`,` or `-`) the previous note,
+      // and one more octave for each extra mark
 
 MetaControl :=
   | `i=` Instrument
   | `v=` Volume
+  | `vib=` Lfo
+  | `trem=` Lfo
+  | `ch=` Int      // MIDI channel from 1 to 16 for the following notes
+  | `d=` Duration   // used by the following notes without `<...>`, 1 beat to begin with
+  | `tag=` Name     // tags the following notes
+  | `mark=` Name    // names this point in the music, for jumping to it
+  | `accent=` Int (`/` Int (`.` Int)*)?
+      // adds to the volume of the following notes that start on these beats of the bar,
+      // counted from 1 and only the downbeat if none are given, e.g. `::accent=20/1.3`
+
+Name := [a-zA-Z0-9_-]+
 
 Instrument := Sine | piano | ...
 
 Volume := Int
 
+Duration := (Int `m+`)? (Int | Int `/` Int) `.`*   // each dot adds half again
+    // a leading count of measures is how durations of a measure or more are printed, e.g. `1m+2`
+
+Lfo := Float `/` Float   // rate in Hz / depth (semitones for vib, fraction of volume for trem)
+
 ------ Examples --------
 
 ```
@@ -49,10 +101,13 @@ B = :0c
 ```
 
 */
-use std::collections::HashSet;
+use std::cell::Cell;
+use std::collections::HashMap;
 use num::rational::Ratio;
-use crate::cfg::{Grammar, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Production, Symbol, Terminal, TerminalNote};
-use crate::composition::{Instrument, Octave, Pitch, Volume};
+use crate::cfg::intern::Name;
+use crate::cfg::script::ScriptTarget;
+use crate::cfg::{Accent, Comparison, Grammar, Guard, GuardVariable, MetaControl, MusicPrimitive, MusicString, MusicTransform, NonTerminal, Production, SplitMode, SplitPolicy, Symbol, Terminal, TerminalNote};
+use crate::composition::{Accidental, Instrument, Lfo, Octave, Pitch, Scale, Spelling, Volume};
 use crate::time::{Beat, MusicTime, TimeCompression};
 
 
@@ -60,20 +115,123 @@ use crate::time::{Beat, MusicTime, TimeCompression};
 pub enum ScanError {
     Generic(String),
     ExpectedEither(String, String),
+    /// The input went past one of the `ParseLimits`.
+    Limit(String),
+}
+
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::Generic(s) => write!(f, "{s}"),
+            ScanError::ExpectedEither(a, b) => write!(f, "Expected {a} or {b}"),
+            ScanError::Limit(s) => write!(f, "{s}"),
+        }
+    }
 }
 
+impl std::error::Error for ScanError {}
+
 pub type Result<T> = std::result::Result<T, ScanError>;
 
+/// Bounds on what is scanned, so pathological or hostile input, e.g. grammars sent to the backend,
+/// fails with `ScanError::Limit` instead of overflowing the stack or running out of memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Most brackets and braces open inside each other.
+    pub max_nesting: usize,
+    /// Largest `x` repeat and `st` stutter count.
+    pub max_repeat: usize,
+    /// Longest input, in bytes.
+    pub max_input_len: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_nesting: 64,
+            max_repeat: 10_000,
+            max_input_len: 1 << 20,
+        }
+    }
+}
+
+thread_local! {
+    static LIMITS: Cell<ParseLimits> = Cell::new(ParseLimits::default());
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+impl ParseLimits {
+    /// Scan with these limits instead of the defaults for as long as `scan` runs on this thread.
+    pub fn apply<T>(self, scan: impl FnOnce() -> T) -> T {
+        let _restore = RestoreLimits(LIMITS.replace(self));
+        scan()
+    }
+
+    pub(crate) fn current() -> Self {
+        LIMITS.get()
+    }
+
+    fn check_len(input: &str) -> Result<()> {
+        let max = Self::current().max_input_len;
+        if input.len() > max {
+            return Err(ScanError::Limit(format!("Input is {} bytes, more than the limit of {max}", input.len())));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_repeat(num: usize) -> Result<usize> {
+        let max = Self::current().max_repeat;
+        if num > max {
+            return Err(ScanError::Limit(format!("Repeat count {num} is more than the limit of {max}")));
+        }
+        Ok(num)
+    }
+}
+
+/// Puts back the limits that were there before `ParseLimits::apply`, even if scanning panics.
+struct RestoreLimits(ParseLimits);
+
+impl Drop for RestoreLimits {
+    fn drop(&mut self) {
+        LIMITS.set(self.0);
+    }
+}
+
+/// One level deeper into brackets for as long as it is kept.
+pub(crate) struct Nesting(());
+
+impl Nesting {
+    pub(crate) fn enter(input: &str) -> Result<Nesting> {
+        let depth = NESTING.get();
+        if depth == 0 {
+            ParseLimits::check_len(input)?;
+        }
+        let max = ParseLimits::current().max_nesting;
+        if depth >= max {
+            return Err(ScanError::Limit(format!("Music is nested more than {max} deep")));
+        }
+        NESTING.set(depth + 1);
+        Ok(Nesting(()))
+    }
+}
+
+impl Drop for Nesting {
+    fn drop(&mut self) {
+        NESTING.set(NESTING.get() - 1);
+    }
+}
+
 pub trait Scanner {
     type Output;
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)>;
 }
 
-type ScanPrefix = String;
+type ScanPrefix = &'static str;
 
 pub struct GrammarScanner;
 
 pub struct ProductionScanner;
+pub struct GuardScanner;
 
 pub struct MusicStringScanner;
 
@@ -81,6 +239,7 @@ pub struct MusicPrimitiveScanner;
 pub struct MusicPrimitiveSplitScanner;
 pub struct MusicPrimitiveRepeatScanner;
 pub struct MusicTransformScanner;
+pub struct MusicTransformListScanner;
 
 pub struct SymbolScanner;
 
@@ -91,9 +250,13 @@ pub struct TerminalScanner;
 pub struct NoteScanner;
 
 pub struct DurationScanner;
+pub struct DefaultDurationScanner;
+pub struct TieScanner;
+pub struct LyricScanner;
 pub struct FractionScanner;
 
 pub struct MetaControlScanner;
+pub struct LfoScanner;
 
 pub struct InstrumentScanner;
 
@@ -103,10 +266,12 @@ impl Scanner for GrammarScanner {
     type Output = Grammar;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        let lines = input.lines()
+        ParseLimits::check_len(input)?;
+        let input = strip_comments(input)?;
+        let lines = join_continued_lines(&input);
+        let lines = lines.iter()
             .map(|line| line.trim())
             .filter(|line| !line.is_empty())
-            .filter(|line| !line.trim().starts_with("//"))
             .collect::<Vec<_>>();
         if lines.is_empty() {
             return Err(ScanError::Generic("Expected at least one line".to_string()));
@@ -117,35 +282,93 @@ impl Scanner for GrammarScanner {
             .ok_or_else(|| ScanError::Generic("Expected 'start' at the beginning of the first line".to_string()))?;
         let start = NonTerminalScanner.scan(start)
             .map(|(nt, _s)| NonTerminal::Custom(nt))?;
-        let productions = lines[1..]
+        let (definitions, lines): (Vec<_>, Vec<_>) = lines[1..]
+            .iter()
+            .partition(|line| line.starts_with("def "));
+        let mut macros = HashMap::new();
+        for definition in definitions {
+            let (name, transforms) = scan_macro_definition(definition, &macros)?;
+            macros.insert(name, transforms);
+        }
+        let productions = lines
             .iter()
             .map(|line| {
                 let line = line.trim();
                 if line.is_empty() {
                     return Ok(None);
                 }
-                let (prod, _s) = ProductionScanner.scan(line)?;
-                Ok(Some(prod))
+                let (Production(nt, ms, guard), _s) = ProductionScanner.scan(line)?;
+                Ok(Some(Production(nt, resolve_macros(&ms, &macros)?, guard)))
             })
             .collect::<Result<Vec<_>>>()?
             .into_iter()
             .filter_map(|x| x)
             .collect();
-        Ok((Grammar { start, productions }, ""))
+        Ok((Grammar { start, productions, macros }, ""))
     }
 }
 
 impl Scanner for ProductionScanner {
     type Output = Production;
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        scan_map(concat(
-            scan_map(
-                concat(NonTerminalScanner, trim(StringScanner("=".to_string()))),
-                |(nt, _s)| NonTerminal::Custom(nt),
-            ),
-            MusicStringScanner,
-        ), |(nt, str)| Production(nt, str))
-            .scan(input)
+        let (nt, rest) = scan_map(
+            concat(NonTerminalScanner, trim(StringScanner("=".to_string()))),
+            |(nt, _s)| NonTerminal::Custom(nt),
+        ).scan(input)?;
+        let (guard, rest) = if rest.trim_start().starts_with('(') {
+            let (guard, rest) = GuardScanner.scan(rest.trim_start())?;
+            (Some(guard), rest)
+        } else {
+            (None, rest)
+        };
+        let (str, rest) = MusicStringScanner.scan(rest)?;
+        Ok((Production(nt, str, guard), rest))
+    }
+}
+
+impl Scanner for GuardScanner {
+    type Output = Guard;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        // `(` variable (`%` usize)? comparison usize `)`
+        let inner = input.strip_prefix('(')
+            .ok_or_else(|| ScanError::Generic("Expected '(' to start a guard".to_string()))?;
+        let end = inner.find(')')
+            .ok_or_else(|| ScanError::Generic("Expected ')' to end the guard".to_string()))?;
+        let (condition, rest) = (&inner[..end], &inner[end + 1..]);
+        let condition = condition.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        let name_end = condition.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(condition.len());
+        let variable = match &condition[..name_end] {
+            "depth" => GuardVariable::Depth,
+            "bar" => GuardVariable::Bar,
+            other => return Err(ScanError::Generic(format!("Expected 'depth' or 'bar' in guard, found '{other}'"))),
+        };
+        let mut condition = &condition[name_end..];
+        let modulus = if let Some(after) = condition.strip_prefix('%') {
+            let digits = after.find(|c: char| !c.is_ascii_digit()).unwrap_or(after.len());
+            let modulus: usize = after[..digits].parse()
+                .ok()
+                .filter(|&m| m > 0)
+                .ok_or_else(|| ScanError::Generic("Expected positive integer after '%' in guard".to_string()))?;
+            condition = &after[digits..];
+            Some(modulus)
+        } else {
+            None
+        };
+        // two character comparisons first, so `<=` isn't read as `<`
+        let (symbol, comparison) = [
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ].into_iter()
+            .find(|(symbol, _)| condition.starts_with(symbol))
+            .ok_or_else(|| ScanError::Generic(format!("Expected a comparison in guard, found '{condition}'")))?;
+        let value = condition[symbol.len()..].parse()
+            .map_err(|_| ScanError::Generic(format!("Expected a whole number to compare to in guard, found '{}'", &condition[symbol.len()..])))?;
+        Ok((Guard { variable, modulus, comparison, value }, rest))
     }
 }
 
@@ -153,6 +376,7 @@ impl Scanner for MusicStringScanner {
     type Output = MusicString;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        let _nesting = Nesting::enter(input)?;
         let mut music_string = Vec::new();
         let mut remaining_input = input;
 
@@ -183,11 +407,11 @@ impl Scanner for MusicPrimitiveScanner {
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
         // split scanner, or else repeat scanner, or else SymbolScanner
         disjoint(
-            ScanPrefix::from("{".to_string()),
+            "{",
             MusicPrimitiveSplitScanner,
             None,
             disjoint(
-                ScanPrefix::from("[".to_string()),
+                "[",
                 MusicPrimitiveRepeatScanner,
                 None,
                 scan_map(SymbolScanner, |s| MusicPrimitive::Simple(s)),
@@ -206,7 +430,16 @@ impl Scanner for MusicPrimitiveSplitScanner {
             let rest = &input[1..];
             if let Some(end) = find_matching(rest, '{', '}') {
                 let inner = &rest[..end];
-                let mut parts = inner.split('|');
+                // a number at the start of a branch is its weight, and makes this a choice
+                let mut weights = vec![];
+                let mut parts = split_branches(inner).into_iter().map(|part| {
+                    let trimmed = part.trim_start();
+                    let digits = trimmed.find(|c: char| !c.is_ascii_digit()).unwrap_or(trimmed.len());
+                    let weight = trimmed[..digits].parse().ok()
+                        .filter(|_| trimmed[digits..].starts_with(char::is_whitespace) || trimmed[digits..].is_empty());
+                    weights.push(weight);
+                    if weight.is_some() { &trimmed[digits..] } else { part }
+                });
                 let first_part = parts.next().unwrap_or("");
                 let rest_parts: Vec<_> = parts.collect();
                 let scanner = consume(MusicStringScanner);
@@ -220,7 +453,26 @@ impl Scanner for MusicPrimitiveSplitScanner {
                         Ok(vec)
                     })?;
                 let rest = &rest[end + 1..];
-                Ok((MusicPrimitive::Split { branches: rest_music_strings }, rest))
+                let mode = if weights.iter().any(Option::is_some) {
+                    let weights = weights.into_iter().map(|w| w.unwrap_or(1)).collect::<Vec<_>>();
+                    if weights.iter().all(|&w| w == 0) {
+                        return Err(ScanError::Generic("Expected at least one branch with a weight above 0".to_string()));
+                    }
+                    SplitMode::Choice { weights }
+                } else {
+                    SplitMode::Parallel
+                };
+                if let Some(rest) = rest.strip_prefix("volta") {
+                    if mode != SplitMode::Parallel {
+                        return Err(ScanError::Generic("Volta endings can't have weights".to_string()));
+                    }
+                    return Ok((MusicPrimitive::Volta { endings: rest_music_strings }, rest));
+                }
+                let (policy, rest) = [SplitPolicy::Pad, SplitPolicy::Truncate, SplitPolicy::Longest]
+                    .into_iter()
+                    .find_map(|p| rest.strip_prefix(p.suffix()).map(|rest| (p, rest)))
+                    .unwrap_or((SplitPolicy::Strict, rest));
+                Ok((MusicPrimitive::Split { branches: rest_music_strings, policy, mode }, rest))
             } else {
                 Err(ScanError::Generic("Expected '}'".to_string()))
             }
@@ -245,15 +497,17 @@ impl Scanner for MusicPrimitiveRepeatScanner {
                         let music_string = &rest[..end_bracket];
                         let scanner = consume(MusicStringScanner);
                         let music_string = scanner.scan(music_string).map(|(ms, _empty)| ms)?;
-                        let transform = consume(MusicTransformScanner).scan(repeat_num).map(|(ms, _empty)| ms)?;
+                        let (transforms, _empty) = MusicTransformListScanner.scan(repeat_num)?;
                         let rest = &rest[end_bracket + 1..];
-                        Ok((
-                            MusicPrimitive::Transform {
-                                transform,
-                                content: music_string,
-                            },
-                            rest,
-                        ))
+                        // the first transform applies to the content directly, the next one to that, and so on
+                        let mut transforms = transforms.into_iter();
+                        let first = transforms.next()
+                            .ok_or_else(|| ScanError::Generic("Expected MusicTransform".to_string()))?;
+                        let primitive = transforms.fold(
+                            MusicPrimitive::Transform { transform: first, content: music_string },
+                            |inner, transform| MusicPrimitive::Transform { transform, content: MusicString(vec![inner]) },
+                        );
+                        Ok((primitive, rest))
                     } else {
                         Err(ScanError::Generic("Expected ']'".to_string()))
                     }
@@ -269,6 +523,46 @@ impl Scanner for MusicPrimitiveRepeatScanner {
     }
 }
 
+impl Scanner for MusicTransformListScanner {
+    type Output = Vec<MusicTransform>;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        // transforms separated by whitespace, e.g. "x2 T5 >>2"
+        // a mirror takes its center and scale as the words after it, e.g. "M 4e minor x2"
+        let mut words = input.split_whitespace().peekable();
+        let mut transforms = vec![];
+        while let Some(word) = words.next() {
+            let mut transform = word.to_string();
+            // scripts can have spaces in them
+            while transform.contains('{') && !transform.ends_with('}') {
+                let Some(more) = words.next() else {
+                    return Err(ScanError::Generic(format!("Expected '}}' to end the script in '{transform}'")));
+                };
+                transform = format!("{transform} {more}");
+            }
+            if word == "M" {
+                if let Some(center) = words.next() {
+                    transform = format!("{transform} {center}");
+                }
+                if let Some(scale) = words.next_if(|w| ["major", "minor"].contains(w)) {
+                    transform = format!("{transform} {scale}");
+                }
+            }
+            let scanned = consume(MusicTransformScanner).scan(&transform).map(|(transform, _empty)| transform);
+            // anything else that looks like a name is a macro, looked up once the grammar is read
+            let is_name = word.starts_with(|c: char| c.is_ascii_alphabetic())
+                && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            transforms.push(match scanned {
+                // e.g. `x99999999` is a repeat that is too big, not a macro
+                Err(e @ ScanError::Limit(_)) => return Err(e),
+                Err(_) if is_name => MusicTransform::Named { name: word.to_string() },
+                scanned => scanned?,
+            });
+        }
+        Ok((transforms, ""))
+    }
+}
+
 impl Scanner for MusicTransformScanner {
     type Output = MusicTransform;
 
@@ -276,13 +570,26 @@ impl Scanner for MusicTransformScanner {
         // if it starts with 'x', then scan a positive integer
         // if it starts with 'T', then scan an integer
         // if it starts with '>>', then scan a Duration
+        // if it starts with 'v*', then scan a decimal volume factor
+        // if it is 'bar', then align to the barlines
+        // if it starts with 'M', then scan a center note and optionally a scale
+        // if it starts with 'st', then scan a positive number of retriggers
+        // if it starts with '?', then scan a probability between 0 and 1
+        // if it is a prefix and then braces, then scan a script
         // otherwise, return an error
+        for target in ScriptTarget::ALL {
+            if let Some(script) = input.strip_prefix(target.prefix()).and_then(|s| s.strip_prefix('{')) {
+                let script = script.strip_suffix('}')
+                    .ok_or_else(|| ScanError::Generic(format!("Expected '}}' to end the script in '{input}'")))?;
+                return Ok((MusicTransform::Script { target, expr: script.parse()? }, ""));
+            }
+        }
         if let Some(first) = input.chars().next() {
             match first {
                 'x' => {
                     let num: usize = (&input[1..]).parse().map_err(|_| ScanError::Generic("Expected positive integer after 'x'".to_string()))?;
                     Ok((MusicTransform::Repeat {
-                        num,
+                        num: ParseLimits::check_repeat(num)?,
                     }, ""))
                 }
                 'T' => {
@@ -302,6 +609,66 @@ impl Scanner for MusicTransformScanner {
                         factor: TimeCompression(fraction.recip())
                     }, rest))
                 }
+                'v' if input.starts_with("v*") => {
+                    let factor: f32 = input[2..].parse()
+                        .map_err(|_| ScanError::Generic("Expected number after 'v*'".to_string()))?;
+                    if factor.is_nan() || factor < 0. {
+                        return Err(ScanError::Generic(format!("Expected a volume factor of at least 0, found {factor}")));
+                    }
+                    Ok((MusicTransform::ScaleVolume {
+                        percent: (factor * 100.).round() as u32,
+                    }, ""))
+                }
+                'b' if input == "bar" => Ok((MusicTransform::AlignToBar, "")),
+                'M' => {
+                    let mut words = input[1..].split_whitespace();
+                    let center = words.next()
+                        .ok_or_else(|| ScanError::Generic("Expected center note after 'M'".to_string()))?;
+                    let center = match consume(NoteScanner).scan(center)? {
+                        (TerminalNote::Note { pitch, relative: 0, .. }, _) => pitch,
+                        _ => return Err(ScanError::Generic(format!("Expected a note with an octave to mirror around, found {center}"))),
+                    };
+                    let scale = match words.next() {
+                        None => None,
+                        Some("major") => Some(Scale::Major),
+                        Some("minor") => Some(Scale::Minor),
+                        Some(other) => return Err(ScanError::Generic(format!("Expected 'major' or 'minor' after the center note, found {other}"))),
+                    };
+                    Ok((MusicTransform::Mirror {
+                        center,
+                        scale,
+                    }, ""))
+                }
+                's' if input.starts_with("st") => {
+                    let times: usize = input[2..].parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| ScanError::Generic("Expected positive integer after 'st'".to_string()))?;
+                    Ok((MusicTransform::Stutter {
+                        times: ParseLimits::check_repeat(times)?,
+                    }, ""))
+                }
+                '?' => {
+                    let probability: f32 = input[1..].parse()
+                        .map_err(|_| ScanError::Generic("Expected number after '?'".to_string()))?;
+                    if !(0. ..=1.).contains(&probability) {
+                        return Err(ScanError::Generic(format!("Expected a probability between 0 and 1, found {probability}")));
+                    }
+                    Ok((MusicTransform::Probability {
+                        percent: (probability * 100.).round() as u32,
+                    }, ""))
+                }
+                '<' if input.starts_with("<<") => {
+                    let (fraction, rest) = consume(FractionScanner).scan(&input[2..])
+                        .map_err(|_| ScanError::Generic("Expected fraction after '<<'".to_string()))?;
+                    if fraction <= Ratio::new(0, 1) {
+                        return Err(ScanError::Generic(format!("Expected a positive stretch factor, found {fraction}")));
+                    }
+                    // `<<2` takes twice as long, so unlike `>>` the factor is used as is
+                    Ok((MusicTransform::Compression {
+                        factor: TimeCompression(fraction)
+                    }, rest))
+                }
                 _ => Err(ScanError::Generic(format!("Expected MusicTransform but found {first}"))),
             }
         } else {
@@ -317,7 +684,7 @@ impl Scanner for SymbolScanner {
         // if it starts with ':', use TerminalScanner
         // otherwise, use NonTerminalScanner
         disjoint(
-            ScanPrefix::from(":".to_string()),
+            ":",
             scan_map(scan_map_input(TerminalScanner, |s| &s[1..]), |s| {
                 Symbol::T(s)
             }),
@@ -335,13 +702,15 @@ impl Scanner for TerminalScanner {
         // if it starts with ':', then use MetaControlScanner
         // otherwise, use TerminalNoteScanner
         disjoint(
-            ScanPrefix::from(":".to_string()),
+            ":",
             scan_map_input(scan_map(MetaControlScanner, |s| Terminal::Meta(s)), |s| &s[1..]),
             None,
-            scan_map(concat(NoteScanner, DurationScanner), |(note, duration)| {
+            scan_map(concat(concat(concat(NoteScanner, DurationScanner), TieScanner), LyricScanner), |(((note, duration), tied), lyric)| {
                 Terminal::Music {
                     note,
                     duration,
+                    tied,
+                    lyric,
                 }
             }),
         )
@@ -356,7 +725,8 @@ impl Scanner for NoteScanner {
         /*
         Note :=
           | `_`
-          | Int?[a-gA-G](b|#)?
+          | Int?[a-gA-G](bb|b|n|#|##|x)?
+          | [a-gA-G](bb|b|n|#|##|x)?(`'`+|`,`+)
         */
         let mut chars = input.chars();
         let mut rest = input;
@@ -386,16 +756,34 @@ impl Scanner for NoteScanner {
                         'g' => note = 10,
                         _ => unreachable!(),
                     }
-                    if let Some(next) = chars.next() {
-                        if next == '#' {
-                            note += 1;
-                            consumed += 1;
-                        } else if next == 'b' {
-                            note = (note + 11) % 12; // 'b' is a flat, so we subtract 1 from the note
-                            consumed += 1;
-                        }
+                    let after_letter = chars.as_str();
+                    let accidental = [
+                        ("##", Accidental::DoubleSharp),
+                        ("x", Accidental::DoubleSharp),
+                        ("#", Accidental::Sharp),
+                        ("bb", Accidental::DoubleFlat),
+                        ("b", Accidental::Flat),
+                        ("n", Accidental::Natural),
+                    ].into_iter()
+                        .find(|(sign, _)| after_letter.starts_with(sign));
+                    if let Some((sign, accidental)) = accidental {
+                        // stays in the same octave, like a plain sharp or flat always has
+                        note = (note as i8 + accidental.semitones()).rem_euclid(12) as u8;
+                        consumed += sign.len();
+                    }
+                    let spelling = Spelling {
+                        letter: next.to_ascii_uppercase(),
+                        accidental: accidental.map(|(_, a)| a),
+                    };
+                    let marks = &input[consumed..];
+                    let up = marks.len() - marks.trim_start_matches(['\'', '+']).len();
+                    let down = marks.len() - marks.trim_start_matches([',', '-']).len();
+                    let relative = up as i8 - down as i8;
+                    if relative != 0 && first.is_ascii_digit() {
+                        return Err(ScanError::Generic(format!("Note {} has both an octave number and a relative octave", &input[..consumed + up + down])));
                     }
-                    Ok((TerminalNote::Note { pitch: Pitch(octave, note) }, &input[consumed..]))
+                    consumed += up + down;
+                    Ok((TerminalNote::Note { pitch: Pitch(octave, note), spelling: Some(spelling), relative }, &input[consumed..]))
                 } else {
                     Err(ScanError::Generic(
                         format!("Expected Note: note name {next} is not a valid note."),
@@ -415,7 +803,7 @@ impl Scanner for NoteScanner {
 }
 
 impl Scanner for DurationScanner {
-    type Output = MusicTime;
+    type Output = Option<MusicTime>;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
         // if it starts with '<', then scan a duration
@@ -423,27 +811,85 @@ impl Scanner for DurationScanner {
             if let Some(end) = find_matching(&input[1..], '<', '>') {
                 let duration = &input[1..=end];
                 let rest = &input[end + 2..];
-                if duration.contains('/') {
-                    // it's a ratio
-                    let mut parts = duration.split('/');
-                    match (parts.next().and_then(|s| s.parse().ok()), parts.next().and_then(|s| s.parse().ok())) {
-                        (Some(num), Some(denom)) => {
-                            Ok((MusicTime(0, Beat::new(num, denom)), rest))
-                        }
-                        _ => {
-                            eprintln!("Unable to parse {duration} as duration. Defaulting to 1");
-                            Ok((MusicTime::beats(1), rest))
-                        }
-                    }
-                } else {
-                    let duration_int = duration.parse::<u32>().unwrap_or(0);
-                    Ok((MusicTime::beats(duration_int), rest))
-                }
+                Ok((Some(parse_duration(duration)), rest))
             } else {
                 Err(ScanError::Generic("Expected '>'".to_string()))
             }
         } else {
-            Ok((MusicTime::beats(1), input))
+            // the composer fills in the current default
+            Ok((None, input))
+        }
+    }
+}
+
+/// Parse a whole number of beats or a `num/denom` fraction of a beat, optionally dotted.
+/// Each trailing `.` adds half of the previous value, so `1.` is 1.5 beats and `1..` is 1.75.
+/// Durations of a measure or more are written with the measures first, like `2m+1/2`.
+fn parse_duration(duration: &str) -> MusicTime {
+    if let Some((measures, beats)) = duration.split_once("m+") {
+        let measures = measures.parse().unwrap_or_else(|_| {
+            warn!(duration, "Unable to parse measures of duration. Defaulting to 0");
+            0
+        });
+        return MusicTime(measures, parse_duration(beats).1);
+    }
+    let undotted = duration.trim_end_matches('.');
+    let dots = (duration.len() - undotted.len()).min(8) as u32;
+    let duration = undotted;
+    let (num, denom) = if duration.contains('/') {
+        // it's a ratio
+        let mut parts = duration.split('/');
+        match (parts.next().and_then(|s| s.parse().ok()), parts.next().and_then(|s| s.parse().ok())) {
+            (Some(num), Some(denom)) => (num, denom),
+            _ => {
+                warn!(duration, "Unable to parse duration. Defaulting to 1");
+                (1, 1)
+            }
+        }
+    } else {
+        (duration.parse::<u32>().unwrap_or(0), 1)
+    };
+    // n dots multiply by (2^(n+1) - 1) / 2^n
+    let dotted = 1 << dots;
+    MusicTime(0, Beat::new(num * (2 * dotted - 1), denom * dotted))
+}
+
+impl Scanner for TieScanner {
+    type Output = bool;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        match input.strip_prefix('~') {
+            Some(rest) => Ok((true, rest)),
+            None => Ok((false, input)),
+        }
+    }
+}
+
+impl Scanner for LyricScanner {
+    type Output = Option<String>;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        let Some(rest) = input.strip_prefix('"') else {
+            return Ok((None, input));
+        };
+        match rest.split_once('"') {
+            Some((lyric, rest)) => Ok((Some(lyric.to_string()), rest)),
+            None => Err(ScanError::Generic(format!("Expected closing '\"' after lyric {rest}"))),
+        }
+    }
+}
+
+impl Scanner for DefaultDurationScanner {
+    type Output = MusicTime;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        // scan the same "num/denom" or whole beats as inside `<...>`
+        let end = input.find(|c: char| !(c.is_ascii_digit() || c == '/' || c == '.')).unwrap_or(input.len());
+        let (duration, rest) = input.split_at(end);
+        if duration.is_empty() {
+            Err(ScanError::Generic("Expected duration".to_string()))
+        } else {
+            Ok((parse_duration(duration), rest))
         }
     }
 }
@@ -475,64 +921,113 @@ impl Scanner for MetaControlScanner {
     type Output = MetaControl;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        let mut chars = input.chars();
-        if let Some(first) = chars.next() {
-            if let Some('=') = chars.next() {
-                let mut rest = &input[2..];
-                match first {
-                    'i' => {
-                        let (instrument, new_input) = InstrumentScanner.scan(rest)?;
-                        rest = new_input;
-                        Ok((MetaControl::ChangeInstrument(instrument), rest))
-                    }
-                    'v' => {
-                        let (volume, new_input) = VolumeScanner.scan(rest)?;
-                        rest = new_input;
-                        Ok((MetaControl::ChangeVolume(volume), rest))
-                    }
-                    _ => {
-                        Err(ScanError::Generic(format!(
-                            "Expected MetaControl: i= or v=, found {}=",
-                            first
-                        )))
-                    }
+        if input.is_empty() {
+            return Err(ScanError::Generic("Expected MetaControl".to_string()));
+        }
+        let key_end = input.find(|c: char| !c.is_alphabetic()).unwrap_or(input.len());
+        let (key, rest) = input.split_at(key_end);
+        let Some(rest) = rest.strip_prefix('=') else {
+            return Err(ScanError::Generic(format!("Expected '=' to follow meta control {key}")));
+        };
+        match key {
+            "i" => {
+                let (instrument, rest) = InstrumentScanner.scan(rest)?;
+                Ok((MetaControl::ChangeInstrument(instrument), rest))
+            }
+            "v" => {
+                let (volume, rest) = VolumeScanner.scan(rest)?;
+                Ok((MetaControl::ChangeVolume(volume), rest))
+            }
+            "vib" => {
+                let (lfo, rest) = LfoScanner.scan(rest)?;
+                Ok((MetaControl::Vibrato(lfo), rest))
+            }
+            "trem" => {
+                let (lfo, rest) = LfoScanner.scan(rest)?;
+                Ok((MetaControl::Tremolo(lfo), rest))
+            }
+            "d" => {
+                let (duration, rest) = DefaultDurationScanner.scan(rest)?;
+                Ok((MetaControl::DefaultDuration(duration), rest))
+            }
+            "ch" => {
+                // written 1 to 16, like on the hardware
+                let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let (channel, rest) = rest.split_at(end);
+                match channel.parse::<u8>() {
+                    Ok(channel @ 1..=16) => Ok((MetaControl::Channel(channel - 1), rest)),
+                    _ => Err(ScanError::Generic(format!("Expected MIDI channel from 1 to 16, found {channel}"))),
                 }
-            } else {
-                Err(ScanError::Generic(format!("Expected '=' to follow meta control character {first}")))
             }
-        } else {
-            Err(ScanError::Generic("Expected MetaControl".to_string()))
+            "accent" => {
+                // an amount, then optionally the accented beats, like 20/1.3
+                let amount_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                let Ok(amount) = rest[..amount_end].parse() else {
+                    return Err(ScanError::Generic(format!("Expected accent amount, found {rest}")));
+                };
+                let rest = &rest[amount_end..];
+                let Some(beats) = rest.strip_prefix('/') else {
+                    return Ok((MetaControl::Accent(Accent::downbeats(amount)), rest));
+                };
+                let end = beats.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(beats.len());
+                let (beats, rest) = beats.split_at(end);
+                let beats = beats.split('.')
+                    .map(|b| b.parse().ok().filter(|&b| b > 0))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| ScanError::Generic(format!("Expected accented beats from 1 like 1.3, found {beats}")))?;
+                Ok((MetaControl::Accent(Accent { amount, beats }), rest))
+            }
+            "tag" | "mark" => {
+                let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_')).unwrap_or(rest.len());
+                let (name, rest) = rest.split_at(end);
+                if name.is_empty() {
+                    return Err(ScanError::Generic(format!("Expected a name after {key}=")));
+                }
+                let control = if key == "tag" {
+                    MetaControl::Tag(name.to_string())
+                } else {
+                    MetaControl::Mark(name.to_string())
+                };
+                Ok((control, rest))
+            }
+            _ => {
+                Err(ScanError::Generic(format!(
+                    "Expected MetaControl: i=, v=, vib=, trem=, d=, ch=, tag=, mark= or accent=, found {}=",
+                    key
+                )))
+            }
+        }
+    }
+}
+
+impl Scanner for LfoScanner {
+    type Output = Lfo;
+
+    fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
+        // scan "rate/depth", e.g. 5/0.3
+        let end = input.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '/')).unwrap_or(input.len());
+        let (lfo, rest) = input.split_at(end);
+        let mut parts = lfo.split('/');
+        match (parts.next().and_then(|s| s.parse::<f32>().ok()), parts.next().and_then(|s| s.parse::<f32>().ok()), parts.next()) {
+            (Some(rate), Some(depth), None) => Ok((Lfo::new(rate, depth), rest)),
+            _ => Err(ScanError::Generic(format!("Expected LFO in the form of rate/depth, found {lfo}"))),
         }
     }
 }
 
 impl Scanner for NonTerminalScanner {
-    type Output = String;
+    type Output = Name;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
         // scan [-a-zA-Z0-9/] and return largest prefix
-        let other_allowed_chars: HashSet<char> = "-/#?".chars().collect();
-        let is_nt_char = |c: char| c.is_alphabetic() || c.is_ascii_digit() ||
-            other_allowed_chars.contains(&c);
-        let mut chars = input.chars();
-        if let Some(first) = chars.next() {
-            if is_nt_char(first) {
-                let mut non_terminal = first.to_string();
-                while let Some(c) = chars.next() {
-                    if is_nt_char(c) {
-                        non_terminal.push(c);
-                    } else {
-                        // prepend chars with c
-                        let rest = &input[non_terminal.len()..];
-                        return Ok((non_terminal, rest));
-                    }
-                }
-                Ok((non_terminal, chars.as_str()))
-            } else {
-                Err(ScanError::Generic(format!("Expected NonTerminal but got {first}")))
+        let is_nt_char = |c: char| c.is_alphabetic() || c.is_ascii_digit() || "-/#?".contains(c);
+        match input.chars().next() {
+            Some(first) if is_nt_char(first) => {
+                let end = input.find(|c: char| !is_nt_char(c)).unwrap_or(input.len());
+                Ok((Name::from(&input[..end]), &input[end..]))
             }
-        } else {
-            Err(ScanError::Generic(format!("Expected NonTerminal, but it's an empty string")))
+            Some(first) => Err(ScanError::Generic(format!("Expected NonTerminal but got {first}"))),
+            None => Err(ScanError::Generic("Expected NonTerminal, but it's an empty string".to_string())),
         }
     }
 }
@@ -541,25 +1036,14 @@ impl Scanner for InstrumentScanner {
     type Output = Instrument;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        // scan instrument name
-        let mut chars = input.chars();
-        if let Some(first) = chars.next() {
-            if first.is_alphabetic() {
-                let mut instrument = first.to_string();
-                while let Some(c) = chars.next() {
-                    if c.is_alphanumeric() || c == '_' {
-                        instrument.push(c);
-                    } else {
-                        return Ok((instrument.parse().unwrap(), chars.as_str()));
-                    }
-                }
-                Ok((instrument.parse().unwrap(), chars.as_str()))
-            } else {
-                Err(ScanError::Generic("Expected Instrument".to_string()))
-            }
-        } else {
-            Err(ScanError::Generic("Expected Instrument".to_string()))
+        // scan instrument name; the character after it is taken along
+        if !input.starts_with(char::is_alphabetic) {
+            return Err(ScanError::Generic("Expected Instrument".to_string()));
         }
+        let end = input.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(input.len());
+        let mut rest = input[end..].chars();
+        rest.next();
+        Ok((input[..end].parse().map_err(ScanError::Generic)?, rest.as_str()))
     }
 }
 
@@ -567,29 +1051,107 @@ impl Scanner for VolumeScanner {
     type Output = Volume;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        // scan volume value
-        let mut chars = input.chars();
-        if let Some(first) = chars.next() {
-            if first.is_digit(10) {
-                let mut volume = first.to_string();
-                while let Some(c) = chars.next() {
-                    if c.is_digit(10) {
-                        volume.push(c);
-                    } else {
-                        return Ok((Volume(volume.parse().unwrap()), chars.as_str()));
+        // scan volume value; the character after it is taken along
+        if !input.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(ScanError::Generic("Expected Volume".to_string()));
+        }
+        let end = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+        let mut rest = input[end..].chars();
+        rest.next();
+        Ok((Volume(input[..end].parse().unwrap()), rest.as_str()))
+    }
+}
+
+/// Blank out `// ...` comments up to the end of the line and `/* ... */` block comments.
+/// Comments turn into spaces and their newlines are kept, so they can go anywhere whitespace
+/// can, including inside brackets, without moving anything onto a different line.
+pub fn strip_comments(input: &str) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                output.push(' ');
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if previous == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                output.push('\n');
+                            }
+                            previous = c;
+                        }
+                        None => return Err(ScanError::Generic("Expected '*/' to close block comment".to_string())),
                     }
                 }
-                Ok((Volume(volume.parse().unwrap()), chars.as_str()))
-            } else {
-                Err(ScanError::Generic("Expected Volume".to_string()))
+                output.push(' ');
             }
+            _ => output.push(c),
+        }
+    }
+    Ok(output)
+}
+
+/// Glue together the lines of a production that spans several of them.
+/// A line continues onto the next when it ends with `\\`, or while a `[` or `{` is still open.
+fn join_continued_lines(input: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current = String::new();
+    let mut depth = 0isize;
+    for line in input.lines() {
+        let line = line.trim_end();
+        let (line, continued) = match line.strip_suffix('\\') {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        depth += line.chars()
+            .map(|c| match c {
+                '[' | '{' => 1,
+                ']' | '}' => -1,
+                _ => 0,
+            })
+            .sum::<isize>();
+        current.push_str(line);
+        if continued || depth > 0 {
+            current.push(' ');
         } else {
-            Err(ScanError::Generic("Expected Volume".to_string()))
+            lines.push(std::mem::take(&mut current));
+            depth = 0;
         }
     }
+    if !current.is_empty() {
+        // unbalanced to the end, let the production scanner report it
+        lines.push(current);
+    }
+    lines
 }
 
 /// Assume that exactly 1 opening char has already been found. Find the next closing char.
+/// The branches of a split, without looking inside the splits, transforms and lyrics in them.
+fn split_branches(inner: &str) -> Vec<&str> {
+    let mut branches = vec![];
+    let (mut depth, mut quoted, mut start) = (0, false, 0);
+    for (i, c) in inner.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '{' | '[' if !quoted => depth += 1,
+            '}' | ']' if !quoted => depth -= 1,
+            '|' if !quoted && depth == 0 => {
+                branches.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    branches.push(&inner[start..]);
+    branches
+}
+
 fn find_matching(input: &str, open: char, close: char) -> Option<usize> {
     let mut stack = 1;
     for (i, c) in input.chars().enumerate() {
@@ -661,6 +1223,69 @@ where
     scan_map_input(scan, |s| s.trim_start().trim_end())
 }
 
+/// Scan `def name = [transforms]`. Earlier definitions can be used in later ones.
+fn scan_macro_definition(line: &str, macros: &HashMap<String, Vec<MusicTransform>>) -> Result<(String, Vec<MusicTransform>)> {
+    let (name, value) = line["def ".len()..].split_once('=')
+        .ok_or_else(|| ScanError::Generic(format!("Expected '=' in definition {line}")))?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(ScanError::Generic(format!("Expected a name made of letters, digits and '_' but found '{name}'")));
+    }
+    let transforms = value.trim()
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .ok_or_else(|| ScanError::Generic(format!("Expected transforms in brackets after 'def {name} ='")))?;
+    let (transforms, _empty) = MusicTransformListScanner.scan(transforms)?;
+    if transforms.is_empty() {
+        return Err(ScanError::Generic(format!("Expected at least one transform in definition of {name}")));
+    }
+    let mut expanded = vec![];
+    for transform in transforms {
+        match transform {
+            MusicTransform::Named { name: used } => expanded.extend(macros.get(&used)
+                .ok_or_else(|| ScanError::Generic(format!("Transform '{used}' used in '{name}' is not defined")))?
+                .iter()
+                .cloned()),
+            transform => expanded.push(transform),
+        }
+    }
+    Ok((name.to_string(), expanded))
+}
+
+/// Replace every named transform in `music_string` by the transforms it was defined as.
+fn resolve_macros(music_string: &MusicString, macros: &HashMap<String, Vec<MusicTransform>>) -> Result<MusicString> {
+    let resolve_all = |strings: &Vec<MusicString>| strings.iter()
+        .map(|ms| resolve_macros(ms, macros))
+        .collect::<Result<Vec<_>>>();
+    let primitives = music_string.0.iter()
+        .map(|mp| Ok(match mp {
+            MusicPrimitive::Simple(symbol) => MusicPrimitive::Simple(symbol.clone()),
+            MusicPrimitive::Split { branches, policy, mode } => MusicPrimitive::Split { branches: resolve_all(branches)?, policy: *policy, mode: mode.clone() },
+            MusicPrimitive::Volta { endings } => MusicPrimitive::Volta { endings: resolve_all(endings)? },
+            #[allow(deprecated)]
+            MusicPrimitive::Repeat { num, content } => MusicPrimitive::Repeat { num: *num, content: resolve_macros(content, macros)? },
+            MusicPrimitive::Transform { transform: MusicTransform::Named { name }, content } => {
+                let transforms = macros.get(name)
+                    .ok_or_else(|| ScanError::Generic(format!("Transform '{name}' is not defined")))?;
+                let content = resolve_macros(content, macros)?;
+                // same nesting as writing the transforms out in the brackets
+                let mut transforms = transforms.iter().cloned();
+                let first = transforms.next()
+                    .ok_or_else(|| ScanError::Generic(format!("Transform '{name}' is empty")))?;
+                transforms.fold(
+                    MusicPrimitive::Transform { transform: first, content },
+                    |inner, transform| MusicPrimitive::Transform { transform, content: MusicString(vec![inner]) },
+                )
+            }
+            MusicPrimitive::Transform { transform, content } => MusicPrimitive::Transform {
+                transform: transform.clone(),
+                content: resolve_macros(content, macros)?,
+            },
+        }))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(MusicString(primitives))
+}
+
 pub fn consume<S>(scan: S) -> impl Scanner<Output=S::Output>
 where
     S: Scanner,
@@ -743,9 +1368,9 @@ where
     type Output = U;
 
     fn scan<'a>(&self, input: &'a str) -> Result<(Self::Output, &'a str)> {
-        if input.starts_with(&self.scanner_a.0) {
+        if input.starts_with(self.scanner_a.0) {
             self.scanner_a.1.scan(input)
-        } else if let Some(prefix) = &self.scanner_b.0 {
+        } else if let Some(prefix) = self.scanner_b.0 {
             if input.starts_with(prefix) {
                 self.scanner_b.1.scan(input)
             } else {
@@ -829,10 +1454,152 @@ where
 }
 
 
+
+/// What a piece of grammar text is, for syntax highlighting.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    /// `start`, `def`, and the policies after splits.
+    Keyword,
+    NonTerminal,
+    Note,
+    Rest,
+    /// A duration in `<...>` after a note.
+    Duration,
+    Lyric,
+    MetaControl,
+    Transform,
+    Guard,
+    /// Brackets, `=`, `|`, `~` and line continuations.
+    Punctuation,
+    Comment,
+    /// Anything that can't start a token.
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte range in the input.
+    pub span: std::ops::Range<usize>,
+}
+
+/// Characters that end a word, a note or a meta control.
+const TOKEN_END: &str = "[]{}|=()<>\"~:";
+
+/// Split `input` into tokens without parsing it, so text that doesn't scan yet can still be
+/// highlighted. Whitespace is left out.
+pub fn lex(input: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut push = |kind, start: usize, end: usize| tokens.push(Token { kind, span: start..end });
+    let end_of = |from: usize, stop: &dyn Fn(char) -> bool| input[from..].find(stop).map_or(input.len(), |i| from + i);
+    let word_end = |from: usize| end_of(from, &|c: char| c.is_whitespace() || TOKEN_END.contains(c));
+    let mut line_start = true;
+    let mut in_def = false;
+    let mut i = 0;
+    while let Some(c) = input[i..].chars().next() {
+        let start = i;
+        let rest = &input[i..];
+        if c == '\n' {
+            line_start = true;
+            in_def = false;
+            i += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        }
+        let at_line_start = std::mem::replace(&mut line_start, false);
+        if rest.starts_with("//") {
+            i = end_of(i, &|c| c == '\n');
+            push(TokenKind::Comment, start, i);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            i = comment.find("*/").map_or(input.len(), |e| i + 2 + e + 2);
+            push(TokenKind::Comment, start, i);
+        } else if at_line_start && (rest.starts_with("start ") || rest.starts_with("def ")) {
+            in_def = rest.starts_with("def ");
+            i = word_end(i);
+            push(TokenKind::Keyword, start, i);
+            let name_start = i + input[i..].len() - input[i..].trim_start().len();
+            i = word_end(name_start);
+            push(if in_def { TokenKind::Transform } else { TokenKind::NonTerminal }, name_start, i);
+        } else if rest.starts_with("::") {
+            i = word_end(i + 2);
+            // the value of a meta control can contain `=` and `/`
+            i = end_of(i, &|c: char| c.is_whitespace() || "[]{}|".contains(c));
+            push(TokenKind::MetaControl, start, i);
+        } else if c == ':' {
+            i = word_end(i + 1);
+            push(if &input[start + 1..i] == "_" { TokenKind::Rest } else { TokenKind::Note }, start, i);
+            if input[i..].starts_with('<') {
+                let duration_start = i;
+                i = end_of(i, &|c: char| c == '>' || c.is_whitespace());
+                if input[i..].starts_with('>') {
+                    i += 1;
+                }
+                push(TokenKind::Duration, duration_start, i);
+            }
+            if input[i..].starts_with('~') {
+                push(TokenKind::Punctuation, i, i + 1);
+                i += 1;
+            }
+            if input[i..].starts_with('"') {
+                let lyric_start = i;
+                i = input[i + 1..].find('"').map_or(input.len(), |e| i + 1 + e + 1);
+                push(TokenKind::Lyric, lyric_start, i);
+            }
+        } else if c == '(' {
+            i = input[i..].find(')').map_or(input.len(), |e| i + e + 1);
+            push(TokenKind::Guard, start, i);
+        } else if c == '[' {
+            push(TokenKind::Punctuation, start, start + 1);
+            i += 1;
+            // the first brackets of `[x2][...]` hold transforms, and so do those of a `def`
+            let close = input[i..].find(']').map(|e| i + e);
+            if let Some(close) = close.filter(|&close| in_def || input[close + 1..].starts_with('[')) {
+                while let Some(offset) = input[i..close].find(|c: char| !c.is_whitespace()) {
+                    let word_start = i + offset;
+                    i = end_of(word_start, &|c: char| c.is_whitespace()).min(close);
+                    push(TokenKind::Transform, word_start, i);
+                }
+                i = close;
+            }
+        } else if c == '}' {
+            push(TokenKind::Punctuation, start, start + 1);
+            i += 1;
+            let suffix_end = end_of(i, &|c: char| !c.is_ascii_alphabetic());
+            if suffix_end > i {
+                push(TokenKind::Keyword, i, suffix_end);
+                i = suffix_end;
+            }
+        } else if c == '|' {
+            // a weight right after the bar belongs with it
+            i = end_of(i + 1, &|c: char| !c.is_ascii_digit());
+            push(TokenKind::Punctuation, start, i);
+        } else if "]{=\\".contains(c) {
+            i += 1;
+            push(TokenKind::Punctuation, start, i);
+        } else if TOKEN_END.contains(c) {
+            i += 1;
+            push(TokenKind::Unknown, start, i);
+        } else {
+            i = word_end(i);
+            push(TokenKind::NonTerminal, start, i);
+        }
+    }
+    tokens
+}
+
 #[cfg(test)]
 mod test {
+    use std::str::FromStr;
     use num::rational::Ratio;
-    use crate::cfg::scan::{consume, ConsumeScanner, DurationScanner, FractionScanner, GrammarScanner, InstrumentScanner, MetaControlScanner, MusicPrimitiveRepeatScanner, MusicPrimitiveScanner, MusicStringScanner, MusicTransformScanner, NonTerminalScanner, NoteScanner, ProductionScanner, Scanner, SymbolScanner, TerminalScanner, VolumeScanner};
+    use crate::cfg::{Accent, Comparison, Guard, GuardVariable, MetaControl, MusicPrimitive, MusicString, SplitMode};
+    use crate::cfg::TerminalNote;
+    use crate::composition::{Accidental, Lfo, Pitch, Scale, Spelling};
+    use crate::cfg::MusicTransform;
+    use crate::time::{Beat, MusicTime, TimeCompression, TimeSignature};
+    use crate::cfg::scan::{consume, lex, strip_comments, ConsumeScanner, DurationScanner, FractionScanner, GrammarScanner, GuardScanner, InstrumentScanner, MetaControlScanner, MusicPrimitiveRepeatScanner, MusicPrimitiveScanner, MusicPrimitiveSplitScanner, MusicStringScanner, MusicTransformListScanner, MusicTransformScanner, NonTerminalScanner, NoteScanner, ParseLimits, ProductionScanner, ScanError, Scanner, SymbolScanner, TerminalScanner, TokenKind, VolumeScanner};
 
     #[test]
     fn test_1() {
@@ -861,6 +1628,192 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_missing_duration_is_left_to_the_default() {
+        let (duration, rest) = DurationScanner.scan(" :d").unwrap();
+        assert_eq!(duration, None);
+        assert_eq!(rest, " :d");
+        let (control, _) = ConsumeScanner(MetaControlScanner).scan("d=1/4").unwrap();
+        assert_eq!(control, MetaControl::DefaultDuration(MusicTime(0, Beat::new(1, 4))));
+        assert!(ConsumeScanner(MetaControlScanner).scan("d=").is_err());
+    }
+
+    #[test]
+    fn test_multiple_transforms() {
+        let (primitive, _) = consume(MusicPrimitiveRepeatScanner).scan("[x2 T5 >>2][:c]").unwrap();
+        let (nested, _) = consume(MusicPrimitiveRepeatScanner).scan("[>>2][[T5][[x2][:c]]]").unwrap();
+        assert_eq!(primitive, nested);
+        assert!(consume(MusicPrimitiveRepeatScanner).scan("[][:c]").is_err());
+        assert!(consume(MusicPrimitiveRepeatScanner).scan("[x2 %][:c]").is_err());
+        // a plain word is the name of a transform defined in the grammar
+        let (named, _) = consume(MusicPrimitiveRepeatScanner).scan("[y][:c]").unwrap();
+        assert!(matches!(named, MusicPrimitive::Transform { transform: MusicTransform::Named { .. }, .. }));
+    }
+
+    #[test]
+    fn test_stretch_is_inverse_of_compression() {
+        let (stretch, _) = consume(MusicTransformScanner).scan("<<3/2").unwrap();
+        assert_eq!(stretch, MusicTransform::Compression { factor: TimeCompression(Ratio::new(3, 2)) });
+        let (compression, _) = consume(MusicTransformScanner).scan(">>2/3").unwrap();
+        assert_eq!(stretch, compression);
+        assert_eq!(stretch.to_string(), "<<3/2");
+        assert_eq!(compression.to_string(), "<<3/2");
+        let (faster, _) = consume(MusicTransformScanner).scan(">>2").unwrap();
+        assert_eq!(faster.to_string(), ">>2");
+        assert!(consume(MusicTransformScanner).scan("<<0").is_err());
+        assert!(consume(MusicTransformScanner).scan("<<-1").is_err());
+    }
+
+    #[test]
+    fn test_volume_scaling_transform() {
+        let (transform, _) = consume(MusicTransformScanner).scan("v*0.5").unwrap();
+        assert_eq!(transform, MusicTransform::ScaleVolume { percent: 50 });
+        assert_eq!(transform.to_string(), "v*0.5");
+        assert!(consume(MusicTransformScanner).scan("v*-1").is_err());
+        assert!(consume(MusicTransformScanner).scan("v*loud").is_err());
+    }
+
+    #[test]
+    fn test_align_to_bar_transform() {
+        let (transform, _) = consume(MusicTransformScanner).scan("bar").unwrap();
+        assert_eq!(transform, MusicTransform::AlignToBar);
+        assert_eq!(transform.to_string(), "bar");
+        let transforms = MusicTransformListScanner.scan("bar x2").unwrap().0;
+        assert_eq!(transforms, vec![MusicTransform::AlignToBar, MusicTransform::Repeat { num: 2 }]);
+    }
+
+    #[test]
+    fn test_weighted_split() {
+        let (split, _) = consume(MusicPrimitiveSplitScanner).scan("{A |2 B |1 C}").unwrap();
+        let MusicPrimitive::Split { branches, mode, .. } = &split else { panic!("{split:?}") };
+        assert_eq!(mode, &SplitMode::Choice { weights: vec![1, 2, 1] });
+        assert_eq!(branches[1], MusicString::from_str("B").unwrap());
+        assert_eq!(MusicString(vec![split.clone()]).to_string().trim(), "{A  | 2 B  | 1 C }");
+        let (split, _) = consume(MusicPrimitiveSplitScanner).scan("{3 A | B}").unwrap();
+        assert!(matches!(split, MusicPrimitive::Split { mode: SplitMode::Choice { weights }, .. } if weights == vec![3, 1]));
+        // a non-terminal that starts with a digit is not a weight
+        let (split, _) = consume(MusicPrimitiveSplitScanner).scan("{A | 2B}").unwrap();
+        assert!(matches!(split, MusicPrimitive::Split { mode: SplitMode::Parallel, .. }));
+        assert!(consume(MusicPrimitiveSplitScanner).scan("{0 A |0 B}").is_err());
+        assert!(consume(MusicPrimitiveSplitScanner).scan("{A |2 B}volta").is_err());
+    }
+
+    #[test]
+    fn test_nested_split() {
+        let (split, rest) = consume(MusicPrimitiveSplitScanner).scan("{:c {:d | [x2][:e :f]} :g | :c<4>}pad").unwrap();
+        assert!(rest.is_empty());
+        let MusicPrimitive::Split { branches, policy, .. } = &split else { panic!("{split:?}") };
+        assert_eq!(*policy, crate::cfg::SplitPolicy::Pad);
+        assert_eq!(branches.len(), 2);
+        assert!(matches!(&branches[0].0[1], MusicPrimitive::Split { branches, .. } if branches.len() == 2));
+        assert_eq!(branches[1], MusicString::from_str(":c<4>").unwrap());
+    }
+
+    #[test]
+    fn test_guard() {
+        let production = consume(ProductionScanner).scan("S =(depth<3) S S").unwrap().0;
+        let guard = Guard { variable: GuardVariable::Depth, modulus: None, comparison: Comparison::Less, value: 3 };
+        assert_eq!(production.2, Some(guard));
+        assert_eq!(production.1, MusicString::from_str("S S").unwrap());
+        let (guard, rest) = GuardScanner.scan("( bar % 4 == 0 ) :c").unwrap();
+        assert_eq!(guard, Guard { variable: GuardVariable::Bar, modulus: Some(4), comparison: Comparison::Equal, value: 0 });
+        assert_eq!(guard.to_string(), "(bar%4==0)");
+        assert_eq!(rest, " :c");
+        assert!(GuardScanner.scan("(beat<3)").is_err());
+        assert!(GuardScanner.scan("(depth<)").is_err());
+        assert!(GuardScanner.scan("(bar%0==0)").is_err());
+        assert!(GuardScanner.scan("(depth<3").is_err());
+        assert_eq!(consume(ProductionScanner).scan("S = :c").unwrap().0.2, None);
+    }
+
+    #[test]
+    fn test_transform_macros() {
+        let input = "start S\ndef soft = [v*0.6 >>1]\ndef echo = [soft x2]\nS = [soft][:c] [echo T2][:d]";
+        let grammar = consume(GrammarScanner).scan(input).unwrap().0;
+        let production = &grammar.productions[0].1;
+        let soft = |content| MusicPrimitive::Transform {
+            transform: MusicTransform::Compression { factor: TimeCompression(Ratio::new(1, 1)) },
+            content: MusicString(vec![MusicPrimitive::Transform {
+                transform: MusicTransform::ScaleVolume { percent: 60 },
+                content,
+            }]),
+        };
+        let c = MusicString::from_str(":c").unwrap();
+        let d = MusicString::from_str(":d").unwrap();
+        assert_eq!(production.0[0], soft(c));
+        let echoed = MusicPrimitive::Transform {
+            transform: MusicTransform::Repeat { num: 2 },
+            content: MusicString(vec![soft(d)]),
+        };
+        assert_eq!(production.0[1], MusicPrimitive::Transform {
+            transform: MusicTransform::Transpose { semitones: 2 },
+            content: MusicString(vec![echoed]),
+        });
+
+        assert!(consume(GrammarScanner).scan("start S\nS = [loud][:c]").is_err());
+        assert!(consume(GrammarScanner).scan("start S\ndef a = [b]\ndef b = [x2]\nS = :c").is_err());
+        assert!(consume(GrammarScanner).scan("start S\ndef a = x2\nS = :c").is_err());
+        // outside a grammar the name is kept, but can't be composed
+        let unresolved = MusicString::from_str("[soft][:c]").unwrap();
+        assert!(unresolved.compose(TimeSignature::common(), None).is_err());
+    }
+
+    #[test]
+    fn test_mirror_transform() {
+        let transforms = MusicTransformListScanner.scan("M 4e minor x2").unwrap().0;
+        assert_eq!(transforms, vec![
+            MusicTransform::Mirror { center: Pitch(4, 7), scale: Some(Scale::Minor) },
+            MusicTransform::Repeat { num: 2 },
+        ]);
+        assert_eq!(transforms[0].to_string(), "M 4E minor");
+        let (chromatic, _) = consume(MusicTransformScanner).scan("M 3C#").unwrap();
+        assert_eq!(chromatic, MusicTransform::Mirror { center: Pitch(3, 4), scale: None });
+        assert_eq!(chromatic.to_string(), "M 3C#");
+        assert!(consume(MusicTransformScanner).scan("M").is_err());
+        assert!(consume(MusicTransformScanner).scan("M e'").is_err());
+        assert!(consume(MusicTransformScanner).scan("M 4e dorian").is_err());
+    }
+
+    #[test]
+    fn test_stutter_transform() {
+        let (transform, _) = consume(MusicTransformScanner).scan("st4").unwrap();
+        assert_eq!(transform, MusicTransform::Stutter { times: 4 });
+        assert_eq!(transform.to_string(), "st4");
+        assert!(consume(MusicTransformScanner).scan("st0").is_err());
+        assert!(consume(MusicTransformScanner).scan("st").is_err());
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let nested = |depth: usize| format!("{}:c{}", "[x2][".repeat(depth), "]".repeat(depth));
+        let scan = |input: &str| consume(MusicStringScanner).scan(input).map(|(ms, _)| ms);
+        assert!(scan(&nested(10)).is_ok());
+        // far deeper than the stack would allow without the limit
+        assert!(matches!(scan(&nested(100_000)), Err(ScanError::Limit(_))));
+        assert!(matches!(scan("[x1000000][:c]"), Err(ScanError::Limit(_))));
+        assert!(matches!(scan("[st1000000][:c]"), Err(ScanError::Limit(_))));
+        assert!(matches!(scan(&":c ".repeat(1 << 20)), Err(ScanError::Limit(_))));
+
+        let tight = ParseLimits { max_nesting: 3, max_repeat: 4, max_input_len: 100 };
+        tight.apply(|| {
+            assert!(scan(&nested(2)).is_ok());
+            assert!(matches!(scan(&nested(3)), Err(ScanError::Limit(_))));
+            assert!(matches!(scan("[x5][:c]"), Err(ScanError::Limit(_))));
+            assert!(matches!(GrammarScanner.scan(&format!("start S\nS = {}", ":c ".repeat(40))), Err(ScanError::Limit(_))));
+        });
+        // back to the defaults afterwards
+        assert!(scan(&nested(10)).is_ok());
+    }
+
+    #[test]
+    fn test_probability_transform() {
+        let (transform, _) = consume(MusicTransformScanner).scan("?0.4").unwrap();
+        assert_eq!(transform, MusicTransform::Probability { percent: 40 });
+        assert_eq!(transform.to_string(), "?0.4");
+        assert!(consume(MusicTransformScanner).scan("?1.5").is_err());
+        assert!(consume(MusicTransformScanner).scan("?-0.1").is_err());
+    }
+
     #[test]
     fn test_fraction() {
         let input = "3/4";
@@ -912,6 +1865,25 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_accidentals() {
+        let pitch_and_spelling = |input| match ConsumeScanner(NoteScanner).scan(input).unwrap().0 {
+            TerminalNote::Note { pitch, spelling, .. } => (pitch, spelling.unwrap()),
+            TerminalNote::Rest => panic!("{input} is not a rest"),
+        };
+        let (f_double_sharp, spelling) = pitch_and_spelling("4f##");
+        assert_eq!(f_double_sharp, pitch_and_spelling("4g").0);
+        assert_eq!(spelling, Spelling { letter: 'F', accidental: Some(Accidental::DoubleSharp) });
+        assert_eq!(pitch_and_spelling("fx"), (f_double_sharp, spelling));
+        let (b_double_flat, spelling) = pitch_and_spelling("bbb");
+        assert_eq!(b_double_flat, pitch_and_spelling("a").0);
+        assert_eq!(spelling.to_string(), "Bbb");
+        let (e_natural, spelling) = pitch_and_spelling("en");
+        assert_eq!(e_natural, pitch_and_spelling("e").0);
+        assert_eq!(spelling.accidental, Some(Accidental::Natural));
+        assert_eq!(pitch_and_spelling("e").1.accidental, None);
+    }
+
     #[test]
     fn test_meta_control() {
         let input = "i=piano";
@@ -930,6 +1902,52 @@ mod test {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_comments() {
+        let input = "start S // the start\n/* a block\n   comment */\nS = [x2][:c /* inline */ :d] // trailing\n// whole line\nB = :e";
+        let (grammar, _) = consume(GrammarScanner).scan(input).unwrap();
+        assert_eq!(grammar.productions.len(), 2);
+        let expected = consume(GrammarScanner).scan("start S\nS = [x2][:c :d]\nB = :e").unwrap().0;
+        assert_eq!(grammar.productions, expected.productions);
+        assert!(strip_comments("S = :c /* never closed").is_err());
+        assert_eq!(strip_comments("[>>1/2][:c]").unwrap(), "[>>1/2][:c]");
+    }
+
+    #[test]
+    fn test_multi_line_productions() {
+        let input = "start S\nS = :c :d \\\n    :e\nB = [x2][\n  :c { :d\n  | :e }\n]\nC = :f";
+        let (grammar, _) = consume(GrammarScanner).scan(input).unwrap();
+        let expected = consume(GrammarScanner).scan("start S\nS = :c :d :e\nB = [x2][:c {:d | :e}]\nC = :f").unwrap().0;
+        assert_eq!(grammar.productions, expected.productions);
+        assert!(consume(GrammarScanner).scan("start S\nS = [x2][:c\nB = :d").is_err());
+    }
+
+    #[test]
+    fn test_meta_control_lfo() {
+        let scanner = ConsumeScanner(MetaControlScanner);
+        let (control, _) = scanner.scan("vib=5/0.3").unwrap();
+        assert_eq!(control, MetaControl::Vibrato(Lfo::new(5., 0.3)));
+        assert_eq!(control.to_string(), "::vib=5/0.3");
+        let (control, _) = scanner.scan("trem=6.5/0.5").unwrap();
+        assert_eq!(control, MetaControl::Tremolo(Lfo::new(6.5, 0.5)));
+        assert!(scanner.scan("vib=5").is_err());
+        assert!(scanner.scan("wob=5/0.3").is_err());
+    }
+
+    #[test]
+    fn test_meta_control_accent() {
+        let scanner = ConsumeScanner(MetaControlScanner);
+        let (control, _) = scanner.scan("accent=20").unwrap();
+        assert_eq!(control, MetaControl::Accent(Accent::downbeats(20)));
+        assert_eq!(control.to_string(), "::accent=20");
+        let (control, _) = scanner.scan("accent=15/1.3").unwrap();
+        assert_eq!(control, MetaControl::Accent(Accent { amount: 15, beats: vec![1, 3] }));
+        assert_eq!(control.to_string(), "::accent=15/1.3");
+        assert!(scanner.scan("accent=").is_err());
+        assert!(scanner.scan("accent=15/0").is_err());
+        assert!(scanner.scan("accent=15/1..3").is_err());
+    }
+
     #[test]
     fn test_terminal() {
         let input = "4c<1>";
@@ -1094,4 +2112,42 @@ mod test {
         println!("result: {result:#?}");
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lex() {
+        let input = "start S // song\ndef soft = [v*0.6 >>1]\nS (depth < 2) = ::i=piano :4c<1>~\"la\" :_ [x2 soft][A {B |2 C}pad]";
+        let tokens = lex(input).into_iter()
+            .map(|t| (t.kind, &input[t.span]))
+            .collect::<Vec<_>>();
+        use TokenKind::*;
+        assert_eq!(tokens, vec![
+            (Keyword, "start"), (NonTerminal, "S"), (Comment, "// song"),
+            (Keyword, "def"), (Transform, "soft"), (Punctuation, "="), (Punctuation, "["),
+            (Transform, "v*0.6"), (Transform, ">>1"), (Punctuation, "]"),
+            (NonTerminal, "S"), (Guard, "(depth < 2)"), (Punctuation, "="), (MetaControl, "::i=piano"),
+            (Note, ":4c"), (Duration, "<1>"), (Punctuation, "~"), (Lyric, "\"la\""), (Rest, ":_"),
+            (Punctuation, "["), (Transform, "x2"), (Transform, "soft"), (Punctuation, "]"),
+            (Punctuation, "["), (NonTerminal, "A"), (Punctuation, "{"), (NonTerminal, "B"), (Punctuation, "|2"),
+            (NonTerminal, "C"), (Punctuation, "}"), (Keyword, "pad"), (Punctuation, "]"),
+        ]);
+        // half written text still gets tokens
+        let kinds = lex("S = :c< [x2").into_iter().map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![NonTerminal, Punctuation, Note, Duration, Punctuation, NonTerminal]);
+    }
+
+    // timing check for reparsing a big grammar, like live editing does on every keystroke,
+    // run with `cargo test --release -- --ignored --nocapture`
+    #[ignore]
+    #[test]
+    fn test_parse_timing() {
+        let bar = "::i=piano ::v=60 :4c<1/2> :d :e~ :e B-1 {:c :e | :g<2>}pad [x2 T3][:f# A/2] ::i=Bass :2c<2>";
+        let production = |i: usize| format!("P{i} = {}", vec![bar; 20].join(" "));
+        let text = format!("start P0\n{}", (0..100).map(production).collect::<Vec<_>>().join("\n"));
+        let start = std::time::Instant::now();
+        let runs = 20;
+        for _ in 0..runs {
+            text.parse::<crate::cfg::Grammar>().unwrap();
+        }
+        println!("{} bytes parsed in {:?} per run", text.len(), start.elapsed() / runs);
+    }
+}
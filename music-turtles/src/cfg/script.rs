@@ -0,0 +1,325 @@
+// Small expressions in grammar files, worked out every time the production they are in is
+// expanded, for music that changes as it goes on: `[x{bar%4+1}]`, `[T{rand(5)-2}]`,
+// `[v%{100-depth*10}]` or `[if{bar%8==7}]`. They only deal in whole numbers.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::cfg::{Comparison, Derivation, GuardVariable, MusicPrimitive, MusicString, MusicTransform};
+use crate::cfg::scan::{Nesting, ParseLimits, ScanError};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Expr {
+    Number(i64),
+    Variable(GuardVariable),
+    /// A random number from 0 to one less than the argument.
+    Rand(Box<Expr>),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Compare(Comparison),
+    And,
+    Or,
+}
+
+/// What a script in a transform works out.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum ScriptTarget {
+    /// `x{...}`, how many times to repeat.
+    Repeat,
+    /// `T{...}`, semitones to transpose by.
+    Transpose,
+    /// `v%{...}`, the volume in percent.
+    Volume,
+    /// `if{...}`, whether to include the content at all. Left out content takes no time.
+    When,
+}
+
+impl ScriptTarget {
+    pub const ALL: [ScriptTarget; 4] = [ScriptTarget::Repeat, ScriptTarget::Transpose, ScriptTarget::Volume, ScriptTarget::When];
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ScriptTarget::Repeat => "x",
+            ScriptTarget::Transpose => "T",
+            ScriptTarget::Volume => "v%",
+            ScriptTarget::When => "if",
+        }
+    }
+}
+
+impl BinaryOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+            BinaryOp::Compare(comparison) => comparison.symbol(),
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+
+    /// Operators that bind tighter come later, and ones on the same level are left associative.
+    const LEVELS: [&'static [BinaryOp]; 5] = [
+        &[BinaryOp::Or],
+        &[BinaryOp::And],
+        // two character comparisons first, so `<=` isn't read as `<`
+        &[
+            BinaryOp::Compare(Comparison::LessOrEqual),
+            BinaryOp::Compare(Comparison::GreaterOrEqual),
+            BinaryOp::Compare(Comparison::Equal),
+            BinaryOp::Compare(Comparison::NotEqual),
+            BinaryOp::Compare(Comparison::Less),
+            BinaryOp::Compare(Comparison::Greater),
+        ],
+        &[BinaryOp::Add, BinaryOp::Subtract],
+        &[BinaryOp::Multiply, BinaryOp::Divide, BinaryOp::Remainder],
+    ];
+
+    fn apply(&self, a: i64, b: i64) -> i64 {
+        // dividing by zero gives zero instead of stopping the music
+        match self {
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Subtract => a.wrapping_sub(b),
+            BinaryOp::Multiply => a.wrapping_mul(b),
+            BinaryOp::Divide => a.checked_div(b).unwrap_or(0),
+            BinaryOp::Remainder => a.checked_rem(b).unwrap_or(0),
+            BinaryOp::Compare(comparison) => {
+                let holds = match comparison {
+                    Comparison::Less => a < b,
+                    Comparison::LessOrEqual => a <= b,
+                    Comparison::Greater => a > b,
+                    Comparison::GreaterOrEqual => a >= b,
+                    Comparison::Equal => a == b,
+                    Comparison::NotEqual => a != b,
+                };
+                holds as i64
+            }
+            BinaryOp::And => (a != 0 && b != 0) as i64,
+            BinaryOp::Or => (a != 0 || b != 0) as i64,
+        }
+    }
+}
+
+impl Expr {
+    /// Whether it can come to something else every time, with the same depth and bar.
+    pub fn is_random(&self) -> bool {
+        match self {
+            Expr::Number(_) | Expr::Variable(_) => false,
+            Expr::Rand(_) => true,
+            Expr::Negate(e) | Expr::Not(e) => e.is_random(),
+            Expr::Binary(_, a, b) => a.is_random() || b.is_random(),
+        }
+    }
+
+    pub fn eval(&self, derivation: Derivation, rng: &mut impl Rng) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Variable(GuardVariable::Depth) => derivation.depth as i64,
+            Expr::Variable(GuardVariable::Bar) => derivation.bar as i64,
+            Expr::Rand(max) => match max.eval(derivation, rng) {
+                max if max > 0 => rng.gen_range(0..max),
+                _ => 0,
+            },
+            Expr::Negate(e) => e.eval(derivation, rng).wrapping_neg(),
+            Expr::Not(e) => (e.eval(derivation, rng) == 0) as i64,
+            Expr::Binary(op, a, b) => op.apply(a.eval(derivation, rng), b.eval(derivation, rng)),
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{n}"),
+            Expr::Variable(GuardVariable::Depth) => write!(f, "depth"),
+            Expr::Variable(GuardVariable::Bar) => write!(f, "bar"),
+            Expr::Rand(max) => write!(f, "rand({max})"),
+            Expr::Negate(e) => write!(f, "-{e}"),
+            Expr::Not(e) => write!(f, "!{e}"),
+            // always in parentheses, so it reads back the same without knowing the precedence
+            Expr::Binary(op, a, b) => write!(f, "({a}{}{b})", op.symbol()),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ScanError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+        let (expr, rest) = scan_level(&source, 0)?;
+        if !rest.is_empty() {
+            return Err(ScanError::Generic(format!("Unexpected '{rest}' in script")));
+        }
+        Ok(expr)
+    }
+}
+
+fn scan_level(input: &str, level: usize) -> Result<(Expr, &str), ScanError> {
+    let Some(ops) = BinaryOp::LEVELS.get(level) else {
+        return scan_unary(input);
+    };
+    let (mut expr, mut rest) = scan_level(input, level + 1)?;
+    while let Some(op) = ops.iter().find(|op| rest.starts_with(op.symbol())) {
+        let (right, after) = scan_level(&rest[op.symbol().len()..], level + 1)?;
+        expr = Expr::Binary(*op, Box::new(expr), Box::new(right));
+        rest = after;
+    }
+    Ok((expr, rest))
+}
+
+fn scan_unary(input: &str) -> Result<(Expr, &str), ScanError> {
+    let _nesting = Nesting::enter(input)?;
+    if let Some(rest) = input.strip_prefix('-') {
+        let (e, rest) = scan_unary(rest)?;
+        return Ok((Expr::Negate(Box::new(e)), rest));
+    }
+    if let Some(rest) = input.strip_prefix('!') {
+        let (e, rest) = scan_unary(rest)?;
+        return Ok((Expr::Not(Box::new(e)), rest));
+    }
+    if let Some(rest) = input.strip_prefix('(') {
+        return scan_closing(scan_level(rest, 0)?);
+    }
+    if let Some(rest) = input.strip_prefix("rand(") {
+        let (max, rest) = scan_closing(scan_level(rest, 0)?)?;
+        return Ok((Expr::Rand(Box::new(max)), rest));
+    }
+    let digits = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    if digits > 0 {
+        let n = input[..digits].parse()
+            .map_err(|_| ScanError::Generic(format!("{} is too big for a script", &input[..digits])))?;
+        return Ok((Expr::Number(n), &input[digits..]));
+    }
+    let name_end = input.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(input.len());
+    match &input[..name_end] {
+        "depth" => Ok((Expr::Variable(GuardVariable::Depth), &input[name_end..])),
+        "bar" => Ok((Expr::Variable(GuardVariable::Bar), &input[name_end..])),
+        "" => Err(ScanError::Generic(format!("Expected a number, 'depth', 'bar' or 'rand(...)' in script, found '{input}'"))),
+        other => Err(ScanError::Generic(format!("Unknown name '{other}' in script"))),
+    }
+}
+
+fn scan_closing((expr, rest): (Expr, &str)) -> Result<(Expr, &str), ScanError> {
+    let rest = rest.strip_prefix(')')
+        .ok_or_else(|| ScanError::Generic("Expected ')' in script".to_string()))?;
+    Ok((expr, rest))
+}
+
+impl MusicString {
+    /// Work out every script in the string, for the rewrite at `derivation`.
+    pub fn run_scripts(&self, derivation: Derivation, rng: &mut impl Rng) -> MusicString {
+        let run = |ms: &MusicString, rng: &mut _| ms.run_scripts(derivation, rng);
+        let primitives = self.0.iter()
+            .filter_map(|mp| match mp {
+                MusicPrimitive::Simple(_) => Some(mp.clone()),
+                MusicPrimitive::Split { branches, policy, mode } => Some(MusicPrimitive::Split {
+                    branches: branches.iter().map(|b| run(b, rng)).collect(),
+                    policy: *policy,
+                    mode: mode.clone(),
+                }),
+                MusicPrimitive::Volta { endings } => Some(MusicPrimitive::Volta {
+                    endings: endings.iter().map(|e| run(e, rng)).collect(),
+                }),
+                #[allow(deprecated)]
+                MusicPrimitive::Repeat { num, content } => Some(MusicPrimitive::Repeat {
+                    num: *num,
+                    content: run(content, rng),
+                }),
+                MusicPrimitive::Transform { transform: MusicTransform::Script { target, expr }, content } => {
+                    run_script(*target, expr, run(content, rng), derivation, rng)
+                }
+                MusicPrimitive::Transform { transform, content } => Some(MusicPrimitive::Transform {
+                    transform: transform.clone(),
+                    content: run(content, rng),
+                }),
+            })
+            .collect();
+        MusicString(primitives)
+    }
+}
+
+/// What `content` with a script in front of it comes to, if anything.
+pub fn run_script(target: ScriptTarget, expr: &Expr, content: MusicString, derivation: Derivation, rng: &mut impl Rng) -> Option<MusicPrimitive> {
+    let value = expr.eval(derivation, rng);
+    let transform = match target {
+        ScriptTarget::Repeat => MusicTransform::Repeat {
+            num: value.clamp(0, ParseLimits::current().max_repeat as i64) as usize,
+        },
+        ScriptTarget::Transpose => MusicTransform::Transpose {
+            semitones: value.clamp(i8::MIN as i64, i8::MAX as i64) as i8,
+        },
+        ScriptTarget::Volume => MusicTransform::ScaleVolume {
+            percent: value.clamp(0, u32::MAX as i64) as u32,
+        },
+        ScriptTarget::When if value != 0 => MusicTransform::Repeat { num: 1 },
+        ScriptTarget::When => return None,
+    };
+    Some(MusicPrimitive::Transform { transform, content })
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::cfg::{Derivation, Grammar, MusicString};
+    use crate::cfg::script::Expr;
+    use crate::time::TimeSignature;
+
+    #[test]
+    fn test_scripts() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let at = |depth, bar| Derivation { depth, bar };
+        let expr = Expr::from_str("bar % 4 + 1 - -depth * 2").unwrap();
+        assert_eq!(expr.eval(at(1, 6), &mut rng), 5);
+        assert_eq!(Expr::from_str(&expr.to_string()).unwrap(), expr);
+        assert_eq!(Expr::from_str("bar>=2 && !(bar==3) || depth/0").unwrap().eval(at(0, 3), &mut rng), 0);
+        assert!((0..100).all(|_| (2..5).contains(&Expr::from_str("rand(3)+2").unwrap().eval(at(0, 0), &mut rng))));
+        assert!(Expr::from_str("-(rand(2)+1)").unwrap().is_random());
+        assert!(!expr.is_random());
+        assert!(Expr::from_str("bar +").is_err());
+        assert!(Expr::from_str("tempo").is_err());
+        assert!(Expr::from_str(&"(".repeat(100)).is_err());
+
+        let grammar = Grammar::from_str("start S\nS = [x{bar % 2 + 1}][:c] [if{bar == 1}][:d] [T{ depth * 12 } v%{50}][:e]").unwrap();
+        let axiom = MusicString::from_str("S").unwrap();
+        let notes = |music: MusicString| {
+            let music = music.compose(TimeSignature::common(), None).unwrap();
+            music.tracks[0].events().iter().map(|e| (e.pitch, e.volume)).collect::<Vec<_>>()
+        };
+        let at_bar = |bar| notes(axiom.parallel_rewrite_at(&grammar, false, true, at(1, bar), TimeSignature::common(), &mut StdRng::seed_from_u64(0)));
+        assert_eq!(at_bar(0), notes(MusicString::from_str(":c [T12 v*0.5][:e]").unwrap()));
+        assert_eq!(at_bar(1), notes(MusicString::from_str(":c :c :d [T12 v*0.5][:e]").unwrap()));
+        // random ones roll with the generator of the rewrite
+        let dice = Grammar::from_str("start S\nS = [T{rand(12)}][:c]").unwrap();
+        let rolls = |seed| {
+            let axiom = MusicString::from_str(&"S ".repeat(8)).unwrap();
+            notes(axiom.parallel_rewrite_n_with(&dice, false, true, 1, TimeSignature::common(), &mut StdRng::seed_from_u64(seed)))
+        };
+        let rolled = rolls(4);
+        assert_eq!(rolled, rolls(4));
+        assert!(rolled.iter().any(|note| *note != rolled[0]), "{rolled:?}");
+        assert_ne!(rolled, rolls(5));
+        // scripts that weren't expanded by a rewrite run when composed
+        let music = MusicString::from_str("[x{1+1}][:c]").unwrap().compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(music.tracks[0].events().len(), 2);
+        // transposing past the range of a semitone count saturates instead of overflowing
+        let music = MusicString::from_str("[T{127}][:b] [T{1000}][:b] [T{-1000}][:c]").unwrap().compose(TimeSignature::common(), None).unwrap();
+        assert_eq!(music.tracks[0].events().len(), 3);
+    }
+}
@@ -0,0 +1,123 @@
+// Writing composed or recorded music back down as a music string, so it can go into a grammar.
+
+use crate::cfg::{MetaControl, MusicPrimitive, MusicString, SplitMode, SplitPolicy, Symbol, Terminal, TerminalNote};
+use crate::composition::{Event, Track, Volume};
+use crate::time::{Beat, MusicTime, TimeSignature};
+
+impl MusicString {
+    /// The notes of `track` as a string that composes back to them. Notes that overlap are put
+    /// in the branches of a split, as few as it takes, with rests for the gaps in between.
+    pub fn from_track(track: &Track, time_signature: TimeSignature) -> MusicString {
+        let mut events = track.events().iter().collect::<Vec<_>>();
+        events.sort_by_key(|e| e.start);
+        // each voice gets the next note that starts after its last one has ended
+        let mut voices: Vec<(MusicTime, Vec<&Event>)> = vec![];
+        for event in events {
+            let end = event.start.with(time_signature) + event.duration.as_music_time(time_signature);
+            match voices.iter_mut().find(|(voice_end, _)| *voice_end <= event.start) {
+                Some((voice_end, voice)) => {
+                    *voice_end = end;
+                    voice.push(event);
+                }
+                None => voices.push((end, vec![event])),
+            }
+        }
+        let mut string = vec![meta(MetaControl::ChangeInstrument(track.instrument))];
+        let mut branches = voices.into_iter()
+            .map(|(_, voice)| voice_string(&voice, time_signature))
+            .collect::<Vec<_>>();
+        if branches.len() == 1 {
+            string.append(&mut branches[0].0);
+        } else if !branches.is_empty() {
+            string.push(MusicPrimitive::Split { branches, policy: SplitPolicy::Pad, mode: SplitMode::Parallel });
+        }
+        MusicString(string)
+    }
+
+    /// The notes of `track` a measure at a time, from the first measure to the last one with a
+    /// note starting in it. Notes held over a barline are cut off there, and every bar is
+    /// padded with rest to a whole measure.
+    pub fn from_track_bars(track: &Track, time_signature: TimeSignature) -> Vec<MusicString> {
+        let Some(last) = track.events().iter().map(|e| e.start.0).max() else {
+            return vec![];
+        };
+        let measure = Beat::whole(time_signature.0);
+        (0..=last)
+            .map(|m| {
+                let events = track.events().iter()
+                    .filter(|e| e.start.0 == m)
+                    .map(|e| Event {
+                        start: MusicTime(0, e.start.1),
+                        duration: if e.start.1 + e.duration > measure { measure - e.start.1 } else { e.duration },
+                        ..*e
+                    })
+                    .collect();
+                let bar = Track::new(track.identifier, track.instrument, events, vec![]);
+                let end = bar.events().iter().map(|e| e.start.1 + e.duration).max().unwrap_or(Beat::zero());
+                let mut string = MusicString::from_track(&bar, time_signature);
+                if end < measure {
+                    string.0.push(music((measure - end).as_music_time(time_signature), TerminalNote::Rest));
+                }
+                string
+            })
+            .collect()
+    }
+}
+
+fn voice_string(voice: &[&Event], time_signature: TimeSignature) -> MusicString {
+    let mut string = vec![];
+    let mut now = MusicTime::zero();
+    let mut volume: Option<Volume> = None;
+    for event in voice {
+        if event.start > now {
+            string.push(music(event.start.with(time_signature) - now, TerminalNote::Rest));
+        }
+        if volume != Some(event.volume) {
+            volume = Some(event.volume);
+            string.push(meta(MetaControl::ChangeVolume(event.volume)));
+        }
+        let duration = event.duration.as_music_time(time_signature);
+        string.push(music(duration, TerminalNote::Note { pitch: event.pitch, spelling: event.spelling, relative: 0 }));
+        now = event.start.with(time_signature) + duration;
+    }
+    MusicString(string)
+}
+
+fn meta(control: MetaControl) -> MusicPrimitive {
+    MusicPrimitive::Simple(Symbol::T(Terminal::Meta(control)))
+}
+
+fn music(duration: MusicTime, note: TerminalNote) -> MusicPrimitive {
+    MusicPrimitive::Simple(Symbol::T(Terminal::Music { duration: Some(duration), note, tied: false, lyric: None }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builder::CompositionBuilder;
+    use crate::cfg::MusicString;
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_track_composes_back() {
+        let composition = CompositionBuilder::track(Instrument::Piano)
+            .note(Pitch(4, 0), MusicTime::zero(), Beat::whole(2), Volume(50))
+            // a chord on top of the first note, after a rest
+            .note(Pitch(4, 4), MusicTime::beats(1), Beat::whole(1), Volume(70))
+            .note(Pitch(4, 7), MusicTime::beats(1), Beat::whole(1), Volume(70))
+            .note(Pitch(3, 0), MusicTime::beats(3), Beat::new(1, 2), Volume(50))
+            .build()
+            .unwrap();
+        let string = MusicString::from_track(&composition.tracks[0], TimeSignature::common());
+        let composed = string.compose(TimeSignature::common(), None).unwrap();
+        let notes = |c: &crate::composition::Composition| {
+            let mut notes = c.tracks.iter()
+                .flat_map(|t| t.events().iter().map(|e| (e.start, e.duration, e.pitch, e.volume)))
+                .collect::<Vec<_>>();
+            notes.sort();
+            notes
+        };
+        assert_eq!(notes(&composed), notes(&composition));
+        assert!(composed.tracks.iter().all(|t| t.instrument == Instrument::Piano));
+    }
+}
@@ -0,0 +1,114 @@
+// Problems that make a grammar unusable, found before any rewriting is attempted.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+use crate::cfg::{Grammar, NonTerminal, Production};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Every way of expanding the first non-terminal of `path` leads back into `path`,
+    /// or to a non-terminal without productions, so it never turns into just music.
+    NeverTerminates {
+        path: Vec<NonTerminal>,
+        /// Whether the last non-terminal of `path` appears earlier in it. If not, it has no productions.
+        cycle: bool,
+    },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::NeverTerminates { path, cycle } => {
+                let names = path.iter().map(|nt| nt.to_string()).collect::<Vec<_>>();
+                let first = names.first().cloned().unwrap_or_default();
+                if *cycle {
+                    write!(f, "{first} can never finish expanding, it always loops back: {}", names.join(" -> "))
+                } else {
+                    write!(f, "{first} can never finish expanding, it always ends up at {} which has no productions",
+                           names.join(" -> "))
+                }
+            }
+        }
+    }
+}
+
+impl Grammar {
+    /// Check for non-terminals that can't be expanded into music in any number of rewrites.
+    /// Such a grammar can still be rewritten a fixed number of times and composed, which leaves
+    /// out whatever wasn't expanded, but that is rarely what was meant.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let terminating = self.terminating_non_terminals();
+        let mut errors = vec![];
+        let mut reported = HashSet::new();
+        for Production(nt, _ms, _guard) in &self.productions {
+            if terminating.contains(nt) || !reported.insert(nt) {
+                continue;
+            }
+            // every production of a non-terminating non-terminal uses another non-terminating one,
+            // so keep following the first of those until one repeats or has no productions
+            let mut path = vec![nt.clone()];
+            let cycle = loop {
+                let last = path.last().unwrap();
+                let next = self.productions.iter()
+                    .filter(|Production(p, _, _)| p == last)
+                    .find_map(|Production(_, ms, _)| ms.non_terminals().into_iter().find(|nt| !terminating.contains(*nt)));
+                match next {
+                    None => break false,
+                    Some(next) => {
+                        let seen = path.contains(next);
+                        path.push(next.clone());
+                        if seen {
+                            break true;
+                        }
+                    }
+                }
+            };
+            errors.push(ValidationError::NeverTerminates { path, cycle });
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Non-terminals with at least one production whose non-terminals all terminate too.
+    fn terminating_non_terminals(&self) -> HashSet<NonTerminal> {
+        let mut terminating: HashSet<NonTerminal> = HashSet::new();
+        loop {
+            let before = terminating.len();
+            for Production(nt, ms, _guard) in &self.productions {
+                if !terminating.contains(nt) && ms.non_terminals().iter().all(|n| terminating.contains(*n)) {
+                    terminating.insert(nt.clone());
+                }
+            }
+            if terminating.len() == before {
+                return terminating;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::{Grammar, NonTerminal};
+    use crate::cfg::validate::ValidationError;
+
+    #[test]
+    fn test_validate() {
+        let nt = |s: &str| NonTerminal::Custom(s.into());
+        let fine = Grammar::from_str("start S\nS = [x2][S S]\nS = {A | :c}\nA = :d").unwrap();
+        assert_eq!(fine.validate(), Ok(()));
+
+        let looping = Grammar::from_str("start S\nS = A :c\nA = :d B\nB = [T2][A]\nC = D\nD = :e").unwrap();
+        let errors = looping.validate().unwrap_err();
+        assert_eq!(errors, vec![
+            ValidationError::NeverTerminates { path: vec![nt("S"), nt("A"), nt("B"), nt("A")], cycle: true },
+            ValidationError::NeverTerminates { path: vec![nt("A"), nt("B"), nt("A")], cycle: true },
+            ValidationError::NeverTerminates { path: vec![nt("B"), nt("A"), nt("B")], cycle: true },
+        ]);
+        assert_eq!(errors[0].to_string(), "S can never finish expanding, it always loops back: S -> A -> B -> A");
+
+        let dangling = Grammar::from_str("start S\nS = :c X").unwrap();
+        assert_eq!(dangling.validate(), Err(vec![ValidationError::NeverTerminates { path: vec![nt("S"), nt("X")], cycle: false }]));
+        let itself = Grammar::from_str("start S\nS = S").unwrap();
+        assert_eq!(itself.validate(), Err(vec![ValidationError::NeverTerminates { path: vec![nt("S"), nt("S")], cycle: true }]));
+    }
+}
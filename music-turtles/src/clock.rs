@@ -0,0 +1,314 @@
+// Where playback gets the time from. The scheduler thread and the player have to agree on it,
+// so they are handed the same clock instead of each reading the system time.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+use midir::{MidiInput, MidiInputConnection};
+use crate::scheduler::SchedulerCommand;
+use crate::time::{Seconds, BPM};
+
+/// MIDI clock sends this many pulses per quarter note.
+const PULSES_PER_QUARTER: u32 = 24;
+const CLOCK_PULSE: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+
+pub trait Clock: Send {
+    /// Seconds since the clock started.
+    fn elapsed(&self) -> Seconds;
+
+    /// Wait until `duration` more seconds have passed on this clock.
+    fn sleep(&self, duration: Seconds);
+}
+
+/// The computer's own time, started when the clock is made.
+#[derive(Debug, Copy, Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+/// Time that only moves when something sleeps on it or calls `advance`, so playback can be run
+/// as fast as the computer allows and gives the same result every time.
+/// Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    now: Arc<Mutex<Seconds>>,
+}
+
+/// Time counted in samples taken by the audio output, so it runs at the speed of the sound card
+/// instead of the system clock, which drifts away from it over a long session.
+/// The output takes samples a buffer ahead of what is heard, so this is ahead by a fixed amount
+/// rather than drifting, which is what the player's output latency is for.
+/// Clones share the same count.
+#[derive(Debug, Clone)]
+pub struct AudioClock {
+    written: Arc<AtomicU64>,
+    start: u64,
+    samples_per_second: f64,
+}
+
+/// Time driven by MIDI clock from a drum machine or DAW, so playback follows its tempo and
+/// its start and stop. Every pulse moves the clock on by a 24th of a quarter note at `bpm`,
+/// which has to be the tempo of the scheduler, whatever tempo the pulses actually come in at.
+/// Clones share the same connection.
+#[derive(Clone)]
+pub struct MidiClockFollower {
+    state: Arc<Mutex<FollowState>>,
+    _connection: Arc<Mutex<MidiInputConnection<()>>>,
+}
+
+/// What has been heard from the MIDI clock so far.
+#[derive(Debug, Clone)]
+struct FollowState {
+    bpm: BPM,
+    pulses: u64,
+    running: bool,
+    last_pulse: Option<Instant>,
+    /// Smoothed time between pulses, to guess how far along the next one is.
+    pulse_interval: Option<Duration>,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Seconds {
+        self.start.elapsed().as_secs_f32()
+    }
+
+    fn sleep(&self, duration: Seconds) {
+        if duration > 0. {
+            thread::sleep(Duration::from_secs_f32(duration));
+        }
+    }
+}
+
+impl VirtualClock {
+    pub fn advance(&self, duration: Seconds) {
+        *self.now.lock().unwrap() += duration.max(0.);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn elapsed(&self) -> Seconds {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Seconds) {
+        self.advance(duration);
+    }
+}
+
+impl AudioClock {
+    /// Start counting from now on `written`, which goes up by one for every sample that is output,
+    /// `channels` samples to a frame.
+    pub fn new(written: Arc<AtomicU64>, sample_rate: u32, channels: u16) -> Self {
+        let start = written.load(Ordering::Relaxed);
+        AudioClock { written, start, samples_per_second: sample_rate as f64 * channels as f64 }
+    }
+}
+
+impl Clock for AudioClock {
+    fn elapsed(&self) -> Seconds {
+        let samples = self.written.load(Ordering::Relaxed) - self.start;
+        (samples as f64 / self.samples_per_second) as Seconds
+    }
+
+    fn sleep(&self, duration: Seconds) {
+        let end = self.elapsed() + duration;
+        // the system clock only gets close, so check against the samples until they catch up
+        loop {
+            let left = end - self.elapsed();
+            if left <= 0. {
+                break;
+            }
+            thread::sleep(Duration::from_secs_f32(left));
+        }
+    }
+}
+
+impl MidiClockFollower {
+    /// Listen for MIDI clock on the first input port whose name contains `port_name`.
+    /// A Start message is passed on to `commands` as `SchedulerCommand::Restart`, if given.
+    pub fn connect(port_name: &str, bpm: BPM, commands: Option<Sender<SchedulerCommand>>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut midi_in = MidiInput::new("music-turtles-clock")?;
+        // clock and transport are ignored by default
+        midi_in.ignore(midir::Ignore::None);
+        let port = midi_in.ports().into_iter()
+            .find(|p| midi_in.port_name(p).is_ok_and(|name| name.contains(port_name)))
+            .ok_or_else(|| format!("No MIDI input port called {port_name}"))?;
+        let state = Arc::new(Mutex::new(FollowState::new(bpm)));
+        let receiver = state.clone();
+        let connection = midi_in.connect(&port, "music-turtles-clock", move |_stamp, message, _| {
+            let command = receiver.lock().unwrap().receive(message, Instant::now());
+            if let (Some(command), Some(commands)) = (command, &commands) {
+                // nobody listening means playback is over
+                let _ = commands.send(command);
+            }
+        }, ())?;
+        Ok(MidiClockFollower { state, _connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    /// The tempo the pulses are coming in at, once there have been a few.
+    pub fn tempo(&self) -> Option<BPM> {
+        self.state.lock().unwrap().tempo()
+    }
+
+    pub fn running(&self) -> bool {
+        self.state.lock().unwrap().running
+    }
+}
+
+impl Clock for MidiClockFollower {
+    fn elapsed(&self) -> Seconds {
+        self.state.lock().unwrap().elapsed_at(Instant::now())
+    }
+
+    /// Waits for the pulses, so this doesn't return while the transport is stopped.
+    fn sleep(&self, duration: Seconds) {
+        let end = self.elapsed() + duration;
+        while self.elapsed() < end {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl FollowState {
+    fn new(bpm: BPM) -> Self {
+        FollowState { bpm, pulses: 0, running: false, last_pulse: None, pulse_interval: None }
+    }
+
+    fn pulse_length(&self) -> Seconds {
+        60. / self.bpm / PULSES_PER_QUARTER as Seconds
+    }
+
+    /// Take in a message received at `at`, returning what the scheduler has to do about it.
+    fn receive(&mut self, message: &[u8], at: Instant) -> Option<SchedulerCommand> {
+        match message.first() {
+            Some(&CLOCK_PULSE) if self.running => {
+                if let Some(last) = self.last_pulse {
+                    let interval = at.duration_since(last);
+                    self.pulse_interval = Some(match self.pulse_interval {
+                        Some(smoothed) => (smoothed * 7 + interval) / 8,
+                        None => interval,
+                    });
+                }
+                self.last_pulse = Some(at);
+                self.pulses += 1;
+                None
+            }
+            Some(&START) => {
+                self.running = true;
+                self.last_pulse = None;
+                Some(SchedulerCommand::Restart)
+            }
+            Some(&CONTINUE) => {
+                self.running = true;
+                self.last_pulse = None;
+                None
+            }
+            Some(&STOP) => {
+                self.running = false;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// The clock is only moved on by pulses, but is guessed forward between them so it doesn't
+    /// advance in steps. The guess never goes past the next pulse, so time never goes backwards.
+    fn elapsed_at(&self, now: Instant) -> Seconds {
+        let counted = self.pulses as Seconds * self.pulse_length();
+        match (self.running, self.last_pulse, self.pulse_interval) {
+            (true, Some(last), Some(interval)) if !interval.is_zero() => {
+                let along = now.duration_since(last).as_secs_f32() / interval.as_secs_f32();
+                counted + along.min(1.) * self.pulse_length()
+            }
+            _ => counted,
+        }
+    }
+
+    fn tempo(&self) -> Option<BPM> {
+        self.pulse_interval
+            .filter(|i| !i.is_zero())
+            .map(|i| 60. / (i.as_secs_f32() * PULSES_PER_QUARTER as Seconds))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use crate::clock::{AudioClock, Clock, FollowState, VirtualClock, CLOCK_PULSE, START, STOP};
+    use crate::scheduler::SchedulerCommand;
+
+    #[test]
+    fn test_audio_clock() {
+        let written = Arc::new(AtomicU64::new(1000));
+        // samples from before it was made don't count
+        let clock = AudioClock::new(written.clone(), 100, 2);
+        assert_eq!(clock.elapsed(), 0.);
+        written.fetch_add(300, Ordering::Relaxed);
+        assert_eq!(clock.elapsed(), 1.5);
+        // sleeping waits for the samples, not the system clock
+        let output = {
+            let written = written.clone();
+            std::thread::spawn(move || for _ in 0..20 {
+                std::thread::sleep(Duration::from_millis(5));
+                written.fetch_add(2, Ordering::Relaxed);
+            })
+        };
+        clock.sleep(0.1);
+        assert!(clock.elapsed() >= 1.6);
+        output.join().unwrap();
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::default();
+        let shared = clock.clone();
+        clock.sleep(0.5);
+        shared.advance(0.25);
+        shared.sleep(-1.);
+        assert_eq!(clock.elapsed(), 0.75);
+    }
+
+    #[test]
+    fn test_follow_midi_clock() {
+        let mut state = FollowState::new(120.);
+        let start = Instant::now();
+        // pulses before the transport starts don't count
+        state.receive(&[CLOCK_PULSE], start);
+        assert!(matches!(state.receive(&[START], start), Some(SchedulerCommand::Restart)));
+        // a quarter note of pulses at 100 bpm, 25ms apart
+        let pulse = Duration::from_millis(25);
+        for i in 1..=24 {
+            state.receive(&[CLOCK_PULSE], start + pulse * i);
+        }
+        let at = start + pulse * 24;
+        // a quarter note at the scheduler's 120 bpm, whatever tempo the pulses came in at
+        assert!((state.elapsed_at(at) - 0.5).abs() < 1e-4);
+        assert!((state.tempo().unwrap() - 100.).abs() < 0.01);
+        // halfway to the next pulse, but never past it
+        assert!((state.elapsed_at(at + pulse / 2) - (0.5 + 0.5 / 48.)).abs() < 1e-4);
+        assert!((state.elapsed_at(at + pulse * 10) - (0.5 + 1. / 48.)).abs() < 1e-4);
+        // stopped, time stands still and pulses are ignored
+        state.receive(&[STOP], at);
+        state.receive(&[CLOCK_PULSE], at + pulse);
+        assert!((state.elapsed_at(at + pulse * 10) - 0.5).abs() < 1e-4);
+    }
+}
@@ -1,18 +1,27 @@
 use std::ops::{Add, Div};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
 use std::fmt::Display;
 use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 use enumkit::EnumValues;
 use num::Integer;
 use num::rational::Ratio;
-use crate::time::{Beat, BeatUnit, MusicTime, TimeCompression, TimeSignature};
+use crate::interval::{IntervalCache, IntervalIndex};
+use crate::time::{Beat, BeatUnit, Measure, MusicTime, TimeCompression, TimeSignature};
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, PartialOrd, Serialize, Deserialize, EnumValues)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize, EnumValues)]
 pub enum Instrument {
     SineWave,
     Piano,
     Bass,
+    Organ,
+    Strings,
+    Pad,
+    Bell,
+    ElectricPiano,
+    Guitar,
+    Harp,
     // percussion
     BassDrum,
     HiHatOpen,
@@ -27,8 +36,10 @@ pub enum Instrument {
 
 impl Instrument {
     pub fn is_percussion(&self) -> bool {
-        // matches!(self, Instrument::Drum | Instrument::Snare | Instrument::Cymbal)
-        false
+        matches!(self,
+            Instrument::BassDrum | Instrument::HiHatOpen | Instrument::HiHatClosed |
+            Instrument::Snare | Instrument::Snare2 | Instrument::BongoHigh | Instrument::BongoLow |
+            Instrument::Shaker1 | Instrument::Shaker2)
     }
     pub fn str_values() -> impl Iterator<Item=(Instrument, String)> {
         Instrument::values()
@@ -42,6 +53,8 @@ pub type Octave = i8;
 
 pub type Frequency = f32;
 
+pub type MidiChannel = u8;
+
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct Pitch(pub Octave, pub NoteNum);
@@ -56,8 +69,12 @@ pub enum TrackId {
 pub struct Track {
     pub identifier: TrackId,
     pub instrument: Instrument,
-    pub events: Vec<Event>,
+    /// Kept sorted by start so windows can be found by binary search.
+    /// Call `sort` after pushing events through `events_mut`.
+    events: Vec<Event>,
     pub rests: Vec<Event>,
+    /// Built on the first `events_sounding_at` query. Every way of changing events clears it.
+    index: IntervalCache,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
@@ -66,6 +83,32 @@ pub struct Event {
     pub duration: Beat,
     pub volume: Volume,
     pub pitch: Pitch,
+    pub modulation: Modulation,
+    /// How the note was written, if it came from the grammar. Cleared when the pitch changes.
+    pub spelling: Option<Spelling>,
+    /// MIDI channel picked with `::ch=`, overriding the one the instrument is mapped to.
+    pub channel: Option<MidiChannel>,
+    /// Picked with `::tag=`, to find parts of the music again after composing.
+    pub tag: Option<Tag>,
+    /// Written after the note, like `:c"la"`.
+    pub lyric: Option<Syllable>,
+}
+
+/// A name for a point in a composition, set with `::mark=`, that playback can jump to.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Marker {
+    pub name: String,
+    pub time: MusicTime,
+}
+
+/// The events of one measure of a track, from `Track::measures`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeasureView<'a> {
+    pub measure: Measure,
+    /// Events starting in this measure, in start order.
+    pub events: &'a [Event],
+    /// Events started in an earlier measure that are still sounding when this one starts.
+    pub held: Vec<Event>,
 }
 
 pub const MAX_VOLUME: u32 = 100;
@@ -79,6 +122,203 @@ impl Volume {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum Accidental {
+    DoubleFlat,
+    Flat,
+    Natural,
+    Sharp,
+    DoubleSharp,
+}
+
+impl Accidental {
+    pub fn semitones(&self) -> i8 {
+        match self {
+            Accidental::DoubleFlat => -2,
+            Accidental::Flat => -1,
+            Accidental::Natural => 0,
+            Accidental::Sharp => 1,
+            Accidental::DoubleSharp => 2,
+        }
+    }
+}
+
+/// Scales a mirror can keep its notes in, built on the mirror's center.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Scale {
+    Major,
+    Minor,
+}
+
+impl Scale {
+    /// Semitones of each degree above the root.
+    pub fn steps(&self) -> [i32; 7] {
+        match self {
+            Scale::Major => [0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => [0, 2, 3, 5, 7, 8, 10],
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scale::Major => "major",
+            Scale::Minor => "minor",
+        }
+    }
+}
+
+/// The letter and accidental a note was written with, so exports can spell it the same way
+/// (`f##` and `g` sound the same but are different notes on the page).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct Spelling {
+    /// Uppercase `A` to `G`.
+    pub letter: char,
+    /// `None` if no accidental was written.
+    pub accidental: Option<Accidental>,
+}
+
+impl Display for Spelling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let accidental = match self.accidental {
+            None => "",
+            Some(Accidental::DoubleFlat) => "bb",
+            Some(Accidental::Flat) => "b",
+            Some(Accidental::Natural) => "n",
+            Some(Accidental::Sharp) => "#",
+            Some(Accidental::DoubleSharp) => "x",
+        };
+        write!(f, "{}{}", self.letter, accidental)
+    }
+}
+
+/// A name attached to events. The names are interned so events can stay `Copy`:
+/// each distinct one is kept until the program exits, which is fine for the few a grammar uses,
+/// but means names from untrusted input have to go through `existing` instead of `new`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Tag(&'static str);
+
+/// A syllable of lyrics sung on a note, interned like `Tag`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Syllable(&'static str);
+
+static INTERNED: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+/// The one copy of `s`, leaked and added to the table if it's new. Nothing is ever taken out.
+pub(crate) fn intern(s: &str) -> &'static str {
+    let mut interned = INTERNED.lock().unwrap();
+    if let Some(s) = interned.get(s) {
+        return s;
+    }
+    let s: &'static str = Box::leak(s.into());
+    interned.insert(s);
+    s
+}
+
+/// The copy of `s` in the table, without adding it if there is none.
+fn interned(s: &str) -> Option<&'static str> {
+    INTERNED.lock().unwrap().get(s).copied()
+}
+
+impl Tag {
+    pub fn new(name: &str) -> Self {
+        Tag(intern(name))
+    }
+
+    /// The tag named `name`, only if one has been made before.
+    pub fn existing(name: &str) -> Option<Self> {
+        interned(name).map(Tag)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Syllable {
+    pub fn new(text: &str) -> Self {
+        Syllable(intern(text))
+    }
+
+    /// The syllable `text`, only if one has been made before.
+    pub fn existing(text: &str) -> Option<Self> {
+        interned(text).map(Syllable)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Display for Syllable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A low frequency oscillator. The rate is kept in millihertz and the depth in thousandths,
+/// so events carrying one can still be compared and hashed.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct Lfo {
+    pub millihertz: u32,
+    pub depth: u32,
+}
+
+impl Lfo {
+    pub const OFF: Lfo = Lfo { millihertz: 0, depth: 0 };
+
+    pub fn new(rate: f32, depth: f32) -> Self {
+        Lfo {
+            millihertz: (rate.max(0.) * 1000.).round() as u32,
+            depth: (depth.max(0.) * 1000.).round() as u32,
+        }
+    }
+
+    /// Cycles per second.
+    pub fn rate(&self) -> f32 {
+        self.millihertz as f32 / 1000.
+    }
+
+    pub fn depth(&self) -> f32 {
+        self.depth as f32 / 1000.
+    }
+
+    pub fn is_off(&self) -> bool {
+        self.millihertz == 0 || self.depth == 0
+    }
+
+    /// Offset of the oscillator at `time` seconds into the note, between -1 and 1.
+    pub fn value_at(&self, time: f32) -> f32 {
+        (std::f32::consts::TAU * self.rate() * time).sin()
+    }
+}
+
+/// Vibrato and tremolo applied to a note.
+/// Vibrato depth is in semitones, tremolo depth is the fraction of the volume taken away at the
+/// bottom of each cycle.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct Modulation {
+    pub vibrato: Lfo,
+    pub tremolo: Lfo,
+}
+
+impl Modulation {
+    pub const NONE: Modulation = Modulation { vibrato: Lfo::OFF, tremolo: Lfo::OFF };
+
+    /// Use `fallback` for whichever oscillators this one leaves off.
+    pub fn or(self, fallback: Modulation) -> Modulation {
+        Modulation {
+            vibrato: if self.vibrato.is_off() { fallback.vibrato } else { self.vibrato },
+            tremolo: if self.tremolo.is_off() { fallback.tremolo } else { self.tremolo },
+        }
+    }
+}
+
 impl Event {
     pub fn get_end(&self, time_signature: TimeSignature) -> MusicTime {
         self.start.with(time_signature) + self.duration.as_music_time(time_signature)
@@ -104,6 +344,22 @@ fn max_option<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
 }
 
 impl Track {
+    pub fn new(identifier: TrackId, instrument: Instrument, events: Vec<Event>, rests: Vec<Event>) -> Self {
+        let mut track = Track { identifier, instrument, events, rests, index: IntervalCache::default() };
+        track.sort();
+        track
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Mutable access to the events. Drops the interval index, since the events may change.
+    pub fn events_mut(&mut self) -> &mut Vec<Event> {
+        self.index.clear();
+        &mut self.events
+    }
+
     pub fn visualize(&self, columns: usize, time_signature: TimeSignature, start: MusicTime, end: MusicTime) -> String {
         let mut s = String::new();
         s.push('[');
@@ -169,22 +425,97 @@ impl Track {
             .unwrap_or(MusicTime::zero())
     }
 
+    /// Silence nobody wrote down: the stretches from the start of the piece to the end of the
+    /// track where no note sounds and no rest was written, as start and length.
+    pub fn gaps(&self, time_signature: TimeSignature) -> Vec<(MusicTime, Beat)> {
+        let beats = |time: MusicTime| time.with(time_signature).total_beats();
+        let mut spans = self.events.iter()
+            .chain(&self.rests)
+            .map(|e| (beats(e.start), beats(e.start) + e.duration))
+            .collect::<Vec<_>>();
+        spans.sort();
+        let mut gaps = vec![];
+        let mut now = Beat::zero();
+        for (start, end) in spans {
+            if start > now {
+                gaps.push((now.as_music_time(time_signature), start - now));
+            }
+            now = now.max(end);
+        }
+        gaps
+    }
+
     /// End is always inclusive
     /// Doesn't include rests
     pub fn get_events_starting_between(&self, start: MusicTime, end: MusicTime, start_exclusive: bool) -> Vec<Event> {
-        if (start_exclusive && start >= end) || start > end {
-            return Vec::new();
+        self.events_starting_between(start, end, start_exclusive).to_vec()
+    }
+
+    /// Same window as `get_events_starting_between`, but borrows the events
+    /// instead of collecting them.
+    pub fn iter_events_starting_between(&self, start: MusicTime, end: MusicTime, start_exclusive: bool) -> impl Iterator<Item=&Event> + '_ {
+        self.events_starting_between(start, end, start_exclusive).iter()
+    }
+
+    /// The events starting from `start` up to but not including `end`, so windows laid end to end
+    /// hand out every event once.
+    pub fn iter_events_starting_in(&self, start: MusicTime, end: MusicTime) -> impl Iterator<Item=&Event> + '_ {
+        let lo = self.events.partition_point(|e| e.start < start);
+        let hi = self.events.partition_point(|e| e.start < end);
+        self.events[lo..hi.max(lo)].iter()
+    }
+
+    /// Every measure from the first one up to the one the track ends in, including empty ones.
+    pub fn measures(&self, time_signature: TimeSignature) -> impl Iterator<Item=MeasureView<'_>> + '_ {
+        let measures = match self.get_end(time_signature) {
+            Some(MusicTime(measure, beat)) if beat == Beat::zero() => measure,
+            Some(MusicTime(measure, _)) => measure + 1,
+            None => 0,
+        };
+        (0..measures).map(move |measure| {
+            let start = MusicTime::measures(measure);
+            let lo = self.events.partition_point(|e| e.start < start);
+            let hi = self.events.partition_point(|e| e.start < MusicTime::measures(measure + 1));
+            let held = self.events[..lo].iter()
+                .filter(|e| e.get_end(time_signature) > start)
+                .copied()
+                .collect();
+            MeasureView { measure, events: &self.events[lo..hi], held }
+        })
+    }
+
+    /// Binary-search the sorted events for the ones starting in the window.
+    fn events_starting_between(&self, start: MusicTime, end: MusicTime, start_exclusive: bool) -> &[Event] {
+        let lo = if start_exclusive {
+            self.events.partition_point(|e| e.start <= start)
+        } else {
+            self.events.partition_point(|e| e.start < start)
+        };
+        let hi = self.events.partition_point(|e| e.start <= end);
+        &self.events[lo..hi.max(lo)]
+    }
+
+    /// Restore the ordering invariant on events and rests.
+    pub fn sort(&mut self) {
+        self.events.sort();
+        self.rests.sort();
+        self.index.clear();
+    }
+
+    /// Events with `start <= time < end`, in start order. Doesn't include rests.
+    pub fn events_sounding_at(&self, time: MusicTime, time_signature: TimeSignature) -> Vec<Event> {
+        match self.index.get_or_build(&self.events, time_signature) {
+            Some(index) => index.sounding_at(&self.events, time)
+                .into_iter()
+                .copied()
+                .collect(),
+            // the cached index belongs to another time signature
+            None => IntervalIndex::new(&self.events, time_signature)
+                .sounding_at(&self.events, time)
+                .into_iter()
+                .copied()
+                .collect(),
         }
-        let mut es = self.events.iter()
-            .filter(|e| if start_exclusive {
-                start < e.start
-            } else {
-                start <= e.start
-            } && e.start <= end)
-            .map(|e| *e)
-            .collect::<Vec<_>>();
-        es.sort();
-        es
     }
 
     pub fn shift_by(&mut self, offset: MusicTime, time_signature: TimeSignature) {
@@ -193,29 +524,43 @@ impl Track {
             .for_each(|e|
                 e.start = e.start.with(time_signature) + offset
             );
+        self.index.clear();
     }
 
     pub fn transpose(&mut self, semitones: i8) {
         for event in &mut self.events {
             event.pitch.transpose(semitones);
+            event.spelling = None;
+        }
+    }
+
+    pub fn mirror(&mut self, center: Pitch, scale: Option<Scale>) {
+        for event in &mut self.events {
+            event.pitch.mirror(center, scale);
+            event.spelling = None;
         }
     }
 
     /// Flip entire track, keeping it within its start/end bounds.
     pub fn reverse(&mut self, time_signature: TimeSignature) {
         if let (Some(start), Some(end)) = (self.get_start(), self.get_end(time_signature)) {
-            self.events.iter_mut()
-                .chain(self.rests.iter_mut())
-                .for_each(|e| {
-                    let offset = e.start.with(time_signature) - start;
-                    let new_start = (end.with(time_signature) - offset).with(time_signature) - e.duration.as_music_time(time_signature);
-                    e.start = new_start;
-                });
-            self.events.reverse();
-            self.rests.reverse();
+            self.reverse_between(start, end, time_signature);
         }
     }
 
+    /// Flip the track so that whatever ended at `end` starts at `start` and vice versa.
+    pub fn reverse_between(&mut self, start: MusicTime, end: MusicTime, time_signature: TimeSignature) {
+        self.events.iter_mut()
+            .chain(self.rests.iter_mut())
+            .for_each(|e| {
+                let offset = e.start.with(time_signature) - start;
+                let new_start = (end.with(time_signature) - offset).with(time_signature) - e.duration.as_music_time(time_signature);
+                e.start = new_start;
+            });
+        // notes of different lengths can swap places, so reversing the vecs isn't enough
+        self.sort();
+    }
+
     /// Compress all timings by the compression factor.
     /// Example: if the factor is 0.5, it will compress the track to half its length.
     pub fn compress(&mut self, time_signature: TimeSignature, compression: TimeCompression) {
@@ -232,10 +577,94 @@ impl Track {
                     e.start = start.with(time_signature) + offset.time;
                     e.duration = (e.duration.as_music_time(time_signature).with(time_signature) * factor).total_beats();
                 });
+            self.index.clear();
+        }
+    }
+
+    /// Replace every event by `times` quick repetitions that together take up its duration.
+    pub fn stutter(&mut self, times: usize, time_signature: TimeSignature) {
+        if times <= 1 {
+            return;
+        }
+        let fraction = Ratio::new(1, times as BeatUnit);
+        self.events = self.events.iter()
+            .flat_map(|e| {
+                let duration = e.duration.as_music_time(time_signature).with(time_signature) * fraction;
+                (0..times).map(move |i| Event {
+                    start: e.start.with(time_signature) + (duration * Ratio::from_integer(i as BeatUnit)).time,
+                    duration: duration.total_beats(),
+                    ..*e
+                })
+            })
+            .collect();
+        self.sort();
+    }
+
+    /// Drop events starting at or after `end` and shorten the ones still sounding there.
+    pub fn truncate(&mut self, end: MusicTime, time_signature: TimeSignature) {
+        let cut = |events: &mut Vec<Event>| {
+            events.retain(|e| e.start < end);
+            for e in events.iter_mut() {
+                if e.get_end(time_signature) > end {
+                    e.duration = (end.with(time_signature) - e.start).with(time_signature).total_beats();
+                }
+            }
+        };
+        cut(&mut self.events);
+        cut(&mut self.rests);
+        self.index.clear();
+    }
+
+    /// Rewrite overlapping events of the same pitch according to the policy, so that
+    /// at most one of them is sounding at a time.
+    pub fn resolve_overlaps(&mut self, policy: OverlapPolicy, time_signature: TimeSignature) {
+        let beats_between = |start: MusicTime, end: MusicTime| (end.with(time_signature) - start).with(time_signature).total_beats();
+        let mut resolved: Vec<Event> = Vec::with_capacity(self.events.len());
+        let mut last_with_pitch: HashMap<Pitch, usize> = HashMap::new();
+        for e in self.events.drain(..) {
+            let prev = last_with_pitch.get(&e.pitch)
+                .copied()
+                .filter(|&i| resolved[i].get_end(time_signature) > e.start);
+            let Some(i) = prev else {
+                last_with_pitch.insert(e.pitch, resolved.len());
+                resolved.push(e);
+                continue;
+            };
+            let end = resolved[i].get_end(time_signature).max(e.get_end(time_signature));
+            match policy {
+                OverlapPolicy::Merge => {
+                    resolved[i].duration = beats_between(resolved[i].start, end);
+                }
+                OverlapPolicy::TruncateFirst | OverlapPolicy::Retrigger => {
+                    resolved[i].duration = beats_between(resolved[i].start, e.start);
+                    let mut e = e;
+                    if policy == OverlapPolicy::Retrigger {
+                        e.duration = beats_between(e.start, end);
+                    }
+                    last_with_pitch.insert(e.pitch, resolved.len());
+                    resolved.push(e);
+                }
+            }
         }
+        // notes that started together were truncated to nothing
+        resolved.retain(|e| e.duration > Beat::zero());
+        self.events = resolved;
+        self.sort();
     }
 }
 
+/// What to do when two events with the same pitch and instrument overlap.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum OverlapPolicy {
+    /// Keep the first note sounding until the last overlapping one ends.
+    Merge,
+    /// End the earlier note where the later one starts.
+    TruncateFirst,
+    /// Strike the note again, and only release it once every overlapping note has ended.
+    #[default]
+    Retrigger,
+}
+
 impl Add<Self> for Track {
     type Output = Track;
 
@@ -258,6 +687,7 @@ impl Add<Self> for Track {
             instrument: self.instrument,
             events,
             rests,
+            index: IntervalCache::default(),
         }
     }
 }
@@ -270,6 +700,12 @@ impl Pitch {
         let frequency = 440.0 * 2f32.powf(octave - 4. + (note_num - 9.0) / 12.0);
         frequency
     }
+    /// The pitch `to_midi_note` gives `key` for.
+    pub fn from_midi_note(key: u8) -> Pitch {
+        let from_octave_zero = key as i16 - 9;
+        Pitch(from_octave_zero.div_euclid(12) as Octave, from_octave_zero.rem_euclid(12) as NoteNum)
+    }
+
     pub fn to_midi_note(&self) -> u8 {
         let Pitch(octave, note_num) = *self;
         let note_num = note_num as u8;
@@ -297,11 +733,31 @@ impl Pitch {
         }.to_string()
     }
 
+    /// Reflect around `center`. With a scale built on the center, notes outside it are first
+    /// snapped down into it and the reflection counts scale degrees instead of semitones.
+    pub fn mirror(&mut self, center: Pitch, scale: Option<Scale>) {
+        let linear = |p: Pitch| p.0 as i32 * 12 + p.1 as i32;
+        let above = linear(*self) - linear(center);
+        let below = match scale {
+            None => -above,
+            Some(scale) => {
+                let steps = scale.steps();
+                let within = above.rem_euclid(12);
+                let index = steps.iter().rposition(|&s| s <= within).unwrap_or(0) as i32;
+                let degree = above.div_euclid(12) * 7 + index;
+                let mirrored = -degree;
+                mirrored.div_euclid(7) * 12 + steps[mirrored.rem_euclid(7) as usize]
+            }
+        };
+        let mirrored = linear(center) + below;
+        *self = Pitch(mirrored.div_euclid(12) as Octave, mirrored.rem_euclid(12) as NoteNum);
+    }
+
+    /// Move by `semitones`, staying in the lowest or highest octave there is rather than wrapping.
     pub fn transpose(&mut self, semitones: i8) {
-        let Pitch(octave, note_num) = *self;
-        let new_note_num = (note_num as i8 + semitones).rem_euclid(12) as u8;
-        let new_octave = octave + ((note_num as i8 + semitones) as f32 / 12.).floor() as i8;
-        *self = Pitch(new_octave, new_note_num);
+        let transposed = self.0 as i32 * 12 + self.1 as i32 + semitones as i32;
+        let octave = transposed.div_euclid(12).clamp(Octave::MIN as i32, Octave::MAX as i32);
+        *self = Pitch(octave as Octave, transposed.rem_euclid(12) as NoteNum);
     }
 }
 
@@ -309,6 +765,8 @@ impl Pitch {
 pub struct Composition {
     pub tracks: Vec<Track>,
     pub time_signature: TimeSignature,
+    /// In the order they were set, so the same name can come up more than once.
+    pub markers: Vec<Marker>,
 }
 
 impl Composition {
@@ -356,6 +814,14 @@ impl Composition {
     pub fn shift_by(&mut self, offset: MusicTime) {
         self.tracks.iter_mut()
             .for_each(|tr| tr.shift_by(offset, self.time_signature));
+        for marker in &mut self.markers {
+            marker.time = marker.time.with(self.time_signature) + offset;
+        }
+    }
+
+    /// Where the first marker called `name` is.
+    pub fn marker(&self, name: &str) -> Option<MusicTime> {
+        self.markers.iter().find(|m| m.name == name).map(|m| m.time)
     }
 
     pub fn transpose(&mut self, semitones: i8) {
@@ -364,12 +830,200 @@ impl Composition {
         }
     }
 
+    /// Play backwards. Tracks are flipped within the bounds of the whole composition,
+    /// so they stay lined up with each other.
+    pub fn reverse(&mut self) {
+        if let (Some(start), Some(end)) = (self.get_start(), self.get_end()) {
+            for track in &mut self.tracks {
+                track.reverse_between(start, end, self.time_signature);
+            }
+            let ts = self.time_signature;
+            for marker in &mut self.markers {
+                marker.time = start.with(ts) + (end.with(ts) - marker.time);
+            }
+            self.markers.reverse();
+        }
+    }
+
+    /// Play `num` times back to back. Each pass starts where the previous one ends.
+    pub fn repeat(&mut self, num: usize) {
+        let period = self.get_duration();
+        let once = self.clone();
+        if num == 0 {
+            for track in &mut self.tracks {
+                track.events.clear();
+                track.rests.clear();
+                track.index.clear();
+            }
+            self.markers.clear();
+            return;
+        }
+        let mut offset = period;
+        for _i in 1..num {
+            let mut pass = once.clone();
+            pass.shift_by(offset);
+            for (target, track) in self.tracks.iter_mut().zip(pass.tracks) {
+                target.events.extend(track.events);
+                target.rests.extend(track.rests);
+            }
+            self.markers.extend(pass.markers);
+            offset = offset.with(self.time_signature) + period;
+        }
+        self.tracks.iter_mut().for_each(Track::sort);
+    }
+
+    /// Multiply the volume of every event, keeping it under `MAX_VOLUME`.
+    pub fn scale_volume(&mut self, factor: f32) {
+        for track in &mut self.tracks {
+            for event in &mut track.events {
+                event.volume = Volume(((event.volume.0 as f32 * factor).round() as u32).min(MAX_VOLUME));
+            }
+        }
+    }
+
+    pub fn mirror(&mut self, center: Pitch, scale: Option<Scale>) {
+        for track in &mut self.tracks {
+            track.mirror(center, scale);
+        }
+    }
+
+    pub fn stutter(&mut self, times: usize) {
+        for track in &mut self.tracks {
+            track.stutter(times, self.time_signature);
+        }
+    }
+
+    /// Add a rest to the end of every track that stops before the others, so that each one
+    /// lasts as long as the whole composition and looping keeps them together.
+    pub fn pad_to_common_end(&mut self) {
+        let Some(end) = self.get_end() else {
+            return;
+        };
+        let time_signature = self.time_signature;
+        for track in &mut self.tracks {
+            match track.get_end(time_signature) {
+                Some(track_end) if track_end < end => {
+                    track.rests.push(Event {
+                        start: track_end,
+                        duration: (end.with(time_signature) - track_end).with(time_signature).total_beats(),
+                        volume: Volume(0),
+                        pitch: Pitch(0, 0),
+                        modulation: Modulation::NONE,
+                        spelling: None,
+                        channel: None,
+                        tag: None,
+                        lyric: None,
+                    });
+                    track.sort();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Play `other` once this composition has ended, after waiting `gap`.
+    pub fn append(&mut self, other: &Composition, gap: MusicTime) {
+        let end = self.get_end().unwrap_or(MusicTime::zero());
+        self.overlay(other, end.with(self.time_signature) + gap);
+    }
+
+    /// Play `other` on top of this composition, starting at `offset`.
+    /// If `other` has another time signature, its events are moved into this one, keeping their length
+    /// in whole notes. Tracks with the same identifier are merged, unless they play different instruments,
+    /// in which case the track from `other` is given a new custom identifier.
+    pub fn overlay(&mut self, other: &Composition, offset: MusicTime) {
+        let time_signature = self.time_signature;
+        let factor = Ratio::new(time_signature.1, other.time_signature.1);
+        let convert = |time: MusicTime| (time.with(other.time_signature) * factor).total_beats();
+        let moved = |e: &Event| Event {
+            start: (convert(e.start) + offset.with(time_signature).total_beats()).as_music_time(time_signature),
+            duration: convert(e.duration.as_music_time(other.time_signature)),
+            ..*e
+        };
+        for track in &other.tracks {
+            let existing = self.tracks.iter().position(|t| t.identifier == track.identifier);
+            let target = match existing {
+                Some(i) if self.tracks[i].instrument == track.instrument => &mut self.tracks[i],
+                _ => {
+                    let identifier = if existing.is_some() {
+                        let next = self.tracks.iter()
+                            .filter_map(|t| match t.identifier {
+                                TrackId::Custom(n) => Some(n + 1),
+                                TrackId::Instrument(_) => None,
+                            })
+                            .max()
+                            .unwrap_or(0);
+                        TrackId::Custom(next)
+                    } else {
+                        track.identifier
+                    };
+                    self.tracks.push(Track {
+                        identifier,
+                        instrument: track.instrument,
+                        events: vec![],
+                        rests: vec![],
+                        index: IntervalCache::default(),
+                    });
+                    self.tracks.last_mut().unwrap()
+                }
+            };
+            target.events.extend(track.events.iter().map(moved));
+            target.rests.extend(track.rests.iter().map(moved));
+            target.sort();
+        }
+        self.markers.extend(other.markers.iter().map(|m| Marker {
+            name: m.name.clone(),
+            time: (convert(m.time) + offset.with(time_signature).total_beats()).as_music_time(time_signature),
+        }));
+    }
+
+    /// Cut everything off at `end`. Notes still sounding there are shortened.
+    pub fn truncate(&mut self, end: MusicTime) {
+        for track in &mut self.tracks {
+            track.truncate(end, self.time_signature);
+        }
+        self.markers.retain(|m| m.time < end);
+    }
+
+    /// Play this composition over and over from time zero until `length`, cutting off the last pass.
+    pub fn looped(&self, length: MusicTime) -> Composition {
+        let period = self.get_duration();
+        let mut passes = 1;
+        let mut covered = period;
+        while period > MusicTime::zero() && covered < length {
+            passes += 1;
+            covered = covered.with(self.time_signature) + period;
+        }
+        let mut looped = self.clone();
+        looped.repeat(passes);
+        looped.truncate(length);
+        looped
+    }
+
+    pub fn resolve_overlaps(&mut self, policy: OverlapPolicy) {
+        for track in &mut self.tracks {
+            track.resolve_overlaps(policy, self.time_signature);
+        }
+    }
+
     /// Compress all timings by the compression factor toward the start of the track.
     /// If the factor is negative, it will reverse the track.
     /// Example, if the factor is 0.5, it will compress the track to half its length.
     pub fn compress(&mut self, compression: TimeCompression) {
+        let ts = self.time_signature;
+        if let (Some(start), Some(end)) = (self.get_start(), self.get_end()) {
+            let factor = Ratio::new(compression.0.numer().unsigned_abs() as BeatUnit, compression.0.denom().unsigned_abs() as BeatUnit);
+            for marker in &mut self.markers {
+                let time = if compression.0 < Ratio::new(0, 1) {
+                    start.with(ts) + (end.with(ts) - marker.time)
+                } else {
+                    marker.time
+                };
+                marker.time = start.with(ts) + ((time.with(ts) - start).with(ts) * factor).time;
+            }
+        }
         for track in &mut self.tracks {
-            track.compress(self.time_signature, compression);
+            track.compress(ts, compression);
         }
     }
 }
@@ -391,9 +1045,12 @@ impl Add<Self> for Composition {
                 map.insert(id, track);
             }
         }
+        let mut markers = self.markers;
+        markers.extend(rhs.markers);
         Composition {
             tracks: map.into_values().collect(),
             time_signature: self.time_signature,
+            markers,
         }
     }
 }
@@ -416,11 +1073,23 @@ impl FromStr for Instrument {
     }
 }
 
+impl Display for TrackId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrackId::Instrument(instrument) => write!(f, "{:?}", instrument),
+            TrackId::Custom(id) => write!(f, "Custom({})", id),
+        }
+    }
+}
+
+#[cfg(test)]
 mod composition_element_tests {
     use num::rational::Ratio;
-    use rodio::cpal::BufferSize::Default;
-    use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume};
-    use crate::time::{Beat, MusicTime, TimeCompression, TimeSignature};
+    use crate::builder::CompositionBuilder;
+    use crate::composition::{Composition, Event, Instrument, NoteNum, Octave, OverlapPolicy, Pitch, Track, TrackId, Volume};
+    use crate::interval::IntervalCache;
+    use crate::testing::event;
+    use crate::time::{Beat, BeatUnit, MusicTime, TimeCompression, TimeSignature};
 
     fn assert_epsilon_close(a: f32, b: f32) {
         if (a - b).abs() < 0.01 {
@@ -472,6 +1141,18 @@ mod composition_element_tests {
         assert_eq!(pitch, Pitch(5, 0)); // C5
     }
 
+    #[test]
+    fn test_transpose_far() {
+        let mut pitch = Pitch(4, 11); // B4
+        pitch.transpose(i8::MAX);
+        assert_eq!(pitch, Pitch(15, 6));
+        pitch.transpose(i8::MIN);
+        assert_eq!(pitch, Pitch(4, 10));
+        let mut pitch = Pitch(Octave::MAX, 11);
+        pitch.transpose(1);
+        assert_eq!(pitch, Pitch(Octave::MAX, 0));
+    }
+
     fn comp_template(events: Vec<Event>) -> Composition {
         Composition {
             tracks: vec![
@@ -480,31 +1161,23 @@ mod composition_element_tests {
                     instrument: Instrument::SineWave,
                     events,
                     rests: vec![],
+                    index: IntervalCache::default(),
                 }
             ],
             time_signature: TimeSignature::common(),
+            markers: vec![],
         }
     }
 
     #[test]
     fn test_compression_1() {
         let compression = TimeCompression(Ratio::new(1, 2)); // 50% compression
-        let mut composition1 = comp_template(vec![
-            Event {
-                start: MusicTime::measures(1),
-                duration: Beat::whole(2),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            }
-        ]);
-        let composition_half = comp_template(vec![
-            Event {
-                start: MusicTime::measures(1),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            }
-        ]);
+        let note = |duration| CompositionBuilder::track(Instrument::SineWave)
+            .note(Pitch(4, 0), MusicTime::measures(1), duration, Volume(100))
+            .build()
+            .unwrap();
+        let mut composition1 = note(Beat::whole(2));
+        let composition_half = note(Beat::whole(1));
         composition1.compress(compression);
         assert_eq!(composition1, composition_half);
     }
@@ -513,20 +1186,10 @@ mod composition_element_tests {
     fn test_compression_2() {
         let compression = TimeCompression(Ratio::new(-1, 1)); // -100% compression (reverse)
         let mut composition1 = comp_template(vec![
-            Event {
-                start: MusicTime::measures(1),
-                duration: Beat::whole(2),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            }
+            event(Pitch(4, 0), MusicTime::measures(1), Beat::whole(2))
         ]);
         let composition_reversed = comp_template(vec![
-            Event {
-                start: MusicTime::measures(1),
-                duration: Beat::whole(2),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            }
+            event(Pitch(4, 0), MusicTime::measures(1), Beat::whole(2))
         ]);
         composition1.compress(compression);
         assert_eq!(composition1, composition_reversed);
@@ -536,32 +1199,12 @@ mod composition_element_tests {
     fn test_compression_3() {
         let compression = TimeCompression(Ratio::new(-1, 1)); // -100% compression (reverse)
         let mut composition1 = comp_template(vec![
-            Event {
-                start: MusicTime(1, Beat::whole(0)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            },
-            Event {
-                start: MusicTime(1, Beat::whole(1)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 1),
-            }
+            event(Pitch(4, 0), MusicTime(1, Beat::whole(0)), Beat::whole(1)),
+            event(Pitch(4, 1), MusicTime(1, Beat::whole(1)), Beat::whole(1))
         ]);
         let composition_reversed = comp_template(vec![
-            Event {
-                start: MusicTime(1, Beat::whole(0)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 1),
-            },
-            Event {
-                start: MusicTime(1, Beat::whole(1)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            }
+            event(Pitch(4, 1), MusicTime(1, Beat::whole(0)), Beat::whole(1)),
+            event(Pitch(4, 0), MusicTime(1, Beat::whole(1)), Beat::whole(1))
         ]);
         composition1.compress(compression);
         assert_eq!(composition1, composition_reversed);
@@ -571,43 +1214,246 @@ mod composition_element_tests {
     fn test_compression_4() {
         let compression = TimeCompression(Ratio::new(1, 2)); // 50% compression
         let mut composition1 = comp_template(vec![
-            Event {
-                start: MusicTime(1, Beat::whole(0)),
-                duration: Beat::whole(2),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            },
-            Event {
-                start: MusicTime(1, Beat::whole(2)),
-                duration: Beat::whole(2),
-                volume: Volume(100),
-                pitch: Pitch(4, 1),
-            }
+            event(Pitch(4, 0), MusicTime(1, Beat::whole(0)), Beat::whole(2)),
+            event(Pitch(4, 1), MusicTime(1, Beat::whole(2)), Beat::whole(2))
         ]);
         let composition_half = comp_template(vec![
-            Event {
-                start: MusicTime(1, Beat::whole(0)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            },
-            Event {
-                start: MusicTime(1, Beat::whole(1)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 1),
-            }
+            event(Pitch(4, 0), MusicTime(1, Beat::whole(0)), Beat::whole(1)),
+            event(Pitch(4, 1), MusicTime(1, Beat::whole(1)), Beat::whole(1))
         ]);
         composition1.compress(compression);
         assert_eq!(composition1, composition_half);
     }
-}
 
-impl Display for TrackId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            TrackId::Instrument(instrument) => write!(f, "{:?}", instrument),
-            TrackId::Custom(id) => write!(f, "Custom({})", id),
-        }
+    fn track_template(events: Vec<Event>) -> Track {
+        Track::new(TrackId::Custom(0), Instrument::SineWave, events, vec![])
+    }
+
+    #[test]
+    fn test_events_starting_between_large_track() {
+        let ts = TimeSignature::common();
+        let track = track_template((0..20_000)
+            .rev()
+            .map(|i| event(Pitch(4, (i % 12) as u8), MusicTime(0, Beat::new(i, 2)).with(ts) + MusicTime::zero(), Beat::new(1, 2)))
+            .collect());
+        let start = MusicTime(100, Beat::whole(1));
+        let end = MusicTime(102, Beat::whole(0));
+        let expected = track.events.iter()
+            .filter(|e| start < e.start && e.start <= end)
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(expected.len(), 14);
+        assert_eq!(track.get_events_starting_between(start, end, true), expected);
+        let inclusive = track.get_events_starting_between(start, end, false);
+        assert_eq!(inclusive.len(), 15);
+        assert_eq!(inclusive[0].start, start);
+        assert!(track.get_events_starting_between(end, start, false).is_empty());
+        assert!(track.get_events_starting_between(start, start, true).is_empty());
+    }
+
+    #[test]
+    fn test_reverse_keeps_events_sorted() {
+        let ts = TimeSignature::common();
+        let mut track = track_template(vec![
+            event(Pitch(4, 0), MusicTime::zero(), Beat::whole(4)),
+            event(Pitch(4, 1), MusicTime(0, Beat::whole(1)), Beat::whole(1)),
+        ]);
+        track.reverse(ts);
+        assert!(track.events.is_sorted_by_key(|e| e.start));
+        assert_eq!(track.get_events_starting_between(MusicTime::zero(), MusicTime::beats(2), false).len(), 2);
+    }
+
+    #[test]
+    fn test_events_sounding_at() {
+        let ts = TimeSignature::common();
+        let mut track = track_template(vec![
+            event(Pitch(4, 0), MusicTime::zero(), Beat::whole(4)),
+            event(Pitch(4, 1), MusicTime(0, Beat::whole(1)), Beat::whole(1)),
+        ]);
+        let at = |track: &Track, beats| track.events_sounding_at(MusicTime::beats(beats), ts)
+            .iter()
+            .map(|e| e.pitch)
+            .collect::<Vec<_>>();
+        assert_eq!(at(&track, 1), vec![Pitch(4, 0), Pitch(4, 1)]);
+        assert_eq!(at(&track, 2), vec![Pitch(4, 0)]);
+        track.shift_by(MusicTime::beats(2), ts);
+        assert_eq!(at(&track, 1), vec![]);
+        assert_eq!(at(&track, 3), vec![Pitch(4, 0), Pitch(4, 1)]);
+    }
+
+    #[test]
+    fn test_events_sounding_at_after_editing_events() {
+        let ts = TimeSignature::common();
+        let mut track = track_template(vec![
+            note(MusicTime::zero(), 1, Pitch(4, 0)),
+            note(MusicTime::beats(2), 1, Pitch(4, 1)),
+        ]);
+        let at = |track: &Track, beats| track.events_sounding_at(MusicTime::beats(beats), ts)
+            .iter()
+            .map(|e| e.pitch)
+            .collect::<Vec<_>>();
+        assert_eq!(at(&track, 1), vec![]);
+        // same number of events, but the first one is held longer
+        track.events_mut()[0].duration = Beat::whole(2);
+        assert_eq!(at(&track, 1), vec![Pitch(4, 0)]);
+        // and replaced by one that starts later
+        track.events_mut()[0] = note(MusicTime::beats(2), 2, Pitch(4, 2));
+        track.sort();
+        assert_eq!(at(&track, 1), vec![]);
+        assert_eq!(at(&track, 3), vec![Pitch(4, 2)]);
+    }
+
+    fn note(start: MusicTime, beats: u32, pitch: Pitch) -> Event {
+        event(pitch, start, Beat::whole(beats))
+    }
+
+    #[test]
+    fn test_resolve_overlaps() {
+        let ts = TimeSignature::common();
+        let overlapping = vec![
+            note(MusicTime::zero(), 2, Pitch(4, 0)),
+            note(MusicTime::beats(1), 2, Pitch(4, 0)),
+            note(MusicTime::beats(1), 2, Pitch(4, 3)),
+        ];
+        let resolved = |policy| {
+            let mut track = track_template(overlapping.clone());
+            track.resolve_overlaps(policy, ts);
+            track.events
+        };
+        assert_eq!(resolved(OverlapPolicy::Merge), vec![
+            note(MusicTime::zero(), 3, Pitch(4, 0)),
+            note(MusicTime::beats(1), 2, Pitch(4, 3)),
+        ]);
+        assert_eq!(resolved(OverlapPolicy::TruncateFirst), vec![
+            note(MusicTime::zero(), 1, Pitch(4, 0)),
+            note(MusicTime::beats(1), 2, Pitch(4, 0)),
+            note(MusicTime::beats(1), 2, Pitch(4, 3)),
+        ]);
+    }
+
+    #[test]
+    fn test_retrigger_holds_until_last_end() {
+        let ts = TimeSignature::common();
+        let mut track = track_template(vec![
+            note(MusicTime::zero(), 4, Pitch(4, 0)),
+            note(MusicTime::beats(1), 1, Pitch(4, 0)),
+            note(MusicTime::beats(1), 1, Pitch(4, 0)),
+        ]);
+        track.resolve_overlaps(OverlapPolicy::Retrigger, ts);
+        assert_eq!(track.events, vec![
+            note(MusicTime::zero(), 1, Pitch(4, 0)),
+            note(MusicTime::beats(1), 3, Pitch(4, 0)),
+        ]);
+    }
+
+    #[test]
+    fn test_composition_repeat_and_reverse() {
+        let ts = TimeSignature::common();
+        let note = |start: MusicTime, duration: BeatUnit, note: NoteNum| event(Pitch(4, note), start, Beat::whole(duration));
+        let mut bass = track_template(vec![note(MusicTime::zero(), 2, 0)]);
+        bass.identifier = TrackId::Custom(1);
+        let melody = track_template(vec![note(MusicTime::zero(), 1, 3), note(MusicTime::beats(1), 1, 5)]);
+        let mut composition = Composition { tracks: vec![bass, melody], time_signature: ts, markers: vec![] };
+
+        composition.reverse();
+        // the bass only lasts half as long as the melody, but stays lined up with it
+        assert_eq!(composition.tracks[0].events[0].start, MusicTime::zero());
+        let melody = composition.tracks[1].events.iter().map(|e| (e.start, e.pitch.1)).collect::<Vec<_>>();
+        assert_eq!(melody, vec![(MusicTime::zero(), 5), (MusicTime::beats(1), 3)]);
+
+        composition.repeat(3);
+        assert_eq!(composition.get_duration(), MusicTime(1, Beat::whole(2)));
+        let starts = composition.tracks[0].events.iter().map(|e| e.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![MusicTime::zero(), MusicTime::beats(2), MusicTime(1, Beat::zero())]);
+        assert_eq!(composition.tracks[1].events.len(), 6);
+
+        composition.repeat(0);
+        assert_eq!(composition.get_duration(), MusicTime::zero());
+    }
+
+    #[test]
+    fn test_pad_to_common_end() {
+        let ts = TimeSignature::common();
+        let note = |start: MusicTime, duration: BeatUnit| event(Pitch(4, 0), start, Beat::whole(duration));
+        let mut short = track_template(vec![note(MusicTime::zero(), 1)]);
+        short.identifier = TrackId::Custom(1);
+        let long = track_template(vec![note(MusicTime::zero(), 3), note(MusicTime::beats(3), 3)]);
+        let mut composition = Composition { tracks: vec![short, long], time_signature: ts, markers: vec![] };
+        composition.pad_to_common_end();
+        let end = MusicTime(1, Beat::whole(2));
+        assert!(composition.tracks.iter().all(|t| t.get_end(ts) == Some(end)));
+        assert_eq!(composition.tracks[0].rests.len(), 1);
+        assert_eq!(composition.tracks[0].rests[0].start, MusicTime::beats(1));
+        assert!(composition.tracks[1].rests.is_empty());
+    }
+
+    #[test]
+    fn test_gaps() {
+        let music = CompositionBuilder::track(Instrument::Piano)
+            .note(Pitch(4, 0), MusicTime::beats(1), Beat::whole(1), Volume(50))
+            .rest(MusicTime::beats(2), Beat::whole(1))
+            .note(Pitch(4, 3), MusicTime::measures(1), Beat::whole(3), Volume(50))
+            .note(Pitch(4, 7), MusicTime(1, Beat::whole(1)), Beat::new(1, 2), Volume(50))
+            .note(Pitch(4, 0), MusicTime(2, Beat::whole(1)), Beat::whole(1), Volume(50))
+            .build()
+            .unwrap();
+        let gaps = music.tracks[0].gaps(music.time_signature);
+        assert_eq!(gaps, vec![
+            (MusicTime::zero(), Beat::whole(1)),
+            (MusicTime::beats(3), Beat::whole(1)),
+            (MusicTime(1, Beat::whole(3)), Beat::whole(2)),
+        ]);
+    }
+
+    #[test]
+    fn test_append_and_overlay() {
+        let mut first = CompositionBuilder::track(Instrument::Piano)
+            .note(Pitch(4, 0), MusicTime::zero(), Beat::whole(4), Volume(50))
+            .build()
+            .unwrap();
+        // three eighths in 6/8 are a dotted quarter, so one and a half beats in 4/4
+        let second = CompositionBuilder::track(Instrument::Piano)
+            .time_signature(TimeSignature(6, 8))
+            .note(Pitch(4, 2), MusicTime::beats(3), Beat::whole(3), Volume(50))
+            .then_track(Instrument::Bass)
+            .note(Pitch(2, 0), MusicTime::zero(), Beat::whole(6), Volume(50))
+            .build()
+            .unwrap();
+        first.append(&second, MusicTime::beats(1));
+        assert_eq!(first.time_signature, TimeSignature::common());
+        assert_eq!(first.tracks.len(), 2);
+        let piano = &first.tracks[0];
+        assert_eq!(piano.events[1].start, MusicTime(1, Beat::new(5, 2)));
+        assert_eq!(piano.events[1].duration, Beat::new(3, 2));
+        assert_eq!(first.tracks[1].events[0].start, MusicTime(1, Beat::whole(1)));
+        assert_eq!(first.tracks[1].events[0].duration, Beat::whole(3));
+
+        // a custom track with the same identifier but another instrument is kept apart
+        let mut base = comp_template(vec![]);
+        let mut other = comp_template(vec![]);
+        other.tracks[0].instrument = Instrument::Organ;
+        base.overlay(&other, MusicTime::beats(2));
+        assert_eq!(base.tracks.iter().map(|t| t.identifier).collect::<Vec<_>>(),
+                   vec![TrackId::Custom(0), TrackId::Custom(1)]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_measures() {
+        let ts = TimeSignature(3, 4);
+        let track = &CompositionBuilder::track(Instrument::SineWave)
+            .time_signature(ts)
+            .note(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(50))
+            .note(Pitch(4, 2), MusicTime::beats(2), Beat::whole(2), Volume(50))
+            .note(Pitch(4, 4), MusicTime(2, Beat::zero()), Beat::whole(3), Volume(50))
+            .build()
+            .unwrap()
+            .tracks[0];
+        let measures = track.measures(ts).collect::<Vec<_>>();
+        assert_eq!(measures.iter().map(|m| m.measure).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(measures[0].events.len(), 2);
+        assert!(measures[0].held.is_empty());
+        assert!(measures[1].events.is_empty());
+        assert_eq!(measures[1].held.iter().map(|e| e.pitch).collect::<Vec<_>>(), vec![Pitch(4, 2)]);
+        assert_eq!(measures[2].events[0].pitch, Pitch(4, 4));
+    }
+}
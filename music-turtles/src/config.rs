@@ -0,0 +1,270 @@
+// Settings read from `vibelive.toml` at startup, for what used to be fixed in the binary.
+// Everything can be left out of the file, and then it is what it was before there was a file.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::composition::{Instrument, MidiChannel};
+use crate::constants::ProgramConfig;
+use crate::time::{Measure, Seconds, BPM};
+#[cfg(feature = "native")]
+use crate::composition::Composition;
+#[cfg(feature = "native")]
+use crate::player::{MidiPlayer, MidiPort, Player};
+#[cfg(feature = "native")]
+use crate::scheduler::Scheduler;
+#[cfg(feature = "native")]
+use crate::time::{MusicTime, TimeSignature};
+
+pub const CONFIG_FILE: &str = "vibelive.toml";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The grammar file to play.
+    pub grammar: PathBuf,
+    /// The string the grammar is rewritten from.
+    pub axiom: String,
+    /// How many times the axiom is rewritten.
+    pub iterations: usize,
+    pub playback: PlaybackConfig,
+    pub midi: MidiConfig,
+    pub synths: SynthConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    pub bpm: BPM,
+    /// How far ahead of the music events are scheduled, in measures.
+    pub lookahead: Measure,
+    pub looped: bool,
+    pub output_latency: Seconds,
+    /// Part of the name of the audio output to play on, instead of the default one.
+    pub audio_device: Option<String>,
+    /// Run the playback threads at real-time priority, for steadier timing under load.
+    pub realtime: bool,
+    /// Port to serve `/metrics` and `/levels` on while playing, to see how playback is keeping up from elsewhere.
+    pub metrics_port: Option<u16>,
+    /// Port to serve `/rooms` on, for clients elsewhere to play the music together.
+    pub room_port: Option<u16>,
+    /// Samples in each spectrum sent out with the progress while playing on the local synths,
+    /// for spectrum displays. Leaving it out saves the work.
+    pub spectrum_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MidiConfig {
+    /// The name the MIDI output ports get.
+    pub name: String,
+    /// Port and channel for instruments that aren't in `channels`, except the drums, which
+    /// each get a port of their own unless they are in `channels`.
+    pub default_channel: (u8, MidiChannel),
+    pub channels: HashMap<Instrument, (u8, MidiChannel)>,
+    pub programs: ProgramConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SynthConfig {
+    /// A `WavetableConfig` file assigning wavetables to instruments.
+    pub wavetables: Option<PathBuf>,
+    pub unison: HashMap<Instrument, UnisonConfig>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnisonConfig {
+    pub voices: usize,
+    pub detune_cents: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Read(String),
+    Parse(String),
+    Device(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(s) => write!(f, "Couldn't read config: {s}"),
+            ConfigError::Parse(s) => write!(f, "Invalid config: {s}"),
+            ConfigError::Device(s) => write!(f, "Couldn't open device from config: {s}"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            grammar: PathBuf::from("data/funky_bach.mtx"),
+            axiom: "S".to_string(),
+            iterations: 20,
+            playback: PlaybackConfig::default(),
+            midi: MidiConfig::default(),
+            synths: SynthConfig::default(),
+        }
+    }
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            bpm: 120.,
+            lookahead: 1,
+            looped: false,
+            output_latency: 0.,
+            audio_device: None,
+            realtime: false,
+            metrics_port: None,
+            room_port: None,
+            spectrum_size: None,
+        }
+    }
+}
+
+impl Default for MidiConfig {
+    fn default() -> Self {
+        MidiConfig {
+            name: "music-turtles".to_string(),
+            default_channel: (1, 1),
+            channels: HashMap::new(),
+            programs: ProgramConfig::default(),
+        }
+    }
+}
+
+const DRUM_PORTS: [(Instrument, (u8, MidiChannel)); 5] = [
+    (Instrument::BassDrum, (2, 1)),
+    (Instrument::HiHatOpen, (3, 1)),
+    (Instrument::HiHatClosed, (4, 1)),
+    (Instrument::Snare, (5, 1)),
+    (Instrument::Snare2, (6, 1)),
+];
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    /// Read the config at `path`. Relative paths in it are resolved against its directory.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(format!("{}: {e}", path.display())))?;
+        let mut config = Self::from_toml(&text).map_err(|e| match e {
+            ConfigError::Parse(s) => ConfigError::Parse(format!("{}: {s}", path.display())),
+            e => e,
+        })?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        config.grammar = base.join(&config.grammar);
+        config.synths.wavetables = config.synths.wavetables.map(|w| base.join(w));
+        Ok(config)
+    }
+
+    /// `vibelive.toml` in the working directory, or the defaults if there isn't one.
+    pub fn load_default() -> Result<Self, ConfigError> {
+        if Path::new(CONFIG_FILE).exists() {
+            Self::load(CONFIG_FILE)
+        } else {
+            Ok(Config::default())
+        }
+    }
+
+    /// Port and channel for every instrument.
+    pub fn midi_channels(&self) -> HashMap<Instrument, (u8, MidiChannel)> {
+        let mut channels: HashMap<_, _> = Instrument::values().map(|i| (i, self.midi.default_channel)).collect();
+        channels.extend(DRUM_PORTS);
+        channels.extend(&self.midi.channels);
+        channels
+    }
+}
+
+#[cfg(feature = "native")]
+impl Config {
+    /// A scheduler with the playback settings and nothing to play yet.
+    pub fn scheduler(&self, time_signature: TimeSignature) -> Scheduler {
+        let nothing = Composition { tracks: vec![], time_signature, markers: vec![] };
+        Scheduler {
+            lookahead: MusicTime::measures(self.playback.lookahead),
+            looped: self.playback.looped,
+            output_latency: self.playback.output_latency,
+            ..Scheduler::new(nothing, self.playback.bpm)
+        }
+    }
+
+    pub fn midi_player(&self) -> Result<MidiPlayer, ConfigError> {
+        let channels: HashMap<Instrument, (MidiPort, MidiChannel)> = self.midi_channels();
+        let mut player = MidiPlayer::new(self.midi.name.clone(), channels).map_err(|e| ConfigError::Device(e.to_string()))?;
+        player.set_output_latency(self.playback.output_latency);
+        let programs = self.midi.programs.mapping();
+        for unmatched in &programs.unmatched {
+            warn!("{unmatched}");
+        }
+        player.set_programs(programs.programs);
+        Ok(player)
+    }
+
+    /// A player on the configured audio device, with the configured synths.
+    pub fn player(&self) -> Result<Player, ConfigError> {
+        let mut player = match &self.playback.audio_device {
+            Some(name) => Player::on_device(name).map_err(|e| ConfigError::Device(e.to_string()))?,
+            None => Player::new().map_err(|e| ConfigError::Device(e.to_string()))?,
+        };
+        player.set_output_latency(self.playback.output_latency);
+        if let Some(size) = self.playback.spectrum_size {
+            player.spectrum().enable(size);
+        }
+        let synths = player.synths_mut();
+        if let Some(wavetables) = &self.synths.wavetables {
+            synths.load_wavetables(wavetables).map_err(|e| ConfigError::Read(e.to_string()))?;
+        }
+        for (&instrument, unison) in &self.synths.unison {
+            synths.set_unison(instrument, unison.voices, unison.detune_cents);
+        }
+        Ok(player)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::Instrument;
+    use crate::config::{Config, ConfigError, UnisonConfig};
+
+    #[test]
+    fn test_config() {
+        let config = Config::from_toml(r#"
+            axiom = "Song"
+
+            [playback]
+            bpm = 96.5
+            looped = true
+
+            [midi.channels]
+            Bass = [1, 2]
+
+            [midi.programs.aliases]
+            Organ = "rock organ"
+
+            [synths.unison.Organ]
+            voices = 3
+            detune_cents = 8.0
+        "#).unwrap();
+        assert_eq!(config.axiom, "Song");
+        assert_eq!(config.iterations, Config::default().iterations);
+        assert_eq!((config.playback.bpm, config.playback.looped, config.playback.lookahead), (96.5, true, 1));
+        assert_eq!(config.synths.unison[&Instrument::Organ], UnisonConfig { voices: 3, detune_cents: 8. });
+        let channels = config.midi_channels();
+        assert_eq!(channels[&Instrument::Bass], (1, 2));
+        // setting some channels leaves the rest at the default
+        assert_eq!(channels[&Instrument::Piano], (1, 1));
+        assert_eq!(channels[&Instrument::Snare], (5, 1));
+        assert_eq!(config.midi.programs.mapping().programs[&Instrument::Organ], 18);
+
+        assert!(matches!(Config::from_toml("bpm = 100"), Err(ConfigError::Parse(_))));
+        assert!(matches!(Config::from_toml("[midi.channels]\nKazoo = [1, 1]"), Err(ConfigError::Parse(_))));
+    }
+}
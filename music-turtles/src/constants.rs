@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::fmt::Display;
+use serde::{Deserialize, Serialize};
 use strsim::normalized_levenshtein;
 use crate::composition::Instrument;
 
@@ -135,18 +137,135 @@ const MIDI_INSTRUMENTS: [(&str, u8); 128] = [
     ("gunshot", 128),
 ];
 
+/// General MIDI instruments for the instruments whose names don't fuzzy match the right one,
+/// e.g. `Piano` on its own is closest to "piccolo".
+const DEFAULT_ALIASES: [(&str, &str); 10] = [
+    ("SineWave", "lead 1 (square)"),
+    ("Piano", "acoustic grand piano"),
+    ("Bass", "electric bass (finger)"),
+    ("Organ", "drawbar organ"),
+    ("Strings", "string ensemble 1"),
+    ("Pad", "pad 2 (warm)"),
+    ("Bell", "tubular bells"),
+    ("ElectricPiano", "electric piano 1"),
+    ("Guitar", "acoustic guitar (nylon)"),
+    ("Harp", "orchestral harp"),
+];
+
+/// How instrument names are matched to General MIDI programs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgramConfig {
+    /// Instrument name to General MIDI instrument name, used before fuzzy matching.
+    /// These are added to the built in ones, and replace them for the same instrument.
+    pub aliases: HashMap<String, String>,
+    /// Fuzzy matches less similar than this, from 0 to 1, are reported as unmatched.
+    pub min_similarity: f64,
+}
+
+/// The General MIDI instrument a name was matched to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramMatch {
+    pub name: String,
+    /// 0-indexed, the way it is sent in a program change.
+    pub program: u8,
+    pub matched: &'static str,
+    /// 1 for an exact match or an alias.
+    pub similarity: f64,
+}
+
+/// Programs for every melodic instrument, and the names that only matched poorly.
+/// Percussion is left out, since it is played by note number rather than by program.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProgramMapping {
+    pub programs: HashMap<Instrument, u8>,
+    pub unmatched: Vec<ProgramMatch>,
+}
+
+impl Display for ProgramMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is played as \"{}\", which is only {:.0}% similar, add an alias for it",
+               self.name, self.matched, self.similarity * 100.)
+    }
+}
+
+impl Default for ProgramConfig {
+    fn default() -> Self {
+        ProgramConfig { aliases: HashMap::new(), min_similarity: 0.7 }
+    }
+}
+
+impl ProgramConfig {
+    /// The program for `name`, from the aliases if it has one and by fuzzy matching otherwise.
+    pub fn program(&self, name: &str) -> ProgramMatch {
+        let aliased = self.aliases.iter()
+            .map(|(n, gm)| (n.as_str(), gm.as_str()))
+            .chain(DEFAULT_ALIASES)
+            .find(|(n, _)| n.eq_ignore_ascii_case(name));
+        match aliased {
+            Some((_, gm_name)) => ProgramMatch { name: name.to_string(), ..closest(gm_name) },
+            None => ProgramMatch { name: name.to_string(), ..closest(name) },
+        }
+    }
+
+    pub fn mapping(&self) -> ProgramMapping {
+        let mut mapping = ProgramMapping::default();
+        for (instrument, name) in Instrument::str_values().filter(|(i, _)| !i.is_percussion()) {
+            let program = self.program(&name);
+            mapping.programs.insert(instrument, program.program);
+            if program.similarity < self.min_similarity {
+                mapping.unmatched.push(program);
+            }
+        }
+        mapping.unmatched.sort_by(|a, b| a.name.cmp(&b.name));
+        mapping
+    }
+}
+
+/// The General MIDI instrument most like `name`, ignoring case.
+fn closest(name: &str) -> ProgramMatch {
+    let name = name.to_lowercase();
+    let (matched, program, similarity) = MIDI_INSTRUMENTS.iter()
+        .map(|&(i_name, i)| (i_name, i, normalized_levenshtein(i_name, &name)))
+        .max_by(|a, b| a.2.total_cmp(&b.2))
+        .unwrap();
+    ProgramMatch { name, program: program - 1, matched, similarity }
+}
+
 pub fn get_instrument_by_fuzzy_name(name: &str) -> u8 {
-    let mut similarities = MIDI_INSTRUMENTS.iter()
-        .map(|(i_name, i)| (i_name, i, normalized_levenshtein(i_name, name)))
-        .collect::<Vec<_>>();
-    similarities
-        .sort_by_key(|(i_name, i, sim)| 1000 - (*sim * 1000.) as u16);
-    println!("Mapped {name} to {}", similarities[0].0);
-    similarities[0].1 - 1
+    ProgramConfig::default().program(name).program
 }
 
 pub fn get_fuzzy_mapping() -> HashMap<Instrument, u8> {
-    Instrument::str_values()
-        .map(|(i, i_str)| (i, get_instrument_by_fuzzy_name(&i_str)))
-        .collect()
-}
\ No newline at end of file
+    ProgramConfig::default().mapping().programs
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::composition::Instrument;
+    use crate::constants::ProgramConfig;
+
+    #[test]
+    fn test_program_mapping() {
+        let defaults = ProgramConfig::default();
+        assert_eq!(defaults.program("piano").matched, "acoustic grand piano");
+        assert_eq!(defaults.program("Trumpet").program, 56);
+        let mapping = defaults.mapping();
+        assert!(mapping.unmatched.is_empty(), "{:?}", mapping.unmatched);
+        assert!(!mapping.programs.contains_key(&Instrument::Snare));
+
+        let config = ProgramConfig {
+            aliases: HashMap::from([
+                ("Bass".to_string(), "synth bass 1".to_string()),
+                ("Pad".to_string(), "pad 2 warm".to_string()),
+            ]),
+            min_similarity: 0.9,
+        };
+        assert_eq!(config.program("Bass").program, 38);
+        // an alias that isn't quite a General MIDI name is matched like any other name
+        let unmatched = config.mapping().unmatched;
+        assert_eq!(unmatched.iter().map(|m| (m.name.as_str(), m.matched)).collect::<Vec<_>>(), vec![("Pad", "pad 2 (warm)")]);
+        assert!(unmatched[0].to_string().starts_with("Pad is played as \"pad 2 (warm)\", which is only"));
+    }
+}
@@ -0,0 +1,323 @@
+// A compact binary form of `Composition`, for sending large generated pieces to clients without
+// megabytes of JSON. Integers are LEB128 varints and strings are length-prefixed UTF-8.
+// Anything that changes the layout has to bump `VERSION`.
+// Tags and lyrics are only decoded when this program already knows them. Names are interned for
+// good, so taking new ones from whoever sent the bytes would let them grow that table forever.
+
+use std::fmt::Display;
+use crate::composition::{Accidental, Composition, Event, Instrument, Lfo, Marker, Modulation, Pitch, Spelling, Syllable, Tag, Track, TrackId, Volume};
+use crate::time::{Beat, MusicTime, TimeSignature};
+
+const MAGIC: &[u8; 3] = b"VLC";
+pub const VERSION: u8 = 1;
+
+const HAS_SPELLING: u8 = 1;
+const HAS_CHANNEL: u8 = 1 << 1;
+const HAS_TAG: u8 = 1 << 2;
+const HAS_LYRIC: u8 = 1 << 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Doesn't start with the magic bytes, so it isn't an encoded composition at all.
+    NotAComposition,
+    UnsupportedVersion(u8),
+    /// Ended in the middle of something.
+    Truncated,
+    Invalid(String),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::NotAComposition => write!(f, "Not an encoded composition"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "Composition encoding version {v} is not supported, expected {VERSION}"),
+            DecodeError::Truncated => write!(f, "Encoded composition ends early"),
+            DecodeError::Invalid(s) => write!(f, "Invalid encoded composition: {s}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode_composition(composition: &Composition) -> Vec<u8> {
+    let mut out = Writer(Vec::new());
+    out.0.extend(MAGIC);
+    out.0.push(VERSION);
+    let TimeSignature(beats, unit) = composition.time_signature;
+    out.uint(beats as u64);
+    out.uint(unit as u64);
+    out.uint(composition.markers.len() as u64);
+    for marker in &composition.markers {
+        out.str(&marker.name);
+        out.time(marker.time);
+    }
+    out.uint(composition.tracks.len() as u64);
+    for track in &composition.tracks {
+        match track.identifier {
+            TrackId::Instrument(instrument) => {
+                out.0.push(0);
+                out.instrument(instrument);
+            }
+            TrackId::Custom(id) => {
+                out.0.push(1);
+                out.uint(id as u64);
+            }
+        }
+        out.instrument(track.instrument);
+        for events in [track.events(), &track.rests] {
+            out.uint(events.len() as u64);
+            for event in events {
+                out.event(event);
+            }
+        }
+    }
+    out.0
+}
+
+pub fn decode_composition(bytes: &[u8]) -> Result<Composition, DecodeError> {
+    let rest = bytes.strip_prefix(MAGIC).ok_or(DecodeError::NotAComposition)?;
+    let (&version, rest) = rest.split_first().ok_or(DecodeError::Truncated)?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let mut input = Reader(rest);
+    let time_signature = TimeSignature(input.u32()?, input.u32()?);
+    if time_signature.1 == 0 {
+        return Err(DecodeError::Invalid("time signature with a zero unit".to_string()));
+    }
+    let markers = (0..input.len()?)
+        .map(|_| Ok(Marker { name: input.str()?.to_string(), time: input.time()? }))
+        .collect::<Result<_, DecodeError>>()?;
+    let tracks = (0..input.len()?)
+        .map(|_| {
+            let identifier = match input.byte()? {
+                0 => TrackId::Instrument(input.instrument()?),
+                1 => TrackId::Custom(input.uint()? as usize),
+                other => return Err(DecodeError::Invalid(format!("track identifier kind {other}"))),
+            };
+            let instrument = input.instrument()?;
+            let events = input.events()?;
+            let rests = input.events()?;
+            Ok(Track::new(identifier, instrument, events, rests))
+        })
+        .collect::<Result<_, DecodeError>>()?;
+    if !input.0.is_empty() {
+        return Err(DecodeError::Invalid(format!("{} bytes left over", input.0.len())));
+    }
+    Ok(Composition { tracks, time_signature, markers })
+}
+
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn uint(&mut self, mut n: u64) {
+        loop {
+            let low = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.0.push(low);
+                return;
+            }
+            self.0.push(low | 0x80);
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.uint(s.len() as u64);
+        self.0.extend(s.as_bytes());
+    }
+
+    fn beat(&mut self, beat: Beat) {
+        self.uint(beat.numerator() as u64);
+        self.uint(beat.denominator() as u64);
+    }
+
+    fn time(&mut self, MusicTime(measure, beat): MusicTime) {
+        self.uint(measure as u64);
+        self.beat(beat);
+    }
+
+    fn instrument(&mut self, instrument: Instrument) {
+        // by name, so adding instruments doesn't change what the old ones decode to
+        self.str(&format!("{instrument:?}"));
+    }
+
+    fn lfo(&mut self, lfo: Lfo) {
+        self.uint(lfo.millihertz as u64);
+        self.uint(lfo.depth as u64);
+    }
+
+    fn event(&mut self, event: &Event) {
+        self.time(event.start);
+        self.beat(event.duration);
+        self.uint(event.volume.0 as u64);
+        self.0.push(event.pitch.0 as u8);
+        self.0.push(event.pitch.1);
+        self.lfo(event.modulation.vibrato);
+        self.lfo(event.modulation.tremolo);
+        let flags = [
+            (event.spelling.is_some(), HAS_SPELLING),
+            (event.channel.is_some(), HAS_CHANNEL),
+            (event.tag.is_some(), HAS_TAG),
+            (event.lyric.is_some(), HAS_LYRIC),
+        ].iter().filter(|(has, _)| *has).fold(0, |flags, (_, flag)| flags | flag);
+        self.0.push(flags);
+        if let Some(spelling) = event.spelling {
+            self.0.push(spelling.letter as u8);
+            self.0.push(match spelling.accidental {
+                None => 0,
+                Some(Accidental::DoubleFlat) => 1,
+                Some(Accidental::Flat) => 2,
+                Some(Accidental::Natural) => 3,
+                Some(Accidental::Sharp) => 4,
+                Some(Accidental::DoubleSharp) => 5,
+            });
+        }
+        if let Some(channel) = event.channel {
+            self.0.push(channel);
+        }
+        if let Some(tag) = event.tag {
+            self.str(tag.as_str());
+        }
+        if let Some(lyric) = event.lyric {
+            self.str(lyric.as_str());
+        }
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (&byte, rest) = self.0.split_first().ok_or(DecodeError::Truncated)?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    pub(crate) fn uint(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(DecodeError::Invalid("integer longer than 64 bits".to_string()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, DecodeError> {
+        let n = self.uint()?;
+        n.try_into().map_err(|_| DecodeError::Invalid(format!("{n} is too big")))
+    }
+
+    /// A count of things that follow, which can't be more than there are bytes left.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let n = self.uint()?;
+        if n > self.0.len() as u64 {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(n as usize)
+    }
+
+    fn str(&mut self) -> Result<&'a str, DecodeError> {
+        let len = self.len()?;
+        let (s, rest) = self.0.split_at(len);
+        self.0 = rest;
+        std::str::from_utf8(s).map_err(|e| DecodeError::Invalid(e.to_string()))
+    }
+
+    /// A name that `find` already has, without interning anything new.
+    fn known<T>(&mut self, what: &str, find: impl FnOnce(&str) -> Option<T>) -> Result<T, DecodeError> {
+        let name = self.str()?;
+        find(name).ok_or_else(|| DecodeError::Invalid(format!("{what} {name:?} isn't one this program knows")))
+    }
+
+    fn beat(&mut self) -> Result<Beat, DecodeError> {
+        let (num, denom) = (self.u32()?, self.u32()?);
+        if denom == 0 {
+            return Err(DecodeError::Invalid("beat with a zero denominator".to_string()));
+        }
+        Ok(Beat::new(num, denom))
+    }
+
+    fn time(&mut self) -> Result<MusicTime, DecodeError> {
+        Ok(MusicTime(self.u32()?, self.beat()?))
+    }
+
+    fn instrument(&mut self) -> Result<Instrument, DecodeError> {
+        self.str()?.parse().map_err(DecodeError::Invalid)
+    }
+
+    fn lfo(&mut self) -> Result<Lfo, DecodeError> {
+        Ok(Lfo { millihertz: self.u32()?, depth: self.u32()? })
+    }
+
+    fn events(&mut self) -> Result<Vec<Event>, DecodeError> {
+        (0..self.len()?).map(|_| self.event()).collect()
+    }
+
+    fn event(&mut self) -> Result<Event, DecodeError> {
+        let start = self.time()?;
+        let duration = self.beat()?;
+        let volume = Volume(self.u32()?);
+        let pitch = Pitch(self.byte()? as i8, self.byte()?);
+        let modulation = Modulation { vibrato: self.lfo()?, tremolo: self.lfo()? };
+        let flags = self.byte()?;
+        let spelling = if flags & HAS_SPELLING != 0 {
+            let letter = self.byte()? as char;
+            let accidental = match self.byte()? {
+                0 => None,
+                1 => Some(Accidental::DoubleFlat),
+                2 => Some(Accidental::Flat),
+                3 => Some(Accidental::Natural),
+                4 => Some(Accidental::Sharp),
+                5 => Some(Accidental::DoubleSharp),
+                other => return Err(DecodeError::Invalid(format!("accidental {other}"))),
+            };
+            Some(Spelling { letter, accidental })
+        } else {
+            None
+        };
+        let channel = if flags & HAS_CHANNEL != 0 { Some(self.byte()?) } else { None };
+        let tag = if flags & HAS_TAG != 0 { Some(self.known("tag", Tag::existing)?) } else { None };
+        let lyric = if flags & HAS_LYRIC != 0 { Some(self.known("lyric", Syllable::existing)?) } else { None };
+        Ok(Event { start, duration, volume, pitch, modulation, spelling, channel, tag, lyric })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::composition::Tag;
+    use crate::encoding::{decode_composition, encode_composition, DecodeError, VERSION};
+    use crate::time::TimeSignature;
+
+    #[test]
+    fn test_encoding_round_trip() {
+        let music = MusicString::from_str("::mark=A ::tag=lead :4c#\"la\" ::ch=3 :d<1/2> ::vib=5/0.5 {:e :e | ::i=bass :_ :4g}")
+            .unwrap()
+            .compose(TimeSignature::common(), None)
+            .unwrap();
+        let bytes = encode_composition(&music);
+        assert_eq!(bytes[3], VERSION);
+        assert_eq!(decode_composition(&bytes).unwrap(), music);
+
+        let mut newer = bytes.clone();
+        newer[3] = VERSION + 1;
+        assert_eq!(decode_composition(&newer), Err(DecodeError::UnsupportedVersion(VERSION + 1)));
+        assert_eq!(decode_composition(b"{\"tracks\": []}"), Err(DecodeError::NotAComposition));
+        // cut anywhere, it fails instead of panicking or making something up
+        for end in 4..bytes.len() {
+            assert!(decode_composition(&bytes[..end]).is_err());
+        }
+
+        // a tag nothing here has used is refused rather than kept for good
+        let at = bytes.windows(4).position(|w| w == b"lead").unwrap();
+        let mut unknown = bytes.clone();
+        unknown[at..at + 4].copy_from_slice(b"le@d");
+        assert!(matches!(decode_composition(&unknown), Err(DecodeError::Invalid(_))));
+        assert_eq!(Tag::existing("le@d"), None);
+    }
+}
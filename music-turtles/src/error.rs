@@ -0,0 +1,139 @@
+// One error for everything the crate can fail at, so callers like the backend can match on what
+// went wrong and report it without knowing which part of the crate it came from.
+
+use std::fmt::Display;
+use crate::cfg::ComposeError;
+use crate::cfg::scan::ScanError;
+use crate::encoding::DecodeError;
+
+#[derive(Debug)]
+pub enum Error {
+    Scan(ScanError),
+    Compose(ComposeError),
+    Decode(DecodeError),
+    /// A MIDI port couldn't be opened or sent to.
+    Midi(String),
+    /// A standard MIDI file couldn't be read.
+    MidiFile(String),
+    /// An audio device couldn't be opened or played on.
+    Audio(String),
+    /// LilyPond couldn't be run or didn't make a score, with what it said about it.
+    Engrave(String),
+    Io(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Scan(e) => write!(f, "Couldn't parse: {e}"),
+            Error::Compose(e) => write!(f, "Couldn't compose: {e}"),
+            Error::Decode(e) => write!(f, "{e}"),
+            Error::Midi(s) => write!(f, "MIDI: {s}"),
+            Error::MidiFile(s) => write!(f, "MIDI file: {s}"),
+            Error::Audio(s) => write!(f, "Audio: {s}"),
+            Error::Engrave(s) => write!(f, "Engraving: {s}"),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Scan(e) => Some(e),
+            Error::Compose(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Midi(_) | Error::MidiFile(_) | Error::Audio(_) | Error::Engrave(_) => None,
+        }
+    }
+}
+
+impl From<ScanError> for Error {
+    fn from(e: ScanError) -> Self {
+        Error::Scan(e)
+    }
+}
+
+impl From<ComposeError> for Error {
+    fn from(e: ComposeError) -> Self {
+        Error::Compose(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "native")]
+mod native {
+    use rodio::{cpal, PlayError, StreamError};
+    use crate::error::Error;
+
+    impl From<midir::InitError> for Error {
+        fn from(e: midir::InitError) -> Self {
+            Error::Midi(e.to_string())
+        }
+    }
+
+    impl<T> From<midir::ConnectError<T>> for Error {
+        fn from(e: midir::ConnectError<T>) -> Self {
+            Error::Midi(e.to_string())
+        }
+    }
+
+    impl From<midir::SendError> for Error {
+        fn from(e: midir::SendError) -> Self {
+            Error::Midi(e.to_string())
+        }
+    }
+
+    impl From<midir::PortInfoError> for Error {
+        fn from(e: midir::PortInfoError) -> Self {
+            Error::Midi(e.to_string())
+        }
+    }
+
+    impl From<StreamError> for Error {
+        fn from(e: StreamError) -> Self {
+            Error::Audio(e.to_string())
+        }
+    }
+
+    impl From<PlayError> for Error {
+        fn from(e: PlayError) -> Self {
+            Error::Audio(e.to_string())
+        }
+    }
+
+    impl From<cpal::DevicesError> for Error {
+        fn from(e: cpal::DevicesError) -> Self {
+            Error::Audio(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::error::Error;
+
+    #[test]
+    fn test_error() {
+        let parse = || -> crate::error::Result<MusicString> { Ok(MusicString::from_str("::i=Kazoo :c")?) };
+        let e = parse().unwrap_err();
+        assert!(matches!(e, Error::Scan(_)));
+        assert!(std::error::Error::source(&e).is_some());
+    }
+}
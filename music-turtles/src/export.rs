@@ -0,0 +1,417 @@
+// Compositions written out for other programs to show, for the web UI and for documenting
+// generated pieces. Nothing here reads anything back in. Sheet music is left to LilyPond, which
+// has to be installed for `engrave`.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+#[cfg(feature = "native")]
+use std::process::Command;
+#[cfg(feature = "native")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use num::rational::Ratio;
+use num::Zero;
+use crate::composition::{Composition, Event, Instrument, MAX_VOLUME};
+#[cfg(feature = "native")]
+use crate::error::Error;
+use crate::time::{Beat, BeatUnit, MusicTime, TimeSignature};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PianoRollOptions {
+    pub pixels_per_beat: f32,
+    pub pixels_per_semitone: f32,
+    /// Fill colors for the tracks in order, as CSS colors, starting over when there are more
+    /// tracks than colors.
+    pub colors: Vec<String>,
+    /// Make quieter notes more transparent.
+    pub volume_as_opacity: bool,
+}
+
+impl Default for PianoRollOptions {
+    fn default() -> Self {
+        PianoRollOptions {
+            pixels_per_beat: 24.,
+            pixels_per_semitone: 6.,
+            colors: ["#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7"]
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            volume_as_opacity: true,
+        }
+    }
+}
+
+/// The notes of `composition` as rectangles in an SVG image, higher notes further up, over a
+/// grid with a line on every beat and a darker one on every barline. Each note has a `<title>`
+/// with its instrument and pitch, so browsers show them on hover. Written rests are faint bands
+/// the height of the grid in their track's color, with the class `rest`.
+pub fn piano_roll_svg(composition: &Composition, options: &PianoRollOptions) -> String {
+    let time_signature = composition.time_signature;
+    let beats = |time: MusicTime| time.with(time_signature).total_beats().as_float();
+    let keys = composition.tracks.iter()
+        .flat_map(|t| t.events().iter().map(|e| e.pitch.to_midi_note()))
+        .collect::<Vec<_>>();
+    // a semitone of room above and below
+    let (lowest, highest) = match (keys.iter().min(), keys.iter().max()) {
+        (Some(&lowest), Some(&highest)) => (lowest.saturating_sub(1), highest.saturating_add(1)),
+        _ => (59, 61),
+    };
+    let end = composition.get_end().map_or(Beat::zero(), |end| end.with(time_signature).total_beats());
+    // whole measures, and at least one
+    let measures = end.numerator().div_ceil(end.denominator() * time_signature.0).max(1);
+    let total_beats = measures * time_signature.0;
+    let width = total_beats as f32 * options.pixels_per_beat;
+    let height = (highest - lowest + 1) as f32 * options.pixels_per_semitone;
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#);
+    let _ = writeln!(svg, r##"<rect width="{width}" height="{height}" fill="#ffffff"/>"##);
+    for beat in 0..=total_beats as BeatUnit {
+        let x = beat as f32 * options.pixels_per_beat;
+        let stroke = if beat % time_signature.0 == 0 { "#888888" } else { "#dddddd" };
+        let _ = writeln!(svg, r#"<line x1="{x}" y1="0" x2="{x}" y2="{height}" stroke="{stroke}" stroke-width="1"/>"#);
+    }
+    for (index, track) in composition.tracks.iter().enumerate() {
+        let color = options.colors.get(index % options.colors.len().max(1)).map_or("#000000", |c| c.as_str());
+        let _ = writeln!(svg, r#"<g fill="{}">"#, escape(color));
+        for rest in &track.rests {
+            let _ = writeln!(svg, r#"<rect class="rest" x="{}" y="0" width="{}" height="{height}" fill-opacity="0.1"><title>{:?} rest</title></rect>"#,
+                beats(rest.start) * options.pixels_per_beat, rest.duration.as_float() * options.pixels_per_beat, track.instrument);
+        }
+        for event in track.events() {
+            let key = event.pitch.to_midi_note();
+            let x = beats(event.start) * options.pixels_per_beat;
+            let y = (highest - key) as f32 * options.pixels_per_semitone;
+            let w = event.duration.as_float() * options.pixels_per_beat;
+            let opacity = if options.volume_as_opacity {
+                format!(r#" fill-opacity="{}""#, (event.volume.0.min(MAX_VOLUME) as f32 / MAX_VOLUME as f32).max(0.1))
+            } else {
+                String::new()
+            };
+            let _ = writeln!(svg, r#"<rect x="{x}" y="{y}" width="{w}" height="{}"{opacity}><title>{:?} {}{}</title></rect>"#,
+                options.pixels_per_semitone, track.instrument, event.pitch.letter_name(), event.pitch.0);
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// The LilyPond name of `event`'s pitch with its octave marks, spelled the way it was written
+/// if it was.
+fn lilypond_pitch(event: &Event) -> String {
+    const NAMES: [&str; 12] = ["c", "cis", "d", "ees", "e", "f", "fis", "g", "aes", "a", "bes", "b"];
+    let key = event.pitch.to_midi_note() as i32;
+    let spelled = event.spelling.and_then(|spelling| {
+        let natural = match spelling.letter.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        // how far the note is from the letter, which a key signature may have moved too
+        let offset = (key - natural + 6).rem_euclid(12) - 6;
+        let accidental = match offset {
+            -2 => "eses",
+            -1 => "es",
+            0 => "",
+            1 => "is",
+            2 => "isis",
+            _ => return None,
+        };
+        Some((format!("{}{accidental}", spelling.letter.to_ascii_lowercase()), key - offset))
+    });
+    let (name, natural) = spelled.unwrap_or_else(|| (NAMES[key.rem_euclid(12) as usize].to_string(), key - key.rem_euclid(12)));
+    // LilyPond's `c` is the C an octave under middle C
+    let octaves = natural.div_euclid(12) - 4;
+    let marks = if octaves >= 0 { "'".repeat(octaves as usize) } else { ",".repeat(octaves.unsigned_abs() as usize) };
+    format!("{name}{marks}")
+}
+
+/// LilyPond durations that add up to `whole_notes`, to be tied together. Lengths that can't be
+/// written with dots and ties get a single whole note scaled to fit.
+fn lilypond_durations(whole_notes: Ratio<u32>) -> Vec<String> {
+    if !whole_notes.denom().is_power_of_two() || *whole_notes.denom() > 128 {
+        return vec![format!("1*{}/{}", whole_notes.numer(), whole_notes.denom())];
+    }
+    let mut durations = vec![];
+    let mut left = whole_notes;
+    while !left.is_zero() {
+        let mut value = Ratio::from_integer(1);
+        while value > left {
+            value /= 2;
+        }
+        left -= value;
+        let written = value.recip().to_integer();
+        if left >= value / 2 && left < value && written < 128 {
+            left -= value / 2;
+            durations.push(format!("{written}."));
+        } else {
+            durations.push(format!("{written}"));
+        }
+    }
+    durations
+}
+
+/// Notes that start together and last as long, with when and for how long.
+type Chord<'a> = (MusicTime, Beat, Vec<&'a Event>);
+
+/// One voice of a staff: notes and chords that never overlap, with rests in the gaps and up to
+/// `end`, so rests written at the end of a track are kept.
+fn lilypond_voice(voice: &[Chord], end: Beat, time_signature: TimeSignature, drum: Option<&str>) -> String {
+    let whole_notes = |beats: Beat| Ratio::new(beats.numerator(), beats.denominator() * time_signature.1);
+    let mut music = vec![];
+    let mut now = Beat::zero();
+    for (start, duration, chord) in voice {
+        let start = start.with(time_signature).total_beats();
+        if start > now {
+            music.extend(lilypond_durations(whole_notes(start - now)).into_iter().map(|d| format!("r{d}")));
+        }
+        let note = match (drum, chord.as_slice()) {
+            (Some(drum), _) => drum.to_string(),
+            (None, [single]) => lilypond_pitch(single),
+            (None, chord) => format!("<{}>", chord.iter().map(|e| lilypond_pitch(e)).collect::<Vec<_>>().join(" ")),
+        };
+        let pieces = lilypond_durations(whole_notes(*duration)).into_iter()
+            .map(|d| format!("{note}{d}"))
+            .collect::<Vec<_>>();
+        music.push(pieces.join(" ~ "));
+        now = start + *duration;
+    }
+    if end > now {
+        music.extend(lilypond_durations(whole_notes(end - now)).into_iter().map(|d| format!("r{d}")));
+    }
+    music.join(" ")
+}
+
+/// The drum LilyPond's drum mode has for a percussion instrument.
+fn lilypond_drum(instrument: Instrument) -> Option<&'static str> {
+    match instrument {
+        Instrument::BassDrum => Some("bd"),
+        Instrument::Snare => Some("sn"),
+        Instrument::Snare2 => Some("sne"),
+        Instrument::HiHatClosed => Some("hh"),
+        Instrument::HiHatOpen => Some("hho"),
+        Instrument::BongoHigh => Some("boh"),
+        Instrument::BongoLow => Some("bol"),
+        Instrument::Shaker1 | Instrument::Shaker2 => Some("mar"),
+        _ => None,
+    }
+}
+
+/// `composition` as a LilyPond score, one staff for each track. Notes that start together and
+/// last as long are written as chords, and other notes that overlap go in voices of their own.
+/// LilyPond splits notes at barlines itself.
+pub fn lilypond(composition: &Composition) -> String {
+    let time_signature = composition.time_signature;
+    let mut staves = vec![];
+    for track in &composition.tracks {
+        let mut events = track.events().iter().collect::<Vec<_>>();
+        events.sort_by_key(|e| (e.start, e.duration, e.pitch.to_midi_note()));
+        let mut chords: Vec<Chord> = vec![];
+        for event in events {
+            match chords.last_mut() {
+                Some((start, duration, chord)) if *start == event.start && *duration == event.duration => chord.push(event),
+                _ => chords.push((event.start, event.duration, vec![event])),
+            }
+        }
+        // each voice gets the next chord that starts after its last one has ended
+        let mut voices: Vec<(MusicTime, Vec<Chord>)> = vec![];
+        for chord in chords {
+            let end = chord.0.with(time_signature) + chord.1.as_music_time(time_signature);
+            match voices.iter_mut().find(|(voice_end, _)| *voice_end <= chord.0) {
+                Some((voice_end, voice)) => {
+                    *voice_end = end;
+                    voice.push(chord);
+                }
+                None => voices.push((end, vec![chord])),
+            }
+        }
+        let drum = lilypond_drum(track.instrument);
+        // the first voice carries the track's trailing rests, the others stop at their last note
+        let end = track.get_end(time_signature).map_or(Beat::zero(), |end| end.with(time_signature).total_beats());
+        let mut voices = voices.iter()
+            .enumerate()
+            .map(|(i, (_, voice))| format!("{{ {} }}", lilypond_voice(voice, if i == 0 { end } else { Beat::zero() }, time_signature, drum)))
+            .collect::<Vec<_>>();
+        if voices.is_empty() && end > Beat::zero() {
+            voices.push(format!("{{ {} }}", lilypond_voice(&[], end, time_signature, drum)));
+        }
+        let music = match voices.len() {
+            0 => format!("{{ s{} }}", lilypond_durations(Ratio::new(time_signature.0, time_signature.1)).join(" s")),
+            1 => voices[0].clone(),
+            _ => format!("<< {} >>", voices.join(" \\\\ ")),
+        };
+        let time = format!("\\time {}/{}", time_signature.0, time_signature.1);
+        let staff = if drum.is_some() {
+            format!("  \\new DrumStaff \\with {{ instrumentName = \"{:?}\" }} \\drummode {{ {time} {music} }}", track.instrument)
+        } else {
+            let low = track.events().iter().map(|e| e.pitch.to_midi_note() as u32).sum::<u32>() < 60 * track.events().len() as u32;
+            let clef = if low { "bass" } else { "treble" };
+            format!("  \\new Staff \\with {{ instrumentName = \"{:?}\" }} {{ \\clef {clef} {time} {music} }}", track.instrument)
+        };
+        staves.push(staff);
+    }
+    format!(r#"\version "2.24.0"
+\header {{ tagline = ##f }}
+\score {{
+  <<
+{}
+  >>
+  \layout {{
+    \context {{
+      \Voice
+      \remove "Note_heads_engraver"
+      \consists "Completion_heads_engraver"
+      \remove "Rest_engraver"
+      \consists "Completion_rest_engraver"
+    }}
+  }}
+}}
+"#, staves.join("\n"))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ScoreFormat {
+    Pdf,
+    Png,
+}
+
+impl ScoreFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScoreFormat::Pdf => "pdf",
+            ScoreFormat::Png => "png",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngraveOptions {
+    /// The `lilypond` program to run, looked up on the path unless it is a path itself.
+    pub lilypond: PathBuf,
+    /// Dots per inch of PNG scores.
+    pub resolution: u32,
+}
+
+impl Default for EngraveOptions {
+    fn default() -> Self {
+        EngraveOptions { lilypond: PathBuf::from("lilypond"), resolution: 110 }
+    }
+}
+
+/// Sheet music for `composition`, engraved by running LilyPond on `lilypond` of it. Scores of
+/// more than a page come back as the first page when they are PNG.
+#[cfg(feature = "native")]
+pub fn engrave(composition: &Composition, format: ScoreFormat, options: &EngraveOptions) -> Result<Vec<u8>, Error> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("music-turtles-engrave-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    std::fs::create_dir_all(&dir)?;
+    let engraved = (|| {
+        std::fs::write(dir.join("score.ly"), lilypond(composition))?;
+        let output = Command::new(&options.lilypond)
+            .current_dir(&dir)
+            .arg(format!("--{}", format.extension()))
+            .arg(format!("-dresolution={}", options.resolution))
+            .args(["-o", "score", "score.ly"])
+            .output()
+            .map_err(|e| Error::Engrave(format!("Couldn't run {}: {e}", options.lilypond.display())))?;
+        if !output.status.success() {
+            return Err(Error::Engrave(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        let extension = format.extension();
+        [format!("score.{extension}"), format!("score-page1.{extension}")].iter()
+            .find_map(|name| std::fs::read(dir.join(name)).ok())
+            .ok_or_else(|| Error::Engrave(format!("LilyPond didn't write a {extension}")))
+    })();
+    let _ = std::fs::remove_dir_all(&dir);
+    engraved
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;")
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::builder::CompositionBuilder;
+    use crate::composition::{Accidental, Event, Instrument, Pitch, Spelling, Volume};
+    use crate::export::{lilypond, piano_roll_svg, PianoRollOptions};
+    use crate::testing::event;
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_piano_roll_svg() {
+        let music = MusicString::from_str("{:4c :4e<2> ::v=25 :4g | ::i=bass :2c<3>}pad")
+            .unwrap()
+            .compose(TimeSignature(3, 4), None)
+            .unwrap();
+        let options = PianoRollOptions { colors: vec!["red".to_string()], ..PianoRollOptions::default() };
+        let svg = piano_roll_svg(&music, &options);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        // two measures of three beats, from a semitone under the bass C to one over the G
+        let height = (67 - 36 + 3) as f32 * options.pixels_per_semitone;
+        assert!(svg.contains(&format!(r#"width="144" height="{height}""#)), "{svg}");
+        assert_eq!(svg.matches("<line").count(), 7);
+        assert_eq!(svg.matches(r##"stroke="#888888""##).count(), 3);
+        assert_eq!(svg.matches("<rect x=").count(), 4);
+        assert_eq!(svg.matches(r#"<g fill="red">"#).count(), 2);
+        // the E starts a beat in, four semitones under the top of the grid
+        let e = format!(r#"<rect x="24" y="{}" width="48" height="6" fill-opacity="0.5">"#, (67 + 1 - 64) as f32 * 6.);
+        assert!(svg.contains(&e), "{svg}");
+        assert!(svg.contains("fill-opacity=\"0.25\"><title>"), "{svg}");
+        // the bass is padded with a rest on the last beat
+        let rest = format!(r#"<rect class="rest" x="72" y="0" width="24" height="{height}" fill-opacity="0.1"><title>Bass rest</title></rect>"#);
+        assert!(svg.contains(&rest), "{svg}");
+    }
+
+    #[test]
+    fn test_lilypond() {
+        let note = |key, start: MusicTime, duration| event(Pitch::from_midi_note(key), start, duration);
+        let mut builder = CompositionBuilder::track(Instrument::Piano)
+            .event(note(60, MusicTime::zero(), Beat::whole(1)))
+            .event(note(64, MusicTime::beats(1), Beat::whole(2)))
+            // a triplet, the second note written as G flat
+            .event(note(66, MusicTime::beats(3), Beat::new(1, 3)))
+            .event(Event { spelling: Some(Spelling { letter: 'G', accidental: Some(Accidental::Flat) }), ..note(66, MusicTime(0, Beat::new(10, 3)), Beat::new(2, 3)) })
+            .event(note(71, MusicTime(2, Beat::new(2, 1)), Beat::new(1, 2)))
+            .event(note(74, MusicTime(2, Beat::new(5, 2)), Beat::new(3, 2)));
+        // a chord held over the barline
+        for key in [55, 60, 64] {
+            builder = builder.event(note(key, MusicTime(1, Beat::whole(1)), Beat::whole(5)));
+        }
+        let music = builder
+            .then_track(Instrument::Bass).note(Pitch::from_midi_note(36), MusicTime::zero(), Beat::whole(8), Volume(50))
+            .then_track(Instrument::BassDrum).note(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(50))
+            .note(Pitch(4, 0), MusicTime::beats(2), Beat::whole(1), Volume(50))
+            .build()
+            .unwrap();
+        let score = lilypond(&music);
+        assert!(score.contains("\\new Staff \\with { instrumentName = \"Piano\" } { \\clef treble \\time 4/4 { c'4 e'2 fis'1*1/12 ges'1*1/6 r4 <g c' e'>1 ~ <g c' e'>4 b'8 d''4. } }"), "{score}");
+        assert!(score.contains("{ \\clef bass \\time 4/4 { c,1 ~ c,1 } }"), "{score}");
+        assert!(score.contains("\\new DrumStaff \\with { instrumentName = \"BassDrum\" } \\drummode { \\time 4/4 { bd4 r4 bd4 } }"), "{score}");
+        // rests written at the end are kept, even on a track with nothing else
+        let rests = CompositionBuilder::track(Instrument::Piano)
+            .note(Pitch::from_midi_note(60), MusicTime::zero(), Beat::whole(1), Volume(50))
+            .rest(MusicTime::beats(1), Beat::whole(3))
+            .then_track(Instrument::Bass).rest(MusicTime::zero(), Beat::whole(4))
+            .build()
+            .unwrap();
+        let score = lilypond(&rests);
+        assert!(score.contains("\\time 4/4 { c'4 r2. } }"), "{score}");
+        assert!(score.contains("\\time 4/4 { r1 } }"), "{score}");
+        #[cfg(feature = "native")]
+        {
+            use crate::error::Error;
+            use crate::export::{engrave, EngraveOptions, ScoreFormat};
+            let options = EngraveOptions { lilypond: "/nonexistent/lilypond".into(), ..EngraveOptions::default() };
+            assert!(matches!(engrave(&music, ScoreFormat::Pdf, &options), Err(Error::Engrave(_))));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+use crate::composition::Event;
+use crate::time::{MusicTime, TimeSignature};
+
+/// Interval index over a slice of events sorted by start.
+/// The events are laid out as an implicit balanced tree (the middle of each range is its root),
+/// and every root remembers the latest end in its range, so whole ranges that have already
+/// ended can be skipped.
+#[derive(Debug, Clone)]
+pub struct IntervalIndex {
+    time_signature: TimeSignature,
+    max_end: Vec<MusicTime>,
+}
+
+impl IntervalIndex {
+    /// `events` must be sorted by start.
+    pub fn new(events: &[Event], time_signature: TimeSignature) -> Self {
+        let mut max_end = vec![MusicTime::zero(); events.len()];
+        fn build(events: &[Event], max_end: &mut [MusicTime], lo: usize, hi: usize, ts: TimeSignature) -> MusicTime {
+            let mid = (lo + hi) / 2;
+            let mut end = events[mid].get_end(ts);
+            if lo < mid {
+                end = end.max(build(events, max_end, lo, mid, ts));
+            }
+            if mid + 1 < hi {
+                end = end.max(build(events, max_end, mid + 1, hi, ts));
+            }
+            max_end[mid] = end;
+            end
+        }
+        if !events.is_empty() {
+            build(events, &mut max_end, 0, events.len(), time_signature);
+        }
+        IntervalIndex { time_signature, max_end }
+    }
+
+    /// Whether this index was built for this time signature.
+    pub fn built_for(&self, time_signature: TimeSignature) -> bool {
+        self.time_signature == time_signature
+    }
+
+    /// Events with `start <= time < end`, in start order.
+    pub fn sounding_at<'a>(&self, events: &'a [Event], time: MusicTime) -> Vec<&'a Event> {
+        self.overlapping(events, time, time)
+    }
+
+    /// Events that sound at any point in `[start, end]`, in start order.
+    pub fn overlapping<'a>(&self, events: &'a [Event], start: MusicTime, end: MusicTime) -> Vec<&'a Event> {
+        let mut found = vec![];
+        self.visit(events, 0, events.len(), start, end, &mut found);
+        found
+    }
+
+    fn visit<'a>(&self, events: &'a [Event], lo: usize, hi: usize, start: MusicTime, end: MusicTime, found: &mut Vec<&'a Event>) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        if self.max_end[mid] <= start {
+            // everything in this range has already ended
+            return;
+        }
+        self.visit(events, lo, mid, start, end, found);
+        let e = &events[mid];
+        if e.start > end {
+            // everything to the right starts even later
+            return;
+        }
+        if e.get_end(self.time_signature) > start {
+            found.push(e);
+        }
+        self.visit(events, mid + 1, hi, start, end, found);
+    }
+}
+
+/// Lazily built `IntervalIndex` for a track.
+/// It only caches what can be derived from the events, so it is ignored by equality and hashing.
+/// The owner has to `clear` it whenever the events change.
+#[derive(Debug, Clone, Default)]
+pub struct IntervalCache(OnceLock<IntervalIndex>);
+
+impl IntervalCache {
+    /// The index over `events`, or None if it was built for another time signature.
+    pub fn get_or_build(&self, events: &[Event], time_signature: TimeSignature) -> Option<&IntervalIndex> {
+        let index = self.0.get_or_init(|| IntervalIndex::new(events, time_signature));
+        if index.built_for(time_signature) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = OnceLock::new();
+    }
+}
+
+impl PartialEq for IntervalCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for IntervalCache {}
+
+impl Hash for IntervalCache {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::{Event, Pitch};
+    use crate::interval::IntervalIndex;
+    use crate::testing;
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    fn event(start: MusicTime, beats: u32) -> Event {
+        testing::event(Pitch(4, 0), start, Beat::whole(beats))
+    }
+
+    #[test]
+    fn test_sounding_at_matches_linear_scan() {
+        let ts = TimeSignature::common();
+        let mut events = (0..500)
+            .map(|i| event(MusicTime::from_whole_beats(ts, i), 1 + (i * 7) % 13))
+            .collect::<Vec<_>>();
+        events.sort();
+        let index = IntervalIndex::new(&events, ts);
+        for beat in 0..520 {
+            let time = MusicTime::from_whole_beats(ts, beat);
+            let expected = events.iter()
+                .filter(|e| e.start <= time && time < e.get_end(ts))
+                .collect::<Vec<_>>();
+            assert_eq!(index.sounding_at(&events, time), expected, "at beat {beat}");
+        }
+    }
+
+    #[test]
+    fn test_end_is_exclusive() {
+        let ts = TimeSignature::common();
+        let events = vec![event(MusicTime::zero(), 2), event(MusicTime::beats(2), 2)];
+        let index = IntervalIndex::new(&events, ts);
+        assert_eq!(index.sounding_at(&events, MusicTime::beats(2)), vec![&events[1]]);
+        assert_eq!(index.overlapping(&events, MusicTime::beats(1), MusicTime::beats(2)).len(), 2);
+        assert!(index.sounding_at(&events, MusicTime::measures(1)).is_empty());
+    }
+}
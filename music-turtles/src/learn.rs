@@ -0,0 +1,307 @@
+// Grammars learned from MIDI files, for "more music like this". Every track of every file is cut
+// into bars, and which bar follows which is counted, so the grammar is a Markov chain over the
+// bars it has seen: each bar gets a production of its own, and what comes after a few bars is a
+// weighted choice of the bars that came after them in the corpus. Everything has a name, so the
+// productions can be edited by hand afterwards.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use crate::cfg::{Grammar, MusicPrimitive, MusicString, NonTerminal, SplitMode, SplitPolicy, Symbol};
+use crate::composition::{Composition, Event, Instrument, Modulation, Pitch, Track, TrackId, Volume, MAX_VOLUME};
+use crate::error::Error;
+use crate::time::{Beat, TimeSignature};
+
+const DRUM_CHANNEL: u8 = 9;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LearnOptions {
+    /// How many bars back are remembered when choosing the next one. More sounds more like the
+    /// corpus, down to copying it outright.
+    pub order: usize,
+    /// Notes are moved to the nearest multiple of this many beats, so bars that were played
+    /// almost the same count as the same.
+    pub grid: Beat,
+}
+
+impl Default for LearnOptions {
+    fn default() -> Self {
+        LearnOptions { order: 1, grid: Beat::new(1, 4) }
+    }
+}
+
+/// The instrument closest to General MIDI `program`.
+fn program_instrument(program: u8) -> Instrument {
+    match program {
+        4..=5 => Instrument::ElectricPiano,
+        8..=15 => Instrument::Bell,
+        16..=23 => Instrument::Organ,
+        24..=31 => Instrument::Guitar,
+        32..=39 => Instrument::Bass,
+        46 => Instrument::Harp,
+        40..=55 => Instrument::Strings,
+        80..=87 => Instrument::SineWave,
+        88..=95 => Instrument::Pad,
+        _ => Instrument::Piano,
+    }
+}
+
+/// The drum General MIDI plays for `key` on the drum channel, if there is one like it.
+fn drum_instrument(key: u8) -> Option<Instrument> {
+    match key {
+        35 | 36 => Some(Instrument::BassDrum),
+        38 | 40 => Some(Instrument::Snare),
+        37 | 39 => Some(Instrument::Snare2),
+        42 | 44 => Some(Instrument::HiHatClosed),
+        46 => Some(Instrument::HiHatOpen),
+        60 => Some(Instrument::BongoHigh),
+        61 => Some(Instrument::BongoLow),
+        69 | 82 => Some(Instrument::Shaker1),
+        70 => Some(Instrument::Shaker2),
+        _ => None,
+    }
+}
+
+/// The notes of a standard MIDI file, with a track for each instrument played on each channel
+/// of each of its tracks, snapped to `grid`. Tempo changes are left out, since compositions are
+/// timed in beats, and only the first time signature counts.
+pub fn read_midi(bytes: &[u8], grid: Beat) -> Result<Composition, Error> {
+    let smf = Smf::parse(bytes).map_err(|e| Error::MidiFile(e.to_string()))?;
+    let ticks_per_quarter = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int() as u64,
+        Timing::Timecode(..) => return Err(Error::MidiFile("files timed in SMPTE frames aren't supported".to_string())),
+    };
+    let time_signature = smf.tracks.iter()
+        .flatten()
+        .find_map(|event| match event.kind {
+            TrackEventKind::Meta(MetaMessage::TimeSignature(beats, unit_power, ..)) if unit_power < 8 => {
+                Some(TimeSignature(beats as u32, 1 << unit_power))
+            }
+            _ => None,
+        })
+        .unwrap_or(TimeSignature::common());
+    // the nearest whole number of grid steps to `ticks`, in beats of the time signature
+    let snap = |ticks: u64| {
+        let per_step = ticks_per_quarter * 4 * grid.numerator() as u64;
+        let steps = (ticks * time_signature.1 as u64 * grid.denominator() as u64 * 2 + per_step) / (per_step * 2);
+        Beat::new(steps as u32 * grid.numerator(), grid.denominator())
+    };
+
+    let mut tracks: Vec<((usize, u8, Instrument), Track)> = vec![];
+    for (index, midi_track) in smf.tracks.iter().enumerate() {
+        let mut now = 0;
+        let mut programs = [0; 16];
+        let mut held: HashMap<(u8, u8), (u64, u8, Instrument)> = HashMap::new();
+        let mut notes = vec![];
+        for event in midi_track {
+            now += event.delta.as_int() as u64;
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+            let channel = channel.as_int();
+            match message {
+                MidiMessage::ProgramChange { program } => programs[channel as usize] = program.as_int(),
+                MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                    let key = key.as_int();
+                    // hitting a key that is still held ends the note before
+                    if let Some(note) = held.remove(&(channel, key)) {
+                        notes.push((channel, key, note, now));
+                    }
+                    let instrument = if channel == DRUM_CHANNEL {
+                        match drum_instrument(key) {
+                            Some(drum) => drum,
+                            None => continue,
+                        }
+                    } else {
+                        program_instrument(programs[channel as usize])
+                    };
+                    held.insert((channel, key), (now, vel.as_int(), instrument));
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    if let Some(note) = held.remove(&(channel, key.as_int())) {
+                        notes.push((channel, key.as_int(), note, now));
+                    }
+                }
+                _ => {}
+            }
+        }
+        notes.extend(held.into_iter().map(|((channel, key), note)| (channel, key, note, now)));
+
+        for (channel, key, (start, velocity, instrument), end) in notes {
+            let position = match tracks.iter().position(|(k, _)| *k == (index, channel, instrument)) {
+                Some(position) => position,
+                None => {
+                    // a second track for the same instrument needs an identifier of its own
+                    let identifier = if tracks.iter().any(|(_, t)| t.instrument == instrument) {
+                        TrackId::Custom(tracks.len())
+                    } else {
+                        TrackId::Instrument(instrument)
+                    };
+                    let track = Track::new(identifier, instrument, vec![], vec![]);
+                    tracks.push(((index, channel, instrument), track));
+                    tracks.len() - 1
+                }
+            };
+            let (start, end) = (snap(start), snap(end));
+            tracks[position].1.events_mut().push(Event {
+                start: start.as_music_time(time_signature),
+                duration: if end > start { end - start } else { grid },
+                volume: Volume(velocity as u32 * MAX_VOLUME / 127),
+                pitch: if instrument.is_percussion() { Pitch(4, 0) } else { Pitch::from_midi_note(key) },
+                modulation: Modulation::NONE,
+                spelling: None,
+                channel: None,
+                tag: None,
+                lyric: None,
+            });
+        }
+    }
+    let mut tracks = tracks.into_iter().map(|(_, track)| track).collect::<Vec<_>>();
+    tracks.iter_mut().for_each(Track::sort);
+    Ok(Composition { tracks, time_signature, markers: vec![] })
+}
+
+fn bar_name(bar: usize) -> NonTerminal {
+    NonTerminal::Custom(format!("Bar-{}", bar + 1).into())
+}
+
+/// Whatever comes after the bars in `context`, or the whole piece if there are none.
+fn context_name(context: &[usize]) -> NonTerminal {
+    if context.is_empty() {
+        return NonTerminal::Custom("S".into());
+    }
+    let bars = context.iter().map(|b| (b + 1).to_string()).collect::<Vec<_>>();
+    NonTerminal::Custom(format!("After-{}", bars.join("-")).into())
+}
+
+/// A grammar for music like `corpus`, starting from `S`. Each rewrite adds a bar, so composing
+/// `S` rewritten twice as many times as there are bars wanted gives that many, or fewer where
+/// the corpus has nothing to follow.
+pub fn learn_grammar(corpus: &[Composition], options: LearnOptions) -> Grammar {
+    let order = options.order.max(1);
+    let mut bar_ids: HashMap<String, usize> = HashMap::new();
+    let mut bar_strings = vec![];
+    // how often each bar came after each context
+    let mut followers: BTreeMap<Vec<usize>, BTreeMap<usize, u32>> = BTreeMap::new();
+    for composition in corpus {
+        for track in &composition.tracks {
+            let mut phrase = vec![];
+            for bar in MusicString::from_track_bars(track, composition.time_signature) {
+                let next_id = bar_strings.len();
+                let id = *bar_ids.entry(bar.to_string()).or_insert(next_id);
+                if id == next_id {
+                    bar_strings.push(bar);
+                }
+                let context = phrase[phrase.len().saturating_sub(order)..].to_vec();
+                *followers.entry(context).or_default().entry(id).or_default() += 1;
+                phrase.push(id);
+            }
+        }
+    }
+    debug!(bars = bar_strings.len(), contexts = followers.len(), "learned grammar");
+
+    let mut grammar = Grammar::new(context_name(&[]), vec![]);
+    for (context, next) in &followers {
+        let mut branches = vec![];
+        let mut weights = vec![];
+        for (&bar, &count) in next {
+            let mut then = context.clone();
+            then.push(bar);
+            let then = &then[then.len().saturating_sub(order)..];
+            let mut branch = vec![MusicPrimitive::Simple(Symbol::NT(bar_name(bar)))];
+            if followers.contains_key(then) {
+                branch.push(MusicPrimitive::Simple(Symbol::NT(context_name(then))));
+            }
+            branches.push(MusicString(branch));
+            weights.push(count);
+        }
+        let body = if branches.len() == 1 {
+            branches.remove(0)
+        } else {
+            MusicString(vec![MusicPrimitive::Split { branches, policy: SplitPolicy::Strict, mode: SplitMode::Choice { weights } }])
+        };
+        grammar.add_production(context_name(context), body);
+    }
+    for (bar, string) in bar_strings.into_iter().enumerate() {
+        grammar.add_production(bar_name(bar), string);
+    }
+    grammar
+}
+
+/// Learn a grammar from every `.mid` and `.midi` file in the folder at `path`.
+pub fn learn_folder(path: impl AsRef<Path>, options: LearnOptions) -> Result<Grammar, Error> {
+    let mut files = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|f| f.extension().is_some_and(|e| e.eq_ignore_ascii_case("mid") || e.eq_ignore_ascii_case("midi")));
+    files.sort();
+    let corpus = files.iter()
+        .map(|file| {
+            let bytes = std::fs::read(file)?;
+            read_midi(&bytes, options.grid).map_err(|e| Error::MidiFile(format!("{}: {e}", file.display())))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(learn_grammar(&corpus, options))
+}
+
+#[cfg(test)]
+mod test {
+    use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+    use crate::cfg::{Grammar, MusicPrimitive, MusicString, NonTerminal, Symbol};
+    use crate::cfg::document::GrammarDocument;
+    use crate::composition::{Instrument, Pitch};
+    use crate::learn::{learn_grammar, read_midi, LearnOptions};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    /// A file with one piano track playing `bars` of four quarter notes each.
+    fn midi_file(bars: &[[u8; 4]]) -> Vec<u8> {
+        let mut track = vec![TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8)) }];
+        for key in bars.iter().flatten() {
+            let note = |delta: u32, vel: u8| TrackEvent {
+                delta: delta.into(),
+                kind: TrackEventKind::Midi { channel: 0.into(), message: MidiMessage::NoteOn { key: (*key).into(), vel: vel.into() } },
+            };
+            // slightly early, which the grid takes care of
+            track.extend([note(0, 100), note(470, 0)]);
+            track.push(TrackEvent { delta: 10.into(), kind: TrackEventKind::Meta(MetaMessage::Marker(b"")) });
+        }
+        track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(480.into())));
+        smf.tracks.push(track);
+        let mut bytes = vec![];
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_learn_from_midi() {
+        let (a, b, c) = ([60, 62, 64, 65], [67, 65, 64, 62], [60, 60, 60, 60]);
+        let piece = read_midi(&midi_file(&[a, b, a, c]), Beat::new(1, 4)).unwrap();
+        assert_eq!(piece.time_signature, TimeSignature::common());
+        assert_eq!(piece.tracks.len(), 1);
+        assert_eq!(piece.tracks[0].instrument, Instrument::Piano);
+        let first = &piece.tracks[0].events()[0];
+        assert_eq!((first.start, first.duration, first.pitch), (MusicTime::zero(), Beat::whole(1), Pitch::from_midi_note(60)));
+        assert_eq!(piece.tracks[0].events()[15].start, MusicTime(3, Beat::whole(3)));
+
+        let grammar = learn_grammar(&[piece.clone(), piece], LearnOptions::default());
+        let text = GrammarDocument::from_grammar(&grammar).to_source() + "\n";
+        // the first bar always starts, and is followed by the second or the fourth equally often
+        assert!(text.contains("S = Bar-1 After-1\n"), "{text}");
+        assert!(text.contains("After-1 = {2 Bar-2 After-2  | 2 Bar-3 }\n"), "{text}");
+        assert!(text.contains("After-2 = Bar-1 After-1\n"), "{text}");
+        let parsed = text.parse::<Grammar>().unwrap();
+        assert_eq!(GrammarDocument::from_grammar(&parsed).to_source() + "\n", text);
+
+        let start = MusicString(vec![MusicPrimitive::Simple(Symbol::NT(NonTerminal::Custom("S".into())))]);
+        for _ in 0..10 {
+            let music = start.parallel_rewrite_n(&grammar, true, true, 8)
+                .compose(TimeSignature::common(), None)
+                .unwrap();
+            let keys = music.tracks[0].events().iter().map(|e| e.pitch.to_midi_note()).collect::<Vec<_>>();
+            assert!(keys.len() >= 8 && keys.len().is_multiple_of(4));
+            assert_eq!(keys[..4], a);
+            assert!(keys.chunks(4).all(|bar| [a, b, c].iter().any(|known| bar == known)));
+        }
+    }
+}
@@ -0,0 +1,50 @@
+// Everything that plays sound, talks MIDI or uses threads needs the `native` feature.
+// Without it the grammar, composition and timing code builds for wasm32-unknown-unknown.
+
+// Logging goes through `tracing`, which also hands everything to `log` for as long as no
+// tracing subscriber is set, so the binary's logger shows it.
+#[macro_use]
+extern crate tracing;
+
+pub mod composition;
+pub mod builder;
+pub mod arrangement;
+pub mod time;
+pub mod cfg;
+pub mod interval;
+pub mod constants;
+pub mod web;
+pub mod capi;
+pub mod config;
+pub mod error;
+pub mod encoding;
+pub mod stream;
+pub mod room;
+pub mod melody;
+pub mod learn;
+pub mod export;
+pub mod testing;
+
+#[cfg(feature = "native")]
+pub mod player;
+#[cfg(feature = "native")]
+pub mod scheduler;
+#[cfg(feature = "native")]
+pub mod clock;
+#[cfg(feature = "native")]
+pub mod capture;
+#[cfg(feature = "native")]
+pub mod recorder;
+#[cfg(feature = "native")]
+pub mod synth;
+#[cfg(feature = "native")]
+pub mod local_playback;
+#[cfg(feature = "native")]
+pub mod realtime;
+#[cfg(feature = "native")]
+pub mod metrics;
+#[cfg(feature = "native")]
+pub mod spectrum;
+
+#[cfg(all(test, feature = "native"))]
+mod test;
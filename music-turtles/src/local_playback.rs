@@ -1,62 +1,364 @@
 use std::ops::DerefMut;
-use std::sync::{mpsc, Arc, Mutex};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use crate::clock::{Clock, VirtualClock};
+use crate::metrics::Metrics;
 use crate::player::{AudioPlayer, Player};
-use crate::scheduler::Scheduler;
+use crate::realtime;
+use crate::realtime::{high_resolution_timers, raise_thread_priority, RealtimePriority};
+use crate::spectrum::SpectrumTap;
+use crate::scheduler::{Progress, ScheduledSound, Scheduler, SchedulerCommand};
+use crate::time::Seconds;
 
-pub fn run<S: DerefMut<Target=Scheduler> + Send>(scheduler: S, scheduler_tick_ms: u64, player: Player) {
-    let (event_send, event_recv) = mpsc::channel();
+/// Asks playback to stop early, for music that would otherwise loop forever.
+/// Clones share the same flag, so one can be kept to stop the playback that was given another.
+#[derive(Debug, Clone, Default)]
+pub struct StopToken(Arc<AtomicBool>);
+
+static CTRL_C: OnceLock<StopToken> = OnceLock::new();
+
+impl StopToken {
+    /// A token that is stopped by Ctrl-C, so playback gets to silence its notes before the
+    /// program ends. Pressing it a second time kills the program as usual.
+    pub fn ctrl_c() -> StopToken {
+        CTRL_C.get_or_init(|| {
+            unsafe {
+                libc::signal(libc::SIGINT, on_ctrl_c as extern "C" fn(libc::c_int) as libc::sighandler_t);
+            }
+            StopToken::default()
+        }).clone()
+    }
+
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+extern "C" fn on_ctrl_c(_signal: libc::c_int) {
+    if let Some(token) = CTRL_C.get() {
+        token.stop();
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Most events the scheduler can get ahead of the player. Past that it waits for the player to
+/// catch up, so a stalled player holds the scheduler back instead of piling up events in memory.
+pub const EVENT_QUEUE_CAPACITY: usize = 1024;
+/// How long the scheduler waits before trying again to hand an event to a full queue.
+const QUEUE_FULL_BACKOFF: Seconds = 0.005;
+
+/// Hand `event` to the player, backing off while its queue is full.
+/// Returns false if the player stopped listening, or playback was stopped while waiting.
+fn send_event<T>(queue: &SyncSender<T>, event: T, metrics: &Metrics, clock: &impl Clock, stop: &StopToken) -> bool {
+    // counted before it is sent, so the player can't take it out of the count first
+    metrics.queued();
+    let mut event = event;
+    loop {
+        match queue.try_send(event) {
+            Ok(()) => return true,
+            Err(TrySendError::Full(back)) if !stop.is_stopped() => {
+                event = back;
+                clock.sleep(QUEUE_FULL_BACKOFF);
+            }
+            Err(_) => {
+                metrics.unqueued();
+                return false;
+            }
+        }
+    }
+}
+
+/// The events from `queue`, counted in `metrics` as the player gets to them.
+fn taken<'a>(queue: Receiver<ScheduledSound>, metrics: &'a Metrics, clock: &'a impl Clock) -> impl Iterator<Item=ScheduledSound> + 'a {
+    queue.into_iter().inspect(|event| metrics.taken(event.start(), clock.elapsed()))
+}
+
+/// Real-time priority for the calling thread if it was asked for with `realtime::enable`, for as
+/// long as it is kept. Playing on at normal priority is better than not playing, so failing only warns.
+fn realtime_priority() -> Option<RealtimePriority> {
+    if !realtime::enabled() {
+        return None;
+    }
+    raise_thread_priority().inspect_err(|e| warn!("{e}")).ok()
+}
+
+/// Sends where playback is to `progress` once per tick, if it is given, and counts the loops.
+/// The newest spectrum from `spectrum` goes along with it.
+fn report(progress: &Option<Sender<Progress>>, scheduler: &Scheduler, elapsed_s: Seconds, spectrum: Option<&SpectrumTap>) {
+    let now = Progress { queued: scheduler.metrics.queue_depth(), ..scheduler.progress(elapsed_s) };
+    scheduler.metrics.set_loops(now.iteration);
+    if let Some(progress) = progress {
+        let now = Progress { spectrum: spectrum.and_then(SpectrumTap::frame), ..now };
+        // nobody watching anymore is no reason to stop playing
+        let _ = progress.send(now);
+    }
+}
+
+/// Play on the local synths. What is heard is also recorded into a WAV file at `recording`, if given.
+/// `player.clock()` keeps the timing in step with the sound card over long sessions.
+pub fn run<S, C>(
+    mut scheduler: S,
+    scheduler_tick_ms: u64,
+    player: Player,
+    clock: C,
+    stop: StopToken,
+    progress: Option<Sender<Progress>>,
+    recording: Option<&Path>,
+)
+where
+    S: DerefMut<Target=Scheduler> + Send,
+    C: Clock + Clone,
+{
+    scheduler.output_latency = player.output_latency();
+    scheduler.metrics.set_levels(player.levels());
+    if let Some(path) = recording
+        && let Err(e) = player.start_recording(path, clock.elapsed()) {
+        warn!("Not recording to {}: {e}", path.display());
+    }
+    let _timers = realtime::enabled().then(high_resolution_timers);
+    let (event_send, event_recv) = mpsc::sync_channel(EVENT_QUEUE_CAPACITY);
+    let metrics = scheduler.metrics.clone();
+    let spectrum = player.spectrum();
     thread::scope(move |s| {
+        let player_clock = clock.clone();
+        let player_stop = stop.clone();
         s.spawn(move || {
-            let start_time = SystemTime::now();
+            let _priority = realtime_priority();
             let mut scheduler = scheduler;
+            let mut events = Vec::new();
             loop {
-                if scheduler.ended() {
+                if scheduler.ended() || stop.is_stopped() {
                     break;
                 }
-                let elapsed_s = start_time.elapsed().unwrap().as_secs_f32();
+                let elapsed_s = clock.elapsed();
+                let _tick = trace_span!("tick", elapsed = elapsed_s).entered();
                 let sc = scheduler.deref_mut();
-                let events = sc.get_next_events_and_update(elapsed_s);
-                for event in events {
-                    event_send.send(event).unwrap();
+                report(&progress, sc, elapsed_s, Some(&spectrum));
+                sc.fill_next_events(elapsed_s, &mut events);
+                trace!(events = events.len(), "scheduled");
+                for event in events.drain(..) {
+                    if !send_event(&event_send, event, &sc.metrics, &clock, &stop) {
+                        return;
+                    }
                 }
-                thread::sleep(Duration::from_millis(scheduler_tick_ms));
+                clock.sleep(scheduler_tick_ms as Seconds / 1000.);
             }
         });
-        player.play_from_ordered_channel(event_recv);
+        let priority = realtime_priority();
+        player.play_from_ordered_channel(taken(event_recv, &metrics, &player_clock), &player_clock, &player_stop);
+        drop(priority);
+        if let Err(e) = player.stop_recording() {
+            warn!("Failed to finish the recording: {e}");
+        }
     });
 }
 
-pub fn run_midi<P>(
-    scheduler: Arc<Mutex<Scheduler>>,
+/// Play on a thread that owns the scheduler, so nothing else ever holds it while events are made.
+/// Changes come in through `commands` and are applied at the start of each tick.
+/// Playing ends when the music does or `stop` is stopped, which silences the notes still sounding.
+/// Where playback is goes out on `progress` once per tick, if it is given.
+/// A player that falls behind holds the scheduler back once `EVENT_QUEUE_CAPACITY` events are waiting.
+pub fn run_midi<P, C>(
+    scheduler: Scheduler,
+    commands: Receiver<SchedulerCommand>,
     scheduler_tick_ms: u64,
-    mut player: P
+    mut player: P,
+    clock: C,
+    stop: StopToken,
+    progress: Option<Sender<Progress>>,
 )
 where
-    P: AudioPlayer
+    P: AudioPlayer,
+    C: Clock + Clone,
 {
-    let (event_send, event_recv) = mpsc::channel();
+    let mut scheduler = scheduler;
+    scheduler.output_latency = player.output_latency();
+    let _timers = realtime::enabled().then(high_resolution_timers);
+    let (event_send, event_recv) = mpsc::sync_channel(EVENT_QUEUE_CAPACITY);
+    let metrics = scheduler.metrics.clone();
     thread::scope(move |s| {
+        let player_clock = clock.clone();
+        let player_stop = stop.clone();
         s.spawn(move || {
-            let start_time = SystemTime::now();
-            let mut scheduler = scheduler;
+            let _priority = realtime_priority();
+            let mut events = Vec::new();
             loop {
-                let mut guard = scheduler.lock().unwrap();
-                if guard.ended() {
-                    drop(guard);
+                let elapsed_s = clock.elapsed();
+                let _tick = trace_span!("tick", elapsed = elapsed_s).entered();
+                for command in commands.try_iter() {
+                    scheduler.apply(command, elapsed_s);
+                }
+                if scheduler.ended() || stop.is_stopped() {
                     break;
                 }
-                let elapsed_s = start_time.elapsed().unwrap().as_secs_f32();
-                let events = guard.get_next_events_and_update(elapsed_s);
-                // info!("{events:#?}");
-                drop(guard);
-                for event in events {
-                    event_send.send(event).unwrap();
+                report(&progress, &scheduler, elapsed_s, None);
+                scheduler.fill_next_events(elapsed_s, &mut events);
+                trace!(events = events.len(), "scheduled");
+                for event in events.drain(..) {
+                    if !send_event(&event_send, event, &scheduler.metrics, &clock, &stop) {
+                        return;
+                    }
                 }
-                thread::sleep(Duration::from_millis(scheduler_tick_ms));
+                clock.sleep(scheduler_tick_ms as Seconds / 1000.);
             }
         });
-        player.play_from_ordered_channel(event_recv);
+        let _priority = realtime_priority();
+        player.play_from_ordered_channel(taken(event_recv, &metrics, &player_clock), &player_clock, &player_stop);
     });
-}
\ No newline at end of file
+}
+
+/// `run_midi` without the threads, for tests: the scheduler ticks every `scheduler_tick_ms` on
+/// `clock`, which is moved on to each sound as it is played and then to the next tick. Nothing
+/// waits for real time, so the same music is played at the same times on every run and machine.
+pub fn run_simulated<P: AudioPlayer>(
+    mut scheduler: Scheduler,
+    commands: Receiver<SchedulerCommand>,
+    scheduler_tick_ms: u64,
+    mut player: P,
+    clock: &VirtualClock,
+    stop: StopToken,
+    progress: Option<Sender<Progress>>,
+) {
+    scheduler.output_latency = player.output_latency();
+    let tick = scheduler_tick_ms as Seconds / 1000.;
+    let mut events = Vec::new();
+    // handed out by the scheduler, in order, and not played yet
+    let mut queue: Vec<ScheduledSound> = Vec::new();
+    let mut play_until = |until: Seconds, queue: &mut Vec<ScheduledSound>, scheduler: &Scheduler| {
+        let due = queue.partition_point(|e| e.start() < until);
+        for event in queue.drain(..due) {
+            clock.advance(event.start() - clock.elapsed());
+            scheduler.metrics.taken(event.start(), clock.elapsed());
+            player.play(event.into());
+            if stop.is_stopped() {
+                player.stop();
+                return false;
+            }
+        }
+        true
+    };
+    loop {
+        let elapsed_s = clock.elapsed();
+        for command in commands.try_iter() {
+            scheduler.apply(command, elapsed_s);
+        }
+        if scheduler.ended() || stop.is_stopped() {
+            break;
+        }
+        report(&progress, &scheduler, elapsed_s, None);
+        scheduler.fill_next_events(elapsed_s, &mut events);
+        for event in events.drain(..) {
+            scheduler.metrics.queued();
+            queue.push(event);
+        }
+        queue.sort_by(|a, b| a.start().total_cmp(&b.start()));
+        if !play_until(elapsed_s + tick, &mut queue, &scheduler) {
+            return;
+        }
+        clock.advance(elapsed_s + tick - clock.elapsed());
+    }
+    if stop.is_stopped() {
+        player.stop();
+        return;
+    }
+    play_until(Seconds::INFINITY, &mut queue, &scheduler);
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use crate::builder::CompositionBuilder;
+    use crate::clock::VirtualClock;
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::local_playback::{run_midi, run_simulated, send_event, StopToken};
+    use crate::metrics::Metrics;
+    use crate::scheduler::Scheduler;
+    use crate::testing::{compose, MockPlayer};
+    use crate::time::{Beat, MusicTime};
+
+    /// A note at the start of every measure, over and over.
+    fn looped_scheduler() -> Scheduler {
+        let composition = CompositionBuilder::track(Instrument::Piano)
+            .note(Pitch(4, 0), MusicTime::zero(), Beat::whole(1), Volume(50))
+            .build()
+            .unwrap();
+        let mut scheduler = Scheduler::new(composition, 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        scheduler.looped = true;
+        scheduler.loop_time = MusicTime::measures(1);
+        scheduler
+    }
+
+    #[test]
+    fn test_stop_looped_playback() {
+        let scheduler = looped_scheduler();
+        let stop = StopToken::default();
+        let clock = VirtualClock::default();
+        let player = MockPlayer::new(clock.clone()).stopping_after(3, stop.clone());
+        // looped music never ends, so this only returns because of the stop
+        run_midi(scheduler, mpsc::channel().1, 50, player.clone(), clock, stop, None);
+        assert_eq!((player.played().len(), player.stops()), (3, 1));
+        // the loop is a measure, two seconds at 120bpm
+        let starts = player.played().iter().map(|p| p.sound.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![0., 2., 4.]);
+        assert!(player.played().iter().all(|p| p.sound.pitch == Pitch(4, 0)));
+
+        // without threads every sound is played right on time
+        let stop = StopToken::default();
+        let clock = VirtualClock::default();
+        let player = MockPlayer::new(clock.clone()).stopping_after(3, stop.clone());
+        run_simulated(looped_scheduler(), mpsc::channel().1, 50, player.clone(), &clock, stop, None);
+        let played = player.played().iter().map(|p| (p.at, p.sound.start)).collect::<Vec<_>>();
+        assert_eq!(played, vec![(0., 0.), (2., 2.), (4., 4.)]);
+        assert_eq!(player.stops(), 1);
+    }
+
+    #[test]
+    fn test_looped_stall_plays_late() {
+        // a note on every beat of a measure long loop, and ticks further apart than the lookahead
+        let composition = compose(":c :d :e :f");
+        let mut scheduler = Scheduler::new(composition, 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        scheduler.looped = true;
+        scheduler.loop_time = MusicTime::measures(1);
+        let metrics = scheduler.metrics.clone();
+        let stop = StopToken::default();
+        let clock = VirtualClock::default();
+        let player = MockPlayer::new(clock.clone()).stopping_after(5, stop.clone());
+        run_simulated(scheduler, mpsc::channel().1, 1750, player.clone(), &clock, stop, None);
+        // what the stalled tick missed is played late, and the loop still starts again on time
+        let played = player.played().iter().map(|p| (p.at, p.sound.start)).collect::<Vec<_>>();
+        assert_eq!(played, vec![(0., 0.), (1.75, 0.5), (1.75, 1.), (1.75, 1.5), (2., 2.)]);
+        assert_eq!(metrics.snapshot().events_late, 3);
+    }
+
+    #[test]
+    fn test_full_queue_backs_off() {
+        let (send, recv) = mpsc::sync_channel(2);
+        let metrics = Metrics::default();
+        let clock = VirtualClock::default();
+        let stop = StopToken::default();
+        assert!(send_event(&send, 1, &metrics, &clock, &stop));
+        assert!(send_event(&send, 2, &metrics, &clock, &stop));
+        assert_eq!(metrics.queue_depth(), 2);
+        assert_eq!(recv.recv().unwrap(), 1);
+        metrics.taken(0., 0.);
+        assert_eq!(metrics.queue_depth(), 1);
+        // a full queue waits for the player, until playback is stopped
+        assert!(send_event(&send, 3, &metrics, &clock, &stop));
+        stop.stop();
+        assert!(!send_event(&send, 4, &metrics, &clock, &stop));
+        assert_eq!(metrics.queue_depth(), 2);
+        drop(recv);
+        assert!(!send_event(&send, 5, &metrics, &clock, &StopToken::default()));
+    }
+}
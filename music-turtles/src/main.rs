@@ -1,31 +1,35 @@
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::{stdin, stdout, Write};
-use crate::time::{Beat, MusicTime, Seconds, TimeSignature, BPM};
+use music_turtles::time::{Seconds, TimeSignature, BPM};
 use rodio::Source;
 use std::ops::DerefMut;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 use midir::{MidiOutput, MidiOutputConnection};
 use midly::live::LiveEvent;
 use midly::MidiMessage;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::State;
-use crate::cfg::{Grammar, MusicString};
-use crate::cfg::scan::{consume, GrammarScanner, ScanError};
-use crate::cfg::scan::Scanner;
+use music_turtles::cfg::{Derivation, Grammar, MusicString};
+use music_turtles::cfg::scan::{consume, GrammarScanner, ScanError};
+use music_turtles::cfg::scan::Scanner;
 use rocket::serde::json::{Json, Value, json};
 use rocket::serde::{Serialize, Deserialize};
 use rocket_cors::CorsOptions;
-use crate::cfg::interactive::TracedString;
-use crate::composition::Instrument;
-use crate::composition::Instrument::*;
-use crate::local_playback::{run, run_midi};
-use crate::player::{MidiPlayer, Player};
-use crate::scheduler::Scheduler;
+use music_turtles::cfg::interactive::TracedString;
+use music_turtles::clock::{MidiClockFollower, SystemClock};
+use music_turtles::local_playback::{run, run_midi, StopToken};
+use music_turtles::metrics::{LevelsSnapshot, Metrics, MetricsSnapshot};
+use music_turtles::composition::Composition;
+use music_turtles::room::{ClientId, ClockSample, Cue, Room, DEFAULT_LEAD};
+use music_turtles::web::WebAudioEvent;
+use music_turtles::export::{engrave, piano_roll_svg, EngraveOptions, PianoRollOptions, ScoreFormat};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
 use simplelog::*;
 
 #[macro_use]
@@ -34,18 +38,6 @@ extern crate simplelog;
 
 extern crate rocket;
 
-mod player;
-mod scheduler;
-mod composition;
-
-mod time;
-mod cfg;
-
-#[cfg(test)]
-mod test;
-pub mod local_playback;
-mod constants;
-
 pub struct ServerConfig {
     pub data_path: String,
 }
@@ -102,6 +94,193 @@ pub struct ServerConfig {
 //         .mount("/", routes![grammar, play])
 // }
 
+#[rocket::get("/metrics")]
+fn get_metrics(metrics: &State<Metrics>) -> Json<MetricsSnapshot> {
+    Json(metrics.snapshot())
+}
+
+/// Levels of the local synth's tracks, for level meters. Not there when playing over MIDI.
+#[rocket::get("/levels")]
+fn get_levels(metrics: &State<Metrics>) -> Option<Json<LevelsSnapshot>> {
+    metrics.levels().map(Json)
+}
+
+/// Serve `metrics` on `port` in the background while playing.
+fn serve_metrics(metrics: Metrics, port: u16) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let cors = CorsOptions::default()
+            .to_cors()
+            .expect("error creating CORS fairing");
+        // Ctrl-C is for stopping playback, which has to silence its notes first
+        let mut shutdown = rocket::config::Shutdown { ctrlc: false, ..Default::default() };
+        shutdown.signals.clear();
+        let server = rocket::custom(rocket::Config { port, shutdown, ..rocket::Config::default() })
+            .attach(cors)
+            .manage(metrics)
+            .mount("/", rocket::routes![get_metrics, get_levels]);
+        if let Err(e) = rocket::execute(server.launch()) {
+            warn!("Stopped serving metrics: {e}");
+        }
+    })
+}
+
+/// Rooms of clients playing together, by name. Every room starts with the same music.
+struct Rooms {
+    composition: Composition,
+    bpm: BPM,
+    /// What the server's clock counts from.
+    epoch: Instant,
+    rooms: Mutex<HashMap<String, Room>>,
+    scores: Mutex<Scores>,
+}
+
+/// Sheet music already engraved for each room, with the generation of the room it shows.
+type Scores = HashMap<(String, ScoreFormat), (u64, Vec<u8>)>;
+
+impl Rooms {
+    fn now(&self) -> Seconds {
+        self.epoch.elapsed().as_secs_f32()
+    }
+
+    fn with_room<T>(&self, name: &str, f: impl FnOnce(&mut Room, Seconds) -> Option<T>) -> Result<T, Status> {
+        let now = self.now();
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.get_mut(name).and_then(|room| f(room, now)).ok_or(Status::NotFound)
+    }
+}
+
+/// The server's clock, for clients to work out how far theirs is from it.
+#[rocket::get("/clock")]
+fn get_clock(rooms: &State<Rooms>) -> Json<Seconds> {
+    Json(rooms.now())
+}
+
+#[rocket::post("/rooms/<name>/join")]
+fn join_room(name: &str, rooms: &State<Rooms>) -> Json<ClientId> {
+    let mut all = rooms.rooms.lock().unwrap();
+    let room = all.entry(name.to_string()).or_insert_with(|| Room::new(rooms.composition.clone(), rooms.bpm));
+    Json(room.join())
+}
+
+#[rocket::delete("/rooms/<name>/<client>")]
+fn leave_room(name: &str, client: ClientId, rooms: &State<Rooms>) -> Result<(), Status> {
+    rooms.with_room(name, |room, _| {
+        room.leave(client);
+        Some(())
+    })
+}
+
+#[rocket::post("/rooms/<name>/<client>/offset", format = "json", data = "<offset>")]
+fn report_offset(name: &str, client: ClientId, offset: Json<Seconds>, rooms: &State<Rooms>) -> Result<(), Status> {
+    rooms.with_room(name, |room, _| room.report_offset(client, offset.into_inner()).then_some(()))
+}
+
+/// A clock sample from a client that timed a request for `/clock`. Answers with its offset so far.
+#[rocket::post("/rooms/<name>/<client>/clock", format = "json", data = "<sample>")]
+fn add_clock_sample(name: &str, client: ClientId, sample: Json<ClockSample>, rooms: &State<Rooms>) -> Result<Json<Seconds>, Status> {
+    rooms.with_room(name, |room, _| room.add_clock_sample(client, sample.into_inner())).map(Json)
+}
+
+#[rocket::post("/rooms/<name>/<client>/latency", format = "json", data = "<latency>")]
+fn report_latency(name: &str, client: ClientId, latency: Json<Seconds>, rooms: &State<Rooms>) -> Result<(), Status> {
+    rooms.with_room(name, |room, _| room.report_latency(client, latency.into_inner()).then_some(()))
+}
+
+#[rocket::get("/rooms/<name>/<client>/cue")]
+fn get_cue(name: &str, client: ClientId, rooms: &State<Rooms>) -> Result<Json<Cue>, Status> {
+    rooms.with_room(name, |room, _| room.cue(client)).map(Json)
+}
+
+#[rocket::get("/rooms/<name>/events")]
+fn get_room_events(name: &str, rooms: &State<Rooms>) -> Result<Json<Vec<WebAudioEvent>>, Status> {
+    rooms.with_room(name, |room, _| Some(room.events())).map(Json)
+}
+
+#[rocket::get("/rooms/<name>/piano_roll.svg")]
+fn get_room_piano_roll(name: &str, rooms: &State<Rooms>) -> Result<(ContentType, String), Status> {
+    rooms.with_room(name, |room, _| Some(piano_roll_svg(room.composition(), &PianoRollOptions::default())))
+        .map(|svg| (ContentType::SVG, svg))
+}
+
+/// Sheet music of the room's composition, as `pdf` or `png`. Needs LilyPond on the server, which
+/// runs on a blocking thread, and only again once the room has changed.
+#[rocket::get("/rooms/<name>/score/<format>")]
+async fn get_room_score(name: &str, format: &str, rooms: &State<Rooms>) -> Result<(ContentType, Vec<u8>), Status> {
+    let (format, content_type) = match format {
+        "pdf" => (ScoreFormat::Pdf, ContentType::PDF),
+        "png" => (ScoreFormat::Png, ContentType::PNG),
+        _ => return Err(Status::NotFound),
+    };
+    let (composition, generation) = rooms.with_room(name, |room, _| Some((room.composition().clone(), room.generation())))?;
+    let key = (name.to_string(), format);
+    if let Some((engraved, score)) = rooms.scores.lock().unwrap().get(&key)
+        && *engraved == generation {
+        return Ok((content_type, score.clone()));
+    }
+    let engraved = rocket::tokio::task::spawn_blocking(move || engrave(&composition, format, &EngraveOptions::default())).await;
+    let score = match engraved {
+        Ok(Ok(score)) => score,
+        Ok(Err(e)) => {
+            warn!("Couldn't engrave room {name}: {e}");
+            return Err(Status::InternalServerError);
+        }
+        Err(e) => {
+            warn!("Engraving room {name} stopped: {e}");
+            return Err(Status::InternalServerError);
+        }
+    };
+    let mut scores = rooms.scores.lock().unwrap();
+    // another request may have engraved the room after a later change in the meantime
+    if scores.get(&key).is_none_or(|(cached, _)| *cached < generation) {
+        scores.insert(key, (generation, score.clone()));
+    }
+    Ok((content_type, score))
+}
+
+#[rocket::post("/rooms/<name>/start?<lead>")]
+fn start_room(name: &str, lead: Option<Seconds>, rooms: &State<Rooms>) -> Result<(), Status> {
+    rooms.with_room(name, |room, now| {
+        room.start(now, lead.unwrap_or(DEFAULT_LEAD));
+        Some(())
+    })
+}
+
+#[rocket::post("/rooms/<name>/stop")]
+fn stop_room(name: &str, rooms: &State<Rooms>) -> Result<(), Status> {
+    rooms.with_room(name, |room, _| {
+        room.stop();
+        Some(())
+    })
+}
+
+#[rocket::post("/rooms/<name>/tempo", format = "json", data = "<bpm>")]
+fn set_room_tempo(name: &str, bpm: Json<BPM>, rooms: &State<Rooms>) -> Result<(), Status> {
+    let bpm = bpm.into_inner();
+    if !(bpm.is_finite() && bpm > 0.) {
+        return Err(Status::BadRequest);
+    }
+    rooms.with_room(name, |room, now| room.set_tempo(bpm, now).then_some(()))
+}
+
+/// Serve rooms playing `composition` on `port` in the background.
+fn serve_rooms(composition: Composition, bpm: BPM, port: u16) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let cors = CorsOptions::default()
+            .to_cors()
+            .expect("error creating CORS fairing");
+        let mut shutdown = rocket::config::Shutdown { ctrlc: false, ..Default::default() };
+        shutdown.signals.clear();
+        let rooms = Rooms { composition, bpm, epoch: Instant::now(), rooms: Mutex::new(HashMap::new()), scores: Mutex::new(HashMap::new()) };
+        let server = rocket::custom(rocket::Config { port, shutdown, ..rocket::Config::default() })
+            .attach(cors)
+            .manage(rooms)
+            .mount("/", rocket::routes![get_clock, join_room, leave_room, report_offset, add_clock_sample, report_latency, get_cue, get_room_events, get_room_piano_roll, get_room_score, start_room, stop_room, set_room_tempo]);
+        if let Err(e) = rocket::execute(server.launch()) {
+            warn!("Stopped serving rooms: {e}");
+        }
+    })
+}
+
 fn file_watcher<F>(file: &str, mut f: F, period: Seconds) -> JoinHandle<()>
 where
     F: FnMut(String) + Send + 'static,
@@ -130,48 +309,62 @@ where
 }
 
 pub fn main() {
-    CombinedLogger::init(vec![
-        TermLogger::new(LevelFilter::Warn, Config::default(), TerminalMode::Mixed, ColorChoice::Auto),
-        TermLogger::new(LevelFilter::Info, Config::default(), TerminalMode::Mixed, ColorChoice::Auto),
-    ]).unwrap();
-    let axiom = "S";
+    // the library logs with `tracing`, which comes through here; VIBELIVE_LOG=trace shows every tick and note
+    let level = std::env::var("VIBELIVE_LOG").ok()
+        .and_then(|level| LevelFilter::from_str(&level).ok())
+        .unwrap_or(LevelFilter::Info);
+    TermLogger::init(level, Config::default(), TerminalMode::Mixed, ColorChoice::Auto).unwrap();
+    let config = music_turtles::config::Config::load_default().unwrap_or_else(|e| panic!("{e}"));
     let time_signature = TimeSignature::common();
-    let bpm: BPM = 120.0;
-    let mt_path = "data/funky_bach.mtx";
-    let mt_contents = std::fs::read_to_string(mt_path).unwrap();
+    let bpm: BPM = config.playback.bpm;
+    let mt_path = config.grammar.display();
+    let mt_contents = std::fs::read_to_string(&config.grammar).unwrap();
     let grammar = Grammar::from_str(&mt_contents).unwrap();
-    let mut string = MusicString::from_str(axiom).unwrap();
-    for i in 0..20 {
+    // looping grammars are fine as long as they are only rewritten a fixed number of times
+    if let Err(errors) = grammar.validate() {
+        for e in errors {
+            warn!("{mt_path}: {e}");
+        }
+    }
+    for lint in grammar.lint(time_signature) {
+        warn!("{mt_path}: {lint}");
+    }
+    for split in grammar.durations(time_signature).unequal_splits {
+        warn!("{mt_path}: {split}");
+    }
+    let mut string = MusicString::from_str(&config.axiom).unwrap();
+    let mut rng = rand::thread_rng();
+    for i in 0..config.iterations {
         println!("After {} iters: {}", i, string.to_string());
-        string = string.parallel_rewrite(&grammar, true, true);
+        string = string.parallel_rewrite_at(&grammar, true, true, Derivation { depth: i, bar: 0 }, time_signature, &mut rng);
     }
     info!("Final string: {}", string.to_string());
 
     let music = string.compose(time_signature, None).unwrap();
     info!("Final music: \n{}", music.visualize(150));
     // println!("{music:#?}");
-    let mut scheduler = Scheduler {
-        bpm,
-        time_signature,
-        tracks: vec![],
-        lookahead: MusicTime::measures(1),
-        looped: false,
-        loop_time: music.get_duration(),
-    };
-    let channel_mapping = Instrument::values().into_iter().map(|i| (i, match i {
-        BassDrum => (2, 1),
-        HiHatOpen => (3, 1),
-        HiHatClosed => (4, 1),
-        Snare => (5, 1),
-        Snare2 => (6, 1),
-        Piano => (1, 1),
-        _ => (1, 1),
-    })).collect();
+    if let Some(port) = config.playback.room_port {
+        serve_rooms(music.clone(), bpm, port);
+    }
+    let mut scheduler = config.scheduler(time_signature);
+    scheduler.loop_time = music.get_duration();
     scheduler.set_composition(music);
-    let sched = Arc::new(Mutex::new(scheduler));
-    let player = MidiPlayer::new("music-turtles".to_string(), channel_mapping).unwrap();
+    // edits are sent here instead of locking the scheduler while it plays
+    let (commands, command_recv) = mpsc::channel();
+    music_turtles::realtime::enable(config.playback.realtime);
+    let player = config.midi_player().unwrap_or_else(|e| panic!("{e}"));
+    if let Some(port) = config.playback.metrics_port {
+        serve_metrics(scheduler.metrics.clone(), port);
+    }
     thread::sleep(Duration::from_millis(1000)); // give player time to get ready
-    run_midi(sched, 100, player);
+    // slave playback to a drum machine or DAW sending MIDI clock on this input port
+    match std::env::var("MIDI_CLOCK_IN") {
+        Ok(port_name) => {
+            let clock = MidiClockFollower::connect(&port_name, bpm, Some(commands)).unwrap();
+            run_midi(scheduler, command_recv, 100, player, clock, StopToken::ctrl_c(), None);
+        }
+        Err(_) => run_midi(scheduler, command_recv, 100, player, SystemClock::new(), StopToken::ctrl_c(), None),
+    }
 }
 
 pub fn other() -> Result<(), Box<dyn std::error::Error>> {
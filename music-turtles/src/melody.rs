@@ -0,0 +1,238 @@
+// Melodies made up to fit under a chord track, for people who know how the music should go
+// underneath and in what rhythm, but not which notes to sing over it. The chord at any moment
+// is whatever the chord track has sounding then, and the rhythm comes from another track whose
+// pitches don't matter. Notes are chosen at random, and backed out of again when they lead
+// somewhere no note fits.
+
+use std::fmt::Display;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use crate::composition::{Event, Instrument, Pitch, Scale, Track, TrackId};
+use crate::time::{Beat, MusicTime, TimeSignature};
+
+/// How many notes are tried in all before giving up, so impossible constraints don't take forever.
+const MAX_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MelodyConstraints {
+    /// The lowest and highest notes the melody can use.
+    pub lowest: Pitch,
+    pub highest: Pitch,
+    /// Largest jump from one note to the next, in semitones.
+    pub max_leap: u8,
+    /// Notes starting on a multiple of this many beats into the measure have to be in the chord.
+    pub strong_beats: Beat,
+    /// The key, with the root in any octave. Notes on weak beats have to be in its scale or in
+    /// the chord. Without one they can be anything.
+    pub key: Option<(Pitch, Scale)>,
+}
+
+impl Default for MelodyConstraints {
+    fn default() -> Self {
+        MelodyConstraints {
+            lowest: Pitch(4, 3),
+            highest: Pitch(5, 3),
+            max_leap: 5,
+            strong_beats: Beat::whole(2),
+            key: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MelodyError {
+    /// No note can go at this time, whatever the notes before it are.
+    Unsatisfiable(MusicTime),
+    /// Every note can go somewhere on its own, but not all of them with small enough leaps.
+    NoMelody,
+    /// Gave up looking before finding a melody that fits or finding out there is none.
+    TooManyTries,
+}
+
+impl Display for MelodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MelodyError::Unsatisfiable(MusicTime(measure, beat)) => {
+                write!(f, "No note fits the constraints at measure {measure}, beat {}", beat.as_float())
+            }
+            MelodyError::NoMelody => write!(f, "No melody fits the constraints"),
+            MelodyError::TooManyTries => write!(f, "Tried {MAX_TRIES} notes without finding a melody that fits"),
+        }
+    }
+}
+
+impl std::error::Error for MelodyError {}
+
+fn semitones(pitch: Pitch) -> i32 {
+    pitch.0 as i32 * 12 + pitch.1 as i32
+}
+
+fn from_semitones(semitones: i32) -> Pitch {
+    Pitch(semitones.div_euclid(12) as i8, semitones.rem_euclid(12) as u8)
+}
+
+impl MelodyConstraints {
+    fn is_strong(&self, MusicTime(_, beat): MusicTime) -> bool {
+        // a whole number of strong beats in
+        let (strong, beat) = (self.strong_beats, beat);
+        (beat.numerator() as u64 * strong.denominator() as u64).is_multiple_of(beat.denominator() as u64 * strong.numerator() as u64)
+    }
+
+    fn in_key(&self, pitch: i32) -> bool {
+        self.key.is_none_or(|(root, scale)| {
+            let above = (pitch - semitones(root)).rem_euclid(12);
+            scale.steps().contains(&above)
+        })
+    }
+
+    /// The notes that could go at `start` if it didn't matter what came before, in random order.
+    fn candidates(&self, start: MusicTime, chord: &[i32], rng: &mut impl Rng) -> Vec<i32> {
+        let in_chord = |pitch: i32| chord.iter().any(|c| (c - pitch).rem_euclid(12) == 0);
+        let strong = self.is_strong(start) && !chord.is_empty();
+        let mut candidates = (semitones(self.lowest)..=semitones(self.highest))
+            .filter(|&p| if strong { in_chord(p) } else { in_chord(p) || self.in_key(p) })
+            .collect::<Vec<_>>();
+        candidates.shuffle(rng);
+        candidates
+    }
+}
+
+/// A melody in the rhythm of `rhythm` over the chords in `chords`, played on `instrument`.
+/// Each note gets the start, length and volume of a note of the rhythm.
+pub fn generate_melody(
+    chords: &Track,
+    rhythm: &Track,
+    constraints: &MelodyConstraints,
+    instrument: Instrument,
+    time_signature: TimeSignature,
+    rng: &mut impl Rng,
+) -> Result<Track, MelodyError> {
+    let mut slots = rhythm.events().to_vec();
+    slots.sort();
+    slots.dedup_by_key(|e| e.start);
+    let mut options = Vec::with_capacity(slots.len());
+    for slot in &slots {
+        let chord = chords.events_sounding_at(slot.start, time_signature)
+            .iter()
+            .map(|e| semitones(e.pitch))
+            .collect::<Vec<_>>();
+        let candidates = constraints.candidates(slot.start, &chord, rng);
+        if candidates.is_empty() {
+            return Err(MelodyError::Unsatisfiable(slot.start));
+        }
+        options.push(candidates);
+    }
+    let pitches = search(&options, constraints.max_leap as i32, rng)?;
+    let events = slots.iter()
+        .zip(pitches)
+        .map(|(slot, pitch)| Event { pitch: from_semitones(pitch), spelling: None, ..*slot })
+        .collect();
+    Ok(Track::new(TrackId::Instrument(instrument), instrument, events, vec![]))
+}
+
+/// One of `options` for each note, with no leap larger than `max_leap` between them.
+fn search(options: &[Vec<i32>], max_leap: i32, rng: &mut impl Rng) -> Result<Vec<i32>, MelodyError> {
+    let Some(first) = options.first() else {
+        return Ok(vec![]);
+    };
+    // what is left to try for each note so far and for the next one, the most likely last
+    let mut untried = vec![first.clone()];
+    let mut melody = Vec::with_capacity(options.len());
+    let mut tries = 0;
+    while melody.len() < options.len() {
+        let Some(pitch) = untried.last_mut().and_then(Vec::pop) else {
+            // nothing fits after the last note, so it has to be another one
+            untried.pop();
+            if melody.pop().is_none() {
+                return Err(MelodyError::NoMelody);
+            }
+            continue;
+        };
+        tries += 1;
+        if tries > MAX_TRIES {
+            return Err(MelodyError::TooManyTries);
+        }
+        melody.push(pitch);
+        if let Some(next) = options.get(melody.len()) {
+            let mut next = next.iter()
+                .copied()
+                .filter(|p| (p - pitch).abs() <= max_leap)
+                .collect::<Vec<_>>();
+            // small steps mostly, but not always the smallest
+            next.sort_by_cached_key(|p| std::cmp::Reverse((p - pitch).abs() + rng.gen_range(0..3)));
+            untried.push(next);
+        }
+    }
+    Ok(melody)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::builder::CompositionBuilder;
+    use crate::composition::{Instrument, Pitch, Scale, Volume};
+    use crate::melody::{generate_melody, MelodyConstraints, MelodyError};
+    use crate::testing::track;
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_melody_fits_chords() {
+        let time_signature = TimeSignature::common();
+        // C major for a bar, then G major
+        let mut chords = CompositionBuilder::track(Instrument::Piano);
+        for (measure, chord) in [(0, [Pitch(3, 3), Pitch(3, 7), Pitch(3, 10)]), (1, [Pitch(3, 10), Pitch(3, 2), Pitch(4, 5)])] {
+            for pitch in chord {
+                chords = chords.note(pitch, MusicTime::measures(measure), Beat::whole(4), Volume(60));
+            }
+        }
+        let chords = chords.build().unwrap();
+        let mut rhythm = CompositionBuilder::track(Instrument::Piano);
+        for eighth in 0..16 {
+            rhythm = rhythm.note(Pitch(4, 0), Beat::new(eighth, 2).as_music_time(time_signature), Beat::new(1, 2), Volume(80));
+        }
+        let rhythm = rhythm.build().unwrap();
+        let constraints = MelodyConstraints { key: Some((Pitch(4, 3), Scale::Major)), ..MelodyConstraints::default() };
+
+        for seed in 0..20 {
+            let melody = generate_melody(&chords.tracks[0], &rhythm.tracks[0], &constraints, Instrument::Harp, time_signature, &mut StdRng::seed_from_u64(seed)).unwrap();
+            assert_eq!(melody.events().len(), 16);
+            assert_eq!(melody.instrument, Instrument::Harp);
+            for pair in melody.events().windows(2) {
+                let leap = (pair[1].pitch.0 as i32 * 12 + pair[1].pitch.1 as i32) - (pair[0].pitch.0 as i32 * 12 + pair[0].pitch.1 as i32);
+                assert!(leap.abs() <= 5);
+            }
+            for event in melody.events() {
+                let MusicTime(measure, beat) = event.start;
+                let chord = if measure == 0 { [3, 7, 10] } else { [10, 2, 5] };
+                let natural = [3, 5, 7, 8, 10, 0, 2];
+                if beat == Beat::zero() || beat == Beat::whole(2) {
+                    assert!(chord.contains(&event.pitch.1), "{event:?}");
+                } else {
+                    assert!(natural.contains(&event.pitch.1), "{event:?}");
+                }
+            }
+        }
+
+        // a range of two semitones with no chord tone in it
+        let narrow = MelodyConstraints { lowest: Pitch(4, 4), highest: Pitch(4, 6), ..constraints };
+        let result = generate_melody(&chords.tracks[0], &rhythm.tracks[0], &narrow, Instrument::Harp, time_signature, &mut StdRng::seed_from_u64(0));
+        assert_eq!(result, Err(MelodyError::Unsatisfiable(MusicTime::zero())));
+        // without leaps, the G that is in both chords is the only melody there is
+        let held = MelodyConstraints { max_leap: 0, ..constraints.clone() };
+        let melody = generate_melody(&chords.tracks[0], &rhythm.tracks[0], &held, Instrument::Harp, time_signature, &mut StdRng::seed_from_u64(0)).unwrap();
+        assert!(melody.events().iter().all(|e| e.pitch == Pitch(4, 10)));
+        // and with no note in both, there is none
+        let mut no_common_tone = chords.tracks[0].clone();
+        no_common_tone.events_mut().retain(|e| e.start.0 != 0 || e.pitch != Pitch(3, 10));
+        no_common_tone.sort();
+        let result = generate_melody(&no_common_tone, &rhythm.tracks[0], &held, Instrument::Harp, time_signature, &mut StdRng::seed_from_u64(0));
+        assert_eq!(result, Err(MelodyError::NoMelody));
+
+        // no rhythm, no melody
+        let silence = track(Instrument::Piano, vec![]);
+        let melody = generate_melody(&chords.tracks[0], &silence, &constraints, Instrument::Harp, time_signature, &mut StdRng::seed_from_u64(0)).unwrap();
+        assert!(melody.events().is_empty());
+        assert_eq!(melody.instrument, Instrument::Harp);
+    }
+}
@@ -0,0 +1,207 @@
+// Counts of how playback is keeping up, for finding out why it stutters on someone else's machine.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use serde::Serialize;
+use crate::composition::Instrument;
+use crate::scheduler::LATE_TOLERANCE;
+use crate::time::Seconds;
+
+/// Updated by the playback threads while they play, and read from anywhere else.
+/// Clones share the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    events_scheduled: AtomicU64,
+    events_late: AtomicU64,
+    /// Bits of a non-negative `Seconds`, which order the same way as the numbers do.
+    max_lateness: AtomicU32,
+    queue_depth: AtomicUsize,
+    loops: AtomicUsize,
+    /// The meters of the mixer that is playing, if it is the local synth.
+    levels: Mutex<Option<Levels>>,
+}
+
+/// The counts at one moment.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    /// Events the scheduler has handed to the player.
+    pub events_scheduled: u64,
+    /// Events the player got to more than `LATE_TOLERANCE` after they should have started.
+    pub events_late: u64,
+    /// The latest any event got to the player.
+    pub max_lateness: Seconds,
+    /// Events handed to the player that it hasn't got to yet.
+    pub queue_depth: usize,
+    /// How many times the loop has been played all the way through.
+    pub loops: usize,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = &self.0;
+        MetricsSnapshot {
+            events_scheduled: counters.events_scheduled.load(Ordering::Relaxed),
+            events_late: counters.events_late.load(Ordering::Relaxed),
+            max_lateness: Seconds::from_bits(counters.max_lateness.load(Ordering::Relaxed)),
+            queue_depth: counters.queue_depth.load(Ordering::Relaxed),
+            loops: counters.loops.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.0.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// An event is on its way to the player.
+    pub fn queued(&self) {
+        self.0.queue_depth.fetch_add(1, Ordering::Relaxed);
+        self.0.events_scheduled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An event that was counted as queued never made it to the player.
+    pub fn unqueued(&self) {
+        self.0.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.0.events_scheduled.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The player got to an event that should have started at `start` when the clock read `now`.
+    pub fn taken(&self, start: Seconds, now: Seconds) {
+        let counters = &self.0;
+        counters.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        let lateness = now - start;
+        if lateness > LATE_TOLERANCE {
+            counters.events_late.fetch_add(1, Ordering::Relaxed);
+            counters.max_lateness.fetch_max(lateness.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_loops(&self, loops: usize) {
+        self.0.loops.store(loops, Ordering::Relaxed);
+    }
+
+    /// Report the levels of `levels` along with the counts, while its mixer plays.
+    pub fn set_levels(&self, levels: Levels) {
+        *self.0.levels.lock().unwrap() = Some(levels);
+    }
+
+    /// How loud every track is right now, if playback is on the local synth.
+    pub fn levels(&self) -> Option<LevelsSnapshot> {
+        self.0.levels.lock().unwrap().as_ref().map(Levels::snapshot)
+    }
+}
+
+/// How loud a signal was over the last stretch the mixer measured, 1 being full scale.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize)]
+pub struct Level {
+    pub rms: f32,
+    pub peak: f32,
+    /// Samples so far that went over full scale, which a peak meter can miss between reads.
+    pub clipped: u64,
+}
+
+/// The level of one signal, written by the audio thread and read from anywhere else.
+#[derive(Debug, Default)]
+pub struct Meter {
+    /// Bits of the `f32`s, like `max_lateness`.
+    rms: AtomicU32,
+    peak: AtomicU32,
+    clipped: AtomicU64,
+}
+
+impl Meter {
+    /// The last stretch of samples came out at `rms` and `peak`, with `clipped` of them over full scale.
+    pub fn update(&self, rms: f32, peak: f32, clipped: u64) {
+        self.rms.store(rms.to_bits(), Ordering::Relaxed);
+        self.peak.store(peak.to_bits(), Ordering::Relaxed);
+        self.clipped.fetch_add(clipped, Ordering::Relaxed);
+    }
+
+    pub fn level(&self) -> Level {
+        Level {
+            rms: f32::from_bits(self.rms.load(Ordering::Relaxed)),
+            peak: f32::from_bits(self.peak.load(Ordering::Relaxed)),
+            clipped: self.clipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Meters on the master bus and on every instrument's track bus. Clones share the same meters.
+#[derive(Debug, Clone, Default)]
+pub struct Levels(Arc<Meters>);
+
+#[derive(Debug, Default)]
+struct Meters {
+    master: Arc<Meter>,
+    tracks: Mutex<HashMap<Instrument, Arc<Meter>>>,
+}
+
+/// Every level at one moment.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LevelsSnapshot {
+    /// The whole mix, before the limiter, so it shows when the limiter has to step in.
+    pub master: Level,
+    /// Only the instruments that have played so far.
+    pub tracks: BTreeMap<Instrument, Level>,
+}
+
+impl Levels {
+    pub fn master(&self) -> Arc<Meter> {
+        self.0.master.clone()
+    }
+
+    /// The meter for `instrument`, made the first time it is asked for.
+    pub fn track(&self, instrument: Instrument) -> Arc<Meter> {
+        self.0.tracks.lock().unwrap().entry(instrument).or_default().clone()
+    }
+
+    pub fn snapshot(&self) -> LevelsSnapshot {
+        LevelsSnapshot {
+            master: self.0.master.level(),
+            tracks: self.0.tracks.lock().unwrap().iter().map(|(&i, meter)| (i, meter.level())).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::Instrument;
+    use crate::metrics::{Level, Levels, Metrics, MetricsSnapshot};
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::default();
+        let shared = metrics.clone();
+        for _ in 0..3 {
+            shared.queued();
+        }
+        metrics.taken(1., 1.01);
+        metrics.taken(2., 2.5);
+        metrics.taken(3., 3.25);
+        shared.queued();
+        shared.queued();
+        shared.unqueued();
+        shared.set_loops(2);
+        assert_eq!(metrics.snapshot(), MetricsSnapshot {
+            events_scheduled: 4,
+            events_late: 2,
+            max_lateness: 0.5,
+            queue_depth: 1,
+            loops: 2,
+        });
+
+        assert_eq!(metrics.levels(), None);
+        let levels = Levels::default();
+        metrics.set_levels(levels.clone());
+        levels.track(Instrument::Bass).update(0.5, 1.25, 3);
+        levels.track(Instrument::Bass).update(0.25, 0.5, 0);
+        levels.master().update(0.1, 0.2, 0);
+        let snapshot = shared.levels().unwrap();
+        assert_eq!(snapshot.master, Level { rms: 0.1, peak: 0.2, clipped: 0 });
+        assert_eq!(snapshot.tracks[&Instrument::Bass], Level { rms: 0.25, peak: 0.5, clipped: 3 });
+        assert_eq!(snapshot.tracks.len(), 1);
+    }
+}
@@ -1,122 +1,644 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::sync::mpsc::Receiver;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
+use hound::{SampleFormat, WavSpec, WavWriter};
 use midly::live::LiveEvent;
 use midly::MidiMessage;
 use rodio::{OutputStream, OutputStreamHandle, Source};
-use crate::composition::{Event, Instrument, Pitch, Volume};
+use rodio::dynamic_mixer::{self, DynamicMixerController};
+use rodio::source::Zero;
+use rodio::cpal;
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+use crate::clock::{AudioClock, Clock};
+use crate::local_playback::StopToken;
+use crate::composition::{Instrument, Modulation, OverlapPolicy, Pitch, Syllable, Tag, Volume};
 use crate::constants::get_fuzzy_mapping;
+use crate::error::Error;
+use crate::metrics::{Levels, Meter};
+use crate::spectrum::{FftTap, SpectrumTap};
+use crate::synth::SynthBank;
 use crate::time::Seconds;
 
-pub type MidiChannel = u8;
+pub use crate::composition::MidiChannel;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct AtomicSound {
     pub start: Seconds,
     pub duration: Seconds,
     pub volume: Volume,
     pub pitch: Pitch,
-    pub instrument: Instrument
+    pub instrument: Instrument,
+    pub modulation: Modulation,
+    /// Overrides the channel the instrument is mapped to.
+    pub channel: Option<MidiChannel>,
+    pub tag: Option<Tag>,
+    /// Sent along for showing the lyrics in time with the music.
+    pub lyric: Option<Syllable>,
 }
 
 pub trait AudioPlayer {
     fn play(&mut self, event: AtomicSound);
 
-    fn play_from_ordered_channel<T: Into<AtomicSound>>(&mut self, queue: Receiver<T>) {
-        let start_time = SystemTime::now();
-        let mut end = start_time;
+    /// Time between `play` being called and the sound being audible.
+    fn output_latency(&self) -> Seconds {
+        0.
+    }
+
+    /// Silence every note that is still sounding, for when playback is stopped early.
+    fn stop(&mut self) {}
+
+    /// Play each event once `clock` reaches its start. `clock` has to be the one the events were timed with.
+    /// Returns as soon as `stop` is stopped, after calling `stop` on the player.
+    fn play_from_ordered_channel<T: Into<AtomicSound>>(&mut self, queue: impl IntoIterator<Item=T>, clock: &impl Clock, stop: &StopToken) {
+        let mut end: Seconds = 0.;
         for event in queue {
             let event = event.into();
-            let current_time = SystemTime::now();
-            let elapsed = current_time.duration_since(start_time).unwrap().as_secs_f32();
+            let elapsed = clock.elapsed();
             let wait_time = event.start - elapsed;
-            if wait_time > 0. {
-                thread::sleep(Duration::from_secs_f32(wait_time));
+            if !sleep_unless_stopped(clock, wait_time, stop) {
+                self.stop();
+                return;
             }
-            end = SystemTime::max(end, current_time + Duration::from_secs_f32(f32::max(wait_time, 0.) + event.duration));
+            end = end.max(elapsed + f32::max(wait_time, 0.) + event.duration);
             // println!("playing sound: {start:?}");
             self.play(event);
         }
-        // wait for the last sound to finish
-        let wait_time = end.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(1)).as_secs_f32();
-        if wait_time > 0. {
-            thread::sleep(Duration::from_secs_f32(wait_time));
+        // the scheduler also stops, which can close the queue before the player notices
+        if stop.is_stopped() {
+            self.stop();
+            return;
         }
+        // wait for the last sound to finish
+        wait_until(clock, end);
     }
 }
 
 pub struct Player {
     stream: OutputStream,
-    output_stream: OutputStreamHandle
+    output_stream: OutputStreamHandle,
+    output_latency: Seconds,
+    /// every sound is mixed into this bus, which goes through a `Limiter` on its way out
+    master: Arc<DynamicMixerController<f32>>,
+    /// A bus for each instrument, mixed into the master, so each can be metered on its own.
+    buses: Mutex<HashMap<Instrument, Arc<DynamicMixerController<f32>>>>,
+    levels: Levels,
+    spectrum: SpectrumTap,
+    synths: SynthBank,
+    recording: Recording,
+    voices: Mutex<Voices>,
+    /// Samples the output has taken so far, for `AudioClock`.
+    written: Arc<AtomicU64>,
+}
+
+/// The file the master bus is being recorded to, if any.
+type Recording = Arc<Mutex<Option<WavWriter<BufWriter<File>>>>>;
+
+const MASTER_CHANNELS: u16 = 2;
+const MASTER_SAMPLE_RATE: u32 = 44100;
+/// Peak level the master bus is kept under.
+pub const LIMITER_THRESHOLD: f32 = 0.9;
+/// Time for the limiter to recover most of the way after a peak.
+pub const LIMITER_RELEASE: Seconds = 0.25;
+
+/// Peak limiter for the master bus. Turns the gain down immediately when a sample would go
+/// over the threshold, so stacked notes never clip, and lets it back up over the release time.
+pub struct Limiter<S> {
+    input: S,
+    threshold: f32,
+    /// how far the gain moves back toward 1 on each sample
+    recovery: f32,
+    gain: f32,
+}
+
+impl<S> Limiter<S>
+where
+    S: Source<Item=f32>,
+{
+    pub fn new(input: S, threshold: f32, release: Seconds) -> Self {
+        let samples_per_second = input.sample_rate() as f32 * input.channels() as f32;
+        let recovery = 1. - (-1. / (release * samples_per_second)).exp();
+        Limiter { input, threshold, recovery, gain: 1. }
+    }
+}
+
+impl<S> Iterator for Limiter<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        self.gain += (1. - self.gain) * self.recovery;
+        if (sample * self.gain).abs() > self.threshold {
+            self.gain = self.threshold / sample.abs();
+        }
+        Some(sample * self.gain)
+    }
+}
+
+impl<S> Source for Limiter<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+/// Passes a source through unchanged, writing every sample to the recording while there is one.
+pub struct Tap<S> {
+    input: S,
+    recording: Recording,
+}
+
+impl<S> Iterator for Tap<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        let mut recording = self.recording.lock().unwrap();
+        if let Some(writer) = recording.as_mut()
+            && let Err(e) = writer.write_sample(sample) {
+            warn!("Stopped recording: {e}");
+            *recording = None;
+        }
+        Some(sample)
+    }
+}
+
+impl<S> Source for Tap<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+/// Samples a `Metered` source adds up before it updates its meter, about 23ms of stereo.
+pub const METER_WINDOW: usize = 2048;
+
+/// Passes a source through unchanged, measuring how loud it is into a meter.
+pub struct Metered<S> {
+    input: S,
+    meter: Arc<Meter>,
+    sum_squares: f32,
+    peak: f32,
+    clipped: u64,
+    count: usize,
+}
+
+impl<S> Metered<S> {
+    pub fn new(input: S, meter: Arc<Meter>) -> Self {
+        Metered { input, meter, sum_squares: 0., peak: 0., clipped: 0, count: 0 }
+    }
+}
+
+impl<S> Iterator for Metered<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        self.sum_squares += sample * sample;
+        self.peak = self.peak.max(sample.abs());
+        if sample.abs() > 1. {
+            self.clipped += 1;
+        }
+        self.count += 1;
+        if self.count == METER_WINDOW {
+            self.meter.update((self.sum_squares / self.count as f32).sqrt(), self.peak, self.clipped);
+            (self.sum_squares, self.peak, self.clipped, self.count) = (0., 0., 0, 0);
+        }
+        Some(sample)
+    }
+}
+
+impl<S> Source for Metered<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
 }
 
 pub trait Playable {
-    /// get start time, duration, and actual sound
-    fn get_source(&self) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>);
+    /// get start time, duration, and actual sound, made with the synth for its instrument
+    fn get_source(&self, synths: &SynthBank) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>);
+
+    fn instrument(&self) -> Instrument;
+
+    fn volume(&self) -> Volume;
+}
+
+/// Passes a source through unchanged, counting the samples that are taken from it.
+pub struct Counted<S> {
+    input: S,
+    written: Arc<AtomicU64>,
+}
+
+impl<S> Iterator for Counted<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        self.written.fetch_add(1, Ordering::Relaxed);
+        Some(sample)
+    }
+}
+
+impl<S> Source for Counted<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+/// Caps on how many notes the local synth plays at once, so huge splits don't pile up voices
+/// until the CPU can't keep up and the limiter squashes everything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolyphonyLimit {
+    pub max_voices: usize,
+    /// Lower caps for single instruments, which are also held to `max_voices` overall.
+    pub per_instrument: HashMap<Instrument, usize>,
+    pub steal: StealPolicy,
+}
+
+/// Which sounding note is cut off to make room for a new one.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum StealPolicy {
+    #[default]
+    Oldest,
+    /// The quietest, and of those the oldest.
+    Quietest,
+}
+
+impl Default for PolyphonyLimit {
+    fn default() -> Self {
+        PolyphonyLimit { max_voices: DEFAULT_MAX_VOICES, per_instrument: HashMap::new(), steal: StealPolicy::default() }
+    }
+}
+
+pub const DEFAULT_MAX_VOICES: usize = 64;
+
+/// A note the local synth is playing, until it ends or is stolen.
+#[derive(Debug, Clone)]
+struct Voice {
+    instrument: Instrument,
+    volume: Volume,
+    start: Seconds,
+    end: Seconds,
+    stolen: Arc<AtomicBool>,
+}
+
+/// The notes sounding right now, kept under a `PolyphonyLimit`.
+#[derive(Debug, Default)]
+struct Voices {
+    limit: PolyphonyLimit,
+    playing: Vec<Voice>,
+}
+
+impl Voices {
+    /// Make room for a note from `start` to `end`, stealing voices if it goes over the limit.
+    /// The returned flag is set when this one gets stolen in turn.
+    fn start(&mut self, instrument: Instrument, volume: Volume, start: Seconds, end: Seconds) -> Arc<AtomicBool> {
+        self.playing.retain(|v| v.end > start && !v.stolen.load(Ordering::Relaxed));
+        if let Some(&cap) = self.limit.per_instrument.get(&instrument) {
+            while self.playing.iter().filter(|v| v.instrument == instrument).count() >= cap.max(1) {
+                self.steal(Some(instrument));
+            }
+        }
+        while self.playing.len() >= self.limit.max_voices.max(1) {
+            self.steal(None);
+        }
+        let stolen = Arc::new(AtomicBool::new(false));
+        self.playing.push(Voice { instrument, volume, start, end, stolen: stolen.clone() });
+        stolen
+    }
+
+    fn steal(&mut self, instrument: Option<Instrument>) {
+        let candidates = self.playing.iter().enumerate()
+            .filter(|(_, v)| instrument.is_none_or(|i| v.instrument == i));
+        let victim = match self.limit.steal {
+            StealPolicy::Oldest => candidates.min_by(|(_, a), (_, b)| a.start.total_cmp(&b.start)),
+            StealPolicy::Quietest => candidates.min_by(|(_, a), (_, b)| a.volume.cmp(&b.volume).then(a.start.total_cmp(&b.start))),
+        };
+        if let Some((i, _)) = victim {
+            self.playing.swap_remove(i).stolen.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How much of the gain a stolen voice loses on each sample, so it fades out in a few
+/// milliseconds instead of clicking off.
+const STEAL_FADE_STEP: f32 = 1. / 256.;
+
+/// Plays a source until its voice is stolen, then fades it out.
+pub struct Stealable<S> {
+    input: S,
+    stolen: Arc<AtomicBool>,
+    fade: f32,
+}
+
+impl<S> Iterator for Stealable<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.stolen.load(Ordering::Relaxed) {
+            self.fade -= STEAL_FADE_STEP;
+            if self.fade <= 0. {
+                return None;
+            }
+        }
+        Some(self.input.next()? * self.fade)
+    }
+}
+
+impl<S> Source for Stealable<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
 }
 
 impl Player {
-    pub fn new() -> Self {
-        let (stream, output_stream) = OutputStream::try_default().unwrap();
-        Player { stream, output_stream }
+    pub fn new() -> Result<Self, Error> {
+        Self::from_stream(OutputStream::try_default()?)
+    }
+
+    /// Play on the first output device whose name contains `device_name`.
+    pub fn on_device(device_name: &str) -> Result<Self, Error> {
+        let device = cpal::default_host().output_devices()?
+            .find(|d| d.name().is_ok_and(|name| name.contains(device_name)))
+            .ok_or_else(|| Error::Audio(format!("No audio output device called {device_name}")))?;
+        Self::from_stream(OutputStream::try_from_device(&device)?)
+    }
+
+    fn from_stream((stream, output_stream): (OutputStream, OutputStreamHandle)) -> Result<Self, Error> {
+        let (master, mixer) = dynamic_mixer::mixer(MASTER_CHANNELS, MASTER_SAMPLE_RATE);
+        // the mixer stops as soon as it runs out of sounds, so keep silence playing on it
+        master.add(Zero::<f32>::new(MASTER_CHANNELS, MASTER_SAMPLE_RATE));
+        let recording = Recording::default();
+        let levels = Levels::default();
+        let limited = Limiter::new(Metered::new(mixer, levels.master()), LIMITER_THRESHOLD, LIMITER_RELEASE);
+        let written = Arc::new(AtomicU64::new(0));
+        let spectrum = SpectrumTap::default();
+        let tap = Tap { input: FftTap::new(limited, spectrum.clone()), recording: recording.clone() };
+        output_stream.play_raw(Counted { input: tap, written: written.clone() })?;
+        let voices = Mutex::new(Voices::default());
+        let buses = Mutex::new(HashMap::new());
+        Ok(Player { stream, output_stream, output_latency: 0., master, buses, levels, spectrum, synths: SynthBank::default(), recording, voices, written })
     }
+
+    /// Record everything that is played from now on into a WAV file at `path`, until `stop_recording`.
+    /// It is now `starting_at` on the playback clock, which is filled with silence, so the file
+    /// starts at zero on the clock and lines up with other recordings made against it.
+    pub fn start_recording(&self, path: impl AsRef<Path>, starting_at: Seconds) -> Result<(), hound::Error> {
+        let spec = WavSpec {
+            channels: MASTER_CHANNELS,
+            sample_rate: MASTER_SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(path, spec)?;
+        let silence = (starting_at.max(0.) * MASTER_SAMPLE_RATE as Seconds) as usize * MASTER_CHANNELS as usize;
+        for _ in 0..silence {
+            writer.write_sample(0f32)?;
+        }
+        *self.recording.lock().unwrap() = Some(writer);
+        Ok(())
+    }
+
+    /// Finish the WAV file being recorded to, if there is one.
+    pub fn stop_recording(&self) -> Result<(), hound::Error> {
+        match self.recording.lock().unwrap().take() {
+            Some(writer) => writer.finalize(),
+            None => Ok(()),
+        }
+    }
+
+    /// A clock running on the samples this player has output, starting now. Playing on it
+    /// instead of a `SystemClock` keeps the music in step with the sound card, and so with
+    /// anything recorded from it, however long it plays.
+    pub fn clock(&self) -> AudioClock {
+        AudioClock::new(self.written.clone(), MASTER_SAMPLE_RATE, MASTER_CHANNELS)
+    }
+
+    /// The synths used for each instrument. Starts out with the built-in presets.
+    pub fn synths_mut(&mut self) -> &mut SynthBank {
+        &mut self.synths
+    }
+
+    /// Time between `play` being called and the sound coming out of the speakers.
+    pub fn output_latency(&self) -> Seconds {
+        self.output_latency
+    }
+
+    pub fn set_output_latency(&mut self, latency: Seconds) {
+        self.output_latency = latency;
+    }
+    /// How many notes can sound at once before older or quieter ones are cut off.
+    pub fn set_polyphony(&mut self, limit: PolyphonyLimit) {
+        self.voices.get_mut().unwrap().limit = limit;
+    }
+
     pub fn play(&self, source: impl Source<Item=f32> + Send + 'static) {
-        let sink = rodio::Sink::try_new(&self.output_stream).unwrap();
-        // thread::spawn(move || {
-        //     let source: Box<dyn Source<Item=f32> + Send> = Box::new(source);
-        //     sink.append(source);
-        //     sink.sleep_until_end();
-        // });
-        let source: Box<dyn Source<Item=f32> + Send> = Box::new(source);
-        sink.append(source);
-        sink.detach();
+        self.master.add(source);
+    }
+
+    /// The meters on the master bus and the instruments' buses, to show while playing.
+    pub fn levels(&self) -> Levels {
+        self.levels.clone()
+    }
+
+    /// The tap on what comes out of the speakers, which is off until it is enabled.
+    pub fn spectrum(&self) -> SpectrumTap {
+        self.spectrum.clone()
+    }
+
+    /// Play `source` on the bus for `instrument`, which is set up the first time it plays.
+    fn play_on_bus(&self, instrument: Instrument, source: impl Source<Item=f32> + Send + 'static) {
+        self.buses.lock().unwrap().entry(instrument)
+            .or_insert_with(|| {
+                let (bus, mixer) = dynamic_mixer::mixer(MASTER_CHANNELS, MASTER_SAMPLE_RATE);
+                // like the master, the bus would stop when nothing is playing on it
+                bus.add(Zero::<f32>::new(MASTER_CHANNELS, MASTER_SAMPLE_RATE));
+                self.play(Metered::new(mixer, self.levels.track(instrument)));
+                bus
+            })
+            .add(source);
     }
 
     /// Incoming events MUST BE IN ORDER
-    pub fn play_from_ordered_channel<T: Playable>(&self, queue: Receiver<T>) {
+    /// Returns as soon as `stop` is stopped, letting the sounds that already started ring out.
+    pub fn play_from_ordered_channel<T: Playable>(&self, queue: impl IntoIterator<Item=T>, clock: &impl Clock, stop: &StopToken) {
         let start_pause = 0.1; // seconds
-        let start_time = SystemTime::now() - std::time::Duration::from_secs_f32(start_pause);
-        let mut end = start_time;
+        let mut end: Seconds = 0.;
         for event in queue {
-            let (start, duration, source) = event.get_source();
-            let current_time = SystemTime::now();
-            let elapsed = current_time.duration_since(start_time).unwrap().as_secs_f32();
+            let (start, duration, source) = event.get_source(&self.synths);
+            let elapsed = clock.elapsed() + start_pause;
             let wait_time = start - elapsed;
             // println!("Waiting for {wait_time} until {start}... (sound is {duration}s long)");
-            if wait_time > 0. {
-                thread::sleep(std::time::Duration::from_secs_f32(wait_time));
+            if !sleep_unless_stopped(clock, wait_time, stop) {
+                return;
             }
-            end = SystemTime::max(end, current_time + std::time::Duration::from_secs_f32(f32::max(wait_time, 0.) + duration));
-            println!("playing sound: {start:?}");
-            self.play(source);
+            end = end.max(elapsed + f32::max(wait_time, 0.) + duration);
+            trace!(instrument = ?event.instrument(), start, duration, "playing sound");
+            let now = clock.elapsed();
+            let stolen = self.voices.lock().unwrap().start(event.instrument(), event.volume(), now, now + duration);
+            self.play_on_bus(event.instrument(), Stealable { input: source, stolen, fade: 1. });
         }
         // wait for the last sound to finish
-        let wait_time = end.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(1)).as_secs_f32();
-        if wait_time > 0. {
-            std::thread::sleep(std::time::Duration::from_secs_f32(wait_time));
+        wait_until(clock, end - start_pause);
+    }
+}
+
+/// Sleep for `duration` on `clock` a little at a time, giving up early if `stop` is stopped.
+/// Returns whether the whole duration was slept.
+fn sleep_unless_stopped(clock: &impl Clock, duration: Seconds, stop: &StopToken) -> bool {
+    let end = clock.elapsed() + duration;
+    loop {
+        if stop.is_stopped() {
+            return false;
+        }
+        let left = end - clock.elapsed();
+        if left <= 0. {
+            return true;
         }
+        clock.sleep(left.min(STOP_CHECK_INTERVAL));
     }
 }
 
+/// Sleep until `clock` reaches `end`, or for a second if it already has, to let the last sounds ring out.
+fn wait_until(clock: &impl Clock, end: Seconds) {
+    let wait_time = end - clock.elapsed();
+    clock.sleep(if wait_time > 0. { wait_time } else { 1. });
+}
+
+/// Longest a player sleeps before checking whether it was asked to stop.
+const STOP_CHECK_INTERVAL: Seconds = 0.05;
+
 pub type MidiPort = u8;
+
+/// Vibrato depth, in semitones, that is sent as the modulation wheel turned all the way up.
+/// MIDI has no standard control for tremolo, so that is left to the receiving synth.
+pub const MIDI_VIBRATO_RANGE: f32 = 1.0;
+const MODULATION_WHEEL: u8 = 1;
+
 pub struct MidiPlayer {
     name: String,
     port_channel_mapping: HashMap<Instrument, (MidiPort, MidiChannel)>,
     instrument_mapping: HashMap<Instrument, u8>,
     conn: Arc<HashMap<MidiPort, Mutex<midir::MidiOutputConnection>>>,
+    overlap_policy: OverlapPolicy,
+    held: Arc<Mutex<HeldNotes>>,
+    output_latency: Seconds,
+    /// last modulation wheel value sent on each channel
+    modulation_sent: HashMap<(MidiPort, MidiChannel), u8>,
+}
+
+type HeldNotes = HashMap<(MidiPort, MidiChannel, u8), HeldNote>;
+
+/// Bookkeeping for a key that has been played, used to decide when to send its note-off.
+#[derive(Debug, Default)]
+struct HeldNote {
+    /// how many overlapping notes still have to end before the note-off is sent
+    holders: usize,
+    /// bumped whenever the note is cut short, so the timers of cut notes don't send a second note-off
+    generation: u64,
 }
 
 impl MidiPlayer {
     /// Create a new player with a name and a mapping. Mapping may be empty.
-    pub fn new(name: String, port_channel_mapping: HashMap<Instrument, (MidiPort, MidiChannel)>) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(name: String, port_channel_mapping: HashMap<Instrument, (MidiPort, MidiChannel)>) -> Result<Self, Error> {
         let midi_out = midir::MidiOutput::new(&name)?;
         let out_ports = midi_out.ports();
-        println!("Available output ports:");
         let mut conns = HashMap::new();
         for (i, p) in out_ports.iter().enumerate() {
-            println!("{}: {} : {}", i, midi_out.port_name(p)?, p.id());
+            info!(port = i, name = %midi_out.port_name(p)?, id = %p.id(), "MIDI output port");
             let port = p;
             let midi_out_i = midir::MidiOutput::new(&format!("{}-{}", name, i))?;
             let conn = midi_out_i.connect(port, &format!("midir-connection-{i}"))?;
@@ -127,8 +649,32 @@ impl MidiPlayer {
         // let conn = midi_out.connect(port, "midir-connection")?;
         // let conn = Arc::new(Mutex::new(conn));
         // conns.insert(0, Mutex::new(midi_out.connect(&out_ports[0], "music-turtles")?));
-        println!("Created {} connections", conns.len());
-        Ok(MidiPlayer { name, port_channel_mapping, conn: Arc::new(conns), instrument_mapping: get_fuzzy_mapping() })
+        info!(connections = conns.len(), "Connected to MIDI outputs");
+        Ok(MidiPlayer {
+            name,
+            port_channel_mapping,
+            conn: Arc::new(conns),
+            instrument_mapping: get_fuzzy_mapping(),
+            overlap_policy: OverlapPolicy::default(),
+            held: Arc::new(Mutex::new(HashMap::new())),
+            output_latency: 0.,
+            modulation_sent: HashMap::new(),
+        })
+    }
+
+    /// Latency of the connected MIDI gear, so it can line up with local playback.
+    pub fn set_output_latency(&mut self, latency: Seconds) {
+        self.output_latency = latency;
+    }
+
+    /// Choose how overlapping notes on the same key are sent. Defaults to `OverlapPolicy::Retrigger`.
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.overlap_policy = policy;
+    }
+
+    /// The General MIDI program for each instrument, instead of the built in ones.
+    pub fn set_programs(&mut self, programs: HashMap<Instrument, u8>) {
+        self.instrument_mapping = programs;
     }
 
     pub fn get_port_channel(&self, instrument: Instrument) -> Option<(MidiPort, MidiChannel)> {
@@ -136,13 +682,68 @@ impl MidiPlayer {
     }
 }
 
+/// Modulation wheel position for a note's vibrato depth.
+fn modulation_wheel(modulation: Modulation) -> u8 {
+    if modulation.vibrato.is_off() {
+        0
+    } else {
+        (modulation.vibrato.depth() / MIDI_VIBRATO_RANGE * 127.).round().min(127.) as u8
+    }
+}
+
 impl AudioPlayer for MidiPlayer {
+    fn output_latency(&self) -> Seconds {
+        self.output_latency
+    }
+
+    /// Sends a note-off for every key that is held, and keeps the timers of those notes from
+    /// sending another one later.
+    fn stop(&mut self) {
+        let mut held = self.held.lock().unwrap();
+        for (&(port, channel, note), held_note) in held.iter_mut() {
+            if held_note.holders == 0 {
+                continue;
+            }
+            held_note.holders = 0;
+            held_note.generation += 1;
+            let ev = LiveEvent::Midi {
+                channel: channel.into(),
+                message: MidiMessage::NoteOff {
+                    key: note.into(),
+                    vel: 0.into(),
+                },
+            };
+            let mut buf = Vec::new();
+            ev.write(&mut buf).unwrap();
+            // one port going away shouldn't leave the notes on the others hanging
+            if let Some(conn) = self.conn.get(&port)
+                && let Err(e) = conn.lock().unwrap().send(&buf) {
+                warn!("Note off: {e}");
+            }
+        }
+    }
+
     fn play(&mut self, event: AtomicSound) {
+        if let Err(e) = self.try_play(event) {
+            warn!("{e}");
+        }
+    }
+}
+
+impl MidiPlayer {
+    /// Like `play`, but says why the note couldn't be sent instead of only logging it.
+    pub fn try_play(&mut self, event: AtomicSound) -> Result<(), Error> {
         let note = event.pitch.to_midi_note();
         let volume = ((event.volume.0 as f32 / 100.) * 128.) as u8;
-        let (port, channel) = self.get_port_channel(event.instrument)
-            .unwrap();
-        info!("Playing instrument {:?} on port {} channel {} at volume {}", event.instrument, port, channel, volume);
+        let (port, channel) = match (self.get_port_channel(event.instrument), event.channel) {
+            (Some((port, _)), Some(channel)) => (port, channel),
+            (Some(mapped), None) => mapped,
+            // a forced channel doesn't need the instrument to be mapped, it goes out on the first port
+            (None, Some(channel)) => (0, channel),
+            (None, None) => return Err(Error::Midi(format!("No MIDI port and channel for {:?}", event.instrument))),
+        };
+        let _note = trace_span!("note", instrument = ?event.instrument, port, channel, note, volume).entered();
+        trace!(start = event.start, duration = event.duration, "playing note");
         let note_on_message = |channel: u8, key: u8, vol: u8| {
             let ev = LiveEvent::Midi {
                 channel: channel.into(),
@@ -169,14 +770,170 @@ impl AudioPlayer for MidiPlayer {
         };
         let arc = Arc::clone(&self.conn);
         let thread_conn = Arc::clone(&self.conn);
-        let mut conn = arc.get(&port).unwrap().lock()
+        let thread_held = Arc::clone(&self.held);
+        let key = (port, channel, note);
+        let mut held = self.held.lock().unwrap();
+        let held_note = held.entry(key).or_default();
+        let mut conn = arc.get(&port)
+            .ok_or_else(|| Error::Midi(format!("No MIDI output on port {port}")))?
+            .lock()
             .unwrap();
-        conn.send(&note_on_message(channel, note, volume)).unwrap();
+        let modulation = modulation_wheel(event.modulation);
+        if self.modulation_sent.insert((port, channel), modulation) != Some(modulation) {
+            let ev = LiveEvent::Midi {
+                channel: channel.into(),
+                message: MidiMessage::Controller {
+                    controller: MODULATION_WHEEL.into(),
+                    value: modulation.into(),
+                },
+            };
+            let mut buf = Vec::new();
+            ev.write(&mut buf).unwrap();
+            conn.send(&buf)?;
+        }
+        match self.overlap_policy {
+            OverlapPolicy::Merge => {
+                if held_note.holders == 0 {
+                    conn.send(&note_on_message(channel, note, volume))?;
+                }
+                held_note.holders += 1;
+            }
+            OverlapPolicy::TruncateFirst => {
+                if held_note.holders > 0 {
+                    conn.send(&note_off_message(channel, note, volume))?;
+                    held_note.generation += 1;
+                    held_note.holders = 0;
+                }
+                conn.send(&note_on_message(channel, note, volume))?;
+                held_note.holders = 1;
+            }
+            OverlapPolicy::Retrigger => {
+                conn.send(&note_on_message(channel, note, volume))?;
+                held_note.holders += 1;
+            }
+        }
+        let generation = held_note.generation;
+        drop(conn);
+        drop(held);
         let duration = event.duration;
         thread::spawn(move || {
             thread::sleep(Duration::from_secs_f32(duration));
-            let mut conn = thread_conn.get(&port).unwrap().lock().unwrap();
-            conn.send(&note_off_message(channel, note, volume)).unwrap();
+            let mut held = thread_held.lock().unwrap();
+            let Some(held_note) = held.get_mut(&key) else {
+                return;
+            };
+            if held_note.generation != generation {
+                // this note was already cut off by a later one
+                return;
+            }
+            held_note.holders -= 1;
+            // the entry stays around so its generation keeps outliving the timers of cut notes
+            if held_note.holders == 0
+                && let Some(conn) = thread_conn.get(&port)
+                && let Err(e) = conn.lock().unwrap().send(&note_off_message(channel, note, volume)) {
+                warn!("Note off: {e}");
+            }
         });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use rodio::Source;
+    use rodio::source::SineWave;
+    use crate::composition::{Lfo, Modulation};
+    use crate::composition::{Instrument, Volume};
+    use crate::metrics::Meter;
+    use crate::player::{modulation_wheel, Limiter, Metered, PolyphonyLimit, Recording, Stealable, StealPolicy, Tap, Voices, METER_WINDOW};
+
+    #[test]
+    fn test_limiter_keeps_peaks_under_threshold() {
+        // four loud notes stacked on top of each other
+        let loud = SineWave::new(220.).amplify(4.).take_duration(Duration::from_millis(200));
+        let limited = Limiter::new(loud, 0.9, 0.25).collect::<Vec<_>>();
+        assert!(!limited.is_empty());
+        assert!(limited.iter().all(|s| s.abs() <= 0.9 + 1e-6));
+        assert!(limited.iter().any(|s| s.abs() > 0.8));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_metered_measures_each_window() {
+        let meter = Arc::new(Meter::default());
+        let loud = SineWave::new(441.).amplify(2.).take_duration(Duration::from_millis(500));
+        let samples = Metered::new(loud, meter.clone()).collect::<Vec<_>>();
+        let level = meter.level();
+        assert!((level.peak - 2.).abs() < 0.01, "{level:?}");
+        assert!((level.rms - 2f32.sqrt()).abs() < 0.05, "{level:?}");
+        let over = samples.iter().filter(|s| s.abs() > 1.).count() as u64;
+        // only whole windows are counted
+        assert!(level.clipped <= over && level.clipped > over - METER_WINDOW as u64, "{level:?}");
+    }
+
+    #[test]
+    fn test_voice_stealing() {
+        let limit = PolyphonyLimit { max_voices: 3, per_instrument: [(Instrument::Bass, 1)].into(), steal: StealPolicy::Oldest };
+        let mut voices = Voices { limit, playing: vec![] };
+        let first = voices.start(Instrument::Piano, Volume(80), 0., 4.);
+        let quiet = voices.start(Instrument::Piano, Volume(20), 0.5, 4.);
+        let bass = voices.start(Instrument::Bass, Volume(80), 1., 4.);
+        assert!(![&first, &quiet, &bass].iter().any(|s| s.load(Ordering::Relaxed)));
+        // only one bass at a time
+        voices.start(Instrument::Bass, Volume(80), 1.5, 4.);
+        assert!(bass.load(Ordering::Relaxed));
+        // over the limit, the oldest goes
+        voices.start(Instrument::Piano, Volume(80), 2., 4.);
+        assert!(first.load(Ordering::Relaxed) && !quiet.load(Ordering::Relaxed));
+        // notes that ended make room without stealing
+        voices.start(Instrument::Piano, Volume(80), 5., 6.);
+        assert_eq!(voices.playing.len(), 1);
+
+        voices.limit.steal = StealPolicy::Quietest;
+        let loud = voices.start(Instrument::Piano, Volume(90), 5., 6.);
+        let quiet = voices.start(Instrument::Piano, Volume(10), 5.5, 6.);
+        voices.start(Instrument::Piano, Volume(50), 5.5, 6.);
+        assert!(quiet.load(Ordering::Relaxed) && !loud.load(Ordering::Relaxed));
+
+        // a stolen voice fades out instead of stopping dead
+        let stolen = Arc::new(AtomicBool::new(false));
+        let mut tone = Stealable { input: SineWave::new(220.), stolen: stolen.clone(), fade: 1. };
+        assert_eq!(tone.by_ref().take(100).count(), 100);
+        stolen.store(true, Ordering::Relaxed);
+        let tail = tone.collect::<Vec<_>>();
+        assert!(!tail.is_empty() && tail.len() < 1000);
+    }
+
+    #[test]
+    fn test_tap_records_what_passes_through() {
+        let path = std::env::temp_dir().join("music-turtles-tap-test.wav");
+        let spec = hound::WavSpec { channels: 1, sample_rate: 48000, bits_per_sample: 32, sample_format: hound::SampleFormat::Float };
+        let recording = Recording::default();
+        *recording.lock().unwrap() = Some(hound::WavWriter::create(&path, spec).unwrap());
+        let tone = SineWave::new(220.).take_duration(Duration::from_millis(10));
+        let heard = Tap { input: tone, recording: recording.clone() }.collect::<Vec<_>>();
+        recording.lock().unwrap().take().unwrap().finalize().unwrap();
+        let recorded = hound::WavReader::open(&path).unwrap().samples::<f32>().map(Result::unwrap).collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(heard.len(), 480);
+        assert_eq!(recorded, heard);
+    }
+
+    #[test]
+    fn test_limiter_leaves_quiet_signal_alone() {
+        let quiet = SineWave::new(220.).amplify(0.5).take_duration(Duration::from_millis(50));
+        let expected = SineWave::new(220.).amplify(0.5).take_duration(Duration::from_millis(50)).collect::<Vec<_>>();
+        assert_eq!(Limiter::new(quiet, 0.9, 0.25).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_modulation_wheel_follows_vibrato_depth() {
+        let vibrato = |rate, depth| Modulation { vibrato: Lfo::new(rate, depth), ..Modulation::NONE };
+        assert_eq!(modulation_wheel(Modulation::NONE), 0);
+        assert_eq!(modulation_wheel(vibrato(5., 0.5)), 64);
+        assert_eq!(modulation_wheel(vibrato(5., 3.)), 127);
+        assert_eq!(modulation_wheel(vibrato(0., 0.5)), 0);
+    }
+}
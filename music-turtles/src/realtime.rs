@@ -0,0 +1,176 @@
+// Asking the OS to treat the playback threads as time critical, so a busy system doesn't make
+// them wake up late and put the notes out of time.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether playback started from now on raises its threads' priority.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Have `run` and `run_midi` put their threads at real-time priority, and on Windows make the
+/// system timer precise while they play. Off by default.
+pub fn enable(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Keeps the calling thread at real-time priority until dropped, then puts it back.
+pub struct RealtimePriority {
+    #[cfg(unix)]
+    previous: (libc::c_int, libc::sched_param),
+    #[cfg(windows)]
+    previous: i32,
+}
+
+/// Keeps the system timer at millisecond resolution until dropped, so sleeps on Windows don't
+/// overshoot by up to 15ms. Other systems already sleep precisely enough, and it does nothing.
+pub struct HighResolutionTimers(());
+
+/// Put the calling thread at real-time priority. This usually needs extra permissions, e.g.
+/// an rtprio limit on Linux, and playback goes on at normal priority if it fails.
+pub fn raise_thread_priority() -> Result<RealtimePriority, String> {
+    platform::raise_thread_priority()
+}
+
+pub fn high_resolution_timers() -> HighResolutionTimers {
+    platform::begin_timer_period();
+    HighResolutionTimers(())
+}
+
+impl Drop for RealtimePriority {
+    fn drop(&mut self) {
+        platform::restore_thread_priority(self);
+    }
+}
+
+impl Drop for HighResolutionTimers {
+    fn drop(&mut self) {
+        platform::end_timer_period();
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use crate::realtime::RealtimePriority;
+
+    pub fn raise_thread_priority() -> Result<RealtimePriority, String> {
+        // SAFETY: only reads and changes the scheduling of the calling thread
+        unsafe {
+            let thread = libc::pthread_self();
+            let mut policy = 0;
+            let mut previous: libc::sched_param = std::mem::zeroed();
+            check(libc::pthread_getschedparam(thread, &mut policy, &mut previous))?;
+            let mut param: libc::sched_param = std::mem::zeroed();
+            // halfway up, leaving room above for the audio drivers' own threads
+            param.sched_priority = (libc::sched_get_priority_min(libc::SCHED_FIFO) + libc::sched_get_priority_max(libc::SCHED_FIFO)) / 2;
+            check(libc::pthread_setschedparam(thread, libc::SCHED_FIFO, &param))?;
+            Ok(RealtimePriority { previous: (policy, previous) })
+        }
+    }
+
+    pub fn restore_thread_priority(priority: &RealtimePriority) {
+        let (policy, param) = priority.previous;
+        // SAFETY: as above, with what the thread had before
+        unsafe {
+            libc::pthread_setschedparam(libc::pthread_self(), policy, &param);
+        }
+    }
+
+    fn check(result: libc::c_int) -> Result<(), String> {
+        match result {
+            0 => Ok(()),
+            e => Err(format!("Couldn't raise thread priority: {}", std::io::Error::from_raw_os_error(e))),
+        }
+    }
+
+    pub fn begin_timer_period() {}
+
+    pub fn end_timer_period() {}
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::ffi::c_void;
+    use crate::realtime::RealtimePriority;
+
+    const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+    const THREAD_PRIORITY_ERROR_RETURN: i32 = 0x7FFFFFFF;
+    /// Milliseconds.
+    const TIMER_PERIOD: u32 = 1;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn GetThreadPriority(thread: *mut c_void) -> i32;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+    }
+
+    #[link(name = "winmm")]
+    unsafe extern "system" {
+        fn timeBeginPeriod(period: u32) -> u32;
+        fn timeEndPeriod(period: u32) -> u32;
+    }
+
+    pub fn raise_thread_priority() -> Result<RealtimePriority, String> {
+        // SAFETY: only reads and changes the priority of the calling thread
+        unsafe {
+            let thread = GetCurrentThread();
+            let previous = GetThreadPriority(thread);
+            if previous == THREAD_PRIORITY_ERROR_RETURN || SetThreadPriority(thread, THREAD_PRIORITY_TIME_CRITICAL) == 0 {
+                return Err(format!("Couldn't raise thread priority: {}", std::io::Error::last_os_error()));
+            }
+            Ok(RealtimePriority { previous })
+        }
+    }
+
+    pub fn restore_thread_priority(priority: &RealtimePriority) {
+        // SAFETY: as above, with what the thread had before
+        unsafe {
+            SetThreadPriority(GetCurrentThread(), priority.previous);
+        }
+    }
+
+    pub fn begin_timer_period() {
+        // SAFETY: matched by `end_timer_period` when the guard is dropped
+        unsafe {
+            timeBeginPeriod(TIMER_PERIOD);
+        }
+    }
+
+    pub fn end_timer_period() {
+        // SAFETY: undoes `begin_timer_period`
+        unsafe {
+            timeEndPeriod(TIMER_PERIOD);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::realtime::raise_thread_priority;
+
+    #[cfg(unix)]
+    fn policy() -> libc::c_int {
+        let mut policy = 0;
+        // SAFETY: only reads the scheduling of the calling thread
+        unsafe {
+            let mut param: libc::sched_param = std::mem::zeroed();
+            libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param);
+        }
+        policy
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_priority_is_put_back() {
+        let before = policy();
+        // without permission there is nothing to put back
+        if let Ok(raised) = raise_thread_priority() {
+            assert_eq!(policy(), libc::SCHED_FIFO);
+            drop(raised);
+        }
+        assert_eq!(policy(), before);
+    }
+}
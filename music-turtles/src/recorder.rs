@@ -0,0 +1,166 @@
+// Recording what is played on a MIDI keyboard, to turn it into music that can be composed with.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use midir::{MidiInput, MidiInputConnection};
+use crate::clock::Clock;
+use crate::composition::{Composition, Event, Instrument, Modulation, Pitch, Track, TrackId, Volume, MAX_VOLUME};
+use crate::player::MidiChannel;
+use crate::time::{Beat, Seconds, TimeSignature, BPM};
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+
+/// A note as it was played, timed on the playback clock.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RecordedNote {
+    pub channel: MidiChannel,
+    pub key: u8,
+    pub velocity: u8,
+    pub start: Seconds,
+    pub end: Seconds,
+}
+
+/// Records the notes coming in on a MIDI input port until it is stopped.
+pub struct MidiRecorder<C> {
+    state: Arc<Mutex<Recording>>,
+    clock: C,
+    _connection: MidiInputConnection<()>,
+}
+
+/// Notes finished so far, and the ones still held down with when and how hard they were hit.
+#[derive(Debug, Clone, Default)]
+struct Recording {
+    held: HashMap<(MidiChannel, u8), (Seconds, u8)>,
+    notes: Vec<RecordedNote>,
+}
+
+impl<C: Clock + Clone + 'static> MidiRecorder<C> {
+    /// Record from the first input port whose name contains `port_name`, timing the notes on `clock`.
+    pub fn connect(port_name: &str, clock: C) -> Result<Self, Box<dyn std::error::Error>> {
+        let midi_in = MidiInput::new("music-turtles-recorder")?;
+        let port = midi_in.ports().into_iter()
+            .find(|p| midi_in.port_name(p).is_ok_and(|name| name.contains(port_name)))
+            .ok_or_else(|| format!("No MIDI input port called {port_name}"))?;
+        let state = Arc::new(Mutex::new(Recording::default()));
+        let (receiver, receiver_clock) = (state.clone(), clock.clone());
+        let connection = midi_in.connect(&port, "music-turtles-recorder", move |_stamp, message, _| {
+            receiver.lock().unwrap().receive(message, receiver_clock.elapsed());
+        }, ())?;
+        Ok(MidiRecorder { state, clock, _connection: connection })
+    }
+
+    /// Stop recording. Keys that are still held end now.
+    pub fn stop(self) -> Vec<RecordedNote> {
+        let mut recording = self.state.lock().unwrap();
+        recording.release_all(self.clock.elapsed());
+        let mut notes = std::mem::take(&mut recording.notes);
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+        notes
+    }
+}
+
+impl Recording {
+    fn receive(&mut self, message: &[u8], at: Seconds) {
+        let &[status, key, velocity, ..] = message else {
+            return;
+        };
+        let channel = status & 0x0F;
+        match status & 0xF0 {
+            // a note on without velocity is how a lot of keyboards send note off
+            NOTE_ON if velocity > 0 => {
+                // hitting a key that is still held ends the note before
+                self.release(channel, key, at);
+                self.held.insert((channel, key), (at, velocity));
+            }
+            NOTE_ON | NOTE_OFF => self.release(channel, key, at),
+            _ => {}
+        }
+    }
+
+    fn release(&mut self, channel: MidiChannel, key: u8, at: Seconds) {
+        if let Some((start, velocity)) = self.held.remove(&(channel, key)) {
+            self.notes.push(RecordedNote { channel, key, velocity, start, end: at });
+        }
+    }
+
+    fn release_all(&mut self, at: Seconds) {
+        for (channel, key) in self.held.keys().copied().collect::<Vec<_>>() {
+            self.release(channel, key, at);
+        }
+    }
+}
+
+/// Snap `seconds` to the nearest multiple of `grid`, counted in beats from the start.
+fn snap(seconds: Seconds, bpm: BPM, grid: Beat) -> Beat {
+    let steps = (seconds.max(0.) * bpm / 60. / grid.as_float()).round() as u32;
+    Beat::new(grid.numerator() * steps, grid.denominator())
+}
+
+/// The notes as a track for `instrument`, with their starts and ends moved to the nearest
+/// multiple of `grid`. Notes shorter than that are made one `grid` long, so none disappear.
+/// `bpm` and `time_signature` have to be the ones the notes were played to.
+pub fn quantize(notes: &[RecordedNote], time_signature: TimeSignature, bpm: BPM, grid: Beat, instrument: Instrument) -> Track {
+    let mut track = Track::new(TrackId::Instrument(instrument), instrument, vec![], vec![]);
+    for note in notes {
+        let start = snap(note.start, bpm, grid);
+        let end = snap(note.end, bpm, grid);
+        let duration = if end > start { end - start } else { grid };
+        track.events_mut().push(Event {
+            start: start.as_music_time(time_signature),
+            duration,
+            volume: Volume(note.velocity as u32 * MAX_VOLUME / 127),
+            pitch: Pitch::from_midi_note(note.key),
+            modulation: Modulation::NONE,
+            spelling: None,
+            channel: None,
+            tag: None,
+            lyric: None,
+        });
+    }
+    track.sort();
+    track
+}
+
+/// The notes quantized into a composition with one track for `instrument`.
+pub fn to_composition(notes: &[RecordedNote], time_signature: TimeSignature, bpm: BPM, grid: Beat, instrument: Instrument) -> Composition {
+    Composition {
+        tracks: vec![quantize(notes, time_signature, bpm, grid, instrument)],
+        time_signature,
+        markers: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::{Grammar, MusicString, NonTerminal};
+    use crate::composition::{Instrument, Pitch, Volume};
+    use crate::recorder::{quantize, Recording};
+    use crate::time::{Beat, MusicTime, TimeSignature};
+
+    #[test]
+    fn test_record_and_quantize() {
+        let mut recording = Recording::default();
+        // played a little off the beat at 120 bpm, so a beat is 0.5s
+        recording.receive(&[0x90, 69, 127], 0.02);
+        recording.receive(&[0x90, 73, 64], 0.48);
+        recording.receive(&[0x80, 69, 0], 0.97);
+        // note on without velocity ends a note too
+        recording.receive(&[0x90, 73, 0], 1.01);
+        // too short to last a grid step
+        recording.receive(&[0x91, 76, 100], 1.5);
+        recording.release_all(1.52);
+        let track = quantize(&recording.notes, TimeSignature::common(), 120., Beat::new(1, 2), Instrument::Piano);
+        let notes = track.events().iter().map(|e| (e.start, e.duration, e.pitch, e.volume)).collect::<Vec<_>>();
+        assert_eq!(notes, vec![
+            (MusicTime::zero(), Beat::whole(2), Pitch(5, 0), Volume(100)),
+            (MusicTime::beats(1), Beat::whole(1), Pitch(5, 4), Volume(50)),
+            (MusicTime::beats(3), Beat::new(1, 2), Pitch(5, 7), Volume(78)),
+        ]);
+
+        let mut grammar = Grammar::new(NonTerminal::Custom("S".into()), vec![]);
+        let take = NonTerminal::Custom("Take".into());
+        grammar.add_production(take.clone(), MusicString::from_track(&track, TimeSignature::common()));
+        assert!(grammar.get_production(&take, Default::default()).is_some());
+    }
+}
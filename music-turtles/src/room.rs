@@ -0,0 +1,255 @@
+// Rooms of clients that play the same composition together, for ensemble performances over the
+// network. Each client schedules the notes itself with `web_audio_events`, so all the server has
+// to agree with them on is when the piece starts and how fast it goes. Clients say how far their
+// clock is from the server's, and each gets the start on its own clock.
+//
+// Clients that can't tell that themselves send clock samples the way NTP does: what their clock
+// read when they asked for the server's, what the server's read, and what theirs read when the
+// answer came. The sample with the shortest round trip is the most trustworthy one.
+
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use crate::composition::Composition;
+use crate::time::{Seconds, BPM};
+use crate::web::{web_audio_events, WebAudioEvent};
+
+/// How long after being started a room starts playing, unless told otherwise, so every client
+/// can hear about it in time.
+pub const DEFAULT_LEAD: Seconds = 1.;
+
+/// How many clock samples are kept for each client.
+pub const CLOCK_SAMPLES: usize = 8;
+
+pub type ClientId = u64;
+
+/// One exchange with the server's clock, with the client's times on its own clock.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClockSample {
+    pub sent_s: Seconds,
+    pub server_s: Seconds,
+    pub received_s: Seconds,
+}
+
+impl ClockSample {
+    pub fn round_trip(&self) -> Seconds {
+        self.received_s - self.sent_s
+    }
+
+    /// How far the client's clock is ahead of the server's, if the request took as long to get
+    /// there as the answer took to come back.
+    pub fn offset(&self) -> Seconds {
+        (self.sent_s + self.received_s) / 2. - self.server_s
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Client {
+    /// How far the client's clock is ahead of the server's.
+    offset: Seconds,
+    samples: VecDeque<ClockSample>,
+    /// How long the client's sound takes to come out after it is scheduled.
+    output_latency: Seconds,
+}
+
+#[derive(Debug, Clone)]
+pub struct Room {
+    composition: Composition,
+    bpm: BPM,
+    /// When the start of the piece was or will be, on the server's clock.
+    start_at: Option<Seconds>,
+    /// Goes up every time the start or the tempo changes, so clients know to reschedule.
+    generation: u64,
+    next_client: ClientId,
+    clients: HashMap<ClientId, Client>,
+}
+
+/// When to play, for one client.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cue {
+    pub generation: u64,
+    /// When the piece starts on the client's own clock, or nothing if the room is stopped.
+    /// It is early by the client's output latency, so everyone hears the start at once.
+    pub start_s: Option<Seconds>,
+    pub bpm: BPM,
+}
+
+impl Room {
+    pub fn new(composition: Composition, bpm: BPM) -> Self {
+        Room { composition, bpm, start_at: None, generation: 0, next_client: 0, clients: HashMap::new() }
+    }
+
+    pub fn join(&mut self) -> ClientId {
+        let id = self.next_client;
+        self.next_client += 1;
+        self.clients.insert(id, Client::default());
+        id
+    }
+
+    pub fn leave(&mut self, client: ClientId) {
+        self.clients.remove(&client);
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// `client`'s clock reads `offset` more than the server's. Returns false for clients that
+    /// aren't in the room.
+    pub fn report_offset(&mut self, client: ClientId, offset: Seconds) -> bool {
+        match self.clients.get_mut(&client) {
+            Some(known) => {
+                known.offset = offset;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Work out `client`'s offset from another clock sample. Returns the offset it comes to, or
+    /// nothing for clients that aren't in the room.
+    pub fn add_clock_sample(&mut self, client: ClientId, sample: ClockSample) -> Option<Seconds> {
+        let known = self.clients.get_mut(&client)?;
+        if known.samples.len() == CLOCK_SAMPLES {
+            known.samples.pop_front();
+        }
+        known.samples.push_back(sample);
+        let best = known.samples.iter()
+            .min_by(|a, b| a.round_trip().total_cmp(&b.round_trip()))
+            .expect("there is at least the one just added");
+        known.offset = best.offset();
+        Some(known.offset)
+    }
+
+    /// `client`'s sound comes out `latency` seconds after it is scheduled. Returns false for
+    /// clients that aren't in the room.
+    pub fn report_latency(&mut self, client: ClientId, latency: Seconds) -> bool {
+        match self.clients.get_mut(&client) {
+            Some(known) => {
+                known.output_latency = latency;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start from the beginning `lead` seconds after `now` on the server's clock.
+    pub fn start(&mut self, now: Seconds, lead: Seconds) {
+        self.start_at = Some(now + lead);
+        self.generation += 1;
+    }
+
+    pub fn stop(&mut self) {
+        self.start_at = None;
+        self.generation += 1;
+    }
+
+    /// Change the tempo at `now` without jumping to another place in the piece. Returns false,
+    /// changing nothing, for a tempo that isn't a positive number.
+    pub fn set_tempo(&mut self, bpm: BPM, now: Seconds) -> bool {
+        if !(bpm.is_finite() && bpm > 0.) {
+            return false;
+        }
+        if let Some(start_at) = self.start_at {
+            let elapsed = now - start_at;
+            if elapsed > 0. {
+                self.start_at = Some(now - elapsed * self.bpm / bpm);
+            }
+        }
+        self.bpm = bpm;
+        self.generation += 1;
+        true
+    }
+
+    pub fn set_composition(&mut self, composition: Composition) {
+        self.composition = composition;
+        self.generation += 1;
+    }
+
+    pub fn composition(&self) -> &Composition {
+        &self.composition
+    }
+
+    /// Counts every change to the room, so what was worked out from it can be told apart from
+    /// what is out of date.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The notes every client plays, at the current tempo.
+    pub fn events(&self) -> Vec<WebAudioEvent> {
+        web_audio_events(&self.composition, self.bpm)
+    }
+
+    pub fn cue(&self, client: ClientId) -> Option<Cue> {
+        let known = self.clients.get(&client)?;
+        Some(Cue {
+            generation: self.generation,
+            start_s: self.start_at.map(|start| start + known.offset - known.output_latency),
+            bpm: self.bpm,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::room::{ClockSample, Cue, Room, CLOCK_SAMPLES};
+    use crate::time::{TimeSignature, BPM};
+
+    #[test]
+    fn test_room_cues() {
+        let music = MusicString::from_str(":c :d :e :f").unwrap()
+            .compose(TimeSignature::common(), None)
+            .unwrap();
+        let mut room = Room::new(music, 120.);
+        let (a, b) = (room.join(), room.join());
+        assert_eq!(room.cue(a), Some(Cue { generation: 0, start_s: None, bpm: 120. }));
+        assert!(room.report_offset(b, -2.5));
+        room.start(10., 1.);
+        assert_eq!(room.cue(a).unwrap().start_s, Some(11.));
+        assert_eq!(room.cue(b).unwrap().start_s, Some(8.5));
+
+        // a beat in at 120 bpm, halving the tempo keeps that beat where it is
+        assert!(room.set_tempo(60., 11.5));
+        let cue = room.cue(b).unwrap();
+        assert_eq!((cue.generation, cue.start_s, cue.bpm), (2, Some(8.), 60.));
+        // a tempo that would stop time or run it backwards is left out
+        for bpm in [0., -60., BPM::NAN, BPM::INFINITY] {
+            assert!(!room.set_tempo(bpm, 12.));
+        }
+        assert_eq!(room.cue(b).unwrap(), cue);
+        assert_eq!(room.generation(), cue.generation);
+        assert_eq!(room.events()[1].start_s, 1.);
+
+        room.leave(a);
+        assert_eq!(room.cue(a), None);
+        assert!(!room.report_offset(a, 1.));
+        assert_eq!(room.clients(), 1);
+    }
+
+    #[test]
+    fn test_clock_samples() {
+        let music = MusicString::from_str(":c").unwrap()
+            .compose(TimeSignature::common(), None)
+            .unwrap();
+        let mut room = Room::new(music, 120.);
+        let client = room.join();
+        // the client's clock is 100s ahead, and the way there is slower than the way back
+        let slow = ClockSample { sent_s: 200., server_s: 100.75, received_s: 201. };
+        assert_eq!(room.add_clock_sample(client, slow), Some(99.75));
+        let quick = ClockSample { sent_s: 300., server_s: 200.0625, received_s: 300.125 };
+        assert_eq!(room.add_clock_sample(client, quick), Some(100.));
+        // the quick one wins until it is forgotten
+        for _ in 2..CLOCK_SAMPLES {
+            assert_eq!(room.add_clock_sample(client, slow), Some(100.));
+        }
+        assert_eq!(room.add_clock_sample(client, slow), Some(100.));
+        assert_eq!(room.add_clock_sample(client, slow), Some(99.75));
+        assert_eq!(room.add_clock_sample(client + 1, quick), None);
+
+        assert!(room.report_latency(client, 0.25));
+        room.start(1., 1.);
+        assert_eq!(room.cue(client).unwrap().start_s, Some(101.5));
+    }
+}
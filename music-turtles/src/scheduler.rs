@@ -1,12 +1,33 @@
-use std::time::Duration;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use rodio::Source;
-use rodio::source::SineWave;
-use crate::composition::{Composition, Frequency, Instrument, Pitch, Track, Volume};
-use crate::player::{AtomicSound, Playable};
-use crate::time::{MusicTime, Seconds, TimeSignature, BPM};
+use rodio::source::ChannelVolume;
+use crate::arrangement::{Arrangement, ArrangementError};
+use crate::clock::{Clock, VirtualClock};
+use crate::composition::{Composition, Event, Instrument, Marker, Modulation, Pitch, Syllable, Tag, Track, TrackId, Volume};
+use crate::metrics::Metrics;
+use crate::spectrum::SpectrumFrame;
+use crate::player::{AtomicSound, MidiChannel, Playable};
+use crate::synth::{modulate, SynthBank};
+use crate::time::{Beat, BeatUnit, Measure, MusicTime, Seconds, TimeSignature, BPM};
 
 pub type Cursor = MusicTime;
 
+/// Position in the stereo field, from -1 (left) through 0 (center) to 1 (right).
+pub type Pan = f32;
+
+/// How tracks are placed in the stereo field by the local synth.
+#[derive(Debug, Clone, Default)]
+pub enum Panning {
+    /// Every track in the middle.
+    #[default]
+    Center,
+    /// A fixed position per instrument. Missing instruments stay centered.
+    Manual(HashMap<Instrument, Pan>),
+    /// Spread the instruments that are playing evenly from left to right.
+    AutoSpread,
+}
+
 pub struct Scheduler {
     pub bpm: BPM,
     pub time_signature: TimeSignature,
@@ -14,6 +35,121 @@ pub struct Scheduler {
     pub lookahead: MusicTime,
     pub looped: bool,
     pub loop_time: MusicTime,
+    /// How long the output takes to make a sound audible. Events are handed out this much
+    /// earlier, so backends with different latencies still line up.
+    pub output_latency: Seconds,
+    pub panning: Panning,
+    /// Vibrato and tremolo for each instrument, used wherever the notes don't set their own.
+    pub modulation: HashMap<Instrument, Modulation>,
+    pub markers: Vec<Marker>,
+    /// How far the music is ahead of the playback clock, moved by jumping to a marker.
+    pub time_offset: Seconds,
+    /// Waiting to take over from the composition that is playing.
+    pub queued: Option<(Composition, SwapPolicy)>,
+    /// Tracks that were stopped or are about to start or stop. Tracks not in here play.
+    pub clips: HashMap<TrackId, ClipState>,
+    /// What happens to events that are already due when they are handed out, and how many there were.
+    pub late: LateEvents,
+    /// Shared with the playback threads, which keep it up to date while they play.
+    pub metrics: Metrics,
+    pub hooks: Hooks,
+}
+
+type NoteHook = Box<dyn FnMut(&ScheduledSound) + Send>;
+
+/// Callbacks for following playback as it goes, for visualizers and lighting that would otherwise
+/// have to keep locking the scheduler to look. They run on the scheduler's thread, so they have to
+/// be quick. Beats, bars and loops are noticed once per tick, so only the latest of the beats a
+/// tick passes is told about.
+#[derive(Default)]
+pub struct Hooks {
+    on_note: Vec<NoteHook>,
+    on_beat: Vec<Box<dyn FnMut(MusicTime) + Send>>,
+    on_bar: Vec<Box<dyn FnMut(Measure) + Send>>,
+    on_loop: Vec<Box<dyn FnMut(usize) + Send>>,
+    /// The loop, bar and beat that was heard the last time they were looked at.
+    last: Option<(usize, Measure, BeatUnit)>,
+}
+
+/// What to do with events that should already have started by the time they are handed out,
+/// because the scheduler thread was held up.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum LatePolicy {
+    /// Play them as soon as possible, all at once.
+    #[default]
+    PlayLate,
+    /// Leave them out.
+    Drop,
+    /// Play them one after another over `over` seconds from now, in the rhythm they had but faster.
+    Compress { over: Seconds },
+}
+
+/// The late policy, with counts of the events it was applied to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LateEvents {
+    pub policy: LatePolicy,
+    pub played_late: usize,
+    pub dropped: usize,
+    pub compressed: usize,
+}
+
+/// How late an event can be handed out and still count as on time, since the scheduler only
+/// looks at the clock once per tick.
+pub const LATE_TOLERANCE: Seconds = 0.02;
+
+/// Where a quantized change happens.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantize {
+    Beat,
+    Bar,
+}
+
+/// Whether a track is heard, for launching and stopping tracks live.
+/// Tracks keep their place in the composition, so starting one joins it wherever the music is.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ClipState {
+    #[default]
+    Playing,
+    Stopped,
+    StartingAt(MusicTime),
+    StoppingAt(MusicTime),
+}
+
+/// Changes for a scheduler that is playing on another thread, so the sender never has to wait for it.
+#[derive(Debug, Clone)]
+pub enum SchedulerCommand {
+    SetComposition(Composition),
+    QueueComposition(Composition, SwapPolicy),
+    JumpToMarker(String),
+    SetBpm(BPM),
+    SetLooped(bool),
+    LaunchTrack(TrackId, Quantize),
+    StopTrack(TrackId, Quantize),
+    Restart,
+}
+
+/// When a queued composition takes over.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SwapPolicy {
+    /// Right after the events that were already handed out.
+    Immediately,
+    /// On the first downbeat after the events that were already handed out.
+    NextBar,
+}
+
+/// Where playback is, for showing it while it plays.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Seconds on the playback clock.
+    pub elapsed: Seconds,
+    /// Where the music is, within the loop if it is looped.
+    pub time: MusicTime,
+    /// How many times the loop has been played all the way through.
+    pub iteration: usize,
+    /// Events handed to the player that it hasn't played yet.
+    pub queued: usize,
+    /// What the output sounded like since the last progress, if the player's spectrum tap is on.
+    pub spectrum: Option<SpectrumFrame>,
 }
 
 #[derive(Debug, PartialOrd, PartialEq)]
@@ -22,35 +158,43 @@ pub struct ScheduledSound {
     duration: Seconds,
     volume: Volume,
     instrument: Instrument,
-    pitch: Pitch
+    pitch: Pitch,
+    pan: Pan,
+    modulation: Modulation,
+    channel: Option<MidiChannel>,
+    tag: Option<Tag>,
+    lyric: Option<Syllable>,
 }
 
-pub fn get_sine_source(length: Seconds, frequency: Frequency) -> impl Source<Item=f32> {
-    let sources: Vec<Box<dyn Source<Item=f32> + Send>> = vec![
-        Box::new(
-            SineWave::new(frequency)
-                .take_duration(Duration::from_secs_f32(length))
-                .fade_in(Duration::from_millis(40))
-        ),
-        Box::new(
-            SineWave::new(frequency).fade_out(Duration::from_millis(40))
-        )
-    ];
-
-    rodio::source::from_iter(sources)
-        .amplify((3.0 * 44.0 / frequency).clamp(0.0, 1.0))
+/// Turn a mono source into a stereo one placed at `pan`, keeping the loudness
+/// constant across the field (equal-power panning).
+pub fn pan_source<S>(source: S, pan: Pan) -> impl Source<Item=f32>
+where
+    S: Source<Item=f32>,
+{
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    ChannelVolume::new(source, vec![angle.cos(), angle.sin()])
 }
 
 impl Playable for ScheduledSound {
     /// start time, duration, and actual sound
-    fn get_source(&self) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>) {
-        let source = get_sine_source(self.duration, self.pitch.to_frequency());
+    fn get_source(&self, synths: &SynthBank) -> (Seconds, Seconds, Box<dyn Source<Item=f32> + Send + 'static>) {
+        let note = synths.get(self.instrument).note(self.pitch.to_frequency(), self.duration);
+        let note = modulate(note, self.modulation);
+        let source = pan_source(note, self.pan);
         (
             self.time,
             self.duration,
             Box::new(source)
         )
     }
+    fn instrument(&self) -> Instrument {
+        self.instrument
+    }
+
+    fn volume(&self) -> Volume {
+        self.volume
+    }
 }
 
 impl From<ScheduledSound> for AtomicSound {
@@ -61,209 +205,766 @@ impl From<ScheduledSound> for AtomicSound {
             volume: value.volume,
             pitch: value.pitch,
             instrument: value.instrument,
+            modulation: value.modulation,
+            channel: value.channel,
+            tag: value.tag,
+            lyric: value.lyric,
+        }
+    }
+}
+
+impl Panning {
+    /// Where the track at `index` should sit.
+    pub fn pan_for(&self, tracks: &[(Track, Cursor)], index: usize) -> Pan {
+        let instrument = tracks[index].0.instrument;
+        match self {
+            Panning::Center => 0.,
+            Panning::Manual(pans) => pans.get(&instrument).copied().unwrap_or(0.),
+            Panning::AutoSpread => {
+                // rank among the distinct instruments, without allocating on every tick
+                let first_of = |i: usize| tracks[..i].iter().all(|(t, _)| t.instrument != tracks[i].0.instrument);
+                let distinct = (0..tracks.len()).filter(|&i| first_of(i));
+                let (count, rank) = distinct.fold((0, 0), |(count, rank), i| {
+                    (count + 1, rank + (tracks[i].0.instrument < instrument) as usize)
+                });
+                if count < 2 {
+                    0.
+                } else {
+                    -1. + 2. * rank as Pan / (count - 1) as Pan
+                }
+            }
         }
     }
 }
 
 impl Scheduler {
+    /// A scheduler for `composition` at `bpm`, handing out a measure ahead and not looped. Looping
+    /// goes round the whole composition. Everything else starts out off or empty.
+    pub fn new(composition: Composition, bpm: BPM) -> Scheduler {
+        let mut scheduler = Scheduler {
+            bpm,
+            time_signature: composition.time_signature,
+            tracks: vec![],
+            lookahead: MusicTime::measures(1),
+            looped: false,
+            loop_time: composition.get_duration(),
+            output_latency: 0.,
+            panning: Panning::Center,
+            modulation: HashMap::new(),
+            markers: vec![],
+            time_offset: 0.,
+            queued: None,
+            clips: HashMap::new(),
+            late: LateEvents::default(),
+            metrics: Metrics::default(),
+            hooks: Hooks::default(),
+        };
+        scheduler.set_composition(composition);
+        scheduler
+    }
+
+    /// Play the sections of `arrangement` in the order its form gives, with a marker at the start of each.
+    pub fn set_arrangement(&mut self, arrangement: &Arrangement) -> Result<(), ArrangementError> {
+        self.set_composition(arrangement.compose()?);
+        Ok(())
+    }
 
     pub fn set_composition(&mut self, composition: Composition) {
         self.time_signature = composition.time_signature;
+        self.markers = composition.markers;
         self.tracks = composition.tracks.into_iter()
-            .map(|t| (t, MusicTime::zero()))
+            .map(|mut t| {
+                t.sort();
+                (t, MusicTime::zero())
+            })
             .collect();
     }
     
+    /// Whether every track has been played to its end. Looped music never ends.
     pub fn ended(&self) -> bool {
-        self.tracks.iter()
+        !self.looped && self.tracks.iter()
             .filter_map(|(t, cursor)| 
                 t.get_end(self.time_signature)
                     .map(|end| *cursor > end)
             ).all(|b| b)
     }
 
+    /// Continue playing from the first marker called `name`, from the moment the playback clock
+    /// reaches `current_track_pos`. Returns false if there is no such marker.
+    pub fn jump_to_marker(&mut self, name: &str, current_track_pos: Seconds) -> bool {
+        let Some(marker) = self.markers.iter().find(|m| m.name == name) else {
+            return false;
+        };
+        self.jump_to(marker.time, current_track_pos);
+        true
+    }
+
+    /// Play from the start again, from the moment the playback clock reaches `current_track_pos`.
+    pub fn restart(&mut self, current_track_pos: Seconds) {
+        self.jump_to(MusicTime::zero(), current_track_pos);
+    }
+
+    fn jump_to(&mut self, time: MusicTime, current_track_pos: Seconds) {
+        self.time_offset = time.to_seconds(self.time_signature, self.bpm) - current_track_pos;
+        for (_track, cursor) in &mut self.tracks {
+            *cursor = time;
+        }
+    }
+
+    /// Where the music being heard is, with the playback clock at `current_track_pos`.
+    pub fn progress(&self, current_track_pos: Seconds) -> Progress {
+        let (time_signature, bpm) = (self.time_signature, self.bpm);
+        let mut music_s = (current_track_pos + self.time_offset).max(0.);
+        let loop_s = self.loop_time.to_seconds(time_signature, bpm);
+        let mut iteration = 0;
+        if self.looped && loop_s > 0. {
+            iteration = (music_s / loop_s).floor() as usize;
+            music_s -= iteration as Seconds * loop_s;
+        }
+        Progress {
+            elapsed: current_track_pos,
+            time: MusicTime::from_seconds(time_signature, bpm, music_s),
+            iteration,
+            queued: 0,
+            spectrum: None,
+        }
+    }
+
+    /// The last marker playback has passed, to show which part of the music is playing.
+    pub fn current_marker(&self, current_track_pos: Seconds) -> Option<&Marker> {
+        let now = MusicTime::from_seconds(self.time_signature, self.bpm, current_track_pos + self.time_offset);
+        self.markers.iter()
+            .filter(|m| m.time <= now)
+            .max_by_key(|m| m.time)
+    }
+
+    /// Play for `duration` seconds on a virtual clock, ticking every `tick` seconds like playback
+    /// would, and return everything that was handed out. Runs as fast as the computer allows,
+    /// for rendering and for checking what playback would do without waiting for it.
+    pub fn simulate(&mut self, duration: Seconds, tick: Seconds) -> Vec<ScheduledSound> {
+        let clock = VirtualClock::default();
+        let mut sounds = Vec::new();
+        for i in 1..=(duration / tick) as u64 {
+            if self.ended() {
+                break;
+            }
+            self.fill_next_events(clock.elapsed(), &mut sounds);
+            // sleep up to the next tick rather than by `tick`, so rounding doesn't add up
+            clock.sleep(i as Seconds * tick - clock.elapsed());
+        }
+        sounds
+    }
+
     /// get the next events and update the cursors if necessary
     pub fn get_next_events_and_update(&mut self, current_track_pos: Seconds) -> Vec<ScheduledSound> {
-        let mut current_music_time = MusicTime::from_seconds(self.time_signature, self.bpm, current_track_pos);
+        let mut sounds = Vec::new();
+        self.fill_next_events(current_track_pos, &mut sounds);
+        sounds
+    }
+
+    /// Call `hook` with every note as it is handed out.
+    pub fn on_note(&mut self, hook: impl FnMut(&ScheduledSound) + Send + 'static) {
+        self.hooks.on_note.push(Box::new(hook));
+    }
+
+    /// Call `hook` with where each beat is when it starts being heard.
+    pub fn on_beat(&mut self, hook: impl FnMut(MusicTime) + Send + 'static) {
+        self.hooks.on_beat.push(Box::new(hook));
+    }
+
+    /// Call `hook` with each bar when it starts being heard.
+    pub fn on_bar(&mut self, hook: impl FnMut(Measure) + Send + 'static) {
+        self.hooks.on_bar.push(Box::new(hook));
+    }
+
+    /// Call `hook` with how many times the loop has been played through whenever it starts over.
+    pub fn on_loop(&mut self, hook: impl FnMut(usize) + Send + 'static) {
+        self.hooks.on_loop.push(Box::new(hook));
+    }
+
+    /// Call the beat, bar and loop hooks for whatever started being heard since they last were.
+    fn fire_hooks(&mut self, current_track_pos: Seconds) {
+        let Progress { time: MusicTime(measure, beat), iteration, .. } = self.progress(current_track_pos);
+        let now = (iteration, measure, beat.numerator() / beat.denominator());
+        let hooks = &mut self.hooks;
+        if hooks.last == Some(now) {
+            return;
+        }
+        let last = hooks.last.replace(now);
+        if last.is_some_and(|(last_iteration, ..)| last_iteration != iteration) {
+            hooks.on_loop.iter_mut().for_each(|hook| hook(iteration));
+        }
+        if last.is_none_or(|(last_iteration, last_measure, _)| (last_iteration, last_measure) != (iteration, measure)) {
+            hooks.on_bar.iter_mut().for_each(|hook| hook(measure));
+        }
+        hooks.on_beat.iter_mut().for_each(|hook| hook(MusicTime(measure, Beat::whole(now.2))));
+    }
+
+    /// Carry out `command`, with the playback clock at `current_track_pos`.
+    pub fn apply(&mut self, command: SchedulerCommand, current_track_pos: Seconds) {
+        match command {
+            SchedulerCommand::SetComposition(composition) => self.set_composition(composition),
+            SchedulerCommand::QueueComposition(composition, policy) => self.queue_composition(composition, policy),
+            SchedulerCommand::JumpToMarker(name) => {
+                if !self.jump_to_marker(&name, current_track_pos) {
+                    warn!(marker = %name, "No marker with that name to jump to");
+                }
+            }
+            SchedulerCommand::SetBpm(bpm) => self.bpm = bpm,
+            SchedulerCommand::SetLooped(looped) => self.looped = looped,
+            SchedulerCommand::LaunchTrack(track, quantize) => self.launch_track(track, quantize),
+            SchedulerCommand::StopTrack(track, quantize) => self.stop_track(track, quantize),
+            SchedulerCommand::Restart => self.restart(current_track_pos),
+        }
+    }
+
+    /// Replace the composition that is playing once `policy` allows, without stopping.
+    /// The new one starts from its beginning and, if looping, loops over its own length.
+    pub fn queue_composition(&mut self, composition: Composition, policy: SwapPolicy) {
+        self.queued = Some((composition, policy));
+    }
+
+    /// Where in the music playing now the queued composition should start.
+    fn swap_time(&self, policy: SwapPolicy) -> MusicTime {
+        match policy {
+            SwapPolicy::Immediately => self.next_boundary(None),
+            SwapPolicy::NextBar => self.next_boundary(Some(Quantize::Bar)),
+        }
+    }
+
+    /// The first beat or bar the events that were already handed out haven't reached.
+    fn next_boundary(&self, quantize: Option<Quantize>) -> MusicTime {
+        let time_signature = self.time_signature;
+        let cursor = self.tracks.iter().map(|(_t, cursor)| *cursor).max().unwrap_or(MusicTime::zero());
+        let MusicTime(measure, beat) = cursor;
+        let boundary = match quantize {
+            None => cursor,
+            Some(_) if beat == Beat::zero() => cursor,
+            Some(Quantize::Bar) => MusicTime::measures(measure + 1),
+            Some(Quantize::Beat) => {
+                let beats = beat.numerator().div_ceil(beat.denominator());
+                MusicTime::measures(measure).with(time_signature) + MusicTime::beats(beats)
+            }
+        };
+        if self.looped { boundary.min(self.loop_time) } else { boundary }
+    }
+
+    /// Start playing `track` on the next beat or bar that hasn't been handed out yet.
+    pub fn launch_track(&mut self, track: TrackId, quantize: Quantize) {
+        let at = self.next_boundary(Some(quantize));
+        self.clips.insert(track, ClipState::StartingAt(at));
+    }
+
+    /// Stop playing `track` on the next beat or bar that hasn't been handed out yet.
+    pub fn stop_track(&mut self, track: TrackId, quantize: Quantize) {
+        let at = self.next_boundary(Some(quantize));
+        self.clips.insert(track, ClipState::StoppingAt(at));
+    }
+
+    /// Same as `get_next_events_and_update`, but appends into a caller-provided buffer
+    /// so the playback loop can reuse one allocation for every tick.
+    /// Only the newly appended sounds are sorted.
+    pub fn fill_next_events(&mut self, current_track_pos: Seconds, sounds: &mut Vec<ScheduledSound>) {
+        self.fire_hooks(current_track_pos);
+        let Some((composition, policy)) = self.queued.take() else {
+            self.fill_window(current_track_pos, sounds);
+            return;
+        };
+        let (time_signature, bpm) = (self.time_signature, self.bpm);
+        let swap = self.swap_time(policy);
+        let mut now = MusicTime::from_seconds(time_signature, bpm, current_track_pos + self.output_latency + self.time_offset);
+        while self.looped && now > self.loop_time {
+            now = now.with(time_signature) - self.loop_time;
+        }
+        let until_swap = if swap >= now {
+            swap.with(time_signature) - now
+        } else {
+            // the cursors already wrapped around the loop
+            (self.loop_time.with(time_signature) - now).with(time_signature) + swap
+        };
+        if until_swap > self.lookahead {
+            self.queued = Some((composition, policy));
+            self.fill_window(current_track_pos, sounds);
+            return;
+        }
+        // finish the old composition up to the swap, then start the new one from zero at that moment
+        for (track, _cursor) in &mut self.tracks {
+            track.truncate(swap, time_signature);
+        }
+        let lookahead = self.lookahead;
+        self.lookahead = until_swap;
+        self.fill_window(current_track_pos, sounds);
+        self.lookahead = lookahead;
+        self.time_offset = -(current_track_pos + self.output_latency + until_swap.to_seconds(time_signature, bpm));
+        if self.looped {
+            self.loop_time = composition.get_duration();
+        }
+        self.set_composition(composition);
+        self.fill_window(current_track_pos, sounds);
+    }
+
+    fn fill_window(&mut self, current_track_pos: Seconds, sounds: &mut Vec<ScheduledSound>) {
+        let clock_time = current_track_pos;
+        // events are timed on the playback clock, which runs behind the music after a jump
+        let latency = self.output_latency + self.time_offset;
+        // what is being heard right now was sent `latency` ago
+        let current_track_pos = current_track_pos + latency;
+        let time_signature = self.time_signature;
+        let bpm = self.bpm;
+        let looped = self.looped;
+        let mut current_music_time = MusicTime::from_seconds(time_signature, bpm, current_track_pos);
         let loop_end = self.loop_time;
-        while self.looped && current_music_time > loop_end {
-            current_music_time = current_music_time.with(self.time_signature) - loop_end;
+        // how many times round the loop the music has been
+        let mut iteration = 0;
+        while looped && current_music_time > loop_end {
+            current_music_time = current_music_time.with(time_signature) - loop_end;
+            iteration += 1;
         }
-        let loop_time_s = self.loop_time.to_seconds(self.time_signature, self.bpm);
-        let mut end_music_time = current_music_time.with(self.time_signature) + self.lookahead;
-        let end_non_looped = end_music_time;
-        let looping = if self.looped && end_music_time > loop_end {
+        let loop_time_s = self.loop_time.to_seconds(time_signature, bpm);
+        let mut end_music_time = current_music_time.with(time_signature) + self.lookahead;
+        let looping = if looped && end_music_time > loop_end {
             while end_music_time > loop_end {
-                end_music_time = end_music_time.with(self.time_signature) - loop_end;
+                end_music_time = end_music_time.with(time_signature) - loop_end;
             }
             true
         } else {
             false
         };
-        let mut sounds = self.tracks.iter_mut()
-            .flat_map(|(track, cursor)| {
-                let be_exclusive = false; // *cursor != MusicTime::zero();
-                let events = if looping {
-                    // if end_non_looped < *cursor {
-                    //     vec![]
-                    // } else
-                    // if *cursor <= end_music_time {
-                    //     track.get_events_starting_between(*cursor, end_music_time, be_exclusive)
-                    // } else {
-                        let mut to_end = track.get_events_starting_between(*cursor, loop_end, be_exclusive);
-                        let from_beg = track.get_events_starting_between(MusicTime::zero(), end_music_time, false);
-                        to_end.extend(from_beg);
-                        to_end
-                    // }
-                } else {
-                    track.get_events_starting_between(*cursor, end_music_time, be_exclusive)
-                };
-                *cursor = end_music_time;
-                // make sure looped sounds happen afterward
-                events.into_iter()
-                    .map(|e| {
-                        let start = e.start.to_seconds(self.time_signature, self.bpm);
-                        let duration = e.duration.as_music_time(self.time_signature).to_seconds(self.time_signature, self.bpm) * 0.9;
-                        let volume = e.volume;
-                        let instrument = track.instrument;
-                        ScheduledSound {
-                            time: start,
-                            duration,
-                            volume,
-                            instrument,
-                            pitch: e.pitch,
-                        }
-                    })
-                    .map(|mut se| {
-                        if self.looped {
-                            while se.time < current_track_pos {
-                                se.time += loop_time_s;
-                            }
-                        }
-                        se
-                    }).collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>();
-        sounds.sort_by(|a: &ScheduledSound, b: &ScheduledSound| a.partial_cmp(b).unwrap());
-        sounds
+        // the time round the loop the end of the window is in
+        let end_iteration = iteration + looping as u32;
+        let first_new = sounds.len();
+        for i in 0..self.tracks.len() {
+            let pan = self.panning.pan_for(&self.tracks, i);
+            let (track, cursor) = &mut self.tracks[i];
+            let instrument = track.instrument;
+            let modulation = self.modulation.get(&instrument).copied().unwrap_or_default();
+            let to_sound = |iteration: u32| move |e: &Event| {
+                let start = e.start.to_seconds(time_signature, bpm) + iteration as Seconds * loop_time_s;
+                let duration = e.duration.as_music_time(time_signature).to_seconds(time_signature, bpm) * 0.9;
+                ScheduledSound {
+                    time: start - latency,
+                    duration,
+                    volume: e.volume,
+                    instrument,
+                    pitch: e.pitch,
+                    pan,
+                    modulation: e.modulation.or(modulation),
+                    channel: e.channel,
+                    tag: e.tag,
+                    lyric: e.lyric,
+                }
+            };
+            let clip = self.clips.get(&track.identifier).copied().unwrap_or_default();
+            if looped && *cursor > end_music_time {
+                // the loop ended since the cursor, on this tick or, after a stall, on one that never came
+                sounds.extend(track.iter_events_starting_in(*cursor, loop_end)
+                    .filter(|e| clip.plays_at(e.start))
+                    .map(to_sound(end_iteration.saturating_sub(1))));
+                // a start or stop is never later than the end of the loop
+                sounds.extend(track.iter_events_starting_in(MusicTime::zero(), end_music_time)
+                    .filter(|e| clip.settled().plays_at(e.start))
+                    .map(to_sound(end_iteration)));
+            } else if looping {
+                // the window already went round the loop on an earlier tick, and took the start of it along
+                sounds.extend(track.iter_events_starting_in(*cursor, end_music_time)
+                    .filter(|e| clip.settled().plays_at(e.start))
+                    .map(to_sound(end_iteration)));
+            } else {
+                sounds.extend(track.iter_events_starting_in(*cursor, end_music_time)
+                    .filter(|e| clip.plays_at(e.start))
+                    .map(to_sound(end_iteration)));
+            }
+            *cursor = end_music_time;
+            if clip.switch_time().is_some_and(|at| looping || at <= end_music_time) {
+                self.clips.insert(track.identifier, clip.settled());
+            }
+        }
+        sounds[first_new..].sort_unstable_by(ScheduledSound::total_cmp);
+        self.handle_late(clock_time, sounds, first_new);
+        for sound in &sounds[first_new..] {
+            self.hooks.on_note.iter_mut().for_each(|hook| hook(sound));
+        }
+    }
+
+    /// Apply the late policy to the sounds from `first_new` on, which are in order.
+    fn handle_late(&mut self, now: Seconds, sounds: &mut Vec<ScheduledSound>, first_new: usize) {
+        let late = sounds[first_new..].iter().take_while(|s| s.time < now - LATE_TOLERANCE).count();
+        if late == 0 {
+            return;
+        }
+        match self.late.policy {
+            LatePolicy::PlayLate => self.late.played_late += late,
+            LatePolicy::Drop => {
+                sounds.drain(first_new..first_new + late);
+                self.late.dropped += late;
+            }
+            LatePolicy::Compress { over } => {
+                let first = sounds[first_new].time;
+                let squeeze = over / (now - first);
+                for sound in &mut sounds[first_new..first_new + late] {
+                    sound.time = now + (sound.time - first) * squeeze;
+                }
+                // the squeezed ones can end up after on-time sounds that come next
+                sounds[first_new..].sort_by(ScheduledSound::total_cmp);
+                self.late.compressed += late;
+            }
+        }
+    }
+}
+
+impl ClipState {
+    pub fn plays_at(&self, time: MusicTime) -> bool {
+        match self {
+            ClipState::Playing => true,
+            ClipState::Stopped => false,
+            ClipState::StartingAt(at) => time >= *at,
+            ClipState::StoppingAt(at) => time < *at,
+        }
+    }
+
+    fn switch_time(&self) -> Option<MusicTime> {
+        match self {
+            ClipState::StartingAt(at) | ClipState::StoppingAt(at) => Some(*at),
+            ClipState::Playing | ClipState::Stopped => None,
+        }
+    }
+
+    /// The state once the pending start or stop has happened.
+    fn settled(self) -> ClipState {
+        match self {
+            ClipState::StartingAt(_) => ClipState::Playing,
+            ClipState::StoppingAt(_) => ClipState::Stopped,
+            state => state,
+        }
+    }
+}
+
+impl ScheduledSound {
+    /// When it should start on the playback clock.
+    pub fn start(&self) -> Seconds {
+        self.time
+    }
+
+    pub fn duration(&self) -> Seconds {
+        self.duration
+    }
+
+    pub fn pitch(&self) -> Pitch {
+        self.pitch
+    }
+
+    /// Total ordering by time, then duration, volume, instrument and pitch.
+    /// Times are never NaN in practice, but `total_cmp` keeps sorting from panicking if they are.
+    pub fn total_cmp(&self, other: &Self) -> Ordering {
+        self.time.total_cmp(&other.time)
+            .then_with(|| self.duration.total_cmp(&other.duration))
+            .then_with(|| self.volume.cmp(&other.volume))
+            .then_with(|| self.instrument.cmp(&other.instrument))
+            .then_with(|| self.pitch.cmp(&other.pitch))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::composition::{Composition, Event, Instrument, Pitch, Track, TrackId, Volume};
-    use crate::scheduler::{ScheduledSound, Scheduler};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use crate::builder::CompositionBuilder;
+    use crate::composition::{Composition, Event, Instrument, Lfo, Marker, Modulation, Pitch, Track, TrackId, Volume};
+    use crate::scheduler::{ClipState, LatePolicy, Panning, Quantize, Scheduler, SchedulerCommand, SwapPolicy};
+    use crate::testing::event;
     use crate::time::{Beat, Measure, MusicTime, Seconds, TimeSignature};
 
     fn comp_template(events: Vec<Event>) -> Composition {
         Composition {
             tracks: vec![
-                Track {
-                    identifier: TrackId::Custom(0),
-                    instrument: Instrument::SineWave,
-                    events,
-                    rests: vec![],
-                }
+                Track::new(TrackId::Custom(0), Instrument::SineWave, events, vec![])
             ],
             time_signature: TimeSignature::common(),
+            markers: vec![],
         }
     }
 
-    fn simulate_play_collect_events(
-        mut scheduler: Scheduler,
-        duration: Seconds,
-        interval: Seconds,
-    ) -> Vec<ScheduledSound> {
-        let mut emitted_sounds = vec![];
-        for i in (0..(duration / interval) as u64) {
-            let elapsed = i as Seconds * interval;
-            let sounds = scheduler.get_next_events_and_update(elapsed);
-            emitted_sounds.extend(sounds);
-        }
-        emitted_sounds
-    }
     #[test]
     fn test_scheduler_1() {
         let comp = comp_template(vec![
-            Event {
-                start: MusicTime(0, Beat::whole(0)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            },
-            Event {
-                start: MusicTime(0, Beat::whole(1)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 1),
-            },
-            Event {
-                start: MusicTime(0, Beat::whole(2)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 2),
-            },
-            Event {
-                start: MusicTime(0, Beat::whole(3)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 3),
-            }
+            event(Pitch(4, 0), MusicTime(0, Beat::whole(0)), Beat::whole(1)),
+            event(Pitch(4, 1), MusicTime(0, Beat::whole(1)), Beat::whole(1)),
+            event(Pitch(4, 2), MusicTime(0, Beat::whole(2)), Beat::whole(1)),
+            event(Pitch(4, 3), MusicTime(0, Beat::whole(3)), Beat::whole(1))
         ]);
-        let mut scheduler = Scheduler {
-            bpm: 120.0,
-            time_signature: TimeSignature::common(),
-            tracks: vec![],
-            lookahead: MusicTime::measures(1),
-            looped: false,
-            loop_time: MusicTime::measures(4),
-        };
-        scheduler.set_composition(comp);
-        let sounds = simulate_play_collect_events(scheduler, 5.0, 0.05);
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        let sounds = scheduler.simulate(5.0, 0.05);
         assert_eq!(sounds.len(), 4);
         assert_eq!(sounds.iter().map(|s| s.pitch).collect::<Vec<_>>(),
                    vec![Pitch(4, 0), Pitch(4, 1), Pitch(4, 2), Pitch(4, 3)]);
     }
+    #[test]
+    fn test_late_policy() {
+        // the scheduler thread was held up until 1.2s, when three of these were due
+        let late_fill = |policy: LatePolicy| {
+            let mut builder = CompositionBuilder::track(Instrument::Piano);
+            for i in 0..4 {
+                builder = builder.note(Pitch(4, i), MusicTime::beats(i as u32), Beat::whole(1), Volume(50));
+            }
+            let mut scheduler = Scheduler::new(builder.build().unwrap(), 120.0);
+            scheduler.late.policy = policy;
+            let mut sounds = vec![];
+            scheduler.fill_next_events(1.2, &mut sounds);
+            (sounds.iter().map(|s| (s.time, s.pitch.1)).collect::<Vec<_>>(), scheduler.late)
+        };
+
+        let (sounds, late) = late_fill(LatePolicy::PlayLate);
+        assert_eq!(sounds, vec![(0., 0), (0.5, 1), (1., 2), (1.5, 3)]);
+        assert_eq!(late.played_late, 3);
+        let (sounds, late) = late_fill(LatePolicy::Drop);
+        assert_eq!(sounds, vec![(1.5, 3)]);
+        assert_eq!(late.dropped, 3);
+        let (sounds, late) = late_fill(LatePolicy::Compress { over: 0.12 });
+        let times = sounds.iter().map(|(t, _)| (t * 100.).round() / 100.).collect::<Vec<_>>();
+        assert_eq!(times, vec![1.2, 1.25, 1.3, 1.5]);
+        assert_eq!(late.compressed, 3);
+    }
+
     #[test]
     fn test_scheduler_2() {
         let comp = comp_template(vec![
-            Event {
-                start: MusicTime(0, Beat::whole(0)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 0),
-            },
-            Event {
-                start: MusicTime(0, Beat::whole(3)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 3),
-            },
-            Event {
-                start: MusicTime(0, Beat::whole(2)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 2),
-            },
-            Event {
-                start: MusicTime(0, Beat::whole(1)),
-                duration: Beat::whole(1),
-                volume: Volume(100),
-                pitch: Pitch(4, 1),
-            }
+            event(Pitch(4, 0), MusicTime(0, Beat::whole(0)), Beat::whole(1)),
+            event(Pitch(4, 3), MusicTime(0, Beat::whole(3)), Beat::whole(1)),
+            event(Pitch(4, 2), MusicTime(0, Beat::whole(2)), Beat::whole(1)),
+            event(Pitch(4, 1), MusicTime(0, Beat::whole(1)), Beat::whole(1))
         ]);
-        let mut scheduler = Scheduler {
-            bpm: 120.0,
-            time_signature: TimeSignature::common(),
-            tracks: vec![],
-            lookahead: MusicTime::measures(1),
-            looped: false,
-            loop_time: MusicTime::measures(4),
-        };
-        scheduler.set_composition(comp);
-        let sounds = simulate_play_collect_events(scheduler, 5.0, 0.05);
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        let sounds = scheduler.simulate(5.0, 0.05);
         assert_eq!(sounds.len(), 4);
         assert_eq!(sounds.iter().map(|s| s.pitch).collect::<Vec<_>>(),
                    vec![Pitch(4, 0), Pitch(4, 1), Pitch(4, 2), Pitch(4, 3)]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_looped_window_sends_loop_start_once() {
+        // a note at the start of a one measure loop, with the window a beat ahead
+        let comp = comp_template(vec![
+            event(Pitch(4, 0), MusicTime::zero(), Beat::whole(1))
+        ]);
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        scheduler.looped = true;
+        scheduler.loop_time = MusicTime::measures(1);
+        // once every time round, even though most ticks see a window past the loop end
+        let times = scheduler.simulate(5.0, 0.05).iter().map(|s| s.time).collect::<Vec<_>>();
+        assert_eq!(times, vec![0., 2., 4.]);
+    }
+
+    #[test]
+    fn test_fill_next_events_reuses_buffer() {
+        let comp = comp_template(vec![
+            event(Pitch(4, 2), MusicTime(0, Beat::whole(2)), Beat::whole(1)),
+            event(Pitch(4, 0), MusicTime(0, Beat::whole(0)), Beat::whole(1)),
+        ]);
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        let mut sounds = Vec::with_capacity(8);
+        scheduler.fill_next_events(0.0, &mut sounds);
+        assert_eq!(sounds.iter().map(|s| s.pitch).collect::<Vec<_>>(),
+                   vec![Pitch(4, 0), Pitch(4, 2)]);
+        sounds.clear();
+        scheduler.fill_next_events(0.05, &mut sounds);
+        assert!(sounds.is_empty());
+        assert_eq!(sounds.capacity(), 8);
+    }
+
+    // timing check for many-track compositions, run with `cargo test -- --ignored --nocapture`
+    #[ignore]
+    #[test]
+    fn bench_many_tracks() {
+        let tracks = (0..128)
+            .map(|t| Track::new(
+                TrackId::Custom(t),
+                Instrument::SineWave,
+                (0..256)
+                    .map(|i| event(Pitch(4, (i % 12) as u8), MusicTime::from_whole_beats(TimeSignature::common(), i), Beat::whole(1)))
+                    .collect(),
+                vec![],
+            ))
+            .collect();
+        let mut scheduler = Scheduler::new(Composition { tracks, time_signature: TimeSignature::common(), markers: vec![] }, 120.0);
+        scheduler.looped = true;
+        scheduler.loop_time = MusicTime::measures(64);
+        let mut sounds = Vec::new();
+        let start = std::time::Instant::now();
+        let ticks = 2000;
+        for i in 0..ticks {
+            sounds.clear();
+            scheduler.fill_next_events(i as Seconds * 0.05, &mut sounds);
+        }
+        println!("{ticks} ticks over 128 tracks took {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_output_latency_sends_events_early() {
+        let comp = comp_template(vec![
+            event(Pitch(4, 2), MusicTime(0, Beat::whole(2)), Beat::whole(1)),
+        ]);
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        scheduler.output_latency = 0.5;
+        // beat 2 is at 1s, which is within a beat of 0.2s + 0.5s latency
+        let sounds = scheduler.get_next_events_and_update(0.2);
+        assert_eq!(sounds.len(), 1);
+        assert!((sounds[0].time - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_auto_spread_panning() {
+        let track = |instrument| (Track::new(TrackId::Instrument(instrument), instrument, vec![], vec![]), MusicTime::zero());
+        let tracks = vec![
+            track(Instrument::Snare),
+            track(Instrument::Piano),
+            track(Instrument::Bass),
+            track(Instrument::Piano),
+        ];
+        let pans = (0..tracks.len())
+            .map(|i| Panning::AutoSpread.pan_for(&tracks, i))
+            .collect::<Vec<_>>();
+        assert_eq!(pans, vec![1., -1., 0., -1.]);
+        assert_eq!(Panning::Center.pan_for(&tracks, 0), 0.);
+    }
+
+    #[test]
+    fn test_instrument_modulation_fills_in_for_notes() {
+        let vibrato = Modulation { vibrato: Lfo::new(5., 0.3), ..Modulation::NONE };
+        let tremolo = Modulation { tremolo: Lfo::new(4., 0.5), ..Modulation::NONE };
+        let comp = comp_template(vec![
+            event(Pitch(4, 0), MusicTime::zero(), Beat::whole(1)),
+            Event { modulation: tremolo, ..event(Pitch(4, 2), MusicTime::beats(1), Beat::whole(1)) },
+        ]);
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        scheduler.lookahead = MusicTime::beats(2);
+        scheduler.modulation = HashMap::from([(Instrument::SineWave, vibrato)]);
+        let sounds = scheduler.get_next_events_and_update(0.);
+        assert_eq!(sounds.len(), 2);
+        assert_eq!(sounds[0].modulation, vibrato);
+        assert_eq!(sounds[1].modulation, Modulation { vibrato: vibrato.vibrato, tremolo: tremolo.tremolo });
+    }
+
+    #[test]
+    fn test_jump_to_marker() {
+        let note = |beat| event(Pitch(4, beat as u8), MusicTime(0, Beat::whole(beat)), Beat::whole(1));
+        let mut comp = comp_template(vec![note(0), note(1), note(2), note(3)]);
+        comp.markers.push(Marker { name: "end".to_string(), time: MusicTime::beats(3) });
+        let mut scheduler = Scheduler::new(comp, 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        assert!(!scheduler.jump_to_marker("start", 0.));
+        assert!(scheduler.current_marker(0.).is_none());
+        assert!(scheduler.jump_to_marker("end", 0.2));
+        assert_eq!(scheduler.current_marker(0.2).map(|m| m.name.as_str()), Some("end"));
+        // beat 3 is at 1.5s in the music, which is 0.2s on the playback clock
+        let sounds = scheduler.get_next_events_and_update(0.2);
+        assert_eq!(sounds.len(), 1);
+        assert_eq!(sounds[0].pitch, Pitch(4, 3));
+        assert!((sounds[0].time - 0.2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_queue_composition_swaps_on_next_bar() {
+        let note = |beat, pitch| event(Pitch(4, pitch), MusicTime::from_whole_beats(TimeSignature::common(), beat), Beat::whole(1));
+        let mut scheduler = Scheduler::new(comp_template((0..8).map(|b| note(b, 0)).collect()), 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        let mut sounds = vec![];
+        for tick in 0..40 {
+            let elapsed = tick as Seconds * 0.1;
+            if tick == 6 {
+                scheduler.queue_composition(comp_template(vec![note(0, 5), note(1, 5)]), SwapPolicy::NextBar);
+            }
+            scheduler.fill_next_events(elapsed, &mut sounds);
+        }
+        // the old composition plays until the second bar starts at 2s, where the new one takes over
+        let mut heard = sounds.iter().map(|s| ((s.time * 10.).round() as u32, s.pitch.1)).collect::<Vec<_>>();
+        heard.dedup();
+        assert_eq!(heard, vec![(0, 0), (5, 0), (10, 0), (15, 0), (20, 5), (25, 5)]);
+        assert!(scheduler.queued.is_none());
+    }
+
+    #[test]
+    fn test_apply_commands() {
+        let mut scheduler = Scheduler::new(comp_template(vec![]), 120.0);
+        scheduler.apply(SchedulerCommand::SetBpm(90.), 0.);
+        scheduler.apply(SchedulerCommand::SetLooped(true), 0.);
+        scheduler.apply(SchedulerCommand::SetComposition(comp_template(vec![])), 0.);
+        scheduler.apply(SchedulerCommand::QueueComposition(comp_template(vec![]), SwapPolicy::NextBar), 0.);
+        assert_eq!(scheduler.bpm, 90.);
+        assert!(scheduler.looped);
+        assert_eq!(scheduler.tracks.len(), 1);
+        assert!(matches!(scheduler.queued, Some((_, SwapPolicy::NextBar))));
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut scheduler = Scheduler::new(comp_template(vec![]), 120.0);
+        scheduler.looped = true;
+        scheduler.loop_time = MusicTime::measures(2);
+        // two bars at 120 bpm loop every 4s
+        let progress = scheduler.progress(9.);
+        assert_eq!(progress.elapsed, 9.);
+        assert_eq!(progress.time, MusicTime::beats(2));
+        assert_eq!(progress.iteration, 2);
+        scheduler.looped = false;
+        assert_eq!(scheduler.progress(9.).time, MusicTime(4, Beat::whole(2)));
+        assert_eq!(scheduler.progress(9.).iteration, 0);
+    }
+
+    #[test]
+    fn test_quantized_track_launching() {
+        let note = |beat| event(Pitch(4, 0), MusicTime::from_whole_beats(TimeSignature::common(), beat), Beat::whole(1));
+        let mut scheduler = Scheduler::new(comp_template((0..8).map(note).collect()), 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        let track = TrackId::Custom(0);
+        let mut sounds = vec![];
+        for tick in 0..40 {
+            match tick {
+                1 => scheduler.stop_track(track, Quantize::Bar),
+                27 => scheduler.launch_track(track, Quantize::Beat),
+                _ => {}
+            }
+            scheduler.fill_next_events(tick as Seconds * 0.1, &mut sounds);
+            if tick == 1 {
+                assert_eq!(scheduler.clips[&track], ClipState::StoppingAt(MusicTime::measures(1)));
+            }
+        }
+        // stopped at the second bar, at 2s, and started again on the beat at 3.5s
+        let mut heard = sounds.iter().map(|s| (s.time * 10.).round() as u32).collect::<Vec<_>>();
+        heard.dedup();
+        assert_eq!(heard, vec![0, 5, 10, 15, 35]);
+        assert_eq!(scheduler.clips[&track], ClipState::Playing);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let note = |beat| event(Pitch(4, beat as u8), MusicTime::from_whole_beats(TimeSignature::common(), beat), Beat::whole(1));
+        let mut scheduler = Scheduler::new(comp_template((0..4).map(note).collect()), 120.0);
+        scheduler.lookahead = MusicTime::beats(1);
+        scheduler.looped = true;
+        scheduler.loop_time = MusicTime::measures(1);
+        let heard = Arc::new(Mutex::new(Vec::new()));
+        let log = |heard: &Arc<Mutex<Vec<String>>>, prefix: &'static str| {
+            let heard = heard.clone();
+            move |s: String| heard.lock().unwrap().push(format!("{prefix}{s}"))
+        };
+        let bar = log(&heard, "bar ");
+        scheduler.on_bar(move |measure| bar(measure.to_string()));
+        let beats = log(&heard, "beat ");
+        scheduler.on_beat(move |MusicTime(_, beat)| beats(beat.numerator().to_string()));
+        let looped = log(&heard, "loop ");
+        scheduler.on_loop(move |iteration| looped(iteration.to_string()));
+        let notes = Arc::new(Mutex::new(Vec::new()));
+        let noted = notes.clone();
+        scheduler.on_note(move |sound| noted.lock().unwrap().push(sound.pitch().1));
+
+        // two times through the loop, a bar of four beats every 2s, and into the third time
+        scheduler.simulate(4.45, 0.1);
+        let heard = heard.lock().unwrap().join(", ");
+        assert_eq!(heard, "bar 0, beat 0, beat 1, beat 2, beat 3, loop 1, bar 0, beat 0, beat 1, beat 2, beat 3, loop 2, bar 0, beat 0");
+        let mut notes = notes.lock().unwrap().clone();
+        notes.dedup();
+        assert_eq!(&notes[..10], &[0, 1, 2, 3, 0, 1, 2, 3, 0, 1]);
+    }
+}
@@ -0,0 +1,215 @@
+// What frequencies are in the master output, for spectrum displays that move with the music.
+// The audio thread only copies samples into a buffer; the FFT is done by whoever reads the tap,
+// which is the scheduler thread once per tick when the spectrum goes out with the progress.
+
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use num::Complex;
+use rodio::Source;
+
+/// How loud every frequency was in the last stretch of output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectrumFrame {
+    /// Amplitude of each bin from 0Hz up to half the sample rate, 1 being a full-scale sine.
+    pub magnitudes: Vec<f32>,
+    /// Hz between one bin and the next.
+    pub bin_width: f32,
+}
+
+/// Where the master output's samples are left for the spectrum. Clones share the same buffer.
+/// It is off until it is given a size.
+#[derive(Debug, Clone, Default)]
+pub struct SpectrumTap(Arc<TapState>);
+
+#[derive(Debug, Default)]
+struct TapState {
+    /// Samples per frame, 0 while off.
+    size: AtomicUsize,
+    latest: Mutex<Latest>,
+}
+
+#[derive(Debug, Default)]
+struct Latest {
+    /// Mixed down to one channel.
+    samples: Vec<f32>,
+    sample_rate: u32,
+    /// Not turned into a frame yet.
+    fresh: bool,
+}
+
+impl SpectrumTap {
+    /// Take frames of `size` samples, rounded up to a power of two, from now on.
+    pub fn enable(&self, size: usize) {
+        self.0.size.store(size.max(2).next_power_of_two(), Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.0.size.store(0, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.size.load(Ordering::Relaxed) > 0
+    }
+
+    /// The spectrum of the samples that came through since the last frame was taken, if a
+    /// whole frame of them has.
+    pub fn frame(&self) -> Option<SpectrumFrame> {
+        let (samples, sample_rate) = {
+            let mut latest = self.0.latest.lock().unwrap();
+            if !latest.fresh {
+                return None;
+            }
+            latest.fresh = false;
+            (latest.samples.clone(), latest.sample_rate)
+        };
+        Some(SpectrumFrame {
+            bin_width: sample_rate as f32 / samples.len() as f32,
+            magnitudes: magnitudes(&samples),
+        })
+    }
+}
+
+/// In-place radix-2 FFT. `values.len()` has to be a power of two.
+fn fft(values: &mut [Complex<f32>]) {
+    let n = values.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let step = Complex::from_polar(1., -2. * PI / len as f32);
+        for chunk in values.chunks_exact_mut(len) {
+            let mut w = Complex::new(1., 0.);
+            let (low, high) = chunk.split_at_mut(len / 2);
+            for (a, b) in low.iter_mut().zip(high) {
+                let t = *b * w;
+                *b = *a - t;
+                *a += t;
+                w *= step;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Amplitudes of the frequencies in `samples`, through a Hann window so that a note between
+/// two bins doesn't smear over all of them.
+pub fn magnitudes(samples: &[f32]) -> Vec<f32> {
+    let n = samples.len();
+    let window = |i: usize| 0.5 - 0.5 * (2. * PI * i as f32 / n as f32).cos();
+    let mut values = samples.iter().enumerate()
+        .map(|(i, &s)| Complex::new(s * window(i), 0.))
+        .collect::<Vec<_>>();
+    fft(&mut values);
+    // a sine shows up in two mirrored bins, each with half the window's sum
+    let scale = 2. / (0..n).map(window).sum::<f32>();
+    values[..=n / 2].iter().map(|v| v.norm() * scale).collect()
+}
+
+/// Passes a source through unchanged, leaving a copy of its samples in a `SpectrumTap` while the tap is on.
+pub struct FftTap<S> {
+    input: S,
+    tap: SpectrumTap,
+    buffer: Vec<f32>,
+    /// The channels of the current frame, added up.
+    frame: f32,
+    channel: u16,
+}
+
+impl<S> FftTap<S> {
+    pub fn new(input: S, tap: SpectrumTap) -> Self {
+        FftTap { input, tap, buffer: vec![], frame: 0., channel: 0 }
+    }
+}
+
+impl<S> Iterator for FftTap<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        let size = self.tap.0.size.load(Ordering::Relaxed);
+        if size == 0 {
+            return Some(sample);
+        }
+        let channels = self.input.channels().max(1);
+        self.frame += sample;
+        self.channel += 1;
+        if self.channel >= channels {
+            self.buffer.push(self.frame / channels as f32);
+            (self.frame, self.channel) = (0., 0);
+        }
+        if self.buffer.len() >= size {
+            // the audio thread never waits on a reader; the frame is skipped instead
+            if let Ok(mut latest) = self.tap.0.latest.try_lock() {
+                std::mem::swap(&mut latest.samples, &mut self.buffer);
+                latest.sample_rate = self.input.sample_rate();
+                latest.fresh = true;
+            }
+            self.buffer.clear();
+        }
+        Some(sample)
+    }
+}
+
+impl<S> Source for FftTap<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use rodio::Source;
+    use rodio::source::SineWave;
+    use crate::spectrum::{FftTap, SpectrumTap};
+
+    #[test]
+    fn test_spectrum_finds_the_note() {
+        let tap = SpectrumTap::default();
+        let sine = SineWave::new(1000.).amplify(0.5).take_duration(Duration::from_millis(100));
+        assert_eq!(FftTap::new(sine.clone(), tap.clone()).count(), sine.clone().count());
+        assert_eq!(tap.frame(), None);
+
+        tap.enable(1000);
+        FftTap::new(sine, tap.clone()).for_each(drop);
+        let frame = tap.frame().unwrap();
+        assert_eq!(frame.magnitudes.len(), 513);
+        assert_eq!(frame.bin_width, 48000. / 1024.);
+        let loudest = (0..frame.magnitudes.len()).max_by(|&a, &b| frame.magnitudes[a].total_cmp(&frame.magnitudes[b])).unwrap();
+        assert_eq!(loudest, (1000. / frame.bin_width).round() as usize);
+        assert!((frame.magnitudes[loudest] - 0.5).abs() < 0.2, "{}", frame.magnitudes[loudest]);
+        assert!(frame.magnitudes[loudest + 10] < 0.01);
+        // taken already
+        assert_eq!(tap.frame(), None);
+    }
+}
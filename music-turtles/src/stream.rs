@@ -0,0 +1,219 @@
+// Sending a composition to clients a bar at a time as it is expanded, for pieces that are too long
+// to send up front or never end. Every bar is a `Chunk` with a sequence number. The sender keeps
+// the last few so a client that missed some can ask for them again.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use crate::composition::{Composition, Event, Marker, Track};
+use crate::encoding::{decode_composition, encode_composition, DecodeError, Reader, Writer};
+use crate::time::{Measure, MusicTime, TimeSignature};
+
+/// How many chunks a `ChunkStream` keeps for resending, unless it is told otherwise.
+pub const DEFAULT_HISTORY: usize = 256;
+
+/// One bar of the piece.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    /// Counts up by one for every chunk, from zero.
+    pub seq: u64,
+    /// Where the bar goes in the whole piece.
+    pub measure: Measure,
+    /// What starts in the bar, with the bar starting at zero. Notes can last into later bars.
+    pub bar: Composition,
+}
+
+impl Chunk {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(Vec::new());
+        out.uint(self.seq);
+        out.uint(self.measure as u64);
+        out.0.extend(encode_composition(&self.bar));
+        out.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, DecodeError> {
+        let mut input = Reader(bytes);
+        let seq = input.uint()?;
+        let measure = input.u32()?;
+        Ok(Chunk { seq, measure, bar: decode_composition(input.0)? })
+    }
+}
+
+/// The sending end: cuts what is expanded into bars and remembers the latest ones.
+#[derive(Debug, Clone)]
+pub struct ChunkStream {
+    time_signature: TimeSignature,
+    next_seq: u64,
+    next_measure: Measure,
+    history: VecDeque<Chunk>,
+    history_len: usize,
+}
+
+impl ChunkStream {
+    pub fn new(time_signature: TimeSignature) -> Self {
+        Self::with_history(time_signature, DEFAULT_HISTORY)
+    }
+
+    pub fn with_history(time_signature: TimeSignature, history_len: usize) -> Self {
+        ChunkStream { time_signature, next_seq: 0, next_measure: 0, history: VecDeque::new(), history_len }
+    }
+
+    /// Continue the piece with `composition`, starting on the bar after everything pushed so far.
+    /// Returns the chunks to send.
+    pub fn push(&mut self, composition: &Composition) -> Vec<Chunk> {
+        let mut piece = Composition { tracks: vec![], time_signature: self.time_signature, markers: vec![] };
+        piece.overlay(composition, MusicTime::zero());
+        let bars = match piece.get_end() {
+            Some(MusicTime(measure, beat)) if beat.numerator() == 0 => measure,
+            Some(MusicTime(measure, _)) => measure + 1,
+            None => 0,
+        };
+        let chunks: Vec<_> = (0..bars)
+            .map(|bar| {
+                let chunk = Chunk { seq: self.next_seq, measure: self.next_measure + bar, bar: bar_of(&piece, bar) };
+                self.next_seq += 1;
+                chunk
+            })
+            .collect();
+        self.next_measure += bars;
+        self.history.extend(chunks.iter().cloned());
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+        chunks
+    }
+
+    /// What a client that got everything up to `last_seq` is missing, as far as it is still kept.
+    /// `None` is for a client that has nothing yet.
+    pub fn resend_after(&self, last_seq: Option<u64>) -> Vec<Chunk> {
+        self.history.iter()
+            .filter(|chunk| last_seq.is_none_or(|last| chunk.seq > last))
+            .cloned()
+            .collect()
+    }
+}
+
+/// What starts in `measure` of `piece`, moved to start at zero.
+fn bar_of(piece: &Composition, measure: Measure) -> Composition {
+    let in_bar = |events: &[Event]| events.iter()
+        .filter(|e| e.start.0 == measure)
+        .map(|e| Event { start: MusicTime(0, e.start.1), ..*e })
+        .collect::<Vec<_>>();
+    let tracks = piece.tracks.iter()
+        .map(|track| Track::new(track.identifier, track.instrument, in_bar(track.events()), in_bar(&track.rests)))
+        .filter(|track| !track.events().is_empty() || !track.rests.is_empty())
+        .collect();
+    let markers = piece.markers.iter()
+        .filter(|m| m.time.0 == measure)
+        .map(|m| Marker { name: m.name.clone(), time: MusicTime(0, m.time.1) })
+        .collect();
+    Composition { tracks, time_signature: piece.time_signature, markers }
+}
+
+/// A chunk came that doesn't follow the last one, so some were lost on the way.
+/// Ask the sender to `resend_after` the last one that was put together.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StreamGap {
+    pub expected: u64,
+    pub got: u64,
+}
+
+impl Display for StreamGap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected chunk {} but got {}", self.expected, self.got)
+    }
+}
+
+/// The receiving end: puts the chunks back together into the piece.
+#[derive(Debug, Clone)]
+pub struct ChunkAssembler {
+    next_seq: u64,
+    pub composition: Composition,
+}
+
+impl ChunkAssembler {
+    pub fn new(time_signature: TimeSignature) -> Self {
+        ChunkAssembler { next_seq: 0, composition: Composition { tracks: vec![], time_signature, markers: vec![] } }
+    }
+
+    /// The last chunk that was put in, to resend after.
+    pub fn last_seq(&self) -> Option<u64> {
+        self.next_seq.checked_sub(1)
+    }
+
+    /// Put in the next chunk. Chunks that were already put in are ignored.
+    pub fn add(&mut self, chunk: &Chunk) -> Result<(), StreamGap> {
+        if chunk.seq < self.next_seq {
+            return Ok(());
+        }
+        if chunk.seq > self.next_seq {
+            return Err(StreamGap { expected: self.next_seq, got: chunk.seq });
+        }
+        self.composition.overlay(&chunk.bar, MusicTime::measures(chunk.measure));
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// Put in what the sender resent after a gap. If it no longer had all the missing chunks, the
+    /// piece goes on from the oldest one it still had. Returns how many chunks were lost for good.
+    pub fn resync(&mut self, chunks: &[Chunk]) -> u64 {
+        let mut lost = 0;
+        for chunk in chunks {
+            if chunk.seq > self.next_seq {
+                lost += chunk.seq - self.next_seq;
+                self.next_seq = chunk.seq;
+            }
+            // can't leave a gap now
+            let _ = self.add(chunk);
+        }
+        lost
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::composition::Composition;
+    use crate::stream::{Chunk, ChunkAssembler, ChunkStream, StreamGap};
+    use crate::time::TimeSignature;
+
+    fn compose(music: &str) -> Composition {
+        MusicString::from_str(music).unwrap().compose(TimeSignature::common(), None).unwrap()
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let time_signature = TimeSignature::common();
+        let mut stream = ChunkStream::with_history(time_signature, 3);
+        let mut assembler = ChunkAssembler::new(time_signature);
+        // a note held over the bar line, and a marker in the second bar
+        let first = compose(":c<3> :d<2> ::mark=B :e<3>");
+        let chunks = stream.push(&first);
+        assert_eq!(chunks.iter().map(|c| (c.seq, c.measure)).collect::<Vec<_>>(), vec![(0, 0), (1, 1)]);
+        for chunk in &chunks {
+            assert_eq!(&Chunk::from_bytes(&chunk.to_bytes()).unwrap(), chunk);
+            assembler.add(chunk).unwrap();
+        }
+        assert_eq!(assembler.composition.tracks[0].events(), first.tracks[0].events());
+        assert_eq!(assembler.composition.markers, first.markers);
+
+        // more of the piece comes on the next bar, and one of its chunks is lost
+        let chunks = stream.push(&compose(":f<4> :g<4> :a<4>"));
+        assert_eq!(chunks[0].measure, 2);
+        assembler.add(&chunks[0]).unwrap();
+        assert_eq!(assembler.add(&chunks[2]), Err(StreamGap { expected: 3, got: 4 }));
+        let resent = stream.resend_after(assembler.last_seq());
+        assert_eq!(resent.iter().map(|c| c.seq).collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(assembler.resync(&resent), 0);
+        assert_eq!(assembler.last_seq(), Some(4));
+        // duplicates are harmless
+        assembler.add(&chunks[1]).unwrap();
+        assert_eq!(assembler.composition.tracks[0].events().len(), 6);
+
+        // a client that fell further behind than the history skips what is gone
+        let mut late = ChunkAssembler::new(time_signature);
+        assert_eq!(late.resync(&stream.resend_after(None)), 2);
+        assert_eq!(late.last_seq(), Some(4));
+    }
+}
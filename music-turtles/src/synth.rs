@@ -0,0 +1,770 @@
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use rodio::{Decoder, Source};
+use serde::Deserialize;
+use crate::composition::{Frequency, Instrument, Modulation};
+use crate::time::Seconds;
+
+pub const SAMPLE_RATE: u32 = 44100;
+const ATTACK: Seconds = 0.04;
+const RELEASE: Seconds = 0.04;
+
+pub type SynthSource = Box<dyn Source<Item=f32> + Send + 'static>;
+
+/// Turns notes into sound for local playback.
+pub trait Synth: Send + Sync {
+    /// Sound of a note held for `length`, release tail included. Must end.
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource;
+}
+
+/// Low notes need more amplitude to sound as loud as high ones.
+pub fn loudness_compensation(frequency: Frequency) -> f32 {
+    (3.0 * 44.0 / frequency).clamp(0.0, 1.0)
+}
+
+/// Endless mono source playing one cycle of `wave` over and over.
+/// `wave` gets the phase within the cycle, in [0, 1).
+pub struct Oscillator<F> {
+    frequency: Frequency,
+    phase: f32,
+    wave: F,
+}
+
+impl<F> Oscillator<F>
+where
+    F: FnMut(f32) -> f32,
+{
+    pub fn new(frequency: Frequency, wave: F) -> Self {
+        Oscillator { frequency, phase: 0., wave }
+    }
+}
+
+impl<F> Iterator for Oscillator<F>
+where
+    F: FnMut(f32) -> f32,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = (self.wave)(self.phase);
+        self.phase = (self.phase + self.frequency / SAMPLE_RATE as f32).fract();
+        Some(sample)
+    }
+}
+
+impl<F> Source for Oscillator<F>
+where
+    F: FnMut(f32) -> f32,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Linear attack, hold for the length of the note, then a linear release,
+/// after which the source ends.
+pub struct Envelope<S> {
+    input: S,
+    position: usize,
+    attack: usize,
+    release_start: usize,
+    end: usize,
+}
+
+pub fn envelope<S>(input: S, length: Seconds) -> Envelope<S>
+where
+    S: Source<Item=f32>,
+{
+    let samples_per_second = input.sample_rate() as f32 * input.channels() as f32;
+    let release_start = (length.max(0.) * samples_per_second) as usize;
+    Envelope {
+        input,
+        position: 0,
+        attack: ((ATTACK * samples_per_second) as usize).max(1),
+        release_start,
+        end: release_start + ((RELEASE * samples_per_second) as usize).max(1),
+    }
+}
+
+impl<S> Iterator for Envelope<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position >= self.end {
+            return None;
+        }
+        let sample = self.input.next()?;
+        let attack = (self.position as f32 / self.attack as f32).min(1.);
+        let release = if self.position < self.release_start {
+            1.
+        } else {
+            (self.end - self.position) as f32 / (self.end - self.release_start) as f32
+        };
+        self.position += 1;
+        Some(sample * attack * release)
+    }
+}
+
+impl<S> Source for Envelope<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.end.saturating_sub(self.position))
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let samples_per_second = self.sample_rate() as f32 * self.channels() as f32;
+        Some(Duration::from_secs_f32(self.end as f32 / samples_per_second))
+    }
+}
+
+/// Vibrato and tremolo on top of any synth's note.
+/// Vibrato reads the note faster and slower than it was made, bending the pitch without the
+/// synth having to know about it. Tremolo scales the volume.
+pub struct Modulated<S> {
+    input: S,
+    modulation: Modulation,
+    sample_rate: f32,
+    time: Seconds,
+    /// position between `current` and `next`, in [0, 1)
+    position: f32,
+    current: Option<f32>,
+    next: Option<f32>,
+}
+
+/// Apply `modulation` to a mono note. Notes without any come back untouched.
+pub fn modulate(mut input: SynthSource, modulation: Modulation) -> SynthSource {
+    if modulation.vibrato.is_off() && modulation.tremolo.is_off() {
+        return input;
+    }
+    let sample_rate = input.sample_rate() as f32;
+    let current = input.next();
+    let next = input.next();
+    Box::new(Modulated { input, modulation, sample_rate, time: 0., position: 0., current, next })
+}
+
+impl<S> Iterator for Modulated<S>
+where
+    S: Source<Item=f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let current = self.current?;
+        let next = self.next.unwrap_or(0.);
+        let Modulation { vibrato, tremolo } = self.modulation;
+        let tremolo_gain = 1. - tremolo.depth().min(1.) * (1. - tremolo.value_at(self.time)) / 2.;
+        let sample = (current + (next - current) * self.position) * tremolo_gain;
+
+        let speed = 2f32.powf(vibrato.depth() * vibrato.value_at(self.time) / 12.);
+        self.position += speed;
+        while self.position >= 1. {
+            self.position -= 1.;
+            self.current = self.next;
+            self.next = self.input.next();
+        }
+        self.time += 1. / self.sample_rate;
+        Some(sample)
+    }
+}
+
+impl<S> Source for Modulated<S>
+where
+    S: Source<Item=f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Plain sine wave. The sound every instrument used to have.
+pub struct SineSynth;
+
+impl Synth for SineSynth {
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource {
+        let wave = Oscillator::new(frequency, |phase| (TAU * phase).sin());
+        Box::new(envelope(wave, length).amplify(loudness_compensation(frequency)))
+    }
+}
+
+/// Sum of harmonics of the note, each with its own amplitude.
+/// `harmonics[0]` is the fundamental, `harmonics[1]` the octave above, and so on.
+#[derive(Debug, Clone)]
+pub struct AdditiveSynth {
+    pub harmonics: Vec<f32>,
+}
+
+impl AdditiveSynth {
+    /// Drawbar-style organ: fundamental, octave, twelfth and a little of the upper partials.
+    pub fn organ() -> Self {
+        AdditiveSynth { harmonics: vec![1.0, 0.8, 0.6, 0.5, 0.0, 0.3, 0.0, 0.25] }
+    }
+
+    /// Bright, sawtooth-like spectrum for string sections.
+    pub fn strings() -> Self {
+        AdditiveSynth { harmonics: (1..=12).map(|k| 1. / k as f32).collect() }
+    }
+
+    /// Soft, hollow spectrum made of odd harmonics.
+    pub fn pad() -> Self {
+        AdditiveSynth { harmonics: (1..=7).map(|k| if k % 2 == 1 { 1. / (k * k) as f32 } else { 0. }).collect() }
+    }
+}
+
+impl Synth for AdditiveSynth {
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource {
+        // drop harmonics above Nyquist, they would alias into noise
+        let nyquist = SAMPLE_RATE as f32 / 2.;
+        let harmonics = self.harmonics.iter()
+            .enumerate()
+            .map(|(k, amp)| ((k + 1) as f32, *amp))
+            .filter(|(k, _amp)| k * frequency < nyquist)
+            .collect::<Vec<_>>();
+        let total = harmonics.iter().map(|(_k, amp)| amp.abs()).sum::<f32>().max(f32::EPSILON);
+        let wave = Oscillator::new(frequency, move |phase| {
+            harmonics.iter()
+                .map(|(k, amp)| amp * (TAU * k * phase).sin())
+                .sum::<f32>() / total
+        });
+        Box::new(envelope(wave, length).amplify(loudness_compensation(frequency)))
+    }
+}
+
+/// Two-operator FM: a sine modulator at `ratio` times the note frequency bends the phase
+/// of a sine carrier. The modulation index starts at `index` and decays toward
+/// `sustain_index`, so the attack is bright and the tail mellow.
+#[derive(Debug, Clone)]
+pub struct FmSynth {
+    pub ratio: f32,
+    pub index: f32,
+    pub sustain_index: f32,
+    /// time for the index to fall most of the way to `sustain_index`
+    pub index_decay: Seconds,
+}
+
+impl FmSynth {
+    /// Inharmonic ratio and a slow decay, like a struck bell.
+    pub fn bell() -> Self {
+        FmSynth { ratio: 3.5, index: 6.0, sustain_index: 0.5, index_decay: 1.2 }
+    }
+
+    /// Harmonic ratio with a quick, bright attack, like a tine electric piano.
+    pub fn electric_piano() -> Self {
+        FmSynth { ratio: 1.0, index: 3.0, sustain_index: 0.3, index_decay: 0.25 }
+    }
+}
+
+impl Synth for FmSynth {
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource {
+        let FmSynth { ratio, index, sustain_index, index_decay } = *self;
+        let decay_per_sample = (-1. / (index_decay.max(0.001) * SAMPLE_RATE as f32)).exp();
+        let mut current_index = index - sustain_index;
+        let mut modulator = Oscillator::new(frequency * ratio, |phase| (TAU * phase).sin());
+        let wave = Oscillator::new(frequency, move |phase| {
+            let modulation = modulator.next().unwrap_or(0.);
+            let sample = (TAU * phase + (sustain_index + current_index) * modulation).sin();
+            current_index *= decay_per_sample;
+            sample
+        });
+        Box::new(envelope(wave, length).amplify(loudness_compensation(frequency)))
+    }
+}
+
+/// Percussion made of a body tone that falls in pitch plus filtered noise, both dying away
+/// over `decay`. Drums ignore the notated pitch and length.
+#[derive(Debug, Clone)]
+pub struct DrumSynth {
+    /// starting frequency of the body tone
+    pub tone: Frequency,
+    /// fraction of `tone` that the pitch falls to
+    pub tone_drop: f32,
+    pub tone_level: f32,
+    pub noise_level: f32,
+    /// one-pole filters on the noise, for hats (high) versus shakers and snares (mid)
+    pub noise_highpass: Frequency,
+    pub noise_lowpass: Frequency,
+    /// time for the sound to fall to about a third of its level
+    pub decay: Seconds,
+}
+
+impl DrumSynth {
+    pub fn kick() -> Self {
+        DrumSynth { tone: 150., tone_drop: 0.35, tone_level: 1.0, noise_level: 0.05, noise_highpass: 20., noise_lowpass: 2000., decay: 0.15 }
+    }
+
+    pub fn snare() -> Self {
+        DrumSynth { tone: 220., tone_drop: 0.8, tone_level: 0.4, noise_level: 0.8, noise_highpass: 500., noise_lowpass: 9000., decay: 0.09 }
+    }
+
+    pub fn closed_hat() -> Self {
+        DrumSynth { tone: 0., tone_drop: 1.0, tone_level: 0.0, noise_level: 0.6, noise_highpass: 7000., noise_lowpass: 20000., decay: 0.03 }
+    }
+
+    pub fn open_hat() -> Self {
+        DrumSynth { decay: 0.2, ..DrumSynth::closed_hat() }
+    }
+
+    pub fn bongo(high: bool) -> Self {
+        let tone = if high { 400. } else { 280. };
+        DrumSynth { tone, tone_drop: 0.9, tone_level: 0.9, noise_level: 0.1, noise_highpass: 1000., noise_lowpass: 5000., decay: 0.1 }
+    }
+
+    pub fn shaker() -> Self {
+        DrumSynth { tone: 0., tone_drop: 1.0, tone_level: 0.0, noise_level: 0.5, noise_highpass: 3000., noise_lowpass: 12000., decay: 0.05 }
+    }
+
+    /// Preset for each of the drum-kit instruments.
+    pub fn for_instrument(instrument: Instrument) -> Option<Self> {
+        match instrument {
+            Instrument::BassDrum => Some(DrumSynth::kick()),
+            Instrument::Snare => Some(DrumSynth::snare()),
+            Instrument::Snare2 => Some(DrumSynth { tone: 260., decay: 0.12, ..DrumSynth::snare() }),
+            Instrument::HiHatClosed => Some(DrumSynth::closed_hat()),
+            Instrument::HiHatOpen => Some(DrumSynth::open_hat()),
+            Instrument::BongoHigh => Some(DrumSynth::bongo(true)),
+            Instrument::BongoLow => Some(DrumSynth::bongo(false)),
+            Instrument::Shaker1 => Some(DrumSynth::shaker()),
+            Instrument::Shaker2 => Some(DrumSynth { decay: 0.09, ..DrumSynth::shaker() }),
+            _ => None,
+        }
+    }
+}
+
+/// Next sample of xorshift noise in [-1, 1]. Cheap, deterministic, and good enough for audio.
+fn white_noise(state: &mut u32) -> f32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state as f32 / u32::MAX as f32 * 2. - 1.
+}
+
+/// Coefficient of a one-pole filter with the given cutoff.
+fn one_pole(cutoff: Frequency) -> f32 {
+    1. - (-TAU * cutoff / SAMPLE_RATE as f32).exp()
+}
+
+impl Synth for DrumSynth {
+    fn note(&self, _frequency: Frequency, _length: Seconds) -> SynthSource {
+        let DrumSynth { tone, tone_drop, tone_level, noise_level, noise_highpass, noise_lowpass, decay } = *self;
+        let seconds_per_sample = 1. / SAMPLE_RATE as f32;
+        let amp_per_sample = (-seconds_per_sample / decay).exp();
+        // the pitch falls quicker than the level
+        let pitch_per_sample = (-seconds_per_sample * 4. / decay).exp();
+        let (highpass, lowpass) = (one_pole(noise_highpass), one_pole(noise_lowpass));
+        let mut amp = 1.;
+        let mut drop = 1.;
+        let mut tone_phase = 0f32;
+        let mut noise_state = 0x2545_f491_u32;
+        let (mut low, mut high_base) = (0f32, 0f32);
+        let wave = Oscillator::new(0., move |_phase| {
+            let white = white_noise(&mut noise_state);
+            low += lowpass * (white - low);
+            high_base += highpass * (low - high_base);
+            let noise = low - high_base;
+            let frequency = tone * (tone_drop + (1. - tone_drop) * drop);
+            tone_phase = (tone_phase + frequency * seconds_per_sample).fract();
+            let sample = tone_level * (TAU * tone_phase).sin() + noise_level * noise;
+            amp *= amp_per_sample;
+            drop *= pitch_per_sample;
+            (sample * amp).clamp(-1., 1.)
+        });
+        Box::new(wave.take_duration(Duration::from_secs_f32(decay * 5.)))
+    }
+}
+
+/// Karplus-Strong plucked string: a burst of noise circulates through a delay line one
+/// period long, and averaging neighbouring samples on every pass dulls it like a real string.
+#[derive(Debug, Clone)]
+pub struct PluckSynth {
+    /// how much of the level survives each pass through the delay line
+    pub feedback: f32,
+    /// weight of the current sample against the next one in the averaging filter;
+    /// 0.5 is the classic, darker sound, values near 1 keep it bright
+    pub brightness: f32,
+}
+
+impl PluckSynth {
+    pub fn guitar() -> Self {
+        PluckSynth { feedback: 0.996, brightness: 0.5 }
+    }
+
+    pub fn harp() -> Self {
+        PluckSynth { feedback: 0.998, brightness: 0.7 }
+    }
+}
+
+impl Synth for PluckSynth {
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource {
+        let PluckSynth { feedback, brightness } = *self;
+        let period = ((SAMPLE_RATE as f32 / frequency).round() as usize).max(2);
+        let mut noise_state = 0x9e37_79b9_u32;
+        let mut line = (0..period)
+            .map(|_| white_noise(&mut noise_state))
+            .collect::<Vec<_>>();
+        let mut position = 0;
+        let wave = Oscillator::new(0., move |_phase| {
+            let next = (position + 1) % period;
+            let sample = line[position];
+            line[position] = feedback * (brightness * sample + (1. - brightness) * line[next]);
+            position = next;
+            sample
+        });
+        Box::new(envelope(wave, length).amplify(0.5 * loudness_compensation(frequency).max(0.3)))
+    }
+}
+
+/// Plays one cycle of a waveform loaded from a WAV file, so sounds can be designed
+/// without touching the code.
+#[derive(Debug, Clone)]
+pub struct WavetableSynth {
+    table: Arc<Vec<f32>>,
+}
+
+impl WavetableSynth {
+    pub fn new(table: Vec<f32>) -> Result<Self, Box<dyn std::error::Error>> {
+        if table.is_empty() {
+            return Err("wavetable is empty".into());
+        }
+        Ok(WavetableSynth { table: Arc::new(table) })
+    }
+
+    /// Load a single-cycle waveform. Only the first channel of the file is used.
+    pub fn from_wav(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = BufReader::new(File::open(path.as_ref())?);
+        let decoder = Decoder::new(file)?;
+        let channels = decoder.channels() as usize;
+        let table = decoder.convert_samples::<f32>()
+            .step_by(channels.max(1))
+            .collect();
+        WavetableSynth::new(table)
+            .map_err(|e| format!("{}: {e}", path.as_ref().display()).into())
+    }
+}
+
+impl Synth for WavetableSynth {
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource {
+        let table = Arc::clone(&self.table);
+        let wave = Oscillator::new(frequency, move |phase| {
+            // linear interpolation between the two nearest samples of the cycle
+            let position = phase * table.len() as f32;
+            let i = position as usize % table.len();
+            let next = (i + 1) % table.len();
+            let t = position.fract();
+            table[i] * (1. - t) + table[next] * t
+        });
+        Box::new(envelope(wave, length).amplify(loudness_compensation(frequency)))
+    }
+}
+
+/// Layers several copies of another synth, each detuned a little, to thicken the sound.
+/// The voices are spread evenly between `-detune_cents` and `+detune_cents`.
+#[derive(Clone)]
+pub struct Unison {
+    pub synth: Arc<dyn Synth>,
+    pub voices: usize,
+    pub detune_cents: f32,
+}
+
+impl Synth for Unison {
+    fn note(&self, frequency: Frequency, length: Seconds) -> SynthSource {
+        let voices = self.voices.max(1);
+        let gain = 1. / (voices as f32).sqrt();
+        (0..voices)
+            .map(|v| {
+                let spread = if voices == 1 {
+                    0.
+                } else {
+                    -1. + 2. * v as f32 / (voices - 1) as f32
+                };
+                let detuned = frequency * 2f32.powf(spread * self.detune_cents / 1200.);
+                Box::new(self.synth.note(detuned, length).amplify(gain)) as SynthSource
+            })
+            .reduce(|a, b| Box::new(a.mix(b)))
+            .unwrap()
+    }
+}
+
+/// Wavetables to load, as a JSON object of instrument name to WAV file:
+/// `{ "wavetables": { "Piano": "tables/piano.wav" } }`.
+/// Relative paths are resolved against the directory of the config file.
+#[derive(Debug, Deserialize)]
+pub struct WavetableConfig {
+    pub wavetables: HashMap<String, PathBuf>,
+}
+
+/// Which synth plays each instrument in local playback.
+#[derive(Clone)]
+pub struct SynthBank {
+    synths: HashMap<Instrument, Arc<dyn Synth>>,
+    fallback: Arc<dyn Synth>,
+}
+
+impl SynthBank {
+    pub fn get(&self, instrument: Instrument) -> &dyn Synth {
+        self.synths.get(&instrument).unwrap_or(&self.fallback).as_ref()
+    }
+
+    /// Assign the wavetables listed in a `WavetableConfig` file to their instruments.
+    pub fn load_wavetables(&mut self, config_path: impl AsRef<Path>) -> Result<(), Box<dyn std::error::Error>> {
+        let config_path = config_path.as_ref();
+        let config: WavetableConfig = serde_json::from_str(&std::fs::read_to_string(config_path)?)?;
+        let base = config_path.parent().unwrap_or(Path::new("."));
+        for (name, wav) in config.wavetables {
+            let instrument = Instrument::from_str(&name)?;
+            self.set(instrument, WavetableSynth::from_wav(base.join(wav))?);
+        }
+        Ok(())
+    }
+
+    /// Play the instrument's current synth as `voices` detuned copies.
+    pub fn set_unison(&mut self, instrument: Instrument, voices: usize, detune_cents: f32) {
+        let synth = Arc::clone(self.synths.get(&instrument).unwrap_or(&self.fallback));
+        self.set(instrument, Unison { synth, voices, detune_cents });
+    }
+
+    /// Whether a synth was picked for this instrument, rather than falling back to sine.
+    pub fn has(&self, instrument: Instrument) -> bool {
+        self.synths.contains_key(&instrument)
+    }
+
+    pub fn set(&mut self, instrument: Instrument, synth: impl Synth + 'static) {
+        self.synths.insert(instrument, Arc::new(synth));
+    }
+}
+
+impl Default for SynthBank {
+    fn default() -> Self {
+        let mut bank = SynthBank {
+            synths: HashMap::new(),
+            fallback: Arc::new(SineSynth),
+        };
+        bank.set(Instrument::Organ, AdditiveSynth::organ());
+        bank.set(Instrument::Strings, AdditiveSynth::strings());
+        bank.set(Instrument::Pad, AdditiveSynth::pad());
+        bank.set_unison(Instrument::Pad, 3, 12.);
+        bank.set(Instrument::Bell, FmSynth::bell());
+        bank.set(Instrument::ElectricPiano, FmSynth::electric_piano());
+        bank.set(Instrument::Guitar, PluckSynth::guitar());
+        bank.set(Instrument::Harp, PluckSynth::harp());
+        for instrument in Instrument::values().filter(Instrument::is_percussion) {
+            if let Some(drum) = DrumSynth::for_instrument(instrument) {
+                bank.set(instrument, drum);
+            }
+        }
+        bank
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::composition::{Instrument, Lfo, Modulation};
+    use std::sync::Arc;
+    use crate::synth::{modulate, AdditiveSynth, DrumSynth, FmSynth, PluckSynth, SineSynth, Synth, SynthBank, Unison, WavetableSynth, SAMPLE_RATE};
+
+    #[test]
+    fn test_note_ends_after_release() {
+        let samples = SineSynth.note(440., 0.5).count();
+        let expected = (0.54 * SAMPLE_RATE as f32) as usize;
+        assert!(samples.abs_diff(expected) <= 2, "{samples} samples");
+    }
+
+    #[test]
+    fn test_additive_single_harmonic_is_a_sine() {
+        let additive = AdditiveSynth { harmonics: vec![1.] }.note(440., 0.1).collect::<Vec<_>>();
+        let sine = SineSynth.note(440., 0.1).collect::<Vec<_>>();
+        assert_eq!(additive.len(), sine.len());
+        assert!(additive.iter().zip(&sine).all(|(a, b)| (a - b).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_additive_stays_in_range() {
+        let organ = AdditiveSynth::organ().note(110., 0.2).collect::<Vec<_>>();
+        assert!(organ.iter().all(|s| s.abs() <= 1.));
+        assert!(organ.iter().any(|s| s.abs() > 0.1));
+    }
+
+    #[test]
+    fn test_fm_without_modulation_is_a_sine() {
+        let fm = FmSynth { ratio: 2.0, index: 0.0, sustain_index: 0.0, index_decay: 1.0 }
+            .note(440., 0.1)
+            .collect::<Vec<_>>();
+        let sine = SineSynth.note(440., 0.1).collect::<Vec<_>>();
+        assert_eq!(fm.len(), sine.len());
+        assert!(fm.iter().zip(&sine).all(|(a, b)| (a - b).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_fm_bell_differs_from_sine() {
+        let bell = FmSynth::bell().note(440., 0.1).collect::<Vec<_>>();
+        let sine = SineSynth.note(440., 0.1).collect::<Vec<_>>();
+        assert!(bell.iter().zip(&sine).any(|(a, b)| (a - b).abs() > 0.1));
+        assert!(bell.iter().all(|s| s.abs() <= 1.));
+    }
+
+    #[test]
+    fn test_drums_ignore_length_and_decay() {
+        let kick = DrumSynth::kick().note(440., 4.).collect::<Vec<_>>();
+        assert!(kick.len().abs_diff((0.75 * SAMPLE_RATE as f32) as usize) <= 2);
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |m, s| m.max(s.abs()));
+        assert!(peak(&kick[..1000]) > 10. * peak(&kick[kick.len() - 1000..]));
+    }
+
+    #[test]
+    fn test_drum_kit_is_in_default_bank() {
+        let bank = SynthBank::default();
+        assert!(Instrument::values()
+            .filter(Instrument::is_percussion)
+            .all(|i| bank.has(i)));
+        assert!(!bank.has(Instrument::SineWave));
+    }
+
+    #[test]
+    fn test_pluck_decays() {
+        let pluck = PluckSynth::guitar().note(220., 2.).collect::<Vec<_>>();
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |m, s| m.max(s.abs()));
+        let second = SAMPLE_RATE as usize;
+        assert!(peak(&pluck[..second / 10]) > 0.1);
+        assert!(peak(&pluck[..second / 10]) > 3. * peak(&pluck[second..second + second / 10]));
+    }
+
+    /// Minimal 16-bit mono PCM WAV file.
+    fn wav_bytes(samples: &[i16]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut bytes = vec![];
+        bytes.extend(b"RIFF");
+        bytes.extend((36 + data_len).to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(16u32.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(SAMPLE_RATE.to_le_bytes());
+        bytes.extend((SAMPLE_RATE * 2).to_le_bytes());
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        bytes.extend(b"data");
+        bytes.extend(data_len.to_le_bytes());
+        samples.iter().for_each(|s| bytes.extend(s.to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn test_wavetable_from_config() {
+        let dir = std::env::temp_dir().join(format!("music-turtles-wavetable-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let square = (0..64).map(|i| if i < 32 { i16::MAX } else { -i16::MAX }).collect::<Vec<_>>();
+        std::fs::write(dir.join("square.wav"), wav_bytes(&square)).unwrap();
+        std::fs::write(dir.join("synths.json"), r#"{ "wavetables": { "organ": "square.wav" } }"#).unwrap();
+        let mut bank = SynthBank::default();
+        bank.load_wavetables(dir.join("synths.json")).unwrap();
+        let note = bank.get(Instrument::Organ).note(110., 0.1).collect::<Vec<_>>();
+        // a square wave spends most of its time near full level once the attack is over
+        let sustained = &note[(0.05 * SAMPLE_RATE as f32) as usize..(0.1 * SAMPLE_RATE as f32) as usize];
+        assert!(sustained.iter().filter(|s| s.abs() > 0.9).count() > sustained.len() / 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_empty_wavetable_is_rejected() {
+        assert!(WavetableSynth::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_unison_of_one_voice_is_unchanged() {
+        let unison = Unison { synth: Arc::new(SineSynth), voices: 1, detune_cents: 20. }
+            .note(440., 0.1)
+            .collect::<Vec<_>>();
+        let sine = SineSynth.note(440., 0.1).collect::<Vec<_>>();
+        assert_eq!(unison, sine);
+    }
+
+    #[test]
+    fn test_unison_voices_beat_against_each_other() {
+        let note = |detune_cents| Unison { synth: Arc::new(SineSynth), voices: 2, detune_cents }
+            .note(440., 1.0)
+            .collect::<Vec<_>>();
+        let in_tune = note(0.);
+        let detuned = note(50.);
+        assert_eq!(in_tune.len(), detuned.len());
+        // detuned voices drift apart in phase and partly cancel somewhere in the second
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |m, s| m.max(s.abs()));
+        let window = SAMPLE_RATE as usize / 400;
+        let sustain = SAMPLE_RATE as usize / 20..SAMPLE_RATE as usize;
+        let quietest = detuned[sustain.clone()]
+            .chunks(window)
+            .map(peak)
+            .fold(f32::MAX, f32::min);
+        assert!(quietest < 0.5 * peak(&in_tune[sustain]));
+    }
+
+    #[test]
+    fn test_no_modulation_leaves_note_alone() {
+        let plain = SineSynth.note(440., 0.1).collect::<Vec<_>>();
+        let modulated = modulate(SineSynth.note(440., 0.1), Modulation::NONE).collect::<Vec<_>>();
+        assert_eq!(plain, modulated);
+    }
+
+    #[test]
+    fn test_tremolo_dips_volume() {
+        let tremolo = Modulation { tremolo: Lfo::new(5., 0.8), ..Modulation::NONE };
+        let samples = modulate(SineSynth.note(440., 0.5), tremolo).collect::<Vec<_>>();
+        let peak = |range: std::ops::Range<f32>| samples[(range.start * SAMPLE_RATE as f32) as usize..(range.end * SAMPLE_RATE as f32) as usize]
+            .iter()
+            .fold(0f32, |m, s| m.max(s.abs()));
+        // the oscillator is at its top after 0.05s and at its bottom after 0.15s
+        assert!(peak(0.14..0.16) < 0.4 * peak(0.04..0.06));
+    }
+
+    #[test]
+    fn test_vibrato_bends_pitch() {
+        let vibrato = Modulation { vibrato: Lfo::new(5., 1.), ..Modulation::NONE };
+        let samples = modulate(SineSynth.note(440., 0.5), vibrato).collect::<Vec<_>>();
+        let crossings = |range: std::ops::Range<f32>| samples[(range.start * SAMPLE_RATE as f32) as usize..(range.end * SAMPLE_RATE as f32) as usize]
+            .windows(2)
+            .filter(|w| (w[0] < 0.) != (w[1] < 0.))
+            .count();
+        // sharp at the top of the oscillator, flat at the bottom
+        assert!(crossings(0.03..0.07) > crossings(0.13..0.17) + 2);
+    }
+}
@@ -1,11 +1,13 @@
 use std::collections::HashMap;
 use std::thread;
 use std::time::Duration;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use std::str::FromStr;
 use crate::cfg::{Grammar, MusicString};
-use crate::composition::{Event, Instrument, Pitch, Track, TrackId, Volume};
-use crate::local_playback::{run, run_midi};
+use crate::builder::CompositionBuilder;
+use crate::composition::{Instrument, Pitch, Volume};
+use crate::clock::SystemClock;
+use crate::local_playback::{run, run_midi, StopToken};
 use crate::player::{MidiPlayer, Player};
 use crate::scheduler::Scheduler;
 use crate::time::{Beat, MusicTime, TimeSignature};
@@ -19,19 +21,11 @@ fn compose_something() {
     let string = MusicString::from_str(input).unwrap();
     let music = string.compose(TimeSignature::common(), None).unwrap();
     println!("{music:#?}");
-    let mut scheduler = Scheduler {
-        bpm: 80.0,
-        time_signature: TimeSignature(4, 4),
-        tracks: vec![],
-        lookahead: MusicTime::measures(1),
-        looped: false,
-        loop_time: MusicTime::measures(1),
-    };
-    scheduler.set_composition(music);
+    let scheduler = Scheduler::new(music, 80.0);
     let player = MidiPlayer::new("test".to_string(), HashMap::new()).unwrap();
     thread::sleep(Duration::from_millis(1000)); // give player time to get ready
     // run(&mut scheduler, 50, player);
-    run_midi(Arc::new(Mutex::new(scheduler)), 50, player);
+    run_midi(scheduler, mpsc::channel().1, 50, player, SystemClock::new(), StopToken::ctrl_c(), None);
 }
 
 // ignore tests that play sounds
@@ -51,88 +45,25 @@ fn run_file_grammar() {
 
     let music = string.compose(TimeSignature::common(), None).unwrap();
     // println!("{music:#?}");
-    let mut scheduler = Scheduler {
-        bpm: 80.0,
-        time_signature: TimeSignature(4, 4),
-        tracks: vec![],
-        lookahead: MusicTime::measures(1),
-        looped: false,
-        loop_time: MusicTime::measures(1),
-    };
-    scheduler.set_composition(music);
+    let scheduler = Scheduler::new(music, 80.0);
     let player = MidiPlayer::new("test".to_string(), HashMap::new()).unwrap();
     thread::sleep(Duration::from_millis(1000)); // give player time to get ready
-    run_midi(Arc::new(Mutex::new(scheduler)), 50, player);
+    run_midi(scheduler, mpsc::channel().1, 50, player, SystemClock::new(), StopToken::ctrl_c(), None);
 }
 
 // ignore tests that play sounds
 #[ignore]
 #[test]
 fn a() {
-    let player = Player::new();
-    let mut scheduler = Scheduler {
-        bpm: 80.0,
-        time_signature: TimeSignature(4, 4),
-        tracks: vec![
-            (Track {
-                identifier: TrackId::Custom(0),
-                instrument: Instrument::SineWave,
-                events: vec![
-                    Event {
-                        start: MusicTime(0, Beat::zero()),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 0),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::new(1, 1)),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 2),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::new(2, 1)),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 4),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::new(3, 1)),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 5),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::zero()),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 4),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::new(1, 1)),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 5),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::new(2, 1)),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 7),
-                    },
-                    Event {
-                        start: MusicTime(0, Beat::new(3, 1)),
-                        duration: Beat::new(1, 1),
-                        volume: Volume(20),
-                        pitch: Pitch(4, 9),
-                    }
-                ],
-                rests: vec![],
-            }, MusicTime(0, Beat::zero())),
-        ],
-        lookahead: MusicTime(1, Beat::zero()),
-        looped: true,
-        loop_time: MusicTime(1, Beat::zero()),
-    };
-    run(&mut scheduler, 50, player);
+    let player = Player::new().unwrap();
+    // two lines a third apart, over and over
+    let mut builder = CompositionBuilder::track(Instrument::SineWave);
+    for (i, note) in [0, 2, 4, 5, 4, 5, 7, 9].into_iter().enumerate() {
+        builder = builder.note(Pitch(4, note), MusicTime::beats(i as u32 % 4), Beat::whole(1), Volume(20));
+    }
+    let mut scheduler = Scheduler::new(builder.build().unwrap(), 80.0);
+    scheduler.looped = true;
+    scheduler.loop_time = MusicTime::measures(1);
+    let clock = player.clock();
+    run(&mut scheduler, 50, player, clock, StopToken::ctrl_c(), None, None);
 }
\ No newline at end of file
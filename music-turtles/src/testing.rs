@@ -0,0 +1,266 @@
+// Helpers for testing grammars: short ways to write the music that is expected, comparing it
+// with what was composed, and keeping bigger results in golden files next to the tests.
+//
+// Music is compared as JSON of what can be heard: the tracks' instruments and notes, the time
+// signature and the markers. Track ids, rests and how the notes were spelled are left out, so
+// music composed from a grammar can be compared with music written here.
+
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "native")]
+use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
+use crate::cfg::{Grammar, MusicString};
+use crate::composition::{Composition, Event, Instrument, Modulation, Pitch, Track, TrackId, Volume};
+use crate::time::{Beat, MusicTime, TimeSignature};
+#[cfg(feature = "native")]
+use crate::clock::Clock;
+#[cfg(feature = "native")]
+use crate::local_playback::StopToken;
+#[cfg(feature = "native")]
+use crate::player::{AtomicSound, AudioPlayer};
+#[cfg(feature = "native")]
+use crate::time::Seconds;
+
+/// Set this environment variable to write golden files instead of checking against them.
+pub const UPDATE_GOLDEN_VAR: &str = "UPDATE_GOLDEN";
+
+/// The volume composing starts at.
+pub const DEFAULT_VOLUME: Volume = Volume(50);
+
+/// A note at the volume composing starts at. Other fields can be set with struct update syntax.
+pub fn event(pitch: Pitch, start: MusicTime, duration: Beat) -> Event {
+    Event {
+        start,
+        duration,
+        volume: DEFAULT_VOLUME,
+        pitch,
+        modulation: Modulation::NONE,
+        spelling: None,
+        channel: None,
+        tag: None,
+        lyric: None,
+    }
+}
+
+/// A track for `instrument` with `events`, sorted.
+pub fn track(instrument: Instrument, events: Vec<Event>) -> Track {
+    Track::new(TrackId::Instrument(instrument), instrument, events, vec![])
+}
+
+/// `tracks` in common time.
+pub fn composition(tracks: Vec<Track>) -> Composition {
+    Composition { tracks, time_signature: TimeSignature::common(), markers: vec![] }
+}
+
+/// `music` composed in common time. Panics if it doesn't parse or compose.
+#[track_caller]
+pub fn compose(music: &str) -> Composition {
+    let string = MusicString::from_str(music).unwrap_or_else(|e| panic!("{music:?} doesn't parse: {e:?}"));
+    string.compose(TimeSignature::common(), None).unwrap_or_else(|e| panic!("{music:?} doesn't compose: {e:?}"))
+}
+
+/// `axiom` rewritten `iterations` times with `grammar`, always taking the first choice, and
+/// composed in common time.
+#[track_caller]
+pub fn compose_grammar(grammar: &Grammar, axiom: &str, iterations: usize) -> Composition {
+    let string = MusicString::from_str(axiom).unwrap_or_else(|e| panic!("{axiom:?} doesn't parse: {e:?}"));
+    string.parallel_rewrite_n(grammar, false, true, iterations)
+        .compose(TimeSignature::common(), None)
+        .unwrap_or_else(|e| panic!("{axiom:?} doesn't compose: {e:?}"))
+}
+
+fn beat_json(beat: Beat) -> String {
+    format!("{}/{}", beat.numerator(), beat.denominator())
+}
+
+fn event_json(event: &Event) -> Value {
+    let mut value = json!({
+        "start": format!("{}+{}", event.start.0, beat_json(event.start.1)),
+        "duration": beat_json(event.duration),
+        "pitch": event.pitch.to_midi_note(),
+        "volume": event.volume.0,
+    });
+    if event.modulation != Modulation::NONE {
+        value["modulation"] = json!(event.modulation);
+    }
+    if let Some(channel) = event.channel {
+        value["channel"] = json!(channel);
+    }
+    if let Some(tag) = &event.tag {
+        value["tag"] = json!(tag.to_string());
+    }
+    if let Some(lyric) = &event.lyric {
+        value["lyric"] = json!(lyric.to_string());
+    }
+    value
+}
+
+/// What can be heard of `composition` as JSON, the same however its tracks are ordered.
+pub fn composition_json(composition: &Composition) -> Value {
+    let mut tracks = composition.tracks.iter()
+        .map(|t| json!({
+            "instrument": t.instrument,
+            "events": t.events().iter().map(event_json).collect::<Vec<_>>(),
+        }))
+        .collect::<Vec<_>>();
+    tracks.sort_by_key(|t| t.to_string());
+    let TimeSignature(beats, unit) = composition.time_signature;
+    json!({
+        "time_signature": format!("{beats}/{unit}"),
+        "tracks": tracks,
+        "markers": composition.markers.iter()
+            .map(|m| json!({ "name": m.name, "time": format!("{}+{}", m.time.0, beat_json(m.time.1)) }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn pretty(composition: &Composition) -> String {
+    serde_json::to_string_pretty(&composition_json(composition)).unwrap()
+}
+
+/// Panics with both as JSON unless `actual` sounds the same as `expected`.
+#[track_caller]
+pub fn assert_same_music(actual: &Composition, expected: &Composition) {
+    let (actual, expected) = (pretty(actual), pretty(expected));
+    assert!(actual == expected, "music differs\n--- composed:\n{actual}\n--- expected:\n{expected}");
+}
+
+/// Panics unless `composition` sounds the same as the one kept in the golden file at `path`.
+/// With `UPDATE_GOLDEN` set, the file is written instead.
+#[track_caller]
+pub fn assert_golden(composition: &Composition, path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    let actual = pretty(composition) + "\n";
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Couldn't create {}: {e}", dir.display()));
+        }
+        std::fs::write(path, &actual).unwrap_or_else(|e| panic!("Couldn't write {}: {e}", path.display()));
+        return;
+    }
+    let expected = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Couldn't read golden file {}: {e}. Run with {UPDATE_GOLDEN_VAR}=1 to write it.", path.display()));
+    assert!(actual == expected,
+        "music differs from {}; run with {UPDATE_GOLDEN_VAR}=1 if that is expected\n--- composed:\n{actual}\n--- golden:\n{expected}",
+        path.display());
+}
+
+/// A sound a `MockPlayer` was asked to play, and when.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayedSound {
+    /// What the player's clock read when the sound was played.
+    pub at: Seconds,
+    pub sound: AtomicSound,
+}
+
+/// A player without a sound card or MIDI port, for playing through `run_midi` in tests. It keeps
+/// every sound it is given, timed on `clock`, which is usually a `VirtualClock` shared with the
+/// playback. Clones share the same log, so a clone can be kept to look at after playback.
+#[cfg(feature = "native")]
+#[derive(Debug, Clone)]
+pub struct MockPlayer<C> {
+    clock: C,
+    log: Arc<Mutex<MockLog>>,
+    output_latency: Seconds,
+    /// Stopped once this many sounds have been played, like someone pressing Ctrl-C.
+    stop_after: Option<(usize, StopToken)>,
+}
+
+#[cfg(feature = "native")]
+#[derive(Debug, Default)]
+struct MockLog {
+    played: Vec<PlayedSound>,
+    stops: usize,
+}
+
+#[cfg(feature = "native")]
+impl<C: Clock> MockPlayer<C> {
+    pub fn new(clock: C) -> Self {
+        MockPlayer { clock, log: Arc::default(), output_latency: 0., stop_after: None }
+    }
+
+    pub fn with_output_latency(mut self, latency: Seconds) -> Self {
+        self.output_latency = latency;
+        self
+    }
+
+    /// Stop `stop` once `sounds` sounds have been played.
+    pub fn stopping_after(mut self, sounds: usize, stop: StopToken) -> Self {
+        self.stop_after = Some((sounds, stop));
+        self
+    }
+
+    /// Everything played so far, in the order it was played.
+    pub fn played(&self) -> Vec<PlayedSound> {
+        self.log.lock().unwrap().played.clone()
+    }
+
+    /// How many times the player was told to silence its notes.
+    pub fn stops(&self) -> usize {
+        self.log.lock().unwrap().stops
+    }
+}
+
+#[cfg(feature = "native")]
+impl<C: Clock> AudioPlayer for MockPlayer<C> {
+    fn play(&mut self, sound: AtomicSound) {
+        let mut log = self.log.lock().unwrap();
+        log.played.push(PlayedSound { at: self.clock.elapsed(), sound });
+        if let Some((sounds, stop)) = &self.stop_after
+            && log.played.len() >= *sounds {
+            stop.stop();
+        }
+    }
+
+    fn output_latency(&self) -> Seconds {
+        self.output_latency
+    }
+
+    fn stop(&mut self) {
+        self.log.lock().unwrap().stops += 1;
+    }
+}
+
+/// Panics unless the music composes to the `expected` composition. Either a music string,
+/// `assert_composes_to!(":c :e", expected)`, or a grammar with the axiom and how many times
+/// to rewrite it, `assert_composes_to!(grammar, "S", 3, expected)`.
+#[macro_export]
+macro_rules! assert_composes_to {
+    ($music:expr, $expected:expr $(,)?) => {
+        $crate::testing::assert_same_music(&$crate::testing::compose($music), &$expected)
+    };
+    ($grammar:expr, $axiom:expr, $iterations:expr, $expected:expr $(,)?) => {
+        $crate::testing::assert_same_music(&$crate::testing::compose_grammar(&$grammar, $axiom, $iterations), &$expected)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::AssertUnwindSafe;
+    use std::str::FromStr;
+    use crate::cfg::Grammar;
+    use crate::composition::{Instrument, Pitch};
+    use crate::testing::{assert_golden, composition, compose, event, track};
+    use crate::time::{Beat, MusicTime};
+
+    #[test]
+    fn test_assert_composes_to() {
+        let expected = composition(vec![
+            track(Instrument::Bass, vec![
+                event(Pitch(3, 3), MusicTime::zero(), Beat::whole(2)),
+                event(Pitch(3, 7), MusicTime::beats(2), Beat::whole(1)),
+            ]),
+        ]);
+        assert_composes_to!("::i=Bass :3c<2> :3e", expected);
+        let grammar = Grammar::from_str("start S\nS = ::i=Bass A :3e\nA = :3c<2>").unwrap();
+        assert_composes_to!(grammar, "S", 2, expected);
+
+        let wrong = std::panic::catch_unwind(AssertUnwindSafe(|| assert_composes_to!("::i=Bass :3c :3e", expected)));
+        assert!(wrong.is_err());
+
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/two_voices.json");
+        assert_golden(&compose("{::i=Piano :c :e :g<2> | ::i=Bass :2c<4>}"), golden);
+    }
+}
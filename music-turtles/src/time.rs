@@ -1,5 +1,5 @@
 use std::fmt::Display;
-use std::ops::{Add, Mul, Sub};
+use std::ops::{Add, Div, Mul, Sub};
 use num::rational::Ratio;
 use num::{FromPrimitive, ToPrimitive, Zero};
 use serde::{Deserialize, Serialize, Serializer};
@@ -26,7 +26,7 @@ pub struct Beat(Ratio<BeatUnit>);
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct TimeSignature(pub BeatUnit, pub BeatUnit);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct TimeCompression(pub Ratio<isize>);
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -46,7 +46,7 @@ impl Beat {
 
     pub fn as_float(&self) -> f32 {
         self.0.to_f32().unwrap_or_else(|| {
-            println!("WARNING: Beat {self:?} could not be converted to f32. Defaulting to 0.");
+            warn!(beat = ?self, "Beat could not be converted to f32. Defaulting to 0.");
             0.
         })
     }
@@ -63,14 +63,14 @@ impl Beat {
 
     pub fn numerator(&self) -> BeatUnit {
         self.0.numer().to_u32().unwrap_or_else(|| {
-            println!("WARNING: Beat {self:?} numerator could not be converted to u32. Defaulting to 0.");
+            warn!(beat = ?self, "Beat numerator could not be converted to u32. Defaulting to 0.");
             0
         })
     }
 
     pub fn denominator(&self) -> BeatUnit {
         self.0.denom().to_u32().unwrap_or_else(|| {
-            println!("WARNING: Beat {self:?} denominator could not be converted to u32. Defaulting to 1.");
+            warn!(beat = ?self, "Beat denominator could not be converted to u32. Defaulting to 1.");
             1
         })
     }
@@ -136,6 +136,14 @@ impl Sub<Beat> for Beat {
     }
 }
 
+impl Div<BeatUnit> for Beat {
+    type Output = Beat;
+
+    fn div(self, rhs: BeatUnit) -> Self::Output {
+        Beat(self.0 / rhs)
+    }
+}
+
 impl Add<MusicTime> for MusicTimeWithSignature {
     type Output = MusicTime;
 
@@ -218,7 +226,7 @@ impl<'de> Deserialize<'de> for Beat {
         }
 
         let data = Beat::deserialize(deserializer)?;
-        Ok(crate::Beat::new(data.numerator, data.denominator))
+        Ok(crate::time::Beat::new(data.numerator, data.denominator))
     }
 }
 
@@ -0,0 +1,59 @@
+// Music as a list of notes for a browser to schedule with the Web Audio API, so the server only
+// has to send the notes once instead of streaming the sound.
+
+use serde::{Deserialize, Serialize};
+use crate::composition::{Composition, Frequency, Instrument, MAX_VOLUME};
+use crate::time::{Seconds, BPM};
+
+/// One note, timed in seconds from the start of the composition.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebAudioEvent {
+    pub start_s: Seconds,
+    pub dur_s: Seconds,
+    pub freq: Frequency,
+    /// Between 0 and 1, for a `GainNode`.
+    pub gain: f32,
+    pub instrument: Instrument,
+}
+
+/// Every note of `composition` played at `bpm`, in the order they start.
+pub fn web_audio_events(composition: &Composition, bpm: BPM) -> Vec<WebAudioEvent> {
+    let time_signature = composition.time_signature;
+    let mut events = composition.tracks.iter()
+        .flat_map(|track| track.events().iter().map(move |e| WebAudioEvent {
+            start_s: e.start.to_seconds(time_signature, bpm),
+            dur_s: e.duration.as_music_time(time_signature).to_seconds(time_signature, bpm),
+            freq: e.pitch.to_frequency(),
+            gain: e.volume.0.min(MAX_VOLUME) as f32 / MAX_VOLUME as f32,
+            instrument: track.instrument,
+        }))
+        .collect::<Vec<_>>();
+    events.sort_by(|a, b| a.start_s.total_cmp(&b.start_s));
+    events
+}
+
+/// `web_audio_events` as a JSON array.
+pub fn web_audio_json(composition: &Composition, bpm: BPM) -> String {
+    serde_json::to_string(&web_audio_events(composition, bpm)).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use crate::cfg::MusicString;
+    use crate::composition::Instrument;
+    use crate::time::TimeSignature;
+    use crate::web::{web_audio_events, web_audio_json};
+
+    #[test]
+    fn test_web_audio_events() {
+        let music = MusicString::from_str("::i=Bass ::v=50 :a<2> :_ :a").unwrap()
+            .compose(TimeSignature::common(), None)
+            .unwrap();
+        let events = web_audio_events(&music, 120.);
+        let timing = events.iter().map(|e| (e.start_s, e.dur_s, e.gain, e.instrument)).collect::<Vec<_>>();
+        assert_eq!(timing, vec![(0., 1., 0.5, Instrument::Bass), (1.5, 0.5, 0.5, Instrument::Bass)]);
+        assert_eq!(events[0].freq, music.tracks[0].events()[0].pitch.to_frequency());
+        assert!(web_audio_json(&music, 120.).starts_with(r#"[{"start_s":0.0,"dur_s":1.0,"freq":"#));
+    }
+}